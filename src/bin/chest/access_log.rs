@@ -0,0 +1,58 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Header carrying the request id, accepted from clients and proxies and echoed in responses
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id accepted from a client
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Reuses a well-formed incoming request id, so ids assigned by a reverse proxy stay the same
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    (!id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|b| b.is_ascii_graphic()))
+    .then(|| id.to_string())
+}
+
+/// Middleware assigning every request an id, returned in `X-Request-Id`, and logging its
+/// method, path, status and latency along with that id.
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = incoming_request_id(&req).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let peer = req
+        .peer_addr()
+        .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+    let started = Instant::now();
+
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    println!(
+        "{} {} {} {} {:.1}ms request_id={}",
+        peer,
+        method,
+        path,
+        status.as_u16(),
+        started.elapsed().as_secs_f64() * 1000.0,
+        id
+    );
+    let mut response = result?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
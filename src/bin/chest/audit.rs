@@ -0,0 +1,193 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, QueryBuilder, Sqlite, SqlitePool};
+
+use crate::auth::Principal;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::nostr;
+
+/// Number of entries returned per page of `GET /admin/audit` unless `limit` is given
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+
+/// Largest page of entries a client may request
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+/// Describes the authenticated caller for the audit log
+pub fn actor(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<Principal>()
+        .map(ToString::to_string)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Appends an entry to the audit log. `parameters` holds the details of the request that are
+/// not already in `target` and `reason`.
+pub async fn record<'c, E>(
+    executor: E,
+    actor: &str,
+    action: &str,
+    target: &str,
+    reason: Option<&str>,
+    parameters: Option<&Value>,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'c, Database = Sqlite>,
+{
+    sqlx::query(
+        "INSERT INTO audit_log (created_at, actor, action, target, reason, parameters)
+         VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(nostr::now() as i64)
+    .bind(actor)
+    .bind(action)
+    .bind(target)
+    .bind(reason)
+    .bind(parameters.map(Value::to_string))
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Records an admin read, such as viewing the configuration, with the request's query string
+/// as its parameters. The request fails when the entry cannot be written, so nothing is shown
+/// without leaving a trace.
+pub async fn record_view(
+    req: &HttpRequest,
+    write_pool: &WritePool,
+    action: &str,
+    target: &str,
+) -> Result<(), ApiError> {
+    let parameters = (!req.query_string().is_empty())
+        .then(|| serde_json::json!({ "query": req.query_string() }));
+    record(
+        &write_pool.0,
+        &actor(req),
+        action,
+        target,
+        None,
+        parameters.as_ref(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// An audit log entry
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct AuditEntry {
+    id: i64,
+    created_at: i64,
+    actor: String,
+    action: String,
+    target: String,
+    reason: Option<String>,
+    /// JSON object of the request details
+    #[serde(serialize_with = "serialize_parameters")]
+    parameters: Option<String>,
+}
+
+/// Embeds the stored parameters as JSON rather than as a string
+fn serialize_parameters<S: serde::Serializer>(
+    parameters: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    parameters
+        .as_deref()
+        .and_then(|parameters| serde_json::from_str::<Value>(parameters).ok())
+        .serialize(serializer)
+}
+
+/// Query parameters of `GET /admin/audit`
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    action: Option<String>,
+    actor: Option<String>,
+    target: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<i64>,
+}
+
+/// Lists the audit log, newest first, optionally filtered by action, actor, target and time.
+/// Pages are continued by passing the returned `next_cursor`, which is `null` on the last page.
+pub async fn list_audit(
+    req: HttpRequest,
+    params: web::Query<AuditQuery>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    record_view(&req, &write_pool, "view_audit", "").await?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT id, created_at, actor, action, target, reason, parameters FROM audit_log
+         WHERE 1 = 1",
+    );
+    for (column, value) in [
+        ("action", &params.action),
+        ("actor", &params.actor),
+        ("target", &params.target),
+    ] {
+        if let Some(value) = value {
+            query.push(format!(" AND {} = ", column));
+            query.push_bind(value.clone());
+        }
+    }
+    if let Some(since) = params.since {
+        query.push(" AND created_at >= ");
+        query.push_bind(since);
+    }
+    if let Some(until) = params.until {
+        query.push(" AND created_at <= ");
+        query.push_bind(until);
+    }
+    if let Some(cursor) = params.cursor {
+        query.push(" AND id < ");
+        query.push_bind(cursor);
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut entries: Vec<AuditEntry> = query.build_query_as().fetch_all(db_pool.get_ref()).await?;
+    let next_cursor = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "entries": entries,
+        "next_cursor": next_cursor,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn views_are_recorded_with_their_query() {
+        let write_pool = WritePool(crate::db::test_pool().await);
+        let req = TestRequest::get()
+            .uri("/admin/duplicates?campaigns=true")
+            .to_http_request();
+        req.extensions_mut().insert(Principal::ApiKey(0));
+        record_view(&req, &write_pool, "view_duplicates", "")
+            .await
+            .unwrap();
+        let (actor, action, parameters): (String, String, String) =
+            sqlx::query_as("SELECT actor, action, parameters FROM audit_log")
+                .fetch_one(&write_pool.0)
+                .await
+                .unwrap();
+        assert_eq!(actor, "api_key:0");
+        assert_eq!(action, "view_duplicates");
+        assert_eq!(parameters, r#"{"query":"campaigns=true"}"#);
+    }
+}
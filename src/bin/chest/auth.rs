@@ -0,0 +1,236 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, ResponseError};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::error::ApiError;
+use crate::nostr::{self, NostrEvent};
+use crate::{AppConfig, ArchiveConfig, AuthConfig};
+
+/// Event kind used for NIP-98 HTTP auth
+const HTTP_AUTH_KIND: u64 = 27235;
+
+/// Route prefixes that always require authentication (admin, publish, backfill, delete)
+const PROTECTED_PREFIXES: &[&str] = &["/admin", "/dms"];
+
+/// Identity of an authenticated caller, stored in the request extensions for handlers
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// Index of the matching key in `auth.api_keys`
+    ApiKey(usize),
+    Pubkey(String),
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::ApiKey(index) => write!(f, "api_key:{}", index),
+            Principal::Pubkey(pubkey) => write!(f, "pubkey:{}", pubkey),
+        }
+    }
+}
+
+/// Strips `server.base_path` and the `/{name}` prefix of the additional archives from a
+/// request path
+fn archive_path<'a>(path: &'a str, base_path: &str, archives: &[ArchiveConfig]) -> &'a str {
+    let path = path
+        .strip_prefix(base_path)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(path);
+    archives
+        .iter()
+        .find_map(|archive| {
+            path.strip_prefix('/')?
+                .strip_prefix(archive.name.as_str())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .unwrap_or(path)
+}
+
+/// Returns whether the request path needs credentials under the given configuration
+fn requires_auth(path: &str, config: &AuthConfig) -> bool {
+    config.protect_reads
+        || PROTECTED_PREFIXES
+            .iter()
+            .any(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)))
+}
+
+/// Compares two keys in constant time. Both are hashed first so that neither their contents
+/// nor their lengths affect the timing.
+fn keys_equal(a: &str, b: &str) -> bool {
+    Sha256::digest(a)
+        .iter()
+        .zip(Sha256::digest(b).iter())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+/// Validates a static API key sent as `Authorization: Bearer <key>`
+fn check_api_key(key: &str, config: &AuthConfig) -> Result<Principal, String> {
+    // Every key is compared so the timing does not reveal which one matched.
+    config
+        .api_keys
+        .iter()
+        .enumerate()
+        .fold(None, |found, (index, allowed)| {
+            found.or(keys_equal(allowed, key).then_some(index))
+        })
+        .map(Principal::ApiKey)
+        .ok_or_else(|| "Invalid API key".to_string())
+}
+
+/// Validates a NIP-98 auth event sent as `Authorization: Nostr <base64 event>`
+async fn check_nip98(
+    encoded: &str,
+    req: &mut ServiceRequest,
+    config: &AuthConfig,
+) -> Result<Principal, String> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| "Malformed NIP-98 header".to_string())?;
+    let event: NostrEvent =
+        serde_json::from_slice(&decoded).map_err(|_| "Malformed NIP-98 event".to_string())?;
+
+    if event.kind != HTTP_AUTH_KIND {
+        return Err("NIP-98 event has wrong kind".to_string());
+    }
+    if nostr::now().abs_diff(event.created_at) > config.nip98_max_age {
+        return Err("NIP-98 event is expired".to_string());
+    }
+
+    let url = {
+        let conn = req.connection_info();
+        format!("{}://{}{}", conn.scheme(), conn.host(), req.uri())
+    };
+    if event.tag_value("u") != Some(url.as_str()) {
+        return Err("NIP-98 event URL does not match request".to_string());
+    }
+    if event.tag_value("method") != Some(req.method().as_str()) {
+        return Err("NIP-98 event method does not match request".to_string());
+    }
+    if !config.admin_pubkeys.contains(&event.pubkey) {
+        return Err("Pubkey is not authorized".to_string());
+    }
+    event
+        .verify()
+        .map_err(|_| "Invalid NIP-98 signature".to_string())?;
+
+    if let Some(payload_hash) = event.tag_value("payload") {
+        let body = req
+            .extract::<web::Bytes>()
+            .await
+            .map_err(|_| "Failed to read request body".to_string())?;
+        let matches = hex::encode(Sha256::digest(&body)) == payload_hash;
+        req.set_payload(Payload::from(body));
+        if !matches {
+            return Err("NIP-98 payload hash does not match body".to_string());
+        }
+    }
+    Ok(Principal::Pubkey(event.pubkey))
+}
+
+/// Checks the request's `Authorization` header against configured API keys and NIP-98 pubkeys
+async fn authorize(req: &mut ServiceRequest, config: &AuthConfig) -> Result<Principal, String> {
+    let header = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| "Missing Authorization header".to_string())?;
+
+    match header.split_once(' ') {
+        Some(("Bearer", key)) => check_api_key(key.trim(), config),
+        Some(("Nostr", encoded)) => check_nip98(encoded.trim(), req, config).await,
+        _ => Err("Unsupported authorization scheme".to_string()),
+    }
+}
+
+/// Returns the path the router matches the request on: percent-decoded, so that `/%61dmin` is
+/// checked as `/admin`, with repeated slashes collapsed
+fn routed_path(req: &ServiceRequest) -> String {
+    let mut path = String::with_capacity(req.match_info().as_str().len());
+    for c in req.match_info().as_str().chars() {
+        if !(c == '/' && path.ends_with('/')) {
+            path.push(c);
+        }
+    }
+    path
+}
+
+/// Middleware enforcing authentication on protected routes (and on reads when configured).
+pub async fn auth_guard(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let routed = routed_path(&req);
+    let (config, path) = match req.app_data::<web::Data<AppConfig>>() {
+        Some(config) => (
+            config.auth.clone(),
+            archive_path(&routed, &config.server.base_path(), &config.archives).to_string(),
+        ),
+        None => (AuthConfig::default(), routed),
+    };
+
+    if requires_auth(&path, &config) {
+        match authorize(&mut req, &config).await {
+            Ok(principal) => {
+                req.extensions_mut().insert(principal);
+            }
+            Err(reason) => {
+                let response = ApiError::Unauthorized(reason).error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn encoded_prefixes_require_auth() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(auth_guard))
+                .route("/admin/config", web::get().to(HttpResponse::Ok))
+                .route("/dms", web::get().to(HttpResponse::Ok))
+                .route("/notes", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for uri in [
+            "/admin/config",
+            "/%61dmin/config",
+            "/%61%64%6D%69%6E/config",
+            "/%64ms",
+        ] {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), 401, "{}", uri);
+        }
+        let response = call_service(&app, TestRequest::get().uri("/notes").to_request()).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn api_keys_are_matched_exactly() {
+        let config = AuthConfig {
+            api_keys: vec!["first".to_string(), "second".to_string()],
+            ..AuthConfig::default()
+        };
+        assert!(matches!(
+            check_api_key("second", &config),
+            Ok(Principal::ApiKey(1))
+        ));
+        assert!(check_api_key("secon", &config).is_err());
+        assert!(check_api_key("", &config).is_err());
+    }
+}
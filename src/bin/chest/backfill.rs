@@ -0,0 +1,348 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::health::RelayHealth;
+use crate::subscriptions::SubscriptionRegistry;
+use crate::{nostr, SubscriptionConfig};
+
+/// Part of a relay's history a walk is requesting
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Events published while chest was not running, down to where the previous run started
+    Gap,
+    /// Older events, down to `backfill_since`
+    History,
+}
+
+/// A walk backwards through a relay's history, one page REQ at a time
+#[derive(Debug)]
+struct Walk {
+    phase: Phase,
+    /// Subscription id of the page being requested
+    subscription_id: String,
+    /// `until` of the page being requested
+    until: u64,
+    /// Lower bound of the phase
+    since: u64,
+    /// Events received for the page so far, and the oldest of them
+    received: usize,
+    oldest: Option<u64>,
+    /// Where the history phase resumes after the gap, if it has not reached the end yet
+    resume_until: Option<u64>,
+}
+
+/// Backfill progress of a relay, as stored: the history between `oldest` and `newest` was
+/// walked for the given kinds
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct BackfillProgress {
+    relay_url: String,
+    /// JSON array of the kinds walked; a walk for other kinds starts over
+    kinds: String,
+    newest: i64,
+    oldest: i64,
+    /// Whether the walk reached `backfill_since` or the start of the relay's history
+    done: bool,
+    events: i64,
+    updated_at: i64,
+}
+
+/// Walks the history of every relay backwards in pages of `limit` events with a sliding
+/// `until`, instead of taking whatever backlog a relay sends for an open-ended REQ. Progress is
+/// stored after every page, so a restart resumes where the walk stopped and first fills the
+/// gap since the previous run.
+#[derive(Debug)]
+pub struct Backfill {
+    db_pool: SqlitePool,
+    kinds: Vec<u64>,
+    page_size: usize,
+    floor: u64,
+    /// Unix time the live subscriptions start from; the walks cover everything before
+    pub started_at: u64,
+    relay_urls: Vec<String>,
+    walks: Mutex<HashMap<String, Walk>>,
+}
+
+impl Backfill {
+    pub fn new(
+        config: &SubscriptionConfig,
+        kinds: &[u64],
+        relay_urls: Vec<String>,
+        db_pool: SqlitePool,
+    ) -> Self {
+        let mut kinds = kinds.to_vec();
+        kinds.sort_unstable();
+        kinds.dedup();
+        Self {
+            db_pool,
+            kinds,
+            page_size: config.limit.max(1),
+            floor: config.backfill_since,
+            started_at: nostr::now(),
+            relay_urls,
+            walks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the number of events per page on a relay, at most its advertised `max_limit`
+    fn page_size(&self, relay_url: &str, registry: &SubscriptionRegistry) -> usize {
+        registry
+            .max_limit(relay_url)
+            .map_or(self.page_size, |max_limit| {
+                self.page_size.min(max_limit.max(1) as usize)
+            })
+    }
+
+    /// Builds the REQ of the page a walk is at
+    fn page_request(&self, walk: &Walk, page_size: usize) -> Value {
+        let mut filter = serde_json::json!({
+            "kinds": self.kinds,
+            "until": walk.until,
+            "limit": page_size,
+        });
+        if walk.since > 0 {
+            filter["since"] = walk.since.into();
+        }
+        serde_json::json!(["REQ", walk.subscription_id, filter])
+    }
+
+    /// Tracks a walk and requests the page it is at
+    async fn request_page(&self, relay_url: &str, walk: Walk, registry: &SubscriptionRegistry) {
+        let req_message = self.page_request(&walk, self.page_size(relay_url, registry));
+        self.walks
+            .lock()
+            .unwrap()
+            .insert(relay_url.to_string(), walk);
+        if let Err(e) = registry.request(relay_url, req_message).await {
+            eprintln!("Error sending backfill request to {}: {}", relay_url, e);
+            self.walks.lock().unwrap().remove(relay_url);
+        }
+    }
+
+    /// Starts or resumes the walk of every connected relay, the healthiest first. Paused relays
+    /// are left for a later start.
+    pub async fn start(
+        &self,
+        registry: &SubscriptionRegistry,
+        health: &RelayHealth,
+    ) -> Result<(), sqlx::Error> {
+        let kinds = serde_json::to_string(&self.kinds).unwrap_or_default();
+        for relay_url in health.ranked(&self.relay_urls) {
+            let progress: Option<BackfillProgress> = sqlx::query_as(
+                "SELECT relay_url, kinds, newest, oldest, done, events, updated_at FROM backfill
+                 WHERE relay_url = ?",
+            )
+            .bind(relay_url)
+            .fetch_optional(&self.db_pool)
+            .await?;
+            let walk = match progress.filter(|progress| progress.kinds == kinds) {
+                Some(progress) => {
+                    let resume_until = (!progress.done).then_some(progress.oldest as u64);
+                    let newest = progress.newest as u64;
+                    if newest < self.started_at {
+                        Walk {
+                            phase: Phase::Gap,
+                            subscription_id: Uuid::new_v4().to_string(),
+                            until: self.started_at,
+                            since: newest,
+                            received: 0,
+                            oldest: None,
+                            resume_until,
+                        }
+                    } else if let Some(until) = resume_until {
+                        self.history_walk(until)
+                    } else {
+                        continue;
+                    }
+                }
+                None => {
+                    sqlx::query(
+                        "INSERT OR REPLACE INTO backfill
+                             (relay_url, kinds, newest, oldest, done, events, updated_at)
+                         VALUES (?, ?, ?, ?, 0, 0, ?)",
+                    )
+                    .bind(relay_url)
+                    .bind(&kinds)
+                    .bind(self.started_at as i64)
+                    .bind(self.started_at as i64)
+                    .bind(self.started_at as i64)
+                    .execute(&self.db_pool)
+                    .await?;
+                    self.history_walk(self.started_at)
+                }
+            };
+            println!(
+                "Backfilling {} from {} in pages of {} events",
+                relay_url,
+                walk.until,
+                self.page_size(relay_url, registry)
+            );
+            self.request_page(relay_url, walk, registry).await;
+        }
+        Ok(())
+    }
+
+    /// Returns a walk through the history older than `until`
+    fn history_walk(&self, until: u64) -> Walk {
+        Walk {
+            phase: Phase::History,
+            subscription_id: Uuid::new_v4().to_string(),
+            until,
+            since: self.floor,
+            received: 0,
+            oldest: None,
+            resume_until: None,
+        }
+    }
+
+    /// Counts an event received for a page
+    pub fn record_event(&self, relay_url: &str, subscription_id: &str, created_at: u64) {
+        if let Some(walk) = self
+            .walks
+            .lock()
+            .unwrap()
+            .get_mut(relay_url)
+            .filter(|walk| walk.subscription_id == subscription_id)
+        {
+            walk.received += 1;
+            walk.oldest = Some(
+                walk.oldest
+                    .map_or(created_at, |oldest| oldest.min(created_at)),
+            );
+        }
+    }
+
+    /// Closes a page the relay finished sending, stores the progress and requests the next
+    /// page, unless the relay was paused meanwhile. A page with fewer events than requested
+    /// ends the phase.
+    pub async fn end_of_page(
+        &self,
+        relay_url: &str,
+        subscription_id: &str,
+        registry: &SubscriptionRegistry,
+        health: &RelayHealth,
+    ) -> Result<(), sqlx::Error> {
+        let Some(walk) = ({
+            let mut walks = self.walks.lock().unwrap();
+            match walks.get(relay_url) {
+                Some(walk) if walk.subscription_id == subscription_id => walks.remove(relay_url),
+                _ => None,
+            }
+        }) else {
+            return Ok(());
+        };
+        registry.close(relay_url, subscription_id).await;
+
+        // Events sharing the oldest second may continue on the next page; a full page of a
+        // single second moves on to avoid requesting it forever.
+        let next_until = match walk.oldest {
+            Some(oldest) if oldest < walk.until => oldest,
+            _ => walk.until.saturating_sub(1),
+        };
+        let finished =
+            walk.received < self.page_size(relay_url, registry) || next_until <= walk.since;
+        let now = nostr::now() as i64;
+        let next = match (walk.phase, finished) {
+            (Phase::Gap, false) => Some(Walk {
+                subscription_id: Uuid::new_v4().to_string(),
+                until: next_until,
+                received: 0,
+                oldest: None,
+                ..walk
+            }),
+            (Phase::Gap, true) => {
+                sqlx::query(
+                    "UPDATE backfill SET newest = ?, events = events + ?, updated_at = ?
+                     WHERE relay_url = ?",
+                )
+                .bind(self.started_at as i64)
+                .bind(walk.received as i64)
+                .bind(now)
+                .bind(relay_url)
+                .execute(&self.db_pool)
+                .await?;
+                walk.resume_until.map(|until| self.history_walk(until))
+            }
+            (Phase::History, false) => {
+                sqlx::query(
+                    "UPDATE backfill SET oldest = ?, events = events + ?, updated_at = ?
+                     WHERE relay_url = ?",
+                )
+                .bind(next_until as i64)
+                .bind(walk.received as i64)
+                .bind(now)
+                .bind(relay_url)
+                .execute(&self.db_pool)
+                .await?;
+                Some(Walk {
+                    subscription_id: Uuid::new_v4().to_string(),
+                    until: next_until,
+                    received: 0,
+                    oldest: None,
+                    ..walk
+                })
+            }
+            (Phase::History, true) => {
+                sqlx::query(
+                    "UPDATE backfill SET oldest = ?, done = 1, events = events + ?, updated_at = ?
+                     WHERE relay_url = ?",
+                )
+                .bind(walk.since as i64)
+                .bind(walk.received as i64)
+                .bind(now)
+                .bind(relay_url)
+                .execute(&self.db_pool)
+                .await?;
+                println!("Backfill of {} complete", relay_url);
+                None
+            }
+        };
+        match next {
+            Some(_) if health.is_paused(relay_url) => {
+                println!("Backfill of {} paused with the relay", relay_url)
+            }
+            Some(next) => self.request_page(relay_url, next, registry).await,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Stops the walk of a relay that closed its page, e.g. when rate limited; it resumes from
+    /// the stored progress on the next start
+    pub fn closed(&self, relay_url: &str, subscription_id: &str) {
+        let mut walks = self.walks.lock().unwrap();
+        if walks
+            .get(relay_url)
+            .is_some_and(|walk| walk.subscription_id == subscription_id)
+        {
+            walks.remove(relay_url);
+            eprintln!(
+                "Backfill of {} stopped: the relay closed the request",
+                relay_url
+            );
+        }
+    }
+}
+
+/// Reports the backfill progress of every relay.
+pub async fn backfill_status(
+    req: HttpRequest,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_backfill", "").await?;
+    let progress: Vec<BackfillProgress> = sqlx::query_as(
+        "SELECT relay_url, kinds, newest, oldest, done, events, updated_at FROM backfill
+         ORDER BY relay_url",
+    )
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    Ok(HttpResponse::Ok().json(progress))
+}
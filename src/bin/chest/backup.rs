@@ -0,0 +1,226 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use cron::Schedule;
+use serde::Serialize;
+use sqlx::{ConnectOptions, Connection};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{db, nostr, s3, BackupConfig, DatabaseConfig};
+
+/// Prefix and extension of the snapshot files written to the backup directory
+const FILE_PREFIX: &str = "chest-";
+const FILE_EXTENSION: &str = ".db";
+
+/// Outcome of the scheduled backups, reported by `GET /admin/backups`
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BackupStatus {
+    /// Unix time of the last backup attempt
+    last_attempt: Option<i64>,
+    /// Unix time of the last successful backup
+    last_success: Option<i64>,
+    /// Snapshot written by the last successful backup
+    last_file: Option<String>,
+    /// Error of the last attempt, if it failed
+    last_error: Option<String>,
+    /// Object key of the last snapshot uploaded offsite
+    last_upload: Option<String>,
+    /// Error of the last upload, if it failed
+    last_upload_error: Option<String>,
+    /// Unix time of the next scheduled backup
+    next_run: Option<i64>,
+}
+
+/// A snapshot in the backup directory
+#[derive(Debug, Serialize)]
+struct BackupFile {
+    name: String,
+    size: u64,
+    created_at: i64,
+}
+
+/// Periodic snapshots of the archive taken with `VACUUM INTO`
+#[derive(Debug)]
+pub struct Backups {
+    config: BackupConfig,
+    database: DatabaseConfig,
+    status: Mutex<BackupStatus>,
+}
+
+impl Backups {
+    pub fn new(config: BackupConfig, database: DatabaseConfig) -> Self {
+        Self {
+            config,
+            database,
+            status: Mutex::new(BackupStatus::default()),
+        }
+    }
+
+    /// Writes a consistent snapshot of the database to the backup directory, returning its path
+    async fn run(&self) -> Result<PathBuf, String> {
+        let directory = Path::new(&self.config.directory);
+        std::fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+        let name = format!(
+            "{}{}{}",
+            FILE_PREFIX,
+            Utc::now().format("%Y%m%dT%H%M%SZ"),
+            FILE_EXTENSION
+        );
+        let path = directory.join(name);
+        // VACUUM INTO refuses to overwrite, and a partial file must never look like a snapshot.
+        let partial = path.with_extension("db.partial");
+        let _ = std::fs::remove_file(&partial);
+
+        // A dedicated connection keeps the write pool free while the snapshot is taken.
+        let mut conn = db::connect_options(&self.database, false)
+            .map_err(|e| format!("Invalid database options: {}", e))?
+            .connect()
+            .await
+            .map_err(|e| format!("Failed to connect to the database: {}", e))?;
+        let result = sqlx::query("VACUUM INTO ?")
+            .bind(partial.to_string_lossy().into_owned())
+            .execute(&mut conn)
+            .await;
+        let _ = conn.close().await;
+        result.map_err(|e| format!("Failed to write snapshot: {}", e))?;
+
+        std::fs::rename(&partial, &path)
+            .map_err(|e| format!("Failed to move {}: {}", partial.display(), e))?;
+        Ok(path)
+    }
+
+    /// Copies a snapshot to the configured object storage, returning its object key
+    async fn upload(&self, path: &Path) -> Result<String, String> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        s3::upload(&self.config.s3, path, &name).await
+    }
+
+    /// Lists the snapshots in the backup directory, oldest first
+    fn list(&self) -> Vec<BackupFile> {
+        let Ok(entries) = std::fs::read_dir(&self.config.directory) else {
+            return Vec::new();
+        };
+        let mut files: Vec<BackupFile> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with(FILE_PREFIX) || !name.ends_with(FILE_EXTENSION) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                let created_at = metadata
+                    .modified()
+                    .ok()?
+                    .duration_since(UNIX_EPOCH)
+                    .ok()?
+                    .as_secs() as i64;
+                Some(BackupFile {
+                    name,
+                    size: metadata.len(),
+                    created_at,
+                })
+            })
+            .collect();
+        // Names embed the UTC time of the snapshot, so they sort chronologically.
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        files
+    }
+
+    /// Deletes the oldest snapshots beyond the configured number of rotations
+    fn rotate(&self) {
+        let files = self.list();
+        let excess = files.len().saturating_sub(self.config.keep.max(1));
+        for file in &files[..excess] {
+            let path = Path::new(&self.config.directory).join(&file.name);
+            if let Err(e) = std::fs::remove_file(&path) {
+                eprintln!("Failed to remove old backup {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Takes a backup at every time matching the configured cron schedule (UTC)
+    pub fn spawn(self: Arc<Self>) {
+        if !self.config.enabled {
+            return;
+        }
+        let schedule = match Schedule::from_str(&self.config.schedule) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                eprintln!(
+                    "Backups disabled: invalid schedule {:?}: {}",
+                    self.config.schedule, e
+                );
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while let Some(next) = schedule.upcoming(Utc).next() {
+                self.status.lock().unwrap().next_run = Some(next.timestamp());
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+
+                let result = self.run().await;
+                let upload = match &result {
+                    Ok(path) if self.config.s3.enabled => Some(self.upload(path).await),
+                    _ => None,
+                };
+                let mut status = self.status.lock().unwrap();
+                status.last_attempt = Some(nostr::now() as i64);
+                match result {
+                    Ok(path) => {
+                        println!("Database backed up to {}", path.display());
+                        status.last_success = status.last_attempt;
+                        status.last_file = Some(path.display().to_string());
+                        status.last_error = None;
+                    }
+                    Err(e) => {
+                        eprintln!("Backup failed: {}", e);
+                        status.last_error = Some(e);
+                    }
+                }
+                match upload {
+                    Some(Ok(key)) => {
+                        println!(
+                            "Backup uploaded to bucket {} as {}",
+                            self.config.s3.bucket, key
+                        );
+                        status.last_upload = Some(key);
+                        status.last_upload_error = None;
+                    }
+                    Some(Err(e)) => {
+                        eprintln!("Backup upload failed: {}", e);
+                        status.last_upload_error = Some(e);
+                    }
+                    None => {}
+                }
+                drop(status);
+                self.rotate();
+            }
+        });
+    }
+}
+
+/// Reports the state of the scheduled backups and the snapshots kept.
+pub async fn backup_status(
+    req: HttpRequest,
+    backups: web::Data<Backups>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_backups", "").await?;
+    let status = backups.status.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": backups.config.enabled,
+        "schedule": backups.config.schedule,
+        "status": status,
+        "backups": backups.list(),
+    })))
+}
@@ -0,0 +1,190 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::error::ApiError;
+use crate::ingest::{BADGE_AWARD_KIND, BADGE_DEFINITION_KIND, PROFILE_BADGES_KIND};
+use crate::{etag, nostr};
+
+/// `d` tag of the kind 30008 event listing the badges a profile displays
+const PROFILE_BADGES_D_TAG: &str = "profile_badges";
+
+/// A badge a profile accepted, resolved to its definition
+#[derive(Debug, Serialize)]
+struct ProfileBadge {
+    /// `30009:<issuer>:<d tag>` address of the badge definition
+    address: String,
+    issuer: String,
+    /// Id of the kind 8 award
+    award_id: String,
+    awarded_at: i64,
+    name: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    /// `thumb` tags of the definition, largest first as published
+    thumbs: Vec<String>,
+}
+
+/// Returns the value of the first tag with the given name
+fn tag(tags: &[Vec<String>], name: &str) -> Option<String> {
+    tags.iter()
+        .find(|tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| tag[1].clone())
+}
+
+/// Returns the badges a pubkey accepted in its kind 30008 profile badges event, in the order it
+/// lists them. Only awards archived with their definition, issued by the definition's author to
+/// the pubkey, are returned.
+pub async fn profile_badges(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let db_pool = db_pool.get_ref();
+
+    let accepted: Option<(String, String)> = sqlx::query_as(
+        "SELECT event_id, tags FROM public_events AS events
+         WHERE folder = 'badges' AND kind = ? AND pubkey = ? AND d_tag = ?",
+    )
+    .bind(PROFILE_BADGES_KIND as i64)
+    .bind(&pubkey)
+    .bind(PROFILE_BADGES_D_TAG)
+    .fetch_optional(db_pool)
+    .await?;
+    let Some((accepted_id, tags)) = accepted else {
+        return Ok(etag::json_with_etag(
+            &req,
+            etag::list_etag([]),
+            &Vec::<ProfileBadge>::new(),
+        ));
+    };
+    let tags: Vec<Vec<String>> = serde_json::from_str(&tags).unwrap_or_default();
+
+    // Accepted badges are listed as an `a` tag naming the definition followed by an `e` tag
+    // naming the award.
+    let mut badges = Vec::new();
+    let mut etag_ids = vec![accepted_id];
+    for pair in tags.windows(2) {
+        let (a, e) = (&pair[0], &pair[1]);
+        if a.len() < 2 || a[0] != "a" || e.len() < 2 || e[0] != "e" {
+            continue;
+        }
+        let Some(address) =
+            nostr::parse_coordinate(&a[1]).filter(|address| address.kind == BADGE_DEFINITION_KIND)
+        else {
+            continue;
+        };
+        let award: Option<(i64,)> = sqlx::query_as(
+            "SELECT created_at FROM public_events AS award
+             WHERE award.folder = 'badge_awards' AND award.kind = ? AND award.event_id = ?
+               AND award.pubkey = ? AND award.ref_address = ?
+               AND EXISTS (
+                 SELECT 1 FROM json_each(award.tags) AS tag
+                 WHERE json_extract(tag.value, '$[0]') = 'p'
+                   AND json_extract(tag.value, '$[1]') = ?
+               )",
+        )
+        .bind(BADGE_AWARD_KIND as i64)
+        .bind(&e[1])
+        .bind(&address.pubkey)
+        .bind(address.coordinate())
+        .bind(&pubkey)
+        .fetch_optional(db_pool)
+        .await?;
+        let Some((awarded_at,)) = award else {
+            continue;
+        };
+        let definition: Option<(String, String)> = sqlx::query_as(
+            "SELECT event_id, tags FROM public_events AS events
+             WHERE folder = 'badges' AND kind = ? AND pubkey = ? AND d_tag = ?",
+        )
+        .bind(BADGE_DEFINITION_KIND as i64)
+        .bind(&address.pubkey)
+        .bind(&address.identifier)
+        .fetch_optional(db_pool)
+        .await?;
+        let Some((definition_id, definition_tags)) = definition else {
+            continue;
+        };
+        let definition_tags: Vec<Vec<String>> =
+            serde_json::from_str(&definition_tags).unwrap_or_default();
+
+        etag_ids.push(e[1].clone());
+        etag_ids.push(definition_id);
+        badges.push(ProfileBadge {
+            address: address.coordinate(),
+            issuer: address.pubkey,
+            award_id: e[1].clone(),
+            awarded_at,
+            name: tag(&definition_tags, "name"),
+            description: tag(&definition_tags, "description"),
+            image: tag(&definition_tags, "image"),
+            thumbs: definition_tags
+                .iter()
+                .filter(|tag| tag.len() >= 2 && tag[0] == "thumb")
+                .map(|tag| tag[1].clone())
+                .collect(),
+        });
+    }
+
+    let etag = etag::list_etag(etag_ids.iter().map(String::as_str));
+    Ok(etag::json_with_etag(&req, etag, &badges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    fn tag(name: &str, value: &str) -> Vec<String> {
+        vec![name.to_string(), value.to_string()]
+    }
+
+    #[actix_web::test]
+    async fn only_awards_from_the_issuer_are_listed() {
+        let db_pool = db::test_pool().await;
+        let profile = "f".repeat(64);
+        let issuer = "1".repeat(64);
+        let address = format!("{}:{}:hero", BADGE_DEFINITION_KIND, issuer);
+        let mut definition = db::test_event('a', BADGE_DEFINITION_KIND);
+        definition.pubkey = issuer.clone();
+        definition.tags = vec![tag("d", "hero"), tag("name", "Hero")];
+        let award = |id: char, author: &str| {
+            let mut award = db::test_event(id, BADGE_AWARD_KIND);
+            award.pubkey = author.to_string();
+            award.tags = vec![tag("a", &address), tag("p", &profile)];
+            award
+        };
+        let genuine = award('b', &issuer);
+        let forged = award('c', &"2".repeat(64));
+        let mut accepted = db::test_event('d', PROFILE_BADGES_KIND);
+        accepted.tags = vec![
+            tag("d", PROFILE_BADGES_D_TAG),
+            tag("a", &address),
+            tag("e", &forged.id),
+            tag("a", &address),
+            tag("e", &genuine.id),
+        ];
+        db::store_test_events(&db_pool, &[&definition, &genuine, &forged, &accepted]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/profiles/{pubkey}/badges", web::get().to(profile_badges)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/profiles/{}/badges", profile))
+            .to_request();
+        let badges: Vec<Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(badges.len(), 1);
+        assert_eq!(badges[0]["award_id"], genuine.id.as_str());
+        assert_eq!(badges[0]["name"], "Hero");
+    }
+}
@@ -0,0 +1,44 @@
+use sqlx::SqlitePool;
+
+use crate::nostr;
+
+/// Kind of the replaceable event listing the Blossom servers a user uploads blobs to
+pub const SERVER_LIST_KIND: u64 = 10063;
+
+/// Returns the Blossom servers a pubkey announced in its archived kind 10063 event, in order of
+/// preference
+pub async fn servers(db_pool: &SqlitePool, pubkey: &str) -> Result<Vec<String>, sqlx::Error> {
+    let servers: Vec<(String,)> = sqlx::query_as(
+        "SELECT json_extract(tag.value, '$[1]')
+         FROM public_events AS events, json_each(events.tags) AS tag
+         WHERE events.folder = 'lists' AND events.kind = ? AND events.pubkey = ?
+           AND json_extract(tag.value, '$[0]') = 'server'
+         ORDER BY tag.key",
+    )
+    .bind(SERVER_LIST_KIND as i64)
+    .bind(pubkey)
+    .fetch_all(db_pool)
+    .await?;
+    Ok(servers
+        .into_iter()
+        .map(|(server,)| server)
+        .filter(|server| server.starts_with("https://") || server.starts_with("http://"))
+        .collect())
+}
+
+/// URL of a blob on a Blossom server
+pub fn blob_url(server: &str, sha256: &str) -> String {
+    format!("{}/{}", server.trim_end_matches('/'), sha256)
+}
+
+/// Returns the SHA-256 hash a Blossom URL names in its last path segment, e.g.
+/// `https://cdn.example.com/<sha256>.png`
+pub fn blob_hash(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let name = url.path_segments()?.next_back()?;
+    let hash = name
+        .split_once('.')
+        .map_or(name, |(hash, _)| hash)
+        .to_lowercase();
+    nostr::is_hex32(&hash).then_some(hash)
+}
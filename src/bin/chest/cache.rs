@@ -0,0 +1,94 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use crate::{CacheConfig, DbEvent};
+
+/// In-memory LRU caches for single-event lookups, shared across HTTP workers and the relay
+/// listeners that invalidate them
+#[derive(Debug)]
+pub struct EventCache {
+    /// Events by id; `None` when disabled
+    events: Option<Mutex<LruCache<String, DbEvent>>>,
+    /// Latest profile (kind 0) by pubkey; `None` when disabled
+    profiles: Option<Mutex<LruCache<String, DbEvent>>>,
+}
+
+impl EventCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        let lru = |capacity| NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap)));
+        Self {
+            events: lru(config.events),
+            profiles: lru(config.profiles),
+        }
+    }
+
+    /// Returns the cached event with the given id
+    pub fn event(&self, event_id: &str) -> Option<DbEvent> {
+        self.events.as_ref()?.lock().unwrap().get(event_id).cloned()
+    }
+
+    pub fn insert_event(&self, event: &DbEvent) {
+        if let Some(events) = &self.events {
+            events
+                .lock()
+                .unwrap()
+                .put(event.event_id.clone(), event.clone());
+        }
+    }
+
+    /// Returns the cached profile of a pubkey
+    pub fn profile(&self, pubkey: &str) -> Option<DbEvent> {
+        self.profiles.as_ref()?.lock().unwrap().get(pubkey).cloned()
+    }
+
+    pub fn insert_profile(&self, event: &DbEvent) {
+        if let Some(profiles) = &self.profiles {
+            profiles
+                .lock()
+                .unwrap()
+                .put(event.pubkey.clone(), event.clone());
+        }
+    }
+
+    /// Forgets an event that was deleted from the archive, including a profile cached under
+    /// its pubkey
+    pub fn invalidate_event(&self, event_id: &str) {
+        if let Some(events) = &self.events {
+            events.lock().unwrap().pop(event_id);
+        }
+        if let Some(profiles) = &self.profiles {
+            let mut profiles = profiles.lock().unwrap();
+            let stale = profiles
+                .iter()
+                .find(|(_, event)| event.event_id == event_id)
+                .map(|(pubkey, _)| pubkey.clone());
+            if let Some(pubkey) = stale {
+                profiles.pop(&pubkey);
+            }
+        }
+    }
+
+    /// Forgets the profile of a pubkey after it was replaced or deleted
+    pub fn invalidate_profile(&self, pubkey: &str) {
+        if let Some(profiles) = &self.profiles {
+            profiles.lock().unwrap().pop(pubkey);
+        }
+    }
+
+    /// Forgets every cached event and the profile of a pubkey whose events were deleted
+    pub fn invalidate_pubkey(&self, pubkey: &str) {
+        self.invalidate_profile(pubkey);
+        if let Some(events) = &self.events {
+            let mut events = events.lock().unwrap();
+            let stale: Vec<String> = events
+                .iter()
+                .filter(|(_, event)| event.pubkey == pubkey)
+                .map(|(event_id, _)| event_id.clone())
+                .collect();
+            for event_id in stale {
+                events.pop(&event_id);
+            }
+        }
+    }
+}
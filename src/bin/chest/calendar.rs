@@ -0,0 +1,367 @@
+use actix_web::http::header::ETAG;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, SqlitePool};
+
+use crate::error::ApiError;
+use crate::ingest::{DATE_CALENDAR_EVENT_KIND, TIME_CALENDAR_EVENT_KIND};
+use crate::{etag, nostr, stream, DbEvent, OutputFormat};
+
+/// Unix time a calendar event starts at: the `start` tag is a unix timestamp for time-based
+/// events and a `YYYY-MM-DD` date for date-based ones
+const START_EXPRESSION: &str = "(SELECT CASE events.kind
+        WHEN 31923 THEN CAST(json_extract(tag.value, '$[1]') AS INTEGER)
+        ELSE CAST(strftime('%s', json_extract(tag.value, '$[1]')) AS INTEGER)
+    END
+    FROM json_each(events.tags) AS tag WHERE json_extract(tag.value, '$[0]') = 'start' LIMIT 1)";
+
+/// Query parameters of `GET /calendar/{pubkey}`
+#[derive(Debug, Deserialize)]
+pub struct CalendarQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Only events starting at or after this Unix time
+    since: Option<i64>,
+    /// Only events starting at or before this Unix time
+    until: Option<i64>,
+}
+
+/// Builds the query selecting the calendar events of a pubkey, earliest start first
+fn calendar_query(
+    columns: &str,
+    pubkey: &str,
+    since: Option<i64>,
+    until: Option<i64>,
+) -> QueryBuilder<'static, sqlx::Sqlite> {
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM public_events AS events WHERE folder = 'calendar' AND pubkey = ",
+        columns
+    ));
+    query.push_bind(pubkey.to_string());
+    if let Some(since) = since {
+        query.push(format!(" AND {} >= ", START_EXPRESSION));
+        query.push_bind(since);
+    }
+    if let Some(until) = until {
+        query.push(format!(" AND {} <= ", START_EXPRESSION));
+        query.push_bind(until);
+    }
+    query.push(format!(" ORDER BY {} ASC, event_id ASC", START_EXPRESSION));
+    query
+}
+
+/// Lists the NIP-52 date and time-based calendar events a pubkey published, earliest start
+/// first.
+pub async fn list_calendar(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<CalendarQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let CalendarQuery {
+        format,
+        since,
+        until,
+    } = params.into_inner();
+    stream::event_listing(&req, db_pool.get_ref(), format, move |columns| {
+        calendar_query(columns, &pubkey, since, until)
+    })
+    .await
+}
+
+/// Query parameters of `GET /calendar/{pubkey}/{d}/rsvps`
+#[derive(Debug, Deserialize)]
+pub struct RsvpQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Only RSVPs with this `status` tag: `accepted`, `declined` or `tentative`
+    status: Option<String>,
+}
+
+/// Lists the NIP-52 RSVPs to a calendar event, i.e. its attendee list, oldest first. Each
+/// attendee's latest RSVP replaces the previous ones.
+pub async fn list_rsvps(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<RsvpQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (input, identifier) = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    // The address names the kind, and RSVPs may answer either kind of calendar event.
+    let coordinates: Vec<String> = [DATE_CALENDAR_EVENT_KIND, TIME_CALENDAR_EVENT_KIND]
+        .into_iter()
+        .map(|kind| {
+            nostr::Address {
+                kind,
+                pubkey: pubkey.clone(),
+                identifier: identifier.clone(),
+            }
+            .coordinate()
+        })
+        .collect();
+    let RsvpQuery { format, status } = params.into_inner();
+
+    stream::event_listing(&req, db_pool.get_ref(), format, move |columns| {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM public_events AS events WHERE folder = 'rsvps' AND ref_address IN (",
+            columns
+        ));
+        let mut separated = query.separated(", ");
+        for coordinate in &coordinates {
+            separated.push_bind(coordinate.clone());
+        }
+        query.push(")");
+        if let Some(status) = &status {
+            query.push(
+                " AND EXISTS (SELECT 1 FROM json_each(events.tags) AS tag
+                 WHERE json_extract(tag.value, '$[0]') = 'status' AND json_extract(tag.value, '$[1]') = ",
+            );
+            query.push_bind(status.clone());
+            query.push(")");
+        }
+        query.push(" ORDER BY created_at ASC, event_id ASC");
+        query
+    })
+    .await
+}
+
+/// Escapes a TEXT value (RFC 5545 section 3.3.11)
+fn escape_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Appends a content line, folded so no line exceeds 75 octets
+fn push_line(ical: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            ical.push_str("\r\n ");
+            length = 1;
+        }
+        ical.push(c);
+        length += c.len_utf8();
+    }
+    ical.push_str("\r\n");
+}
+
+/// Formats a unix timestamp as a UTC DATE-TIME value
+fn ical_time(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp(timestamp, 0).map(|time| time.format("%Y%m%dT%H%M%SZ").to_string())
+}
+
+/// Formats a `YYYY-MM-DD` date as a DATE value
+fn ical_date(date: &str) -> Option<String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.format("%Y%m%d").to_string())
+}
+
+/// Returns the VEVENT of a calendar event, or `None` when its `start` tag is missing or invalid
+fn vevent(event: &DbEvent) -> Option<String> {
+    let tags: Vec<Vec<String>> = serde_json::from_str(&event.tags).unwrap_or_default();
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|tag| tag.len() >= 2 && tag[0] == name)
+            .map(|tag| tag[1].as_str())
+    };
+    let (start, end) = match event.kind as u64 {
+        TIME_CALENDAR_EVENT_KIND => {
+            let time = |value: &str| value.parse().ok().and_then(ical_time);
+            (
+                format!("DTSTART:{}", time(tag("start")?)?),
+                tag("end")
+                    .and_then(time)
+                    .map(|end| format!("DTEND:{}", end)),
+            )
+        }
+        // NIP-52 and iCalendar both treat the end date as exclusive.
+        DATE_CALENDAR_EVENT_KIND => (
+            format!("DTSTART;VALUE=DATE:{}", ical_date(tag("start")?)?),
+            tag("end")
+                .and_then(ical_date)
+                .map(|end| format!("DTEND;VALUE=DATE:{}", end)),
+        ),
+        _ => return None,
+    };
+    let address = nostr::Address {
+        kind: event.kind as u64,
+        pubkey: event.pubkey.clone(),
+        identifier: tag("d").unwrap_or_default().to_string(),
+    };
+
+    let mut vevent = String::new();
+    push_line(&mut vevent, "BEGIN:VEVENT");
+    push_line(&mut vevent, &format!("UID:{}", address.coordinate()));
+    push_line(
+        &mut vevent,
+        &format!(
+            "DTSTAMP:{}",
+            ical_time(event.created_at).unwrap_or_default()
+        ),
+    );
+    push_line(&mut vevent, &start);
+    if let Some(end) = end {
+        push_line(&mut vevent, &end);
+    }
+    if let Some(title) = tag("title").or_else(|| tag("name")) {
+        push_line(&mut vevent, &format!("SUMMARY:{}", escape_text(title)));
+    }
+    if !event.content.is_empty() {
+        push_line(
+            &mut vevent,
+            &format!("DESCRIPTION:{}", escape_text(&event.content)),
+        );
+    }
+    if let Some(location) = tag("location") {
+        push_line(&mut vevent, &format!("LOCATION:{}", escape_text(location)));
+    }
+    push_line(&mut vevent, "END:VEVENT");
+    Some(vevent)
+}
+
+/// iCalendar export of the calendar events of a pubkey, for subscribing from calendar clients.
+pub async fn ical_export(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let events = calendar_query(stream::EVENT_COLUMNS, &pubkey, None, None)
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    if let Some(response) = etag::not_modified(&req, &etag) {
+        return Ok(response);
+    }
+
+    let mut ical = String::new();
+    push_line(&mut ical, "BEGIN:VCALENDAR");
+    push_line(&mut ical, "VERSION:2.0");
+    push_line(&mut ical, "PRODID:-//chest//nostr archive//EN");
+    push_line(&mut ical, &format!("X-WR-CALNAME:{}", pubkey));
+    for event in &events {
+        if let Some(vevent) = vevent(event) {
+            ical.push_str(&vevent);
+        }
+    }
+    push_line(&mut ical, "END:VCALENDAR");
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header((ETAG, etag.to_string()))
+        .body(ical))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+
+    fn tag(name: &str, value: &str) -> Vec<String> {
+        vec![name.to_string(), value.to_string()]
+    }
+
+    fn ids(events: &[serde_json::Value]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| event["id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn calendar_is_filtered_by_start() {
+        let db_pool = db::test_pool().await;
+        let events: Vec<_> = [('a', "300"), ('b', "100"), ('c', "200")]
+            .into_iter()
+            .map(|(id, start)| {
+                let mut event = db::test_event(id, TIME_CALENDAR_EVENT_KIND);
+                event.tags = vec![tag("d", &id.to_string()), tag("start", start)];
+                event
+            })
+            .collect();
+        db::store_test_events(&db_pool, &events.iter().collect::<Vec<_>>()).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/calendar/{pubkey}", web::get().to(list_calendar)),
+        )
+        .await;
+        let pubkey = "f".repeat(64);
+
+        let request = TestRequest::get()
+            .uri(&format!("/calendar/{}?format=nostr", pubkey))
+            .to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(ids(&listed), [&events[1].id, &events[2].id, &events[0].id]);
+
+        let request = TestRequest::get()
+            .uri(&format!(
+                "/calendar/{}?format=nostr&since=150&until=300",
+                pubkey
+            ))
+            .to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(ids(&listed), [&events[2].id, &events[0].id]);
+    }
+
+    #[actix_web::test]
+    async fn rsvps_are_listed_per_calendar_event() {
+        let db_pool = db::test_pool().await;
+        let organizer = "f".repeat(64);
+        let rsvp = |id: char, attendee: char, identifier: &str, status: &str| {
+            let mut event = db::test_event(id, 31925);
+            event.pubkey = attendee.to_string().repeat(64);
+            event.tags = vec![
+                tag("d", &id.to_string()),
+                tag("a", &format!("31923:{}:{}", organizer, identifier)),
+                tag("status", status),
+            ];
+            event
+        };
+        let accepted = rsvp('a', '1', "meetup", "accepted");
+        let declined = rsvp('b', '2', "meetup", "declined");
+        let elsewhere = rsvp('c', '3', "party", "accepted");
+        db::store_test_events(&db_pool, &[&accepted, &declined, &elsewhere]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/calendar/{pubkey}/{d}/rsvps", web::get().to(list_rsvps)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!(
+                "/calendar/{}/meetup/rsvps?format=nostr",
+                organizer
+            ))
+            .to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(ids(&listed), [&accepted.id, &declined.id]);
+
+        let request = TestRequest::get()
+            .uri(&format!(
+                "/calendar/{}/meetup/rsvps?format=nostr&status=accepted",
+                organizer
+            ))
+            .to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(ids(&listed), [&accepted.id]);
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::home::parse_cursor;
+use crate::{etag, format_events, nostr, retain_visible, DbEvent, OutputFormat};
+
+/// Number of channels or messages returned per page unless `limit` is given
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+
+/// Largest page a client may request
+const MAX_PAGE_LIMIT: i64 = 500;
+
+/// Query parameters of the paginated channel endpoints
+#[derive(Debug, Deserialize)]
+pub struct ChannelPageQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+impl ChannelPageQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    fn cursor(&self) -> Result<Option<(i64, String)>, ApiError> {
+        self.cursor
+            .as_deref()
+            .map(|cursor| {
+                parse_cursor(cursor)
+                    .ok_or_else(|| ApiError::BadRequest(format!("Invalid cursor: {}", cursor)))
+            })
+            .transpose()
+    }
+}
+
+/// A NIP-28 public chat channel
+#[derive(Debug, Serialize)]
+struct Channel {
+    /// Id of the kind 40 event creating the channel
+    id: String,
+    pubkey: String,
+    created_at: i64,
+    /// Name, about and picture from the latest kind 41 metadata update by the creator, or from
+    /// the creation event
+    metadata: Value,
+    messages: i64,
+}
+
+/// Row of a channel creation joined with its latest metadata and message count
+type ChannelRow = (String, String, i64, String, Option<String>, i64);
+
+const CHANNEL_QUERY: &str = r#"
+    SELECT channel.event_id, channel.pubkey, channel.created_at, channel.content,
+        (SELECT metadata.content FROM public_events AS metadata
+         WHERE metadata.folder = 'channels' AND metadata.kind = 41
+           AND metadata.ref_event = channel.event_id AND metadata.pubkey = channel.pubkey
+         ORDER BY metadata.created_at DESC LIMIT 1),
+        (SELECT COUNT(*) FROM public_events AS message
+         WHERE message.folder = 'channel_messages' AND message.ref_event = channel.event_id)
+    FROM public_events AS channel
+    WHERE channel.folder = 'channels' AND channel.kind = 40"#;
+
+fn to_channel((id, pubkey, created_at, content, metadata, messages): ChannelRow) -> Channel {
+    let metadata = metadata
+        .and_then(|metadata| serde_json::from_str(&metadata).ok())
+        .or_else(|| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    Channel {
+        id,
+        pubkey,
+        created_at,
+        metadata,
+        messages,
+    }
+}
+
+/// Appends the keyset condition continuing a page ordered by `created_at` and id, newest first
+fn push_cursor(query: &mut QueryBuilder<'_, Sqlite>, table: &str, cursor: (i64, String)) {
+    let (created_at, event_id) = cursor;
+    query.push(format!(" AND ({}.created_at < ", table));
+    query.push_bind(created_at);
+    query.push(format!(" OR ({0}.created_at = ", table));
+    query.push_bind(created_at);
+    query.push(format!(" AND {}.event_id < ", table));
+    query.push_bind(event_id);
+    query.push("))");
+}
+
+/// Lists archived channels, newest first, continued by passing the returned `next_cursor`.
+pub async fn list_channels(
+    params: web::Query<ChannelPageQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = params.limit();
+    let mut query = QueryBuilder::<Sqlite>::new(CHANNEL_QUERY);
+    if let Some(cursor) = params.cursor()? {
+        push_cursor(&mut query, "channel", cursor);
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY channel.created_at DESC, channel.event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut rows: Vec<ChannelRow> = query.build_query_as().fetch_all(db_pool.get_ref()).await?;
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| format!("{}:{}", row.2, row.0))
+    } else {
+        None
+    };
+    let channels: Vec<Channel> = rows.into_iter().map(to_channel).collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "channels": channels,
+        "next_cursor": next_cursor,
+    })))
+}
+
+/// Returns a channel with its current metadata.
+pub async fn get_channel(
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid channel id: {}", input)))?;
+    let row: Option<ChannelRow> =
+        sqlx::query_as(&format!("{} AND channel.event_id = ?", CHANNEL_QUERY))
+            .bind(&id)
+            .fetch_optional(db_pool.get_ref())
+            .await?;
+    let row = row.ok_or_else(|| ApiError::NotFound("Channel not found".to_string()))?;
+    Ok(HttpResponse::Ok().json(to_channel(row)))
+}
+
+/// Lists the messages of a channel, newest first, continued by passing the returned
+/// `next_cursor`.
+pub async fn list_channel_messages(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<ChannelPageQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid channel id: {}", input)))?;
+    let limit = params.limit();
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS message WHERE folder = 'channel_messages' AND ref_event = ",
+    );
+    query.push_bind(id);
+    if let Some(cursor) = params.cursor()? {
+        push_cursor(&mut query, "message", cursor);
+    }
+    query.push(" ORDER BY created_at DESC, event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| format!("{}:{}", event.created_at, event.event_id))
+    } else {
+        None
+    };
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &serde_json::json!({
+            "events": format_events(&events, params.format),
+            "next_cursor": next_cursor,
+        }),
+    ))
+}
@@ -0,0 +1,346 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::ingest::REPLACEABLE_FOLDERS;
+use crate::{
+    format_event, format_events, nostr, retain_visible, AppConfig, ColdConfig, DbEvent,
+    FormatQuery, OutputFormat,
+};
+
+/// Index rows written per statement when a segment is recorded
+const INDEX_CHUNK_SIZE: usize = 500;
+
+/// Number of events returned by `GET /cold/events` unless `limit` is given
+const DEFAULT_COLD_LIMIT: i64 = 100;
+
+/// Largest page of events a client may request from cold storage
+const MAX_COLD_LIMIT: i64 = 1000;
+
+/// Writes events as zstd-compressed JSONL, one stored row per line
+fn write_segment(path: &Path, events: &[DbEvent], level: i32) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut encoder = zstd::Encoder::new(File::create(path)?, level)?;
+    for event in events {
+        serde_json::to_writer(&mut encoder, event)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()?.sync_all()
+}
+
+/// Decompresses a segment, keeping the events with the wanted ids
+pub fn read_segment(path: &Path, wanted: &HashSet<String>) -> io::Result<Vec<DbEvent>> {
+    let decoder = zstd::Decoder::new(File::open(path)?)?;
+    let mut events = Vec::new();
+    for line in BufReader::new(decoder).lines() {
+        let event: DbEvent = serde_json::from_str(&line?)?;
+        if wanted.contains(&event.event_id) {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+/// Moves the oldest events past the cold storage age into a new segment, returning how many
+/// were moved. Replaceable events stay to be replaced, deletion requests stay to keep blocking
+/// their targets, and the operator's direct messages stay behind the `/dms` authentication.
+async fn archive_segment(db_pool: &SqlitePool, config: &ColdConfig) -> Result<usize, String> {
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+    let cutoff = nostr::now().saturating_sub(config.after_days * 24 * 60 * 60) as i64;
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events WHERE created_at < ",
+    );
+    query.push_bind(cutoff);
+    query.push(" AND folder NOT IN ('deletions'");
+    for folder in REPLACEABLE_FOLDERS {
+        query.push(", ");
+        query.push_bind(folder);
+    }
+    query.push(") ORDER BY created_at, event_id LIMIT ");
+    query.push_bind(config.segment_size.max(1) as i64);
+    let events: Vec<DbEvent> = query
+        .build_query_as()
+        .fetch_all(db_pool)
+        .await
+        .map_err(db_error)?;
+    let (Some(first), Some(last)) = (events.first(), events.last()) else {
+        return Ok(0);
+    };
+    let (min_created_at, max_created_at) = (first.created_at, last.created_at);
+
+    let file = format!("{}-{}.jsonl.zst", min_created_at, Uuid::new_v4().simple());
+    let path = Path::new(&config.directory).join(&file);
+    // Compression is CPU bound: keep it off the runtime's worker threads.
+    let level = config.compression_level;
+    let (events, written) = tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || {
+            let written = write_segment(&path, &events, level);
+            (events, written)
+        }
+    })
+    .await
+    .map_err(|e| format!("Segment writer failed: {}", e))?;
+    written.map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    // The segment only becomes visible, and the events leave the hot table, in one transaction.
+    let recorded: Result<(), sqlx::Error> = async {
+        let mut tx = db_pool.begin().await?;
+        let segment_id = sqlx::query(
+            "INSERT INTO cold_segments (file, events, min_created_at, max_created_at, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&file)
+        .bind(events.len() as i64)
+        .bind(min_created_at)
+        .bind(max_created_at)
+        .bind(nostr::now() as i64)
+        .execute(&mut tx)
+        .await?
+        .last_insert_rowid();
+        for chunk in events.chunks(INDEX_CHUNK_SIZE) {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT OR IGNORE INTO cold_events (event_id, segment_id, pubkey, kind, created_at) ",
+            );
+            query.push_values(chunk, |mut row, event| {
+                row.push_bind(&event.event_id)
+                    .push_bind(segment_id)
+                    .push_bind(&event.pubkey)
+                    .push_bind(event.kind)
+                    .push_bind(event.created_at);
+            });
+            query.build().execute(&mut tx).await?;
+        }
+        sqlx::query(
+            "DELETE FROM events WHERE event_id IN
+                 (SELECT event_id FROM cold_events WHERE segment_id = ?)",
+        )
+        .bind(segment_id)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = recorded {
+        let _ = fs::remove_file(&path);
+        return Err(db_error(e));
+    }
+    Ok(events.len())
+}
+
+/// Moves events older than `after_days` to compressed segment files every `interval` seconds
+pub fn spawn(config: ColdConfig, db_pool: SqlitePool) {
+    if !config.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        loop {
+            interval.tick().await;
+            loop {
+                match archive_segment(&db_pool, &config).await {
+                    Ok(0) => break,
+                    Ok(moved) => {
+                        println!("Moved {} events to cold storage", moved);
+                        if moved < config.segment_size {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to move events to cold storage: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Reads cold events from their segments, given their ids with the file holding each. Direct
+/// messages moved by earlier versions are left out.
+async fn load_cold_events(
+    directory: &str,
+    located: Vec<(String, String)>,
+) -> Result<Vec<DbEvent>, ApiError> {
+    let mut by_file: HashMap<String, HashSet<String>> = HashMap::new();
+    for (event_id, file) in located {
+        by_file.entry(file).or_default().insert(event_id);
+    }
+    let directory = PathBuf::from(directory);
+    tokio::task::spawn_blocking(move || {
+        let mut events = Vec::new();
+        for (file, wanted) in by_file {
+            let path = directory.join(&file);
+            match read_segment(&path, &wanted) {
+                Ok(found) => events.extend(found.into_iter().filter(|event| event.folder != "dms")),
+                Err(e) => {
+                    eprintln!("Failed to read cold segment {}: {}", path.display(), e);
+                    return Err(ApiError::Internal);
+                }
+            }
+        }
+        Ok(events)
+    })
+    .await
+    .map_err(|_| ApiError::Internal)?
+}
+
+/// Returns an event moved to cold storage, decompressing its segment.
+pub async fn get_cold_event(
+    path: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let event_id = path.into_inner();
+    let located: Vec<(String, String)> = sqlx::query_as(
+        "SELECT e.event_id, s.file FROM cold_events AS e
+         JOIN cold_segments AS s ON s.id = e.segment_id
+         WHERE e.event_id = ?",
+    )
+    .bind(&event_id)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    let events = load_cold_events(&config.cold.directory, located).await?;
+    let event = events
+        .first()
+        .ok_or_else(|| ApiError::NotFound(format!("Event {} is not in cold storage", event_id)))?;
+    Ok(HttpResponse::Ok().json(format_event(event, params.format)))
+}
+
+/// Query parameters of `GET /cold/events`
+#[derive(Debug, Deserialize)]
+pub struct ColdQuery {
+    /// Comma-separated hex pubkeys
+    authors: Option<String>,
+    /// Comma-separated kinds
+    kinds: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+/// Lists events moved to cold storage, newest first, filtered by author, kind and time. Only
+/// the segments holding matching events are decompressed.
+pub async fn query_cold(
+    req: HttpRequest,
+    params: web::Query<ColdQuery>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_COLD_LIMIT)
+        .clamp(1, MAX_COLD_LIMIT);
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT e.event_id, s.file FROM cold_events AS e
+         JOIN cold_segments AS s ON s.id = e.segment_id WHERE 1 = 1",
+    );
+    if let Some(authors) = &params.authors {
+        query.push(" AND e.pubkey IN (");
+        let mut separated = query.separated(", ");
+        for author in authors.split(',') {
+            separated.push_bind(author.trim().to_string());
+        }
+        query.push(")");
+    }
+    if let Some(kinds) = &params.kinds {
+        let kinds = kinds
+            .split(',')
+            .map(|kind| {
+                kind.trim()
+                    .parse::<i64>()
+                    .map_err(|_| ApiError::BadRequest(format!("Invalid kind: {}", kind)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        query.push(" AND e.kind IN (");
+        let mut separated = query.separated(", ");
+        for kind in kinds {
+            separated.push_bind(kind);
+        }
+        query.push(")");
+    }
+    if let Some(since) = params.since {
+        query.push(" AND e.created_at >= ");
+        query.push_bind(since);
+    }
+    if let Some(until) = params.until {
+        query.push(" AND e.created_at <= ");
+        query.push_bind(until);
+    }
+    query.push(" ORDER BY e.created_at DESC, e.event_id DESC LIMIT ");
+    query.push_bind(limit);
+    let located: Vec<(String, String)> =
+        query.build_query_as().fetch_all(db_pool.get_ref()).await?;
+
+    let mut events = load_cold_events(&config.cold.directory, located).await?;
+    events.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| b.event_id.cmp(&a.event_id))
+    });
+    retain_visible(&req, &mut events);
+    Ok(HttpResponse::Ok().json(format_events(&events, params.format)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, test_event};
+    use crate::test_config;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn direct_messages_stay_out_of_cold_storage() {
+        let db_pool = db::test_pool().await;
+        let (mut note, mut dm) = (test_event('a', 1), test_event('b', 4));
+        note.created_at = 1_600_000_000;
+        dm.created_at = 1_600_000_000;
+        db::store_test_events(&db_pool, &[&note, &dm]).await;
+        let mut config = test_config();
+        config.cold.directory = std::env::temp_dir()
+            .join(format!("chest-cold-{}", Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .into_owned();
+        config.cold.after_days = 1;
+        assert_eq!(archive_segment(&db_pool, &config.cold).await.unwrap(), 1);
+
+        let directory = config.cold.directory.clone();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(db_pool))
+                .route("/cold/events", web::get().to(query_cold))
+                .route("/cold/events/{id}", web::get().to(get_cold_event)),
+        )
+        .await;
+        let request = TestRequest::get().uri("/cold/events").to_request();
+        let events: Value = read_body_json(call_service(&app, request).await).await;
+        let ids: Vec<&str> = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|event| event["event_id"].as_str())
+            .collect();
+        assert_eq!(ids, [note.id.as_str()]);
+        let request = TestRequest::get()
+            .uri(&format!("/cold/events/{}", dm.id))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), 404);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, SqlitePool};
+
+use crate::error::ApiError;
+use crate::ingest::COMMUNITY_KIND;
+use crate::{nostr, stream, OutputFormat};
+
+/// Query parameters of `GET /communities/{naddr}/posts`
+#[derive(Debug, Deserialize)]
+pub struct CommunityPostsQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Only list posts approved by the community's owner or one of its moderators
+    #[serde(default)]
+    approved: bool,
+}
+
+/// Lists the posts addressed to a NIP-72 community, newest first. With `approved=true`, only
+/// posts with a kind 4550 approval by a moderator named in the community definition are listed.
+pub async fn list_posts(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<CommunityPostsQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let address = nostr::parse_naddr(&input)
+        .or_else(|| nostr::parse_coordinate(&input))
+        .filter(|address| address.kind == COMMUNITY_KIND)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid community address: {}", input)))?;
+    let coordinate = address.coordinate();
+    let approved = params.approved;
+
+    stream::event_listing(&req, db_pool.get_ref(), params.format, move |columns| {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM public_events AS post
+             WHERE post.kind IN (1, 1111) AND post.ref_address = ",
+            columns
+        ));
+        query.push_bind(coordinate.clone());
+        if approved {
+            query.push(
+                " AND EXISTS (
+                    SELECT 1 FROM public_events AS approval
+                    WHERE approval.folder = 'approvals' AND approval.ref_event = post.event_id
+                      AND approval.ref_address = ",
+            );
+            query.push_bind(coordinate.clone());
+            query.push(
+                " AND approval.pubkey IN (
+                        SELECT community.pubkey FROM public_events AS community
+                        WHERE community.folder = 'communities' AND community.kind = ",
+            );
+            query.push_bind(address.kind as i64);
+            query.push(" AND community.pubkey = ");
+            query.push_bind(address.pubkey.clone());
+            query.push(" AND community.d_tag = ");
+            query.push_bind(address.identifier.clone());
+            query.push(
+                "
+                        UNION
+                        SELECT json_extract(tag.value, '$[1]')
+                        FROM public_events AS community, json_each(community.tags) AS tag
+                        WHERE community.folder = 'communities' AND community.kind = ",
+            );
+            query.push_bind(address.kind as i64);
+            query.push(" AND community.pubkey = ");
+            query.push_bind(address.pubkey.clone());
+            query.push(" AND community.d_tag = ");
+            query.push_bind(address.identifier.clone());
+            query.push(
+                " AND json_extract(tag.value, '$[0]') = 'p'
+                          AND json_extract(tag.value, '$[3]') = 'moderator'
+                    )
+                )",
+            );
+        }
+        query.push(" ORDER BY post.created_at DESC");
+        query
+    })
+    .await
+}
@@ -0,0 +1,209 @@
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use base64::Engine;
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secp256k1::{ecdh, PublicKey, SecretKey};
+use sha2::Sha256;
+
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// Parses a secret key given as hex or `nsec1...`
+pub fn parse_secret_key(input: &str) -> Option<SecretKey> {
+    let input = input.trim();
+    let bytes = if input.starts_with("nsec1") {
+        let (hrp, data, _) = bech32::decode(input).ok()?;
+        if hrp != "nsec" {
+            return None;
+        }
+        bech32::FromBase32::from_base32(&data).ok()?
+    } else {
+        hex::decode(input).ok()?
+    };
+    SecretKey::from_slice(&bytes).ok()
+}
+
+/// Returns the hex x-only pubkey of a secret key
+pub fn public_key_hex(secret: &SecretKey) -> String {
+    let (pubkey, _) = secret.x_only_public_key(secp256k1::SECP256K1);
+    pubkey.to_string()
+}
+
+/// Returns the x coordinate of the ECDH point shared with a hex x-only pubkey
+fn shared_x(secret: &SecretKey, pubkey: &str) -> Option<[u8; 32]> {
+    let mut compressed = [2u8; 33];
+    hex::decode_to_slice(pubkey, &mut compressed[1..]).ok()?;
+    let point = ecdh::shared_secret_point(&PublicKey::from_slice(&compressed).ok()?, secret);
+    point[..32].try_into().ok()
+}
+
+/// Decrypts a NIP-04 `<base64 ciphertext>?iv=<base64 iv>` payload exchanged with `pubkey`
+pub fn nip04_decrypt(secret: &SecretKey, pubkey: &str, payload: &str) -> Option<String> {
+    let (ciphertext, iv) = payload.split_once("?iv=")?;
+    let engine = base64::engine::general_purpose::STANDARD;
+    let mut buffer = engine.decode(ciphertext).ok()?;
+    let iv: [u8; 16] = engine.decode(iv).ok()?.try_into().ok()?;
+    let key = shared_x(secret, pubkey)?;
+    let plaintext = Aes256CbcDec::new(&key.into(), &iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .ok()?;
+    String::from_utf8(plaintext.to_vec()).ok()
+}
+
+/// Derives the NIP-44 conversation key shared with `pubkey`
+pub fn nip44_conversation_key(secret: &SecretKey, pubkey: &str) -> Option<[u8; 32]> {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(b"nip44-v2"), &shared_x(secret, pubkey)?);
+    prk.as_slice().try_into().ok()
+}
+
+/// Derives the ChaCha20 key, ChaCha20 nonce and HMAC key of a NIP-44 message
+fn nip44_message_keys(conversation_key: &[u8; 32], nonce: &[u8]) -> Option<[u8; 76]> {
+    let hkdf = Hkdf::<Sha256>::from_prk(conversation_key).ok()?;
+    let mut keys = [0u8; 76];
+    hkdf.expand(nonce, &mut keys).ok()?;
+    Some(keys)
+}
+
+/// Length a NIP-44 plaintext is padded to, hiding its exact size
+fn nip44_padded_length(length: usize) -> usize {
+    if length <= 32 {
+        return 32;
+    }
+    let next_power = length.next_power_of_two();
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((length - 1) / chunk + 1)
+}
+
+/// Encrypts a message as a NIP-44 version 2 payload with a random nonce
+pub fn nip44_encrypt(conversation_key: &[u8; 32], plaintext: &str) -> Option<String> {
+    let length = plaintext.len();
+    if length == 0 || length > u16::MAX as usize {
+        return None;
+    }
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let keys = nip44_message_keys(conversation_key, &nonce)?;
+
+    let mut padded = vec![0u8; 2 + nip44_padded_length(length)];
+    padded[..2].copy_from_slice(&(length as u16).to_be_bytes());
+    padded[2..2 + length].copy_from_slice(plaintext.as_bytes());
+    ChaCha20::new(keys[..32].into(), keys[32..44].into()).apply_keystream(&mut padded);
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(&keys[44..]).ok()?;
+    hmac.update(&nonce);
+    hmac.update(&padded);
+
+    let mut data = vec![2u8];
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&padded);
+    data.extend_from_slice(&hmac.finalize().into_bytes());
+    Some(base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+/// Decrypts a NIP-44 version 2 payload, rejecting one whose padding does not match the length
+/// of its plaintext
+pub fn nip44_decrypt(conversation_key: &[u8; 32], payload: &str) -> Option<String> {
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .ok()?;
+    // version (1) | nonce (32) | ciphertext (at least 34) | mac (32)
+    if data.len() < 99 || data[0] != 2 {
+        return None;
+    }
+    let (nonce, rest) = data[1..].split_at(32);
+    let (ciphertext, mac) = rest.split_at(rest.len() - 32);
+    let keys = nip44_message_keys(conversation_key, nonce)?;
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(&keys[44..]).ok()?;
+    hmac.update(nonce);
+    hmac.update(ciphertext);
+    hmac.verify_slice(mac).ok()?;
+
+    let mut padded = ciphertext.to_vec();
+    ChaCha20::new(keys[..32].into(), keys[32..44].into()).apply_keystream(&mut padded);
+    let length = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if length == 0 || padded.len() != 2 + nip44_padded_length(length) {
+        return None;
+    }
+    String::from_utf8(padded[2..2 + length].to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret key with the given value, as in the NIP-44 test vectors
+    fn secret(value: u8) -> SecretKey {
+        let mut bytes = [0u8; 32];
+        bytes[31] = value;
+        SecretKey::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn padded_lengths() {
+        for (length, padded) in [
+            (16, 32),
+            (32, 32),
+            (33, 64),
+            (64, 64),
+            (65, 96),
+            (100, 128),
+            (200, 224),
+            (250, 256),
+            (320, 320),
+            (383, 384),
+            (400, 448),
+            (515, 640),
+            (900, 1024),
+        ] {
+            assert_eq!(nip44_padded_length(length), padded, "{}", length);
+        }
+    }
+
+    #[test]
+    fn decrypts_the_nip44_vector() {
+        let conversation_key =
+            nip44_conversation_key(&secret(1), &public_key_hex(&secret(2))).unwrap();
+        assert_eq!(
+            hex::encode(conversation_key),
+            "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d"
+        );
+        let payload = "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb";
+        assert_eq!(
+            nip44_decrypt(&conversation_key, payload).as_deref(),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_padding() {
+        let conversation_key = [7u8; 32];
+        let nonce = [1u8; 32];
+        let keys = nip44_message_keys(&conversation_key, &nonce).unwrap();
+        // "a" padded to 64 bytes instead of 32, with a valid MAC
+        let mut padded = vec![0u8; 2 + 64];
+        padded[..3].copy_from_slice(&[0, 1, b'a']);
+        ChaCha20::new(keys[..32].into(), keys[32..44].into()).apply_keystream(&mut padded);
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&keys[44..]).unwrap();
+        hmac.update(&nonce);
+        hmac.update(&padded);
+        let mut data = vec![2u8];
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&padded);
+        data.extend_from_slice(&hmac.finalize().into_bytes());
+        let payload = base64::engine::general_purpose::STANDARD.encode(data);
+        assert_eq!(nip44_decrypt(&conversation_key, &payload), None);
+
+        let payload = nip44_encrypt(&conversation_key, "a").unwrap();
+        assert_eq!(
+            nip44_decrypt(&conversation_key, &payload).as_deref(),
+            Some("a")
+        );
+    }
+}
@@ -0,0 +1,1053 @@
+use sqlx::sqlite::{
+    SqliteAutoVacuum, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous,
+};
+use sqlx::SqlitePool;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::ingest;
+use crate::nostr::NostrEvent;
+use crate::DatabaseConfig;
+
+/// Version of the schema created by `init_schema`, stored in `PRAGMA user_version`. Bumped
+/// whenever `init_schema` changes, so `chest doctor` can tell which databases need migrating.
+pub const SCHEMA_VERSION: i64 = 16;
+
+/// Creates the events table if it does not exist.
+const CREATE_EVENTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS events (
+        event_id TEXT PRIMARY KEY,
+        pubkey TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        kind INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        sig TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        ref_event TEXT
+    );
+"#;
+
+/// Events served to unauthenticated callers: everything but the operator's direct messages,
+/// which only `GET /dms` returns. Public handlers select `FROM public_events AS events` rather
+/// than from `events`, so none of them has to repeat the folder condition. The view has no rowid,
+/// so jobs that page through the table by rowid keep reading `events`.
+const CREATE_PUBLIC_EVENTS_VIEW: &str = r#"
+    CREATE VIEW public_events AS SELECT * FROM events WHERE folder != 'dms';
+"#;
+
+/// Records moderation actions taken through the admin API.
+const CREATE_AUDIT_LOG_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        actor TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target TEXT NOT NULL,
+        reason TEXT
+    );
+"#;
+
+/// Event ids removed by moderators, which must not be archived again.
+const CREATE_BANNED_EVENTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS banned_events (
+        event_id TEXT PRIMARY KEY,
+        banned_at INTEGER NOT NULL,
+        reason TEXT
+    );
+"#;
+
+/// Pubkeys removed by moderators, whose events must not be archived again.
+const CREATE_BANNED_PUBKEYS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS banned_pubkeys (
+        pubkey TEXT PRIMARY KEY,
+        banned_at INTEGER NOT NULL,
+        reason TEXT
+    );
+"#;
+
+/// Events referenced by archived events but missing from the archive, with lookup attempts.
+const CREATE_ORPHANS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS orphans (
+        event_id TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_attempt INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0
+    );
+"#;
+
+/// Hourly event counts per kind backing `GET /stats/timeseries`, kept up to date by triggers
+/// on the events table.
+const CREATE_ACTIVITY_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS activity (
+        bucket INTEGER NOT NULL,
+        kind INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (bucket, kind)
+    );
+"#;
+
+const CREATE_ACTIVITY_INSERT_TRIGGER: &str = r#"
+    CREATE TRIGGER IF NOT EXISTS activity_insert AFTER INSERT ON events BEGIN
+        INSERT INTO activity (bucket, kind, count)
+        VALUES (NEW.created_at - NEW.created_at % 3600, NEW.kind, 1)
+        ON CONFLICT (bucket, kind) DO UPDATE SET count = count + 1;
+    END;
+"#;
+
+const CREATE_ACTIVITY_DELETE_TRIGGER: &str = r#"
+    CREATE TRIGGER IF NOT EXISTS activity_delete AFTER DELETE ON events BEGIN
+        UPDATE activity SET count = count - 1
+        WHERE bucket = OLD.created_at - OLD.created_at % 3600 AND kind = OLD.kind;
+    END;
+"#;
+
+/// Superseded versions of replaceable events, kept when `versions.enabled` is set
+const CREATE_EVENT_VERSIONS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS event_versions (
+        event_id TEXT PRIMARY KEY,
+        pubkey TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        kind INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        sig TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        ref_event TEXT,
+        d_tag TEXT,
+        superseded_at INTEGER NOT NULL
+    );
+"#;
+
+/// Events and bytes of content and tags archived per pubkey, checked against the ingestion
+/// quotas and kept up to date by triggers on the events table.
+const CREATE_PUBKEY_USAGE_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS pubkey_usage (
+        pubkey TEXT PRIMARY KEY,
+        events INTEGER NOT NULL,
+        bytes INTEGER NOT NULL
+    );
+"#;
+
+/// Progress of the signature re-verification job: the rowid of the last checked event and the
+/// counts so far.
+const CREATE_REVERIFICATION_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS reverification (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        last_rowid INTEGER NOT NULL,
+        checked INTEGER NOT NULL,
+        invalid INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+"#;
+
+/// Events moved out of the archive by `chest verify --fix` because their id, signature or tags
+/// cannot be trusted.
+const CREATE_QUARANTINE_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS quarantine (
+        event_id TEXT PRIMARY KEY,
+        pubkey TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        kind INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        sig TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        folder TEXT NOT NULL,
+        ref_event TEXT,
+        problem TEXT NOT NULL,
+        quarantined_at INTEGER NOT NULL
+    );
+"#;
+
+/// Manifest of the cold storage segments: compressed JSONL files of events moved out of the
+/// events table.
+const CREATE_COLD_SEGMENTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS cold_segments (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        file TEXT NOT NULL,
+        events INTEGER NOT NULL,
+        min_created_at INTEGER NOT NULL,
+        max_created_at INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+"#;
+
+/// Index of the events in cold storage, naming the segment holding each.
+const CREATE_COLD_EVENTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS cold_events (
+        event_id TEXT PRIMARY KEY,
+        segment_id INTEGER NOT NULL,
+        pubkey TEXT NOT NULL,
+        kind INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+"#;
+
+/// Status of the archived NIP-03 OpenTimestamps attestations, filled in by the timestamps job.
+/// `target_event` is the attested event and `commitment` the hex merkle root the proof commits
+/// to in the Bitcoin block at `bitcoin_height`.
+const CREATE_ATTESTATIONS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS attestations (
+        event_id TEXT PRIMARY KEY,
+        target_event TEXT,
+        status TEXT NOT NULL,
+        bitcoin_height INTEGER,
+        commitment TEXT,
+        calendar TEXT,
+        attested_at INTEGER,
+        error TEXT,
+        checked_at INTEGER NOT NULL
+    );
+"#;
+
+/// Progress of the windowed backfill of each relay: the history between `oldest` and `newest`
+/// was walked for the kinds listed in `kinds`.
+const CREATE_BACKFILL_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS backfill (
+        relay_url TEXT PRIMARY KEY,
+        kinds TEXT NOT NULL,
+        newest INTEGER NOT NULL,
+        oldest INTEGER NOT NULL,
+        done INTEGER NOT NULL DEFAULT 0,
+        events INTEGER NOT NULL DEFAULT 0,
+        updated_at INTEGER NOT NULL
+    );
+"#;
+
+/// Latency, error and duplicate counts of each upstream relay, halved at every evaluation
+const CREATE_RELAY_HEALTH_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS relay_health (
+        relay_url TEXT PRIMARY KEY,
+        latency_ms REAL,
+        requests REAL NOT NULL DEFAULT 0,
+        errors REAL NOT NULL DEFAULT 0,
+        events REAL NOT NULL DEFAULT 0,
+        duplicates REAL NOT NULL DEFAULT 0,
+        paused INTEGER NOT NULL DEFAULT 0,
+        updated_at INTEGER NOT NULL
+    );
+"#;
+
+/// Relays hinted by the tags and `nostr:` references of archived events, with the outcome of
+/// probing them and whether discovery added them
+const CREATE_RELAY_CANDIDATES_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS relay_candidates (
+        relay_url TEXT PRIMARY KEY,
+        hints INTEGER NOT NULL DEFAULT 0,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        sample_event TEXT,
+        sample_pubkey TEXT,
+        probes INTEGER NOT NULL DEFAULT 0,
+        successes INTEGER NOT NULL DEFAULT 0,
+        last_probe INTEGER NOT NULL DEFAULT 0,
+        added INTEGER NOT NULL DEFAULT 0
+    );
+"#;
+
+/// Last row scanned for relay hints
+const CREATE_RELAY_DISCOVERY_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS relay_discovery (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        last_rowid INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+"#;
+
+/// Simhash fingerprints of the content of archived notes and replies, with the near-duplicate
+/// cluster each belongs to. `id` is the rowid of the event in the events table, and a cluster is
+/// named by the id of its first fingerprint. `band0` to `band3` are the four 16-bit quarters of
+/// the fingerprint, indexed to find the fingerprints differing from a new one in few bits.
+const CREATE_FINGERPRINTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS fingerprints (
+        id INTEGER PRIMARY KEY,
+        event_id TEXT NOT NULL,
+        pubkey TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        simhash INTEGER NOT NULL,
+        band0 INTEGER NOT NULL,
+        band1 INTEGER NOT NULL,
+        band2 INTEGER NOT NULL,
+        band3 INTEGER NOT NULL,
+        cluster_id INTEGER NOT NULL
+    );
+"#;
+
+/// Last row fingerprinted by the duplicate detection job
+const CREATE_DUPLICATE_SCAN_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS duplicate_scan (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        last_rowid INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+"#;
+
+/// Bytes an event row is charged against its author's quota
+pub const USAGE_BYTES: &str = "length(CAST(content AS BLOB)) + length(CAST(tags AS BLOB))";
+
+/// Searchable fields of the archived kind 0 profiles, kept in sync with the `users` folder by
+/// triggers on the events table.
+const CREATE_PROFILES_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS profiles (
+        pubkey TEXT PRIMARY KEY,
+        event_id TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        name TEXT,
+        display_name TEXT,
+        nip05 TEXT,
+        about TEXT,
+        picture TEXT
+    );
+"#;
+
+/// URL, hash, mime type and size of the archived NIP-94 file metadata events, kept in sync
+/// with the `files` folder by triggers on the events table.
+const CREATE_FILES_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS files (
+        event_id TEXT PRIMARY KEY,
+        pubkey TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        url TEXT,
+        sha256 TEXT,
+        mime TEXT,
+        size INTEGER
+    );
+"#;
+
+/// Most precise NIP-52 `g` tag geohash of the archived notes, kept in sync with the `notes`
+/// folder by triggers on the events table.
+const CREATE_GEOHASHES_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS geohashes (
+        event_id TEXT PRIMARY KEY,
+        geohash TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+"#;
+
+/// NIP-89 `client` tag of the archived notes and replies, kept in sync with those folders by
+/// triggers on the events table.
+const CREATE_NOTE_CLIENTS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS note_clients (
+        event_id TEXT PRIMARY KEY,
+        pubkey TEXT NOT NULL,
+        client TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+"#;
+
+/// Media files downloaded into the media cache, by the URL they were fetched from
+const CREATE_MEDIA_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS media (
+        url TEXT PRIMARY KEY,
+        sha256 TEXT NOT NULL,
+        mime TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        fetched_at INTEGER NOT NULL
+    );
+"#;
+
+/// OpenGraph and Twitter card metadata of the web pages linked from archived notes
+const CREATE_LINK_PREVIEWS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS link_previews (
+        url TEXT PRIMARY KEY,
+        title TEXT,
+        description TEXT,
+        image TEXT,
+        site_name TEXT,
+        fetched_at INTEGER NOT NULL
+    );
+"#;
+
+/// Extracts the indexed tags of a stored kind 1063 event (`NEW`)
+const FILE_COLUMNS: &str = r#"
+    NEW.event_id, NEW.pubkey, NEW.created_at,
+    (SELECT json_extract(tag.value, '$[1]') FROM json_each(NEW.tags) AS tag
+     WHERE json_extract(tag.value, '$[0]') = 'url' LIMIT 1),
+    (SELECT lower(json_extract(tag.value, '$[1]')) FROM json_each(NEW.tags) AS tag
+     WHERE json_extract(tag.value, '$[0]') = 'x' LIMIT 1),
+    (SELECT lower(json_extract(tag.value, '$[1]')) FROM json_each(NEW.tags) AS tag
+     WHERE json_extract(tag.value, '$[0]') = 'm' LIMIT 1),
+    (SELECT CAST(json_extract(tag.value, '$[1]') AS INTEGER) FROM json_each(NEW.tags) AS tag
+     WHERE json_extract(tag.value, '$[0]') = 'size' LIMIT 1)
+"#;
+
+/// Matches the `g` tags (`tag`) of a stored note (`NEW`) holding a valid geohash
+const GEOHASH_TAG: &str = r#"
+    json_extract(tag.value, '$[0]') = 'g'
+    AND length(json_extract(tag.value, '$[1]')) BETWEEN 1 AND 12
+    AND lower(json_extract(tag.value, '$[1]')) NOT GLOB '*[^0-9b-hjkmnp-z]*'
+"#;
+
+/// Extracts the client name of a stored note (`NEW`), `NULL` without a `client` tag
+const NOTE_CLIENT: &str = r#"
+    (SELECT trim(json_extract(tag.value, '$[1]')) FROM json_each(NEW.tags) AS tag
+     WHERE json_extract(tag.value, '$[0]') = 'client'
+       AND trim(json_extract(tag.value, '$[1]')) != '' LIMIT 1)
+"#;
+
+/// Extracts the profile fields of a stored kind 0 event (`NEW`), tolerating malformed content
+const PROFILE_COLUMNS: &str = r#"
+    NEW.pubkey, NEW.event_id, NEW.created_at,
+    CASE WHEN json_valid(NEW.content) THEN json_extract(NEW.content, '$.name') END,
+    CASE WHEN json_valid(NEW.content) THEN json_extract(NEW.content, '$.display_name') END,
+    CASE WHEN json_valid(NEW.content) THEN json_extract(NEW.content, '$.nip05') END,
+    CASE WHEN json_valid(NEW.content) THEN json_extract(NEW.content, '$.about') END,
+    CASE WHEN json_valid(NEW.content) THEN json_extract(NEW.content, '$.picture') END
+"#;
+
+/// Adds a column to an existing table unless it is already present, returning whether it was added
+async fn ensure_column(
+    db_pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, sqlx::Error> {
+    let columns: Vec<(String,)> =
+        sqlx::query_as(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .fetch_all(db_pool)
+            .await?;
+    if columns.iter().any(|(name,)| name == column) {
+        return Ok(false);
+    }
+    sqlx::query(&format!(
+        "ALTER TABLE {} ADD COLUMN {} {}",
+        table, column, definition
+    ))
+    .execute(db_pool)
+    .await?;
+    Ok(true)
+}
+
+/// Returns whether a table exists, so tables derived from the archive can be filled on creation
+async fn table_exists(db_pool: &SqlitePool, table: &str) -> Result<bool, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+    )
+    .bind(table)
+    .fetch_one(db_pool)
+    .await?;
+    Ok(exists)
+}
+
+/// Pool of writable connections, shared with the HTTP handlers that modify the archive
+#[derive(Debug, Clone)]
+pub struct WritePool(pub SqlitePool);
+
+/// Environment variable holding the database passphrase, taking precedence over `key_file`
+const KEY_VARIABLE: &str = "CHEST_DATABASE_KEY";
+
+/// Returns the SQLCipher passphrase of the database, or `None` when it is not encrypted
+fn encryption_key(config: &DatabaseConfig) -> Result<Option<String>, sqlx::Error> {
+    if let Ok(key) = std::env::var(KEY_VARIABLE) {
+        return Ok(Some(key).filter(|key| !key.is_empty()));
+    }
+    if config.key_file.is_empty() {
+        return Ok(None);
+    }
+    let key = std::fs::read_to_string(&config.key_file)?;
+    let key = key.trim_end_matches(['\r', '\n']);
+    if key.is_empty() {
+        return Err(sqlx::Error::Configuration(
+            format!("Database key file {} is empty", config.key_file).into(),
+        ));
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// Builds the options of a database connection with the configured pragmas applied
+pub fn connect_options(
+    config: &DatabaseConfig,
+    read_only: bool,
+) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let mut options = SqliteConnectOptions::from_str(&config.path)?;
+    // SQLCipher needs the key before anything else touches the file; sqlx sends it first.
+    if let Some(key) = encryption_key(config)? {
+        options = options.pragma("key", format!("'{}'", key.replace('\'', "''")));
+    }
+    Ok(options
+        .read_only(read_only)
+        .journal_mode(SqliteJournalMode::from_str(&config.journal_mode)?)
+        .synchronous(SqliteSynchronous::from_str(&config.synchronous)?)
+        .busy_timeout(Duration::from_secs(config.busy_timeout))
+        .pragma("cache_size", config.cache_size.to_string())
+        .pragma("mmap_size", config.mmap_size.to_string()))
+}
+
+/// Switches a database to the configured `auto_vacuum` mode. The mode is fixed when the first
+/// table is created and cannot follow the switch to WAL on connect, so an empty database is
+/// vacuumed to store it; an existing one is only converted by a manual VACUUM.
+async fn apply_auto_vacuum(
+    db_pool: &SqlitePool,
+    config: &DatabaseConfig,
+) -> Result<(), sqlx::Error> {
+    let mode = match SqliteAutoVacuum::from_str(&config.auto_vacuum)? {
+        SqliteAutoVacuum::None => 0,
+        SqliteAutoVacuum::Full => 1,
+        SqliteAutoVacuum::Incremental => 2,
+    };
+    let (current,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+        .fetch_one(db_pool)
+        .await?;
+    if current == mode {
+        return Ok(());
+    }
+    let (tables,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'")
+            .fetch_one(db_pool)
+            .await?;
+    if tables > 0 {
+        println!(
+            "Database auto_vacuum is {}; run VACUUM to switch it to {}",
+            current, config.auto_vacuum
+        );
+        return Ok(());
+    }
+    let mut conn = db_pool.acquire().await?;
+    sqlx::query(&format!("PRAGMA auto_vacuum = {}", mode))
+        .execute(&mut conn)
+        .await?;
+    sqlx::query("VACUUM").execute(&mut conn).await?;
+    Ok(())
+}
+
+/// Opens a connection pool, either the read-only pool or the pool used for writes
+pub async fn connect(config: &DatabaseConfig, read_only: bool) -> Result<SqlitePool, sqlx::Error> {
+    let options = connect_options(config, read_only)?;
+    let max_connections = if read_only {
+        config.read_connections
+    } else {
+        config.write_connections
+    };
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(max_connections.max(1))
+        .connect_with(options)
+        .await?;
+    if !read_only {
+        apply_auto_vacuum(&db_pool, config).await?;
+    }
+    Ok(db_pool)
+}
+
+/// Creates the schema and applies column migrations for databases created by older versions.
+pub async fn init_schema(db_pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    // Version the database was last opened with, 0 when it was just created
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(db_pool)
+        .await?;
+    sqlx::query(CREATE_EVENTS_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_AUDIT_LOG_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_BANNED_EVENTS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_BANNED_PUBKEYS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_ORPHANS_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_MEDIA_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_LINK_PREVIEWS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_EVENT_VERSIONS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_event_versions_address
+         ON event_versions (pubkey, kind, d_tag)",
+    )
+    .execute(db_pool)
+    .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_media_sha256 ON media (sha256)")
+        .execute(db_pool)
+        .await?;
+
+    // Activity counts of databases created by older versions are computed once from the archive.
+    let has_activity = table_exists(db_pool, "activity").await?;
+    sqlx::query(CREATE_ACTIVITY_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_ACTIVITY_INSERT_TRIGGER)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_ACTIVITY_DELETE_TRIGGER)
+        .execute(db_pool)
+        .await?;
+    if !has_activity {
+        sqlx::query(
+            "INSERT INTO activity (bucket, kind, count)
+             SELECT created_at - created_at % 3600, kind, COUNT(*) FROM events GROUP BY 1, 2",
+        )
+        .execute(db_pool)
+        .await?;
+    }
+
+    sqlx::query(CREATE_REVERIFICATION_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_QUARANTINE_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_COLD_SEGMENTS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_COLD_EVENTS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_cold_events_segment ON cold_events (segment_id)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_cold_events_pubkey ON cold_events (pubkey, created_at)",
+    )
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_cold_events_kind ON cold_events (kind, created_at)",
+    )
+    .execute(db_pool)
+    .await?;
+    sqlx::query(CREATE_ATTESTATIONS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_BACKFILL_TABLE).execute(db_pool).await?;
+    sqlx::query(CREATE_RELAY_HEALTH_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_RELAY_CANDIDATES_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_RELAY_DISCOVERY_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_FINGERPRINTS_TABLE)
+        .execute(db_pool)
+        .await?;
+    for band in 0..4 {
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_fingerprints_band{0} ON fingerprints (band{0})",
+            band
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_fingerprints_cluster ON fingerprints (cluster_id)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_fingerprints_event ON fingerprints (event_id)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS fingerprints_delete AFTER DELETE ON events
+         WHEN OLD.folder IN ('notes', 'replies') BEGIN
+             DELETE FROM fingerprints WHERE event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+    sqlx::query(CREATE_DUPLICATE_SCAN_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_attestations_status ON attestations (status, checked_at)",
+    )
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS attestations_delete AFTER DELETE ON events
+         WHEN OLD.folder = 'attestations' BEGIN
+             DELETE FROM attestations WHERE event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+
+    // Usage of databases created by older versions is computed once from the archive. Replaceable
+    // events are left out, as the quotas exempt them; versions before 12 counted them, so their
+    // usage is computed again.
+    if version < 12 {
+        for statement in [
+            "DROP TRIGGER IF EXISTS pubkey_usage_insert",
+            "DROP TRIGGER IF EXISTS pubkey_usage_delete",
+            "DROP TABLE IF EXISTS pubkey_usage",
+        ] {
+            sqlx::query(statement).execute(db_pool).await?;
+        }
+    }
+    let has_usage = table_exists(db_pool, "pubkey_usage").await?;
+    let counted = format!(
+        "folder NOT IN ({})",
+        ingest::REPLACEABLE_FOLDERS
+            .iter()
+            .map(|folder| format!("'{}'", folder))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    sqlx::query(CREATE_PUBKEY_USAGE_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS pubkey_usage_insert AFTER INSERT ON events
+         WHEN NEW.{} BEGIN
+             INSERT INTO pubkey_usage (pubkey, events, bytes)
+             VALUES (NEW.pubkey, 1, {})
+             ON CONFLICT (pubkey) DO UPDATE SET events = events + 1, bytes = bytes + excluded.bytes;
+         END",
+        counted,
+        USAGE_BYTES
+            .replace("content", "NEW.content")
+            .replace("tags", "NEW.tags")
+    ))
+    .execute(db_pool)
+    .await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS pubkey_usage_delete AFTER DELETE ON events
+         WHEN OLD.{} BEGIN
+             UPDATE pubkey_usage SET events = events - 1, bytes = bytes - ({})
+             WHERE pubkey = OLD.pubkey;
+         END",
+        counted,
+        USAGE_BYTES
+            .replace("content", "OLD.content")
+            .replace("tags", "OLD.tags")
+    ))
+    .execute(db_pool)
+    .await?;
+    if !has_usage {
+        sqlx::query(&format!(
+            "INSERT INTO pubkey_usage (pubkey, events, bytes)
+             SELECT pubkey, COUNT(*), SUM({}) FROM events WHERE {} GROUP BY pubkey",
+            USAGE_BYTES, counted
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+
+    // Profiles of databases created by older versions are parsed once from the archive.
+    let has_profiles = table_exists(db_pool, "profiles").await?;
+    sqlx::query(CREATE_PROFILES_TABLE).execute(db_pool).await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS profiles_insert AFTER INSERT ON events
+         WHEN NEW.folder = 'users' BEGIN
+             INSERT OR REPLACE INTO profiles
+                 (pubkey, event_id, created_at, name, display_name, nip05, about, picture)
+             VALUES ({});
+         END",
+        PROFILE_COLUMNS
+    ))
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS profiles_delete AFTER DELETE ON events
+         WHEN OLD.folder = 'users' BEGIN
+             DELETE FROM profiles WHERE pubkey = OLD.pubkey AND event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+    if !has_profiles {
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO profiles
+                 (pubkey, event_id, created_at, name, display_name, nip05, about, picture)
+             SELECT {} FROM events AS NEW WHERE folder = 'users' ORDER BY created_at",
+            PROFILE_COLUMNS
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_profiles_name ON profiles (name)")
+        .execute(db_pool)
+        .await?;
+
+    // File metadata of databases created by older versions is indexed once from the archive.
+    let has_files = table_exists(db_pool, "files").await?;
+    sqlx::query(CREATE_FILES_TABLE).execute(db_pool).await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS files_insert AFTER INSERT ON events
+         WHEN NEW.folder = 'files' BEGIN
+             INSERT OR REPLACE INTO files (event_id, pubkey, created_at, url, sha256, mime, size)
+             VALUES ({});
+         END",
+        FILE_COLUMNS
+    ))
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS files_delete AFTER DELETE ON events
+         WHEN OLD.folder = 'files' BEGIN
+             DELETE FROM files WHERE event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+    if !has_files {
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO files (event_id, pubkey, created_at, url, sha256, mime, size)
+             SELECT {} FROM events AS NEW WHERE folder = 'files'",
+            FILE_COLUMNS
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_files_sha256 ON files (sha256)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_files_mime ON files (mime, created_at)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_files_url ON files (url)")
+        .execute(db_pool)
+        .await?;
+
+    // Geohashes of databases created by older versions are indexed once from the archive.
+    let has_geohashes = table_exists(db_pool, "geohashes").await?;
+    sqlx::query(CREATE_GEOHASHES_TABLE).execute(db_pool).await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS geohashes_insert AFTER INSERT ON events
+         WHEN NEW.folder = 'notes' BEGIN
+             INSERT OR REPLACE INTO geohashes (event_id, geohash, created_at)
+             SELECT NEW.event_id, lower(json_extract(tag.value, '$[1]')), NEW.created_at
+             FROM json_each(NEW.tags) AS tag WHERE {}
+             ORDER BY length(json_extract(tag.value, '$[1]')) DESC LIMIT 1;
+         END",
+        GEOHASH_TAG
+    ))
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS geohashes_delete AFTER DELETE ON events
+         WHEN OLD.folder = 'notes' BEGIN
+             DELETE FROM geohashes WHERE event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+    if !has_geohashes {
+        // Inserted shortest first, so the longest geohash of each note replaces the others.
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO geohashes (event_id, geohash, created_at)
+             SELECT NEW.event_id, lower(json_extract(tag.value, '$[1]')), NEW.created_at
+             FROM events AS NEW, json_each(NEW.tags) AS tag WHERE NEW.folder = 'notes' AND {}
+             ORDER BY length(json_extract(tag.value, '$[1]'))",
+            GEOHASH_TAG
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_geohashes_geohash ON geohashes (geohash)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_geohashes_created ON geohashes (created_at, event_id)",
+    )
+    .execute(db_pool)
+    .await?;
+
+    // Clients of databases created by older versions are indexed once from the archive.
+    let has_note_clients = table_exists(db_pool, "note_clients").await?;
+    sqlx::query(CREATE_NOTE_CLIENTS_TABLE)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE TRIGGER IF NOT EXISTS note_clients_insert AFTER INSERT ON events
+         WHEN NEW.folder IN ('notes', 'replies') AND {0} IS NOT NULL BEGIN
+             INSERT OR REPLACE INTO note_clients (event_id, pubkey, client, created_at)
+             VALUES (NEW.event_id, NEW.pubkey, {0}, NEW.created_at);
+         END",
+        NOTE_CLIENT
+    ))
+    .execute(db_pool)
+    .await?;
+    sqlx::query(
+        "CREATE TRIGGER IF NOT EXISTS note_clients_delete AFTER DELETE ON events
+         WHEN OLD.folder IN ('notes', 'replies') BEGIN
+             DELETE FROM note_clients WHERE event_id = OLD.event_id;
+         END",
+    )
+    .execute(db_pool)
+    .await?;
+    if !has_note_clients {
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO note_clients (event_id, pubkey, client, created_at)
+             SELECT * FROM (
+                 SELECT NEW.event_id, NEW.pubkey, {} AS client, NEW.created_at
+                 FROM events AS NEW WHERE NEW.folder IN ('notes', 'replies')
+             ) WHERE client IS NOT NULL",
+            NOTE_CLIENT
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_note_clients_created ON note_clients (created_at, client)",
+    )
+    .execute(db_pool)
+    .await?;
+
+    // Request details of audit log entries, added after the first version of the table.
+    ensure_column(db_pool, "audit_log", "parameters", "TEXT").await?;
+
+    // `d` tag of parameterized replaceable events, which address them together with pubkey and kind.
+    if ensure_column(db_pool, "events", "d_tag", "TEXT").await? {
+        sqlx::query(
+            r#"
+            UPDATE events SET d_tag = COALESCE((
+                SELECT json_extract(tag.value, '$[1]') FROM json_each(events.tags) AS tag
+                WHERE json_extract(tag.value, '$[0]') = 'd' LIMIT 1
+            ), '')
+            WHERE kind BETWEEN 30000 AND 39999
+            "#,
+        )
+        .execute(db_pool)
+        .await?;
+    }
+    // Set on events archived from pubkeys outside the web of trust.
+    ensure_column(db_pool, "events", "flagged", "INTEGER NOT NULL DEFAULT 0").await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_events_address ON events (pubkey, kind, d_tag)")
+        .execute(db_pool)
+        .await?;
+    // Set by the re-verification job on events whose id or signature does not verify.
+    ensure_column(db_pool, "events", "invalid", "INTEGER NOT NULL DEFAULT 0").await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_events_invalid ON events (invalid) WHERE invalid = 1",
+    )
+    .execute(db_pool)
+    .await?;
+    // `a` tag of interactions with parameterized replaceable events such as articles.
+    if ensure_column(db_pool, "events", "ref_address", "TEXT").await? {
+        sqlx::query(
+            r#"
+            UPDATE events SET ref_address = (
+                SELECT json_extract(tag.value, '$[1]') FROM json_each(events.tags) AS tag
+                WHERE json_extract(tag.value, '$[0]') = 'a' ORDER BY tag.key DESC LIMIT 1
+            )
+            WHERE folder IN ('replies', 'notes', 'reactions', 'zaps', 'reposts', 'approvals', 'live_chat',
+                'badge_awards', 'rsvps')
+            "#,
+        )
+        .execute(db_pool)
+        .await?;
+    }
+    // Versions before 16 archived RSVPs without their address.
+    if version < 16 {
+        sqlx::query(
+            r#"
+            UPDATE events SET ref_address = (
+                SELECT json_extract(tag.value, '$[1]') FROM json_each(events.tags) AS tag
+                WHERE json_extract(tag.value, '$[0]') = 'a' ORDER BY tag.key DESC LIMIT 1
+            )
+            WHERE folder = 'rsvps' AND ref_address IS NULL
+            "#,
+        )
+        .execute(db_pool)
+        .await?;
+    }
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_events_ref_address ON events (ref_address, folder)",
+    )
+    .execute(db_pool)
+    .await?;
+    // Folder listings of the interactions with an event and of a pubkey's events.
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_events_ref_event ON events (ref_event, folder)")
+        .execute(db_pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_events_folder_pubkey ON events (folder, pubkey, created_at)",
+    )
+    .execute(db_pool)
+    .await?;
+    // Malformed events per relay, added after the first version of the table.
+    ensure_column(
+        db_pool,
+        "relay_health",
+        "malformed",
+        "REAL NOT NULL DEFAULT 0",
+    )
+    .await?;
+    // NIP-26 delegator of events whose delegation token verifies.
+    if ensure_column(db_pool, "events", "delegator", "TEXT").await? {
+        let rows: Vec<(String, String, i64, i64, String, String, String)> = sqlx::query_as(
+            r#"SELECT event_id, pubkey, created_at, kind, content, sig, tags FROM events
+               WHERE tags LIKE '%"delegation"%'"#,
+        )
+        .fetch_all(db_pool)
+        .await?;
+        for (id, pubkey, created_at, kind, content, sig, tags) in rows {
+            let event = NostrEvent {
+                id,
+                pubkey,
+                created_at: created_at as u64,
+                kind: kind as u64,
+                tags: serde_json::from_str(&tags).unwrap_or_default(),
+                content,
+                sig,
+            };
+            if let Some(delegator) = event.delegator() {
+                sqlx::query("UPDATE events SET delegator = ? WHERE event_id = ?")
+                    .bind(delegator)
+                    .bind(&event.id)
+                    .execute(db_pool)
+                    .await?;
+            }
+        }
+    }
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_events_delegator ON events (delegator, created_at)
+         WHERE delegator IS NOT NULL",
+    )
+    .execute(db_pool)
+    .await?;
+    // Set on events cut down to the ingestion size limits, whose id and signature no longer
+    // verify.
+    ensure_column(db_pool, "events", "truncated", "INTEGER NOT NULL DEFAULT 0").await?;
+    // Recreated once the columns are in place, so the view exposes every column of `events`.
+    sqlx::query("DROP VIEW IF EXISTS public_events")
+        .execute(db_pool)
+        .await?;
+    sqlx::query(CREATE_PUBLIC_EVENTS_VIEW)
+        .execute(db_pool)
+        .await?;
+    sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+        .execute(db_pool)
+        .await?;
+    Ok(())
+}
+
+/// Opens an in-memory database with the current schema for tests
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("in-memory databases open");
+    init_schema(&db_pool).await.expect("the schema applies");
+    db_pool
+}
+
+/// Returns an unsigned event for tests, with an id made of `id` repeated and a `p` tag
+#[cfg(test)]
+pub fn test_event(id: char, kind: u64) -> NostrEvent {
+    NostrEvent {
+        id: id.to_string().repeat(64),
+        pubkey: "f".repeat(64),
+        created_at: 1_700_000_000,
+        kind,
+        tags: vec![vec!["p".to_string(), "e".repeat(64)]],
+        content: "hello".to_string(),
+        sig: String::new(),
+    }
+}
+
+/// Stores events for tests in their folders, unflagged and with the default version settings
+#[cfg(test)]
+pub async fn store_test_events(db_pool: &SqlitePool, events: &[&NostrEvent]) {
+    for event in events {
+        ingest::store_event(
+            db_pool,
+            event,
+            false,
+            false,
+            &crate::VersionConfig::default(),
+            &[],
+        )
+        .await
+        .expect("the event is stored");
+    }
+}
@@ -0,0 +1,89 @@
+use futures_util::TryStreamExt;
+use sqlx::SqlitePool;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{nostr, IngestConfig};
+
+/// Bloom filter of the ids of events already processed, so copies of an event received from
+/// several relays are dropped without touching the database. Ids are sha256 hashes, so the bit
+/// positions are derived from them directly by double hashing.
+#[derive(Debug)]
+pub struct SeenFilter {
+    /// Bit array; empty when the filter is disabled
+    bits: Vec<AtomicU64>,
+    /// Number of bits set per id
+    hashes: u64,
+}
+
+impl SeenFilter {
+    /// Sizes the filter for `capacity` ids at the configured false positive rate
+    fn with_capacity(capacity: usize, false_positive_rate: f64) -> Self {
+        if capacity == 0 {
+            return Self {
+                bits: Vec::new(),
+                hashes: 0,
+            };
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-(capacity as f64) * rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let words = bits.div_ceil(64).max(1);
+        let hashes = ((words * 64) as f64 / capacity as f64 * ln2)
+            .round()
+            .max(1.0) as u64;
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes,
+        }
+    }
+
+    /// Returns the bit positions of an id, `None` if it is not a 32-byte hex string
+    fn positions(&self, event_id: &str) -> Option<impl Iterator<Item = usize> + '_> {
+        if self.bits.is_empty() || !nostr::is_hex32(event_id) {
+            return None;
+        }
+        let h1 = u64::from_str_radix(&event_id[..16], 16).ok()?;
+        let h2 = u64::from_str_radix(&event_id[16..32], 16).ok()? | 1;
+        let len = (self.bits.len() * 64) as u64;
+        Some((0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize))
+    }
+
+    /// Returns whether the id was probably inserted before; never `false` for an inserted id
+    pub fn contains(&self, event_id: &str) -> bool {
+        let Some(mut positions) = self.positions(event_id) else {
+            return false;
+        };
+        positions.all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn insert(&self, event_id: &str) {
+        if let Some(positions) = self.positions(event_id) {
+            for bit in positions {
+                self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Builds the filter from the ids already archived, sized for at least twice as many
+    pub async fn load(db_pool: &SqlitePool, config: &IngestConfig) -> Result<Self, sqlx::Error> {
+        if config.dedup_capacity == 0 {
+            return Ok(Self::with_capacity(0, 0.0));
+        }
+        let (archived,): (i64,) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM events) + (SELECT COUNT(*) FROM cold_events)",
+        )
+        .fetch_one(db_pool)
+        .await?;
+        let capacity = config.dedup_capacity.max(archived as usize * 2);
+        let filter = Self::with_capacity(capacity, config.dedup_false_positive_rate);
+
+        let mut rows = sqlx::query_as::<_, (String,)>(
+            "SELECT event_id FROM events UNION ALL SELECT event_id FROM cold_events",
+        )
+        .fetch(db_pool);
+        while let Some((event_id,)) = rows.try_next().await? {
+            filter.insert(&event_id);
+        }
+        Ok(filter)
+    }
+}
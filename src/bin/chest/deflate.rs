@@ -0,0 +1,297 @@
+use flate2::{Decompress, FlushDecompress, Status};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Extension offered in the opening handshake. Chest sends little upstream, so it only asks
+/// relays to compress what they send and keeps its own frames uncompressed, as RFC 7692 allows.
+pub const OFFER: &str = "permessage-deflate";
+
+/// Largest frame or inflated message accepted, matching tungstenite's default message size
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Bytes a compressed message is missing at its end, per RFC 7692 section 7.2.2
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASK: u8 = 0x80;
+const OPCODE: u8 = 0x0f;
+const CONTINUATION: u8 = 0x0;
+const CONTROL: u8 = 0x8;
+
+#[derive(Debug)]
+enum State {
+    /// Reading the HTTP response of the opening handshake
+    Handshake,
+    /// The relay accepted the extension: compressed messages are inflated
+    Frames,
+    /// No extension in use, bytes are passed through untouched
+    Passthrough,
+}
+
+/// A compressed message being received
+#[derive(Debug)]
+struct Message {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Wraps a relay connection to inflate the permessage-deflate messages a relay sends.
+/// tungstenite 0.18 has no support for the extension and rejects frames with RSV1 set, so
+/// the adapter reads the handshake response to learn whether the relay accepted the offer, and
+/// then rewrites every compressed message as one plain frame before tungstenite parses it.
+#[derive(Debug)]
+pub struct Inflate<S> {
+    inner: S,
+    state: State,
+    /// Bytes read from the relay and not processed yet
+    input: Vec<u8>,
+    /// Bytes ready for tungstenite, from `position` on
+    output: Vec<u8>,
+    position: usize,
+    decompress: Decompress,
+    /// Whether the relay resets its compression context after every message
+    reset: bool,
+    message: Option<Message>,
+}
+
+impl<S> Inflate<S> {
+    /// Wraps a connection; without `offered` every byte is passed through
+    pub fn new(inner: S, offered: bool) -> Self {
+        Self {
+            inner,
+            state: if offered {
+                State::Handshake
+            } else {
+                State::Passthrough
+            },
+            input: Vec::new(),
+            output: Vec::new(),
+            position: 0,
+            decompress: Decompress::new(false),
+            reset: false,
+            message: None,
+        }
+    }
+
+    /// Moves the processed input to the output
+    fn pass(&mut self, length: usize) {
+        self.output.extend(self.input.drain(..length));
+    }
+
+    /// Processes the buffered input, returning whether more input is needed
+    fn process(&mut self) -> io::Result<bool> {
+        match self.state {
+            State::Handshake => {
+                let Some(end) = self
+                    .input
+                    .windows(4)
+                    .position(|window| window == b"\r\n\r\n")
+                else {
+                    return Ok(true);
+                };
+                let response = String::from_utf8_lossy(&self.input[..end]).into_owned();
+                let accepted = response.lines().skip(1).find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("sec-websocket-extensions")
+                        .then_some(value)
+                        .filter(|value| value.trim_start().starts_with(OFFER))
+                });
+                self.pass(end + 4);
+                self.state = match accepted {
+                    Some(parameters) => {
+                        self.reset = parameters
+                            .split(';')
+                            .any(|parameter| parameter.trim() == "server_no_context_takeover");
+                        State::Frames
+                    }
+                    None => State::Passthrough,
+                };
+                Ok(false)
+            }
+            State::Frames => self.frame(),
+            State::Passthrough => {
+                self.pass(self.input.len());
+                Ok(false)
+            }
+        }
+    }
+
+    /// Processes the next complete frame of the input
+    fn frame(&mut self) -> io::Result<bool> {
+        let Some(&[first, second]) = self.input.get(..2) else {
+            return Ok(true);
+        };
+        let (length, mut header) = match second & 0x7f {
+            126 => match self.input.get(2..4) {
+                Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+                None => return Ok(true),
+            },
+            127 => match self.input.get(2..10) {
+                Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+                None => return Ok(true),
+            },
+            length => (length as u64, 2),
+        };
+        if length > MAX_MESSAGE_SIZE as u64 {
+            return Err(invalid("frame too large"));
+        }
+        let mask = (second & MASK != 0).then_some(header);
+        if mask.is_some() {
+            header += 4;
+        }
+        let end = header + length as usize;
+        if self.input.len() < end {
+            return Ok(true);
+        }
+
+        let opcode = first & OPCODE;
+        let compressed = match &self.message {
+            _ if opcode & CONTROL != 0 => false,
+            Some(_) => opcode == CONTINUATION,
+            None => first & RSV1 != 0,
+        };
+        if !compressed {
+            self.pass(end);
+            return Ok(false);
+        }
+        let key = mask.map(|start| [0, 1, 2, 3].map(|i| self.input[start + i]));
+        let mut payload: Vec<u8> = self.input.drain(..end).skip(header).collect();
+        if let Some(key) = key {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= key[i % 4];
+            }
+        }
+        let message = self.message.get_or_insert(Message {
+            opcode,
+            payload: Vec::new(),
+        });
+        if message.payload.len() + payload.len() > MAX_MESSAGE_SIZE {
+            return Err(invalid("message too large"));
+        }
+        message.payload.append(&mut payload);
+        if first & FIN != 0 {
+            let message = self.message.take().expect("message started above");
+            let inflated = self.inflate(message.payload)?;
+            self.emit(message.opcode, &inflated);
+        }
+        Ok(false)
+    }
+
+    /// Inflates the payload of a compressed message
+    fn inflate(&mut self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.extend_from_slice(&TAIL);
+        let start = self.decompress.total_in();
+        let mut inflated = Vec::with_capacity(payload.len() * 4);
+        let mut ended = false;
+        loop {
+            if inflated.len() == inflated.capacity() {
+                inflated.reserve(inflated.len().max(4096));
+            }
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let consumed = (total_in - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&payload[consumed..], &mut inflated, FlushDecompress::Sync)
+                .map_err(|e| invalid(&e.to_string()))?;
+            if inflated.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid("inflated message too large"));
+            }
+            // A relay may end the deflate stream with a final block; the next message starts
+            // a new one.
+            if status == Status::StreamEnd {
+                ended = true;
+                break;
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == payload.len() && inflated.len() < inflated.capacity() {
+                break;
+            }
+            if self.decompress.total_in() == total_in && self.decompress.total_out() == total_out {
+                return Err(invalid("truncated compressed message"));
+            }
+        }
+        if self.reset || ended {
+            self.decompress.reset(false);
+        }
+        Ok(inflated)
+    }
+
+    /// Queues an unmasked, unfragmented frame for tungstenite
+    fn emit(&mut self, opcode: u8, payload: &[u8]) {
+        self.output.push(FIN | opcode);
+        match payload.len() {
+            length if length < 126 => self.output.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                self.output.push(126);
+                self.output.extend((length as u16).to_be_bytes());
+            }
+            length => {
+                self.output.push(127);
+                self.output.extend((length as u64).to_be_bytes());
+            }
+        }
+        self.output.extend_from_slice(payload);
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inflate<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.output.len() {
+                let length = buf.remaining().min(this.output.len() - this.position);
+                buf.put_slice(&this.output[this.position..this.position + length]);
+                this.position += length;
+                if this.position == this.output.len() {
+                    this.output.clear();
+                    this.position = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if matches!(this.state, State::Passthrough) && this.input.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            if !this.process()? {
+                continue;
+            }
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) if read.filled().is_empty() => return Poll::Ready(Ok(())),
+                Poll::Ready(Ok(())) => this.input.extend_from_slice(read.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inflate<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
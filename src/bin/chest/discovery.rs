@@ -0,0 +1,403 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::error::Error;
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use url::Url;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{nostr, DbEvent, DiscoveryConfig};
+
+/// Archived events scanned for relay hints per statement
+const SCAN_BATCH_SIZE: i64 = 1000;
+
+/// Number of candidates returned by `GET /admin/relays/candidates` unless `limit` is given
+const DEFAULT_LIST_LIMIT: i64 = 100;
+
+/// Time a candidate relay gets to answer a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tags whose third value is a relay hint
+const HINTED_TAGS: &[&str] = &["e", "p", "a", "q"];
+
+/// A batch of archived rows, in rowid order
+#[derive(Debug, sqlx::FromRow)]
+struct Row {
+    rowid: i64,
+    #[sqlx(flatten)]
+    event: DbEvent,
+}
+
+/// A relay hint found in an event, with what the hinted relay should hold
+#[derive(Debug)]
+struct Hint {
+    relay_url: String,
+    event_id: Option<String>,
+    pubkey: Option<String>,
+}
+
+/// Normalizes a hinted relay URL, returning `None` for anything but a plain `ws://` or
+/// `wss://` URL
+fn normalize(relay_url: &str) -> Option<String> {
+    let url = Url::parse(relay_url.trim()).ok()?;
+    if !matches!(url.scheme(), "ws" | "wss")
+        || url.host_str().is_none()
+        || !url.username().is_empty()
+        || url.query().is_some()
+    {
+        return None;
+    }
+    Some(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Collects the relay hints of an event's tags and of the `nostr:` entities in its content
+fn hints(event: &DbEvent) -> Vec<Hint> {
+    let mut hints = Vec::new();
+    let tags: Vec<Vec<String>> = serde_json::from_str(&event.tags).unwrap_or_default();
+    for tag in &tags {
+        let [name, value, relay_url, ..] = tag.as_slice() else {
+            continue;
+        };
+        if !HINTED_TAGS.contains(&name.as_str()) {
+            continue;
+        }
+        let Some(relay_url) = normalize(relay_url) else {
+            continue;
+        };
+        hints.push(Hint {
+            relay_url,
+            event_id: matches!(name.as_str(), "e" | "q")
+                .then(|| value.clone())
+                .filter(|id| nostr::is_hex32(id)),
+            pubkey: (name == "p")
+                .then(|| value.clone())
+                .filter(|pubkey| nostr::is_hex32(pubkey)),
+        });
+    }
+    for (index, prefix) in event.content.match_indices("nostr:") {
+        let entity: String = event.content[index + prefix.len()..]
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect();
+        let event_id = nostr::parse_event_id(&entity);
+        let pubkey = nostr::parse_pubkey(&entity);
+        for relay_url in nostr::relay_hints(&entity) {
+            if let Some(relay_url) = normalize(&relay_url) {
+                hints.push(Hint {
+                    relay_url,
+                    event_id: event_id.clone(),
+                    pubkey: pubkey.clone(),
+                });
+            }
+        }
+    }
+    // An event counts once per relay it hints.
+    let mut seen = Vec::new();
+    hints.retain(|hint| {
+        let first = !seen.contains(&hint.relay_url);
+        seen.push(hint.relay_url.clone());
+        first
+    });
+    hints
+}
+
+/// Records the relay hints of the next batch of archived events, returning how many events
+/// were scanned
+async fn scan_batch(db_pool: &SqlitePool, known: &[String]) -> Result<usize, sqlx::Error> {
+    let last_rowid: Option<(i64,)> =
+        sqlx::query_as("SELECT last_rowid FROM relay_discovery WHERE id = 1")
+            .fetch_optional(db_pool)
+            .await?;
+    let rows: Vec<Row> = sqlx::query_as(
+        "SELECT rowid, event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM events WHERE rowid > ? ORDER BY rowid LIMIT ?",
+    )
+    .bind(last_rowid.map_or(0, |(rowid,)| rowid))
+    .bind(SCAN_BATCH_SIZE)
+    .fetch_all(db_pool)
+    .await?;
+    let Some(last) = rows.last().map(|row| row.rowid) else {
+        return Ok(0);
+    };
+
+    let now = nostr::now() as i64;
+    let mut tx = db_pool.begin().await?;
+    for hint in rows.iter().flat_map(|row| hints(&row.event)) {
+        if known.contains(&hint.relay_url) {
+            continue;
+        }
+        sqlx::query(
+            "INSERT INTO relay_candidates
+                 (relay_url, hints, first_seen, last_seen, sample_event, sample_pubkey)
+             VALUES (?, 1, ?, ?, ?, ?)
+             ON CONFLICT (relay_url) DO UPDATE SET hints = hints + 1,
+                 last_seen = excluded.last_seen,
+                 sample_event = COALESCE(excluded.sample_event, sample_event),
+                 sample_pubkey = COALESCE(excluded.sample_pubkey, sample_pubkey)",
+        )
+        .bind(&hint.relay_url)
+        .bind(now)
+        .bind(now)
+        .bind(hint.event_id)
+        .bind(hint.pubkey)
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query(
+        "INSERT INTO relay_discovery (id, last_rowid, updated_at) VALUES (1, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET last_rowid = excluded.last_rowid,
+             updated_at = excluded.updated_at",
+    )
+    .bind(last)
+    .bind(now)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(rows.len())
+}
+
+/// Returns whether a relay URL matches a pattern, where `*` stands for any run of characters
+fn matches(pattern: &str, relay_url: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = relay_url.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Returns whether the `allow` and `deny` patterns let chest connect to a candidate relay
+fn allowed(config: &DiscoveryConfig, relay_url: &str) -> bool {
+    !config
+        .deny
+        .iter()
+        .any(|pattern| matches(pattern, relay_url))
+        && (config.allow.is_empty()
+            || config
+                .allow
+                .iter()
+                .any(|pattern| matches(pattern, relay_url)))
+}
+
+/// Sends a REQ to a relay over a short-lived connection, returning whether it sent any event
+/// before EOSE
+async fn probe(relay_url: &str, filter: Value) -> Result<bool, Box<dyn Error>> {
+    let (mut ws_stream, _) = connect_async(Url::parse(relay_url)?).await?;
+    let subscription_id = Uuid::new_v4().to_string();
+    let req_message = serde_json::json!(["REQ", subscription_id, filter]);
+    ws_stream
+        .send(Message::Text(req_message.to_string()))
+        .await?;
+    let mut found = false;
+    while let Some(message) = ws_stream.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        if parts.get(1).and_then(Value::as_str) != Some(subscription_id.as_str()) {
+            continue;
+        }
+        match parts.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                found = true;
+                break;
+            }
+            Some("EOSE" | "CLOSED") => break,
+            _ => {}
+        }
+    }
+    let _ = ws_stream.close(None).await;
+    Ok(found)
+}
+
+/// Asks the most hinted candidates for an event or author they were hinted for, counting a
+/// probe as successful when the relay has it
+async fn probe_candidates(
+    db_pool: &SqlitePool,
+    config: &DiscoveryConfig,
+) -> Result<(), sqlx::Error> {
+    let candidates: Vec<(String, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT relay_url, sample_event, sample_pubkey FROM relay_candidates
+         WHERE hints >= ? AND added = 0 AND last_probe < ?
+         ORDER BY last_probe, hints DESC",
+    )
+    .bind(config.min_hints as i64)
+    .bind(nostr::now().saturating_sub(config.interval) as i64)
+    .fetch_all(db_pool)
+    .await?;
+    for (relay_url, sample_event, sample_pubkey) in candidates
+        .into_iter()
+        .filter(|(relay_url, _, _)| allowed(config, relay_url))
+        .take(config.probes)
+    {
+        let filter = match (sample_event, sample_pubkey) {
+            (Some(event_id), _) => serde_json::json!({ "ids": [event_id] }),
+            (None, Some(pubkey)) => serde_json::json!({ "authors": [pubkey], "limit": 1 }),
+            (None, None) => continue,
+        };
+        let success = match tokio::time::timeout(PROBE_TIMEOUT, probe(&relay_url, filter)).await {
+            Ok(Ok(found)) => found,
+            Ok(Err(e)) => {
+                eprintln!("Failed to probe candidate relay {}: {}", relay_url, e);
+                false
+            }
+            Err(_) => false,
+        };
+        sqlx::query(
+            "UPDATE relay_candidates SET probes = probes + 1, successes = successes + ?,
+                 last_probe = ?
+             WHERE relay_url = ?",
+        )
+        .bind(success as i64)
+        .bind(nostr::now() as i64)
+        .bind(&relay_url)
+        .execute(db_pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Marks the best candidates as added, up to `max_relays`: those hinted at least `min_hints`
+/// times that had what they were hinted for in at least `min_success_ratio` of the probes
+async fn add_candidates(db_pool: &SqlitePool, config: &DiscoveryConfig) -> Result<(), sqlx::Error> {
+    let (added,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM relay_candidates WHERE added = 1")
+        .fetch_one(db_pool)
+        .await?;
+    let room = (config.max_relays as i64).saturating_sub(added);
+    if room <= 0 {
+        return Ok(());
+    }
+    let candidates: Vec<(String,)> = sqlx::query_as(
+        "SELECT relay_url FROM relay_candidates
+         WHERE added = 0 AND hints >= ? AND probes > 0
+             AND CAST(successes AS REAL) / probes >= ?
+         ORDER BY hints DESC",
+    )
+    .bind(config.min_hints as i64)
+    .bind(config.min_success_ratio)
+    .fetch_all(db_pool)
+    .await?;
+    for (relay_url,) in candidates
+        .into_iter()
+        .filter(|(relay_url,)| allowed(config, relay_url))
+        .take(room as usize)
+    {
+        sqlx::query("UPDATE relay_candidates SET added = 1 WHERE relay_url = ?")
+            .bind(&relay_url)
+            .execute(db_pool)
+            .await?;
+        println!(
+            "Added discovered relay {}; it is connected from the next start",
+            relay_url
+        );
+    }
+    Ok(())
+}
+
+/// Lists the relays added by discovery that the `allow` and `deny` patterns still accept
+pub async fn added_relays(
+    db_pool: &SqlitePool,
+    config: &DiscoveryConfig,
+) -> Result<Vec<String>, sqlx::Error> {
+    let relays: Vec<(String,)> = sqlx::query_as(
+        "SELECT relay_url FROM relay_candidates WHERE added = 1 ORDER BY hints DESC LIMIT ?",
+    )
+    .bind(config.max_relays as i64)
+    .fetch_all(db_pool)
+    .await?;
+    Ok(relays
+        .into_iter()
+        .map(|(relay_url,)| relay_url)
+        .filter(|relay_url| allowed(config, relay_url))
+        .collect())
+}
+
+/// Collects the relay hints of newly archived events every `interval` seconds, probes the most
+/// hinted candidates and, with `auto_add`, adds the best ones
+pub fn spawn(config: DiscoveryConfig, known: Vec<String>, db_pool: SqlitePool) {
+    if !config.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        loop {
+            interval.tick().await;
+            loop {
+                match scan_batch(&db_pool, &known).await {
+                    Ok(scanned) if (scanned as i64) < SCAN_BATCH_SIZE => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to collect relay hints: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = probe_candidates(&db_pool, &config).await {
+                eprintln!("Failed to probe candidate relays: {:?}", e);
+            }
+            if config.auto_add {
+                if let Err(e) = add_candidates(&db_pool, &config).await {
+                    eprintln!("Failed to add discovered relays: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+/// A relay hinted by archived events, reported by `GET /admin/relays/candidates`
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Candidate {
+    relay_url: String,
+    /// Times the relay was hinted
+    hints: i64,
+    first_seen: i64,
+    last_seen: i64,
+    probes: i64,
+    /// Probes the relay had the hinted event or author for
+    successes: i64,
+    last_probe: i64,
+    /// Whether discovery added the relay to the ones chest subscribes to
+    added: bool,
+}
+
+/// Query parameters for `GET /admin/relays/candidates`
+#[derive(Debug, Deserialize)]
+pub struct CandidateQuery {
+    limit: Option<i64>,
+}
+
+/// Lists the relays hinted by archived events, most hinted first.
+pub async fn list_candidates(
+    req: HttpRequest,
+    params: web::Query<CandidateQuery>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_relay_candidates", "").await?;
+    let candidates: Vec<Candidate> = sqlx::query_as(
+        "SELECT relay_url, hints, first_seen, last_seen, probes, successes, last_probe, added
+         FROM relay_candidates ORDER BY hints DESC LIMIT ?",
+    )
+    .bind(params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, 1000))
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    Ok(HttpResponse::Ok().json(candidates))
+}
@@ -0,0 +1,233 @@
+use actix_web::{web, HttpResponse};
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::crypto;
+use crate::error::ApiError;
+use crate::home::parse_cursor;
+use crate::ingest::Ingestor;
+use crate::nostr::NostrEvent;
+use crate::{DbEvent, DmConfig};
+
+/// NIP-04 encrypted direct message
+pub const ENCRYPTED_DM_KIND: u64 = 4;
+
+/// NIP-59 gift wrap, carrying a sealed NIP-17 private message
+pub const GIFT_WRAP_KIND: u64 = 1059;
+
+/// Returns whether an event is a direct message. Only the operator's own are archived, and they
+/// are never handed to the MQTT, notification, media or preview sinks.
+pub fn is_direct_message(event: &NostrEvent) -> bool {
+    matches!(event.kind, ENCRYPTED_DM_KIND | GIFT_WRAP_KIND)
+}
+
+/// NIP-59 seal, the signed layer between a gift wrap and its message
+const SEAL_KIND: u64 = 13;
+
+/// Number of messages returned per page of `GET /dms` unless `limit` is given
+const DEFAULT_DMS_LIMIT: i64 = 50;
+
+/// Largest page of messages a client may request
+const MAX_DMS_LIMIT: i64 = 500;
+
+/// Archives the direct messages sent to and by the operator's key, decrypting them only when
+/// they are read through `GET /dms`
+#[derive(Debug)]
+pub struct DmBackup {
+    secret: SecretKey,
+    /// Hex pubkey of the operator
+    pub pubkey: String,
+}
+
+impl DmBackup {
+    /// Returns `None` when the DM backup is disabled or the private key is invalid
+    pub fn new(config: &DmConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let Some(secret) = crypto::parse_secret_key(&config.private_key) else {
+            eprintln!("Invalid dms.private_key, DM backup disabled");
+            return None;
+        };
+        Some(Self {
+            pubkey: crypto::public_key_hex(&secret),
+            secret,
+        })
+    }
+
+    /// Returns whether an event is a direct message to or from the operator
+    pub fn accepts(&self, event: &NostrEvent) -> bool {
+        let tags_me = event
+            .tags
+            .iter()
+            .any(|tag| tag.len() >= 2 && tag[0] == "p" && tag[1] == self.pubkey);
+        match event.kind {
+            ENCRYPTED_DM_KIND => tags_me || event.pubkey == self.pubkey,
+            GIFT_WRAP_KIND => tags_me,
+            _ => false,
+        }
+    }
+
+    /// Filters of the REQ subscribing to the operator's direct messages
+    pub fn filters(&self) -> [Value; 2] {
+        [
+            serde_json::json!({
+                "kinds": [ENCRYPTED_DM_KIND, GIFT_WRAP_KIND],
+                "#p": [self.pubkey],
+            }),
+            serde_json::json!({ "kinds": [ENCRYPTED_DM_KIND], "authors": [self.pubkey] }),
+        ]
+    }
+
+    /// Decrypts a NIP-04 message
+    fn open_nip04(&self, event: &DbEvent, tags: &[Vec<String>]) -> Option<Message> {
+        let recipient = tags
+            .iter()
+            .find(|tag| tag.len() >= 2 && tag[0] == "p")
+            .map(|tag| tag[1].clone())?;
+        let counterparty = if event.pubkey == self.pubkey {
+            &recipient
+        } else {
+            &event.pubkey
+        };
+        Some(Message {
+            kind: ENCRYPTED_DM_KIND,
+            from: event.pubkey.clone(),
+            to: vec![recipient.clone()],
+            created_at: event.created_at,
+            content: crypto::nip04_decrypt(&self.secret, counterparty, &event.content)?,
+        })
+    }
+
+    /// Unwraps a NIP-59 gift wrap: the wrap decrypts to a seal signed by the sender, which
+    /// decrypts to the unsigned message
+    fn open_gift_wrap(&self, event: &DbEvent) -> Option<Message> {
+        let key = crypto::nip44_conversation_key(&self.secret, &event.pubkey)?;
+        let seal: NostrEvent =
+            serde_json::from_str(&crypto::nip44_decrypt(&key, &event.content)?).ok()?;
+        if seal.kind != SEAL_KIND || seal.verify().is_err() {
+            return None;
+        }
+        let key = crypto::nip44_conversation_key(&self.secret, &seal.pubkey)?;
+        let rumor: Value =
+            serde_json::from_str(&crypto::nip44_decrypt(&key, &seal.content)?).ok()?;
+        // The message must be written by whoever signed the seal.
+        if rumor["pubkey"].as_str() != Some(seal.pubkey.as_str()) {
+            return None;
+        }
+        let to = rumor["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|tag| tag[0] == "p")
+            .filter_map(|tag| tag[1].as_str().map(str::to_string))
+            .collect();
+        Some(Message {
+            kind: rumor["kind"].as_u64()?,
+            from: seal.pubkey,
+            to,
+            created_at: rumor["created_at"].as_i64()?,
+            content: rumor["content"].as_str()?.to_string(),
+        })
+    }
+
+    /// Decrypts an archived direct message
+    fn open(&self, event: &DbEvent) -> Option<Message> {
+        match event.kind as u64 {
+            ENCRYPTED_DM_KIND => {
+                let tags: Vec<Vec<String>> = serde_json::from_str(&event.tags).ok()?;
+                self.open_nip04(event, &tags)
+            }
+            GIFT_WRAP_KIND => self.open_gift_wrap(event),
+            _ => None,
+        }
+    }
+}
+
+/// A decrypted direct message
+#[derive(Debug, Serialize)]
+struct Message {
+    /// 4 for NIP-04 messages, the kind of the unwrapped message (14 for chat) for gift wraps
+    kind: u64,
+    from: String,
+    to: Vec<String>,
+    created_at: i64,
+    content: String,
+}
+
+/// Query parameters of `GET /dms`
+#[derive(Debug, Deserialize)]
+pub struct DmQuery {
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+/// Lists the archived direct messages of the operator, decrypted, newest archived first. Pages
+/// are continued by passing the returned `next_cursor`, which is `null` on the last page.
+/// Messages that cannot be decrypted have a `null` message.
+pub async fn list_dms(
+    params: web::Query<DmQuery>,
+    db_pool: web::Data<SqlitePool>,
+    ingestor: web::Data<Ingestor>,
+) -> Result<HttpResponse, ApiError> {
+    let backup = ingestor
+        .dms
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("DM backup is not enabled".to_string()))?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_DMS_LIMIT)
+        .clamp(1, MAX_DMS_LIMIT);
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM events WHERE folder = 'dms'",
+    );
+    if let Some(cursor) = params.cursor.as_deref() {
+        let (created_at, event_id) = parse_cursor(cursor)
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid cursor: {}", cursor)))?;
+        query.push(" AND (created_at < ");
+        query.push_bind(created_at);
+        query.push(" OR (created_at = ");
+        query.push_bind(created_at);
+        query.push(" AND event_id < ");
+        query.push_bind(event_id);
+        query.push("))");
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY created_at DESC, event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| format!("{}:{}", event.created_at, event.event_id))
+    } else {
+        None
+    };
+
+    let messages: Vec<Value> = events
+        .iter()
+        .map(|event| {
+            serde_json::json!({
+                "event_id": event.event_id,
+                "kind": event.kind,
+                "message": backup.open(event),
+            })
+        })
+        .collect();
+    // Decrypted messages are private: never cached by shared caches.
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(serde_json::json!({
+            "messages": messages,
+            "next_cursor": next_cursor,
+        })))
+}
@@ -0,0 +1,543 @@
+use cron::Schedule;
+use futures_util::{SinkExt, StreamExt};
+use sqlx::sqlite::SqliteConnection;
+use sqlx::{ConnectOptions, Connection};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use crate::db::{self, SCHEMA_VERSION};
+use crate::ingest;
+use crate::{crypto, load_config, nip11, nostr, signer, tls, AppConfig, DatabaseConfig, TlsConfig};
+
+/// Seconds to wait for a relay to accept the connection and answer a REQ
+const RELAY_TIMEOUT: u64 = 10;
+
+/// Tally of the checks, printed as they run
+#[derive(Debug, Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: impl Display) {
+        println!("[ok]   {}", check);
+    }
+
+    /// Something works but probably not as intended
+    fn warn(&mut self, check: impl Display, hint: impl Display) {
+        self.warnings += 1;
+        println!("[warn] {}\n       -> {}", check, hint);
+    }
+
+    /// Something that keeps chest from starting or archiving
+    fn fail(&mut self, check: impl Display, hint: impl Display) {
+        self.failures += 1;
+        println!("[FAIL] {}\n       -> {}", check, hint);
+    }
+}
+
+/// Checks settings whose mistakes only show up once the server is running
+fn check_config(config: &AppConfig, report: &mut Report) {
+    let addresses = config.server.bind_address.addresses();
+    if addresses.is_empty() {
+        report.fail(
+            "server.bind_address is empty",
+            "list at least one address, e.g. [\"127.0.0.1:8080\", \"[::1]:8080\"]",
+        );
+    }
+    for address in addresses {
+        match address.strip_prefix("unix:") {
+            Some(path) => {
+                let directory = Path::new(path)
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty());
+                if path.is_empty() || directory.is_some_and(|directory| !directory.is_dir()) {
+                    report.fail(
+                        format!("server.bind_address {:?} is not a usable socket path", address),
+                        "use unix: followed by a path in an existing directory, e.g. \"unix:/run/chest/chest.sock\"",
+                    );
+                }
+            }
+            None if SocketAddr::from_str(address).is_err() => report.fail(
+                format!("server.bind_address {:?} is not an address", address),
+                "use an IP address and port, e.g. \"127.0.0.1:8080\" or \"[::1]:8080\"",
+            ),
+            None => {}
+        }
+    }
+    if !config.server.tls.cert.is_empty() {
+        if let Err(e) = tls::server_config(&config.server.tls) {
+            report.fail(
+                format!("server.tls: {}", e),
+                "point cert and key to the PEM certificate chain and private key",
+            );
+        }
+    } else if !config.server.tls.key.is_empty() {
+        report.warn(
+            "server.tls.key is set without server.tls.cert",
+            "set cert as well to serve HTTPS, or remove key",
+        );
+    }
+    if config.relays.urls.is_empty() {
+        report.warn(
+            "relays.urls is empty",
+            "add the relays to archive from, e.g. urls = [\"wss://relay.damus.io\"]",
+        );
+    }
+    let archive_urls = config
+        .archives
+        .iter()
+        .flat_map(|archive| &archive.relays.urls);
+    for relay_url in config.relays.urls.iter().chain(archive_urls) {
+        if !Url::parse(relay_url).is_ok_and(|url| matches!(url.scheme(), "ws" | "wss")) {
+            report.fail(
+                format!("relay {:?} is not a WebSocket URL", relay_url),
+                "relay URLs start with wss:// (or ws:// for local relays)",
+            );
+        }
+    }
+    let tls_settings = std::iter::once(&config.relays)
+        .chain(config.archives.iter().map(|archive| &archive.relays))
+        .flat_map(|relays| {
+            std::iter::once(("relays.tls".to_string(), &relays.tls)).chain(
+                relays
+                    .relay_tls
+                    .iter()
+                    .map(|(relay_url, tls)| (format!("relays.relay_tls.{:?}", relay_url), tls)),
+            )
+        });
+    for (name, settings) in tls_settings {
+        if let Err(e) = tls::connector(settings) {
+            report.fail(
+                format!("{}: {}", name, e),
+                "point ca_file to a readable PEM file and give pins as SHA-256 fingerprints",
+            );
+        }
+    }
+    if config.event.kinds.is_empty() {
+        report.warn(
+            "event.kinds is empty",
+            "list the kinds to archive, e.g. kinds = [0, 1, 3, 7, 9735]",
+        );
+    }
+    let ephemeral: Vec<u64> = config
+        .event
+        .kinds
+        .iter()
+        .copied()
+        .filter(|kind| ingest::EPHEMERAL_KINDS.contains(kind))
+        .collect();
+    if !ephemeral.is_empty() {
+        report.warn(
+            format!("event.kinds lists the ephemeral kinds {:?}", ephemeral),
+            "ephemeral events are never archived: move them to event.ephemeral_kinds to forward them to MQTT and notifications",
+        );
+    }
+    let persistent: Vec<u64> = config
+        .event
+        .ephemeral_kinds
+        .iter()
+        .copied()
+        .filter(|kind| !ingest::EPHEMERAL_KINDS.contains(kind))
+        .collect();
+    if !persistent.is_empty() {
+        report.warn(
+            format!(
+                "event.ephemeral_kinds lists the non-ephemeral kinds {:?}",
+                persistent
+            ),
+            "only kinds 20000 to 29999 are forwarded; archive the others through event.kinds",
+        );
+    }
+    let no_credentials = config.auth.api_keys.is_empty() && config.auth.admin_pubkeys.is_empty();
+    if no_credentials && config.auth.protect_reads {
+        report.fail(
+            "auth.protect_reads is set but no API key or admin pubkey is configured",
+            "every request will be rejected: add auth.api_keys or auth.admin_pubkeys",
+        );
+    } else if no_credentials {
+        report.warn(
+            "no API key or admin pubkey is configured",
+            "the /admin and /dms routes are unreachable until auth.api_keys or auth.admin_pubkeys is set",
+        );
+    }
+    if config.wot.enabled && nostr::parse_pubkey(&config.wot.root_pubkey).is_none() {
+        report.fail(
+            "wot.root_pubkey is not a valid pubkey",
+            "set it to the hex or npub pubkey the web of trust starts from",
+        );
+    }
+    if config.dms.enabled && crypto::parse_secret_key(&config.dms.private_key).is_none() {
+        report.fail(
+            "dms.private_key is not a valid private key",
+            "set it to the hex or nsec key of the account whose messages are backed up",
+        );
+    }
+    if config.backup.enabled {
+        if let Err(e) = Schedule::from_str(&config.backup.schedule) {
+            report.fail(
+                format!(
+                    "backup.schedule {:?} is invalid: {}",
+                    config.backup.schedule, e
+                ),
+                "use a cron expression with seconds, e.g. \"0 0 3 * * *\" for 03:00 UTC",
+            );
+        }
+        let s3 = &config.backup.s3;
+        if s3.enabled
+            && [&s3.endpoint, &s3.bucket, &s3.access_key, &s3.secret_key]
+                .iter()
+                .any(|value| value.is_empty())
+        {
+            report.fail(
+                "backup.s3 is enabled but incomplete",
+                "set endpoint, bucket, access_key and secret_key",
+            );
+        }
+    }
+    if config.notify.enabled {
+        let telegram = !config.notify.telegram_bot_token.is_empty()
+            && !config.notify.telegram_chat_id.is_empty();
+        if !telegram && config.notify.discord_webhook_url.is_empty() {
+            report.fail(
+                "notify is enabled without a destination",
+                "set telegram_bot_token and telegram_chat_id, or discord_webhook_url",
+            );
+        }
+    }
+    if !config.signer.bunker.is_empty() {
+        if let Err(e) = signer::parse_bunker_uri(&config.signer.bunker) {
+            report.fail(
+                format!("signer.bunker: {}", e),
+                "paste the bunker:// URI shown by your remote signer",
+            );
+        }
+    }
+    if config.ipfs.enabled {
+        if let Err(e) = Schedule::from_str(&config.ipfs.schedule) {
+            report.fail(
+                format!("ipfs.schedule {:?} is invalid: {}", config.ipfs.schedule, e),
+                "use a cron expression with seconds, e.g. \"0 0 4 * * Sun\" for Sundays 04:00 UTC",
+            );
+        }
+        if let Err(e) = Url::parse(&config.ipfs.api_url) {
+            report.fail(
+                format!("ipfs.api_url {:?} is invalid: {}", config.ipfs.api_url, e),
+                "set it to the RPC API of your IPFS node, e.g. \"http://127.0.0.1:5001\"",
+            );
+        }
+        let pinning = &config.ipfs.pinning;
+        if pinning.enabled && (pinning.endpoint.is_empty() || pinning.access_token.is_empty()) {
+            report.fail(
+                "ipfs.pinning is enabled but incomplete",
+                "set endpoint and access_token",
+            );
+        }
+        if config.ipfs.announce && config.signer.bunker.is_empty() {
+            report.warn(
+                "ipfs.announce is set but no remote signer is configured",
+                "configure [signer] with a bunker URI, or set announce = false",
+            );
+        }
+    }
+    let mut names = HashSet::new();
+    let mut paths = HashSet::from([config.database.path.as_str()]);
+    for archive in &config.archives {
+        if archive.name.is_empty() || archive.name.contains('/') {
+            report.fail(
+                format!("archive name {:?} is not a path segment", archive.name),
+                "use a name without slashes, e.g. \"community\"",
+            );
+        }
+        if !names.insert(archive.name.as_str()) {
+            report.fail(
+                format!("archive name {:?} is used twice", archive.name),
+                "give every [[archives]] entry its own name",
+            );
+        }
+        if !paths.insert(archive.database.path.as_str()) {
+            report.fail(
+                format!(
+                    "archive {:?} shares the database {}",
+                    archive.name, archive.database.path
+                ),
+                "give every archive its own database.path",
+            );
+        }
+    }
+}
+
+/// Checks that a database exists, opens and accepts writes
+async fn check_database(label: &str, config: &DatabaseConfig, report: &mut Report) {
+    let path = &config.path;
+    if !Path::new(path).exists() {
+        report.fail(
+            format!("{} database {} does not exist", label, path),
+            format!("create it with `touch {}` or fix database.path", path),
+        );
+        return;
+    }
+    // Files created with `touch` hold no database yet, which a read-only connection cannot
+    // set up.
+    let empty = std::fs::metadata(path).is_ok_and(|metadata| metadata.len() == 0);
+    if empty {
+        report.ok(format!(
+            "{} database {} is empty; the schema is created on start",
+            label, path
+        ));
+    }
+    if !empty && !check_schema(label, config, report).await {
+        return;
+    }
+
+    // Taking the write lock and rolling back proves writability without changing anything.
+    let writable: Result<(), sqlx::Error> = async {
+        let mut connection: SqliteConnection =
+            db::connect_options(config, false)?.connect().await?;
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("ROLLBACK").execute(&mut connection).await?;
+        connection.close().await
+    }
+    .await;
+    match writable {
+        Ok(()) => report.ok(format!("{} database {} is writable", label, path)),
+        Err(e) => report.fail(
+            format!("{} database {} is not writable: {}", label, path, e),
+            "chest needs write access to the file and its directory (for the WAL)",
+        ),
+    }
+}
+
+/// Checks that a database opens with the configured key and has a schema this version
+/// understands, returning whether it opened
+async fn check_schema(label: &str, config: &DatabaseConfig, report: &mut Report) -> bool {
+    let path = &config.path;
+    let key_hint = if config.key_file.is_empty() && std::env::var("CHEST_DATABASE_KEY").is_err() {
+        "the file is not a SQLite database, or it is encrypted and no key is configured"
+    } else {
+        "the encryption key does not match, or the file is not a SQLite database"
+    };
+    let options = match db::connect_options(config, true) {
+        Ok(options) => options,
+        Err(e) => {
+            report.fail(
+                format!("{} database settings: {}", label, e),
+                "check the database section of config.toml and the key file",
+            );
+            return false;
+        }
+    };
+    let mut connection = match options.connect().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            report.fail(format!("{} database {}: {}", label, path, e), key_hint);
+            return false;
+        }
+    };
+    let schema: Result<((i64,), (i64,)), sqlx::Error> = async {
+        let tables = sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'")
+            .fetch_one(&mut connection)
+            .await?;
+        let version = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&mut connection)
+            .await?;
+        Ok((tables, version))
+    }
+    .await;
+    match schema {
+        Err(e) => {
+            report.fail(format!("{} database {}: {}", label, path, e), key_hint);
+            return false;
+        }
+        Ok(((0,), _)) => report.ok(format!(
+            "{} database {} has no tables yet; the schema is created on start",
+            label, path
+        )),
+        Ok((_, (version,))) if version > SCHEMA_VERSION => report.fail(
+            format!(
+                "{} database {} has schema version {}, newer than this chest ({})",
+                label, path, version, SCHEMA_VERSION
+            ),
+            "upgrade chest, or restore a backup made by this version",
+        ),
+        Ok((_, (version,))) if version < SCHEMA_VERSION => report.warn(
+            format!(
+                "{} database {} has schema version {}, this chest uses {}",
+                label, path, version, SCHEMA_VERSION
+            ),
+            "it is migrated on the next start; take a backup first",
+        ),
+        Ok(_) => report.ok(format!(
+            "{} database {} opens with schema version {}",
+            label, path, SCHEMA_VERSION
+        )),
+    }
+    let _ = connection.close().await;
+    true
+}
+
+/// How a relay answered a test REQ
+enum RelayAnswer {
+    Events,
+    AuthRequired,
+    Closed(String),
+}
+
+/// Connects to a relay and sends a REQ for one event
+async fn probe_relay(relay_url: &str, tls: &TlsConfig) -> Result<RelayAnswer, String> {
+    let mut ws_stream = tls::connect_websocket(relay_url, tls)
+        .await
+        .map_err(|e| e.to_string())?;
+    let req_message = serde_json::json!(["REQ", "chest-doctor", { "limit": 1 }]);
+    ws_stream
+        .send(Message::Text(req_message.to_string()))
+        .await
+        .map_err(|e| e.to_string())?;
+    while let Some(message) = ws_stream.next().await {
+        let Message::Text(text) = message.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let Ok(serde_json::Value::Array(message)) = serde_json::from_str(&text) else {
+            continue;
+        };
+        match message.first().and_then(|value| value.as_str()) {
+            Some("EVENT") | Some("EOSE") => {
+                let _ = ws_stream.close(None).await;
+                return Ok(RelayAnswer::Events);
+            }
+            Some("AUTH") => return Ok(RelayAnswer::AuthRequired),
+            Some("CLOSED") => {
+                let reason = message
+                    .get(2)
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                if reason.starts_with("auth-required") {
+                    return Ok(RelayAnswer::AuthRequired);
+                }
+                return Ok(RelayAnswer::Closed(reason));
+            }
+            _ => {}
+        }
+    }
+    Err("connection closed before answering".to_string())
+}
+
+/// Checks that a relay accepts connections and subscriptions, and reports its NIP-11
+/// capabilities
+async fn check_relay(relay_url: &str, tls: &TlsConfig, config: &AppConfig, report: &mut Report) {
+    let has_signer = !config.signer.bunker.is_empty() && config.signer.auth;
+    let timeout = Duration::from_secs(RELAY_TIMEOUT);
+    match tokio::time::timeout(timeout, probe_relay(relay_url, tls)).await {
+        Err(_) => report.fail(
+            format!(
+                "relay {} did not answer within {}s",
+                relay_url, RELAY_TIMEOUT
+            ),
+            "check the URL and that the relay is up",
+        ),
+        Ok(Err(e)) => {
+            report.fail(
+                format!("relay {}: {}", relay_url, e),
+                "check the URL, DNS, proxy and firewall settings",
+            );
+            return;
+        }
+        Ok(Ok(RelayAnswer::Events)) => report.ok(format!("relay {} serves events", relay_url)),
+        Ok(Ok(RelayAnswer::AuthRequired)) if has_signer => report.ok(format!(
+            "relay {} requires NIP-42 AUTH, answered by the signer",
+            relay_url
+        )),
+        Ok(Ok(RelayAnswer::AuthRequired)) => report.fail(
+            format!("relay {} requires NIP-42 AUTH", relay_url),
+            "configure [signer] with a bunker URI and auth = true, or use another relay",
+        ),
+        Ok(Ok(RelayAnswer::Closed(reason))) => report.warn(
+            format!("relay {} refused the subscription: {}", relay_url, reason),
+            "the relay may restrict reads; check its policy",
+        ),
+    }
+
+    match nip11::fetch(relay_url, tls).await {
+        Ok(info) => {
+            report.ok(format!(
+                "relay {} supports NIPs {:?}",
+                relay_url, info.supported_nips
+            ));
+            if !info.supported_nips.contains(&45) {
+                println!("       (no NIP-45: event counts are not requested before subscribing)");
+            }
+            if info.limitation.payment_required {
+                report.warn(
+                    format!("relay {} requires payment", relay_url),
+                    "pay for access with the account chest authenticates as, or use another relay",
+                );
+            }
+        }
+        Err(e) => report.warn(
+            format!("relay {} has no NIP-11 document: {}", relay_url, e),
+            "optional, but chest only requests event counts from relays advertising NIP-45",
+        ),
+    }
+}
+
+/// Validates the configuration, the databases and the relays, printing what is wrong and how
+/// to fix it. Fails when a check failed.
+pub async fn doctor(args: &[String]) -> Result<(), String> {
+    if let Some(arg) = args.first() {
+        return Err(format!("Unknown argument: {}", arg));
+    }
+    let mut report = Report::default();
+
+    let config = match load_config() {
+        Ok(config) => {
+            report.ok("config.toml parses");
+            config
+        }
+        Err(e) => {
+            report.fail(
+                format!("config.toml: {}", e),
+                "compare it with the config.toml in the repository; the error names the key",
+            );
+            return Err("1 check failed".to_string());
+        }
+    };
+    check_config(&config, &mut report);
+
+    check_database("main", &config.database, &mut report).await;
+    for archive in &config.archives {
+        check_database(&archive.name, &archive.database, &mut report).await;
+    }
+
+    let mut relays = HashSet::new();
+    let urls = std::iter::once(&config.relays)
+        .chain(config.archives.iter().map(|archive| &archive.relays))
+        .flat_map(|relays| {
+            relays
+                .urls
+                .iter()
+                .map(move |url| (url, relays.tls_for(url)))
+        });
+    for (relay_url, tls) in urls {
+        // Invalid URLs were reported with the configuration.
+        let valid = Url::parse(relay_url).is_ok_and(|url| matches!(url.scheme(), "ws" | "wss"));
+        if valid && relays.insert(relay_url) {
+            check_relay(relay_url, tls, &config, &mut report).await;
+        }
+    }
+
+    println!("{} failures, {} warnings", report.failures, report.warnings);
+    if report.failures > 0 {
+        return Err(format!("{} checks failed", report.failures));
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ingest;
+use crate::nostr::NostrEvent;
+use crate::IngestConfig;
+
+/// Seconds between two summaries of the per-kind counters
+const REPORT_INTERVAL: u64 = 60;
+
+/// What a dry run saw of one kind
+#[derive(Debug, Default, Clone, Copy)]
+struct KindTally {
+    /// Events that would have been archived
+    archived: u64,
+    /// Serialized size of those events, an estimate of the storage they would use
+    bytes: u64,
+    /// Events rejected by the ingestion filters
+    dropped: u64,
+}
+
+/// Counts the events a dry run would archive instead of writing them, so filters can be tuned
+/// and storage estimated before archiving for real
+#[derive(Debug, Default)]
+pub struct DryRun {
+    tallies: Mutex<BTreeMap<u64, KindTally>>,
+}
+
+impl DryRun {
+    /// Returns `None` unless `ingest.dry_run` is set
+    pub fn new(config: &IngestConfig) -> Option<Self> {
+        config.dry_run.then(Self::default)
+    }
+
+    /// Counts an event rejected by the ingestion filters
+    pub fn record_dropped(&self, event: &NostrEvent) {
+        self.tallies
+            .lock()
+            .unwrap()
+            .entry(event.kind)
+            .or_default()
+            .dropped += 1;
+    }
+
+    /// Logs and counts an event that passed the filters, returning whether it would have been
+    /// archived: it has a folder and is not archived yet
+    pub async fn record(&self, db_pool: &SqlitePool, event: &NostrEvent, flagged: bool) -> bool {
+        let Some((folder, _)) = ingest::classify(event) else {
+            return false;
+        };
+        let archived: Result<(bool,), _> =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM events WHERE event_id = ?)")
+                .bind(&event.id)
+                .fetch_one(db_pool)
+                .await;
+        if archived.is_ok_and(|(archived,)| archived) {
+            return false;
+        }
+        let bytes = serde_json::to_string(event).map_or(0, |json| json.len()) as u64;
+        println!(
+            "Dry run: would archive kind {} event {} in {}{} ({} bytes)",
+            event.kind,
+            event.id,
+            folder,
+            if flagged { ", flagged" } else { "" },
+            bytes
+        );
+        let mut tallies = self.tallies.lock().unwrap();
+        let tally = tallies.entry(event.kind).or_default();
+        tally.archived += 1;
+        tally.bytes += bytes;
+        true
+    }
+
+    /// Prints a summary of the counters every minute
+    pub fn spawn_report(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(REPORT_INTERVAL));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let tallies = self.tallies.lock().unwrap().clone();
+                let total = tallies
+                    .values()
+                    .fold(KindTally::default(), |total, tally| KindTally {
+                        archived: total.archived + tally.archived,
+                        bytes: total.bytes + tally.bytes,
+                        dropped: total.dropped + tally.dropped,
+                    });
+                println!(
+                    "Dry run: {} events ({} bytes) would have been archived, {} dropped by filters",
+                    total.archived, total.bytes, total.dropped
+                );
+                for (kind, tally) in tallies {
+                    println!(
+                        "Dry run:   kind {}: {} archived ({} bytes), {} dropped",
+                        kind, tally.archived, tally.bytes, tally.dropped
+                    );
+                }
+            }
+        });
+    }
+}
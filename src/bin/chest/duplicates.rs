@@ -0,0 +1,368 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+use crate::cache::EventCache;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{audit, media, moderation, nostr, AppConfig, DuplicatesConfig};
+
+/// Archived rows fingerprinted per transaction
+const SCAN_BATCH_SIZE: i64 = 500;
+
+/// Number of clusters returned by `GET /admin/duplicates` unless `limit` is given
+const DEFAULT_LIST_LIMIT: i64 = 100;
+
+/// Largest Hamming distance the four indexed bands guarantee to find: fingerprints differing
+/// in at most 3 bits share at least one of their 16-bit quarters.
+const MAX_DISTANCE: u32 = 3;
+
+/// FNV-1a, a hash that stays the same across builds, as fingerprints are stored
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Computes the 64-bit simhash of a text from its lowercase words, so that texts differing in a
+/// few words, such as a campaign rotating links or greetings, get fingerprints differing in few
+/// bits
+fn simhash(content: &str) -> u64 {
+    let mut weights = [0i64; 64];
+    let words = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty());
+    for word in words {
+        let hash = fnv1a(&word.to_lowercase());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |simhash, (bit, _)| simhash | 1 << bit)
+}
+
+/// Splits a fingerprint into its four indexed 16-bit bands
+fn bands(simhash: u64) -> [i64; 4] {
+    [0, 1, 2, 3].map(|band| (simhash >> (band * 16) & 0xffff) as i64)
+}
+
+/// A row of the events table to fingerprint
+#[derive(Debug, sqlx::FromRow)]
+struct Row {
+    rowid: i64,
+    event_id: String,
+    pubkey: String,
+    created_at: i64,
+    folder: String,
+    content: String,
+}
+
+/// Fingerprints the notes and replies of the next batch of archived rows, adding each to the
+/// cluster of the first earlier fingerprint close enough to it, or starting a new cluster.
+/// Returns the number of rows scanned.
+async fn scan_batch(db_pool: &SqlitePool, config: &DuplicatesConfig) -> Result<usize, sqlx::Error> {
+    let last_rowid: Option<(i64,)> =
+        sqlx::query_as("SELECT last_rowid FROM duplicate_scan WHERE id = 1")
+            .fetch_optional(db_pool)
+            .await?;
+    let rows: Vec<Row> = sqlx::query_as(
+        "SELECT rowid, event_id, pubkey, created_at, folder, content
+         FROM events WHERE rowid > ? ORDER BY rowid LIMIT ?",
+    )
+    .bind(last_rowid.map_or(0, |(rowid,)| rowid))
+    .bind(SCAN_BATCH_SIZE)
+    .fetch_all(db_pool)
+    .await?;
+    let Some(last) = rows.last().map(|row| row.rowid) else {
+        return Ok(0);
+    };
+
+    let max_distance = config.max_distance.min(MAX_DISTANCE);
+    let mut tx = db_pool.begin().await?;
+    for row in &rows {
+        if !matches!(row.folder.as_str(), "notes" | "replies")
+            || row.content.chars().count() < config.min_length
+        {
+            continue;
+        }
+        let simhash = simhash(&row.content);
+        let bands = bands(simhash);
+        let candidates: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT simhash, cluster_id FROM fingerprints
+             WHERE (band0 = ? OR band1 = ? OR band2 = ? OR band3 = ?) AND created_at >= ?
+             ORDER BY id",
+        )
+        .bind(bands[0])
+        .bind(bands[1])
+        .bind(bands[2])
+        .bind(bands[3])
+        .bind(row.created_at - config.window as i64)
+        .fetch_all(&mut tx)
+        .await?;
+        let cluster_id = candidates
+            .into_iter()
+            .find(|(other, _)| (*other as u64 ^ simhash).count_ones() <= max_distance)
+            .map_or(row.rowid, |(_, cluster_id)| cluster_id);
+        sqlx::query(
+            "INSERT OR REPLACE INTO fingerprints
+                 (id, event_id, pubkey, created_at, simhash, band0, band1, band2, band3, cluster_id)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(row.rowid)
+        .bind(&row.event_id)
+        .bind(&row.pubkey)
+        .bind(row.created_at)
+        .bind(simhash as i64)
+        .bind(bands[0])
+        .bind(bands[1])
+        .bind(bands[2])
+        .bind(bands[3])
+        .bind(cluster_id)
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query(
+        "INSERT INTO duplicate_scan (id, last_rowid, updated_at) VALUES (1, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET last_rowid = excluded.last_rowid,
+             updated_at = excluded.updated_at",
+    )
+    .bind(last)
+    .bind(nostr::now() as i64)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(rows.len())
+}
+
+/// Fingerprints newly archived notes and replies every `interval` seconds
+pub fn spawn(config: DuplicatesConfig, db_pool: SqlitePool) {
+    if !config.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        loop {
+            interval.tick().await;
+            loop {
+                match scan_batch(&db_pool, &config).await {
+                    Ok(scanned) if (scanned as i64) < SCAN_BATCH_SIZE => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to fingerprint archived notes: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// A cluster of near-duplicate notes, reported by `GET /admin/duplicates`
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Cluster {
+    id: i64,
+    /// Archived notes and replies in the cluster
+    size: i64,
+    /// Distinct authors of the notes
+    pubkeys: i64,
+    first_seen: i64,
+    last_seen: i64,
+    /// Whether the cluster is large enough to be a probable spam campaign
+    campaign: bool,
+    /// Content of the first note of the cluster
+    sample: Option<String>,
+}
+
+/// Selects the clusters of at least two notes, with the size a campaign starts at bound first
+const CLUSTER_QUERY: &str = "
+    SELECT f.cluster_id AS id, COUNT(*) AS size, COUNT(DISTINCT f.pubkey) AS pubkeys,
+        MIN(f.created_at) AS first_seen, MAX(f.created_at) AS last_seen,
+        COUNT(*) >= ? AS campaign,
+        (SELECT content FROM events WHERE event_id =
+            (SELECT event_id FROM fingerprints WHERE cluster_id = f.cluster_id ORDER BY id LIMIT 1)
+        ) AS sample
+    FROM fingerprints AS f";
+
+/// Query parameters for `GET /admin/duplicates`
+#[derive(Debug, Deserialize)]
+pub struct ClusterQuery {
+    /// Only list the probable spam campaigns
+    #[serde(default)]
+    campaigns: bool,
+    limit: Option<i64>,
+}
+
+/// Lists the clusters of near-duplicate notes, largest first.
+pub async fn list_clusters(
+    req: HttpRequest,
+    params: web::Query<ClusterQuery>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_duplicates", "").await?;
+    let min_size = if params.campaigns {
+        config.duplicates.campaign_size.max(2)
+    } else {
+        2
+    };
+    let clusters: Vec<Cluster> = sqlx::query_as(&format!(
+        "{} GROUP BY f.cluster_id HAVING COUNT(*) >= ? ORDER BY size DESC, id LIMIT ?",
+        CLUSTER_QUERY
+    ))
+    .bind(config.duplicates.campaign_size)
+    .bind(min_size)
+    .bind(params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, 1000))
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    Ok(HttpResponse::Ok().json(clusters))
+}
+
+/// An author of notes in a cluster
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ClusterAuthor {
+    pubkey: String,
+    /// Notes of the author in the cluster
+    events: i64,
+}
+
+/// Loads a cluster and its authors, most prolific first
+async fn load_cluster(
+    id: i64,
+    campaign_size: i64,
+    db_pool: &SqlitePool,
+) -> Result<(Cluster, Vec<ClusterAuthor>), ApiError> {
+    let cluster: Cluster = sqlx::query_as(&format!(
+        "{} WHERE f.cluster_id = ? GROUP BY f.cluster_id",
+        CLUSTER_QUERY
+    ))
+    .bind(campaign_size)
+    .bind(id)
+    .fetch_optional(db_pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("Cluster {} not found", id)))?;
+    let authors: Vec<ClusterAuthor> = sqlx::query_as(
+        "SELECT pubkey, COUNT(*) AS events FROM fingerprints WHERE cluster_id = ?
+         GROUP BY pubkey ORDER BY events DESC, pubkey",
+    )
+    .bind(id)
+    .fetch_all(db_pool)
+    .await?;
+    Ok((cluster, authors))
+}
+
+/// Returns a cluster of near-duplicate notes with its authors and the ids of its notes.
+pub async fn get_cluster(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    audit::record_view(&req, &write_pool, "view_duplicate_cluster", &id.to_string()).await?;
+    let (cluster, authors) =
+        load_cluster(id, config.duplicates.campaign_size, db_pool.get_ref()).await?;
+    let events: Vec<String> =
+        sqlx::query_scalar("SELECT event_id FROM fingerprints WHERE cluster_id = ? ORDER BY id")
+            .bind(id)
+            .fetch_all(db_pool.get_ref())
+            .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "cluster": cluster,
+        "authors": authors,
+        "events": events,
+    })))
+}
+
+/// Query parameters for `POST /admin/duplicates/{id}/ban`
+#[derive(Debug, Deserialize)]
+pub struct BanQuery {
+    /// Why the authors are banned, kept in the audit log and blocklist
+    reason: Option<String>,
+}
+
+/// Bans every author of a cluster at once: their archived events are removed and their
+/// pubkeys blocklisted, as `DELETE /admin/pubkeys/{pubkey}` does for one.
+pub async fn ban_cluster(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    params: web::Query<BanQuery>,
+    config: web::Data<AppConfig>,
+    write_pool: web::Data<WritePool>,
+    cache: web::Data<EventCache>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let (_, authors) = load_cluster(id, config.duplicates.campaign_size, &write_pool.0).await?;
+    let reason = params
+        .reason
+        .clone()
+        .unwrap_or_else(|| format!("duplicate content cluster {}", id));
+
+    let actor = audit::actor(&req);
+    let mut tx = write_pool.0.begin().await?;
+    let mut deleted = 0;
+    let mut media = Vec::new();
+    for author in &authors {
+        let (removed, files) =
+            moderation::remove_pubkey(&mut tx, &actor, &author.pubkey, Some(&reason)).await?;
+        deleted += removed;
+        media.extend(files);
+    }
+    tx.commit().await?;
+    for author in &authors {
+        cache.invalidate_pubkey(&author.pubkey);
+    }
+    media::remove_files(&config.media.directory, &media).await;
+
+    println!(
+        "Banned {} pubkeys of duplicate cluster {} ({} events)",
+        authors.len(),
+        id,
+        deleted
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "cluster": id,
+        "pubkeys": authors.iter().map(|author| &author.pubkey).collect::<Vec<_>>(),
+        "deleted": deleted,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(simhash(""), 0);
+        assert_eq!(simhash("word"), fnv1a("word"));
+        // Case, punctuation and spacing do not change a fingerprint.
+        assert_eq!(simhash("Hello, World!"), simhash("hello   world"));
+
+        let campaign = "Claim your free airdrop today before it ends, only a few spots are left \
+                        so connect your wallet now and share with friends to earn extra rewards";
+        let rotated = campaign.replace("today", "tonight");
+        let unrelated = "The weather in the mountains was lovely this weekend and we walked for \
+                         hours along the ridge before dinner at a small hut near the lake";
+        let distance = |a: &str, b: &str| (simhash(a) ^ simhash(b)).count_ones();
+        assert!(distance(campaign, &rotated) <= MAX_DISTANCE);
+        assert!(distance(campaign, unrelated) > MAX_DISTANCE);
+    }
+
+    #[test]
+    fn fingerprint_bands() {
+        assert_eq!(bands(0x0004_0003_0002_0001), [1, 2, 3, 4]);
+        assert_eq!(bands(u64::MAX), [0xffff; 4]);
+    }
+}
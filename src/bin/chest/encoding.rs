@@ -0,0 +1,60 @@
+use actix_web::http::header::{HeaderValue, ACCEPT};
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+/// Serialization of response bodies, negotiated with the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Picks the first binary format listed in `Accept`, falling back to JSON
+    pub fn from_request(req: &HttpRequest) -> Self {
+        req.headers()
+            .get_all(ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(
+                |media_range| match media_range.split(';').next().unwrap_or("").trim() {
+                    "application/msgpack" | "application/x-msgpack" => Some(Encoding::MessagePack),
+                    "application/cbor" => Some(Encoding::Cbor),
+                    "application/json" => Some(Encoding::Json),
+                    _ => None,
+                },
+            )
+            .unwrap_or(Encoding::Json)
+    }
+
+    pub fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        })
+    }
+
+    /// Distinguishes the ETags of the representations of the same content
+    pub fn etag_suffix(self) -> &'static str {
+        match self {
+            Encoding::Json => "",
+            Encoding::MessagePack => "-msgpack",
+            Encoding::Cbor => "-cbor",
+        }
+    }
+
+    /// Serializes a body, with field names kept so binary maps mirror the JSON objects
+    pub fn encode<T: Serialize>(self, body: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(body).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(body).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(body, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+}
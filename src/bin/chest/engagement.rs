@@ -0,0 +1,242 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::error::ApiError;
+use crate::nostr;
+use crate::stats::parse_duration;
+
+/// Number of reacting pubkeys returned per reaction content
+const SAMPLE_PUBKEYS: i64 = 5;
+
+/// Aggregated reactions sharing the same content
+#[derive(Debug, Serialize, Default)]
+struct ReactionGroup {
+    count: i64,
+    /// Most recent reacting pubkeys
+    pubkeys: Vec<String>,
+    /// Image URL for NIP-30 custom emoji reactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emoji_url: Option<String>,
+}
+
+/// Response of `GET /reactions/{ref_event}/summary`
+#[derive(Debug, Serialize)]
+struct ReactionSummary {
+    ref_event: String,
+    total: i64,
+    reactions: BTreeMap<String, ReactionGroup>,
+}
+
+/// NIP-25: an empty reaction is interpreted as a like.
+fn normalize_content(content: String) -> String {
+    if content.is_empty() {
+        "+".to_string()
+    } else {
+        content
+    }
+}
+
+/// Finds the image URL of a `:shortcode:` reaction in its NIP-30 `emoji` tags
+fn emoji_url(content: &str, tags: &str) -> Option<String> {
+    let shortcode = content.strip_prefix(':')?.strip_suffix(':')?;
+    let tags: Vec<Vec<String>> = serde_json::from_str(tags).ok()?;
+    tags.into_iter()
+        .find(|tag| tag.len() >= 3 && tag[0] == "emoji" && tag[1] == shortcode)
+        .map(|tag| tag[2].clone())
+}
+
+/// Returns reaction counts for an event grouped by reaction content, with sample pubkeys.
+pub async fn reaction_summary(
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let ref_event = path.into_inner();
+
+    let counts: Vec<(String, i64)> = sqlx::query_as(
+        r#"
+        SELECT content, COUNT(*)
+        FROM public_events AS events
+        WHERE folder = 'reactions' AND ref_event = ?
+        GROUP BY content
+        "#,
+    )
+    .bind(&ref_event)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let samples: Vec<(String, String, String)> = sqlx::query_as(
+        r#"
+        SELECT content, pubkey, tags FROM (
+            SELECT content, pubkey, tags,
+                   ROW_NUMBER() OVER (PARTITION BY content ORDER BY created_at DESC) AS rank
+            FROM public_events AS events
+            WHERE folder = 'reactions' AND ref_event = ?
+        )
+        WHERE rank <= ?
+        "#,
+    )
+    .bind(&ref_event)
+    .bind(SAMPLE_PUBKEYS)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let mut reactions: BTreeMap<String, ReactionGroup> = BTreeMap::new();
+    for (content, count) in counts {
+        reactions
+            .entry(normalize_content(content))
+            .or_default()
+            .count += count;
+    }
+    for (content, pubkey, tags) in samples {
+        let url = emoji_url(&content, &tags);
+        let group = reactions.entry(normalize_content(content)).or_default();
+        if group.pubkeys.len() < SAMPLE_PUBKEYS as usize {
+            group.pubkeys.push(pubkey);
+        }
+        if group.emoji_url.is_none() {
+            group.emoji_url = url;
+        }
+    }
+
+    let total = reactions.values().map(|group| group.count).sum();
+    Ok(HttpResponse::Ok().json(ReactionSummary {
+        ref_event,
+        total,
+        reactions,
+    }))
+}
+
+/// Response of `GET /engagement/{id}`
+#[derive(Debug, Serialize, Default)]
+pub struct EngagementCounts {
+    pub event_id: String,
+    pub replies: i64,
+    pub reactions: i64,
+    pub zaps: i64,
+    pub reposts: i64,
+    /// Notes quoting the event with a `q` tag
+    pub quotes: i64,
+}
+
+/// Counts the archived events referencing an event, by folder
+pub async fn fetch_counts(
+    event_id: &str,
+    db_pool: &SqlitePool,
+) -> Result<EngagementCounts, sqlx::Error> {
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT folder, COUNT(*) FROM public_events AS events WHERE ref_event = ? GROUP BY folder",
+    )
+    .bind(event_id)
+    .fetch_all(db_pool)
+    .await?;
+
+    let mut counts = EngagementCounts {
+        event_id: event_id.to_string(),
+        ..Default::default()
+    };
+    for (folder, count) in rows {
+        match folder.as_str() {
+            "replies" => counts.replies = count,
+            "reactions" => counts.reactions = count,
+            "zaps" => counts.zaps = count,
+            "reposts" => counts.reposts = count,
+            "notes" => counts.quotes = count,
+            _ => {}
+        }
+    }
+    Ok(counts)
+}
+
+/// Returns how many replies, reactions, zaps, reposts and quotes reference an event.
+pub async fn engagement_counts(
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let counts = fetch_counts(&path.into_inner(), db_pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json(counts))
+}
+
+/// Query parameters of `GET /engagement/{id}/timeline`
+#[derive(Debug, Deserialize)]
+pub struct TimelineQuery {
+    /// Width of the buckets, e.g. `15m`, `1h` or `1d` (default `1h`)
+    bucket: Option<String>,
+}
+
+/// Engagement received within one bucket of the timeline
+#[derive(Debug, Serialize, Default)]
+struct TimelinePoint {
+    /// Unix time the bucket starts at
+    start: i64,
+    replies: i64,
+    reactions: i64,
+    zaps: i64,
+    reposts: i64,
+    quotes: i64,
+}
+
+/// Returns the engagement an event received per time bucket since its publication, to show
+/// how it spread. Buckets start at the event's `created_at`; engagement timestamped before it
+/// counts in the first bucket, and buckets without engagement are omitted.
+pub async fn engagement_timeline(
+    path: web::Path<String>,
+    params: web::Query<TimelineQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let event_id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", input)))?;
+    let bucket_param = params.bucket.as_deref().unwrap_or("1h");
+    let bucket = parse_duration(bucket_param)
+        .filter(|bucket| *bucket > 0)
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Invalid bucket: {} (expected e.g. 15m, 1h or 1d)",
+                bucket_param
+            ))
+        })? as i64;
+
+    let published_at: i64 =
+        sqlx::query_scalar("SELECT created_at FROM public_events WHERE event_id = ?")
+            .bind(&event_id)
+            .fetch_optional(db_pool.get_ref())
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Event not found".to_string()))?;
+    let rows: Vec<(i64, String, i64)> = sqlx::query_as(
+        r#"
+        SELECT MAX(created_at - ?, 0) / ? AS position, folder, COUNT(*)
+        FROM public_events AS events
+        WHERE ref_event = ? AND folder IN ('replies', 'reactions', 'zaps', 'reposts', 'notes')
+        GROUP BY position, folder
+        "#,
+    )
+    .bind(published_at)
+    .bind(bucket)
+    .bind(&event_id)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let mut points: BTreeMap<i64, TimelinePoint> = BTreeMap::new();
+    for (position, folder, count) in rows {
+        let point = points.entry(position).or_insert_with(|| TimelinePoint {
+            start: published_at + position * bucket,
+            ..Default::default()
+        });
+        match folder.as_str() {
+            "replies" => point.replies = count,
+            "reactions" => point.reactions = count,
+            "zaps" => point.zaps = count,
+            "reposts" => point.reposts = count,
+            "notes" => point.quotes = count,
+            _ => {}
+        }
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "event_id": event_id,
+        "published_at": published_at,
+        "bucket": bucket,
+        "points": points.into_values().collect::<Vec<_>>(),
+    })))
+}
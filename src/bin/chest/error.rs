@@ -0,0 +1,85 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use std::fmt;
+
+/// Error returned by HTTP endpoints, rendered as `{"error": {"code": ..., "message": ...}}`
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(String),
+    BadRequest(String),
+    Unauthorized(String),
+    /// An upstream service, e.g. the remote signer, failed the request
+    BadGateway(String),
+    RateLimited {
+        retry_after: u64,
+    },
+    Internal,
+}
+
+impl ApiError {
+    /// Machine-readable error code clients can branch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::BadGateway(_) => "bad_gateway",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Internal => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound(message)
+            | ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::BadGateway(message) => write!(f, "{}", message),
+            ApiError::RateLimited { retry_after } => {
+                write!(f, "Too many requests, retry after {} seconds", retry_after)
+            }
+            ApiError::Internal => write!(f, "Internal error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Unauthorized(_) => {
+                response.insert_header(("WWW-Authenticate", "Nostr"));
+            }
+            ApiError::RateLimited { retry_after } => {
+                response.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            _ => {}
+        }
+        response.json(serde_json::json!({
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+            }
+        }))
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        eprintln!("Database query error: {:?}", e);
+        ApiError::Internal
+    }
+}
@@ -0,0 +1,61 @@
+use actix_web::http::header::{EntityTag, Header, IfNoneMatch, ETAG, VARY};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::encoding::Encoding;
+use crate::error::ApiError;
+
+/// ETag for a single stored event; events are immutable so the id identifies the content
+pub fn event_etag(event_id: &str) -> EntityTag {
+    EntityTag::new_strong(event_id.to_string())
+}
+
+/// ETag for a listing, derived from the ids of the events it contains
+pub fn list_etag<'a>(event_ids: impl IntoIterator<Item = &'a str>) -> EntityTag {
+    let mut hasher = Sha256::new();
+    for id in event_ids {
+        hasher.update(id.as_bytes());
+        hasher.update(b",");
+    }
+    EntityTag::new_strong(hex::encode(hasher.finalize()))
+}
+
+/// Returns whether the request's `If-None-Match` header matches `etag`
+fn is_fresh(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+/// Returns a 304 Not Modified response if the client already has the representation tagged `etag`
+pub fn not_modified(req: &HttpRequest, etag: &EntityTag) -> Option<HttpResponse> {
+    is_fresh(req, etag).then(|| {
+        HttpResponse::NotModified()
+            .insert_header((ETAG, etag.to_string()))
+            .finish()
+    })
+}
+
+/// Responds with `body` tagged with `etag`, or 304 Not Modified if the client already has it.
+/// The body is JSON unless the client accepts MessagePack or CBOR.
+pub fn json_with_etag<T: Serialize>(req: &HttpRequest, etag: EntityTag, body: &T) -> HttpResponse {
+    let encoding = Encoding::from_request(req);
+    let etag = EntityTag::new_strong(format!("{}{}", etag.tag(), encoding.etag_suffix()));
+    if let Some(response) = not_modified(req, &etag) {
+        return response;
+    }
+    match encoding.encode(body) {
+        Ok(bytes) => HttpResponse::Ok()
+            .insert_header((ETAG, etag.to_string()))
+            .insert_header((VARY, "Accept"))
+            .content_type(encoding.content_type())
+            .body(bytes),
+        Err(e) => {
+            eprintln!("Failed to encode response: {}", e);
+            ApiError::Internal.error_response()
+        }
+    }
+}
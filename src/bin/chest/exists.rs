@@ -0,0 +1,139 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::error::ApiError;
+use crate::{dms, etag, nostr, AppConfig, BatchRequest};
+
+/// Where an archived event is stored
+#[derive(Debug, Serialize)]
+pub struct Existence {
+    /// Folder of an event in the live database; `None` for events moved to cold storage, whose
+    /// folder is only known after decompressing their segment
+    folder: Option<String>,
+    cold: bool,
+}
+
+/// Looks up which of the given event ids are archived, live or in cold storage. The operator's
+/// direct messages are reported as missing, so their ids are not disclosed.
+async fn lookup(
+    ids: &[String],
+    db_pool: &SqlitePool,
+) -> Result<HashMap<String, Existence>, sqlx::Error> {
+    let mut found = HashMap::new();
+    if ids.is_empty() {
+        return Ok(found);
+    }
+    let queries = [
+        "SELECT event_id, folder FROM public_events WHERE".to_string(),
+        // Leaves out direct messages moved to cold storage by earlier versions
+        format!(
+            "SELECT event_id, NULL FROM cold_events WHERE kind NOT IN ({}, {}) AND",
+            dms::ENCRYPTED_DM_KIND,
+            dms::GIFT_WRAP_KIND
+        ),
+    ];
+    for select in queries {
+        let mut query = QueryBuilder::<Sqlite>::new(select);
+        query.push(" event_id IN (");
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(id);
+        }
+        query.push(")");
+        let rows: Vec<(String, Option<String>)> = query.build_query_as().fetch_all(db_pool).await?;
+        for (event_id, folder) in rows {
+            found.entry(event_id).or_insert(Existence {
+                cold: folder.is_none(),
+                folder,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Answers whether an event is archived without sending it: 200 with its folder in the
+/// `X-Chest-Folder` header, or `X-Chest-Cold: true` once moved to cold storage, and 404 when
+/// chest does not have it.
+pub async fn head_event(
+    req: HttpRequest,
+    id: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = id.into_inner();
+    let event_id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", input)))?;
+    let found = lookup(std::slice::from_ref(&event_id), db_pool.get_ref()).await?;
+    let existence = found
+        .get(&event_id)
+        .ok_or_else(|| ApiError::NotFound("Event not found".to_string()))?;
+    let etag = etag::event_etag(&event_id);
+    if let Some(response) = etag::not_modified(&req, &etag) {
+        return Ok(response);
+    }
+    let mut response = HttpResponse::Ok();
+    response.insert_header((actix_web::http::header::ETAG, etag.to_string()));
+    match &existence.folder {
+        Some(folder) => response.insert_header(("X-Chest-Folder", folder.as_str())),
+        None => response.insert_header(("X-Chest-Cold", "true")),
+    };
+    Ok(response.finish())
+}
+
+/// Answers which of a list of event ids (hex, note or nevent) are archived, keyed by hex id:
+/// where each is stored, or `null` when chest does not have it.
+pub async fn events_exist(
+    body: web::Json<BatchRequest>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    if body.ids.len() > config.api.max_batch_size {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} ids may be checked at once",
+            config.api.max_batch_size
+        )));
+    }
+    let ids = body
+        .ids
+        .iter()
+        .map(|id| {
+            nostr::parse_event_id(id)
+                .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", id)))
+        })
+        .collect::<Result<HashSet<_>, _>>()?
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut found = lookup(&ids, db_pool.get_ref()).await?;
+    let answer: BTreeMap<String, Option<Existence>> = ids
+        .into_iter()
+        .map(|id| {
+            let existence = found.remove(&id);
+            (id, existence)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(answer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, test_event};
+
+    #[tokio::test]
+    async fn direct_messages_are_not_disclosed() {
+        let db_pool = db::test_pool().await;
+        let (note, dm, wrap) = (
+            test_event('a', 1),
+            test_event('b', 4),
+            test_event('c', 1059),
+        );
+        db::store_test_events(&db_pool, &[&note, &dm, &wrap]).await;
+
+        let found = lookup(&[note.id.clone(), dm.id, wrap.id], &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[&note.id].folder.as_deref(), Some("notes"));
+    }
+}
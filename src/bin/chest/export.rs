@@ -0,0 +1,263 @@
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::engagement::{fetch_counts, EngagementCounts};
+use crate::feeds::{fetch_profile, profile_name, rfc3339};
+use crate::markdown::{escape_html, render_markdown, render_note};
+use crate::{nostr, DbEvent};
+
+/// Stylesheet shared by every page of the exported site
+const STYLE: &str =
+    "body{max-width:42rem;margin:2rem auto;padding:0 1rem;font-family:sans-serif;line-height:1.5}\
+article,.reply{border-bottom:1px solid #ddd;padding:1rem 0}\
+.replies{margin-left:1.5rem;border-left:2px solid #eee;padding-left:1rem}\
+.meta{color:#666;font-size:.875rem}\
+img{max-width:100%}";
+
+/// Options of the `export-site` subcommand
+struct ExportOptions {
+    pubkey: String,
+    out: PathBuf,
+}
+
+/// Parses `--pubkey <pubkey> --out <dir>` from the subcommand arguments
+fn parse_options(args: &[String]) -> Result<ExportOptions, String> {
+    let mut pubkey = None;
+    let mut out = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pubkey" => pubkey = args.next().cloned(),
+            "--out" => out = args.next().map(PathBuf::from),
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    let pubkey = pubkey.ok_or("Missing --pubkey")?;
+    Ok(ExportOptions {
+        pubkey: nostr::parse_pubkey(&pubkey).ok_or(format!("Invalid pubkey: {}", pubkey))?,
+        out: out.ok_or("Missing --out")?,
+    })
+}
+
+/// Fetches the events of a folder written by a pubkey, newest first, keeping only the latest
+/// version of addressable articles
+async fn fetch_authored(
+    folder: &str,
+    pubkey: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(
+        r#"
+        SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+        FROM public_events AS e
+        WHERE folder = ? AND pubkey = ? AND kind != 30024 AND NOT EXISTS (
+            SELECT 1 FROM public_events AS newer
+            WHERE e.d_tag IS NOT NULL AND newer.folder = e.folder AND newer.pubkey = e.pubkey
+              AND newer.kind = e.kind AND newer.d_tag = e.d_tag
+              AND newer.created_at > e.created_at
+        )
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(folder)
+    .bind(pubkey)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Collects every archived reply below an event, keyed by the event they answer
+async fn fetch_thread(
+    root_id: &str,
+    db_pool: &SqlitePool,
+) -> Result<HashMap<String, Vec<DbEvent>>, sqlx::Error> {
+    let mut children: HashMap<String, Vec<DbEvent>> = HashMap::new();
+    let mut pending = vec![root_id.to_string()];
+    while let Some(parent) = pending.pop() {
+        let replies = sqlx::query_as::<_, DbEvent>(
+            "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+             FROM public_events AS events WHERE folder = 'replies' AND ref_event = ? ORDER BY created_at",
+        )
+        .bind(&parent)
+        .fetch_all(db_pool)
+        .await?;
+        for reply in &replies {
+            if !children.contains_key(&reply.event_id) {
+                pending.push(reply.event_id.clone());
+            }
+        }
+        children.insert(parent, replies);
+    }
+    Ok(children)
+}
+
+/// Wraps page content in an HTML document
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_html(title),
+        STYLE,
+        body
+    )
+}
+
+/// Renders the date and engagement line shown under a note or article
+fn meta_line(event: &DbEvent, counts: &EngagementCounts) -> String {
+    format!(
+        "<p class=\"meta\">{} · {} replies · {} reactions · {} zaps · {} reposts · {} quotes</p>\n",
+        rfc3339(event.created_at),
+        counts.replies,
+        counts.reactions,
+        counts.zaps,
+        counts.reposts,
+        counts.quotes
+    )
+}
+
+/// Renders the replies below `parent`, nesting each reply above its own replies
+fn render_replies(parent: &str, thread: &HashMap<String, Vec<DbEvent>>) -> String {
+    let Some(replies) = thread.get(parent).filter(|replies| !replies.is_empty()) else {
+        return String::new();
+    };
+    let mut html = String::from("<div class=\"replies\">\n");
+    for reply in replies {
+        html.push_str(&format!(
+            "<div class=\"reply\" id=\"{}\">\n<p class=\"meta\">{} · {}</p>\n{}\n",
+            reply.event_id,
+            escape_html(&reply.pubkey),
+            rfc3339(reply.created_at),
+            render_note(&reply.content)
+        ));
+        html.push_str(&render_replies(&reply.event_id, thread));
+        html.push_str("</div>\n");
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+/// Renders the profile header of the index page
+fn profile_header(pubkey: &str, profile: Option<&DbEvent>) -> String {
+    let metadata: Value = profile
+        .and_then(|profile| serde_json::from_str(&profile.content).ok())
+        .unwrap_or_default();
+    let name = profile
+        .and_then(profile_name)
+        .unwrap_or_else(|| pubkey.to_string());
+    let mut html = String::from("<header>\n");
+    if let Some(picture) = metadata.get("picture").and_then(Value::as_str) {
+        html.push_str(&format!(
+            "<img src=\"{}\" alt=\"\" width=\"96\" height=\"96\">\n",
+            escape_html(picture)
+        ));
+    }
+    html.push_str(&format!("<h1>{}</h1>\n", escape_html(&name)));
+    html.push_str(&format!("<p class=\"meta\">{}</p>\n", pubkey));
+    if let Some(about) = metadata.get("about").and_then(Value::as_str) {
+        html.push_str(&render_note(about));
+    }
+    html.push_str("</header>\n");
+    html
+}
+
+/// Writes one page of the site, creating its directory if needed
+fn write_page(path: &Path, html: &str) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, html).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Renders a static site of a pubkey's profile, notes with their threads, and articles.
+pub async fn export_site(args: &[String], db_pool: &SqlitePool) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+
+    let profile = fetch_profile(&options.pubkey, db_pool)
+        .await
+        .map_err(db_error)?;
+    let notes = fetch_authored("notes", &options.pubkey, db_pool)
+        .await
+        .map_err(db_error)?;
+    let articles = fetch_authored("long", &options.pubkey, db_pool)
+        .await
+        .map_err(db_error)?;
+
+    let mut index = profile_header(&options.pubkey, profile.as_ref());
+
+    index.push_str("<h2>Articles</h2>\n<ul>\n");
+    for article in &articles {
+        let title = article
+            .to_nostr()
+            .tag_value("title")
+            .unwrap_or("Untitled")
+            .to_string();
+        let counts = fetch_counts(&article.event_id, db_pool)
+            .await
+            .map_err(db_error)?;
+        let body = format!(
+            "<p><a href=\"../index.html\">← Back</a></p>\n<article>\n<h1>{}</h1>\n{}{}</article>\n",
+            escape_html(&title),
+            meta_line(article, &counts),
+            render_markdown(&article.content)
+        );
+        write_page(
+            &options
+                .out
+                .join("articles")
+                .join(format!("{}.html", article.event_id)),
+            &page(&title, &body),
+        )?;
+        index.push_str(&format!(
+            "<li><a href=\"articles/{}.html\">{}</a> <span class=\"meta\">{}</span></li>\n",
+            article.event_id,
+            escape_html(&title),
+            rfc3339(article.created_at)
+        ));
+    }
+    index.push_str("</ul>\n<h2>Notes</h2>\n");
+
+    for note in &notes {
+        let counts = fetch_counts(&note.event_id, db_pool)
+            .await
+            .map_err(db_error)?;
+        let thread = fetch_thread(&note.event_id, db_pool)
+            .await
+            .map_err(db_error)?;
+        let body = format!(
+            "<p><a href=\"../index.html\">← Back</a></p>\n<article>\n{}{}</article>\n{}",
+            render_note(&note.content),
+            meta_line(note, &counts),
+            render_replies(&note.event_id, &thread)
+        );
+        write_page(
+            &options
+                .out
+                .join("notes")
+                .join(format!("{}.html", note.event_id)),
+            &page("Note", &body),
+        )?;
+        index.push_str(&format!(
+            "<article>\n{}<p class=\"meta\"><a href=\"notes/{}.html\">{}</a> · {} replies</p>\n</article>\n",
+            render_note(&note.content),
+            note.event_id,
+            rfc3339(note.created_at),
+            counts.replies
+        ));
+    }
+
+    let name = profile
+        .as_ref()
+        .and_then(profile_name)
+        .unwrap_or_else(|| options.pubkey.clone());
+    write_page(&options.out.join("index.html"), &page(&name, &index))?;
+    println!(
+        "Exported {} notes and {} articles to {}",
+        notes.len(),
+        articles.len(),
+        options.out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use actix_web::http::header::ETAG;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::Value;
+use sqlx::SqlitePool;
+
+use crate::error::ApiError;
+use crate::markdown::{escape_html, render_markdown, render_note};
+use crate::{etag, nostr, DbEvent};
+
+/// Maximum number of entries included in a feed
+const FEED_LIMIT: i64 = 50;
+
+/// Number of characters of a note used as its entry title
+const NOTE_TITLE_LENGTH: usize = 80;
+
+/// Formats a unix timestamp as an RFC 3339 UTC date, as required by Atom
+pub fn rfc3339(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+
+    // Converts days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Fetches the most recent notes and current article versions of a pubkey
+async fn fetch_feed_events(
+    pubkey: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(
+        r#"
+        SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+        FROM public_events AS e
+        WHERE pubkey = ? AND (
+            folder = 'notes' OR (
+                folder = 'long' AND kind = 30023 AND NOT EXISTS (
+                    SELECT 1 FROM public_events AS newer
+                    WHERE newer.folder = 'long' AND newer.pubkey = e.pubkey
+                      AND newer.kind = e.kind AND newer.d_tag = e.d_tag
+                      AND newer.created_at > e.created_at
+                )
+            )
+        )
+        ORDER BY created_at DESC
+        LIMIT ?
+        "#,
+    )
+    .bind(pubkey)
+    .bind(FEED_LIMIT)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Fetches the archived profile of a pubkey
+pub async fn fetch_profile(
+    pubkey: &str,
+    db_pool: &SqlitePool,
+) -> Result<Option<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = 'users' AND pubkey = ?
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(pubkey)
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Picks the display name from kind 0 profile metadata
+pub fn profile_name(profile: &DbEvent) -> Option<String> {
+    let metadata: Value = serde_json::from_str(&profile.content).ok()?;
+    ["display_name", "name"]
+        .iter()
+        .filter_map(|field| metadata.get(*field)?.as_str())
+        .find(|name| !name.trim().is_empty())
+        .map(str::to_string)
+}
+
+/// Uses the first line of a note, shortened, as its title
+fn note_title(content: &str) -> String {
+    let line = content
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("");
+    let mut title: String = line.chars().take(NOTE_TITLE_LENGTH).collect();
+    if line.chars().count() > NOTE_TITLE_LENGTH {
+        title.push('…');
+    }
+    title
+}
+
+/// Renders a single feed entry
+fn feed_entry(event: &DbEvent, base_url: &str) -> String {
+    let nostr_event = event.to_nostr();
+    let (title, link, html) = if event.folder == "long" {
+        (
+            nostr_event
+                .tag_value("title")
+                .unwrap_or("Untitled")
+                .to_string(),
+            format!("{}/long/{}/html", base_url, event.event_id),
+            render_markdown(&event.content),
+        )
+    } else {
+        (
+            note_title(&event.content),
+            format!("{}/notes/{}", base_url, event.event_id),
+            render_note(&event.content),
+        )
+    };
+    let published = nostr_event
+        .tag_value("published_at")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(event.created_at);
+
+    let mut entry = format!(
+        "<entry>\n<id>urn:nostr:{}</id>\n<title>{}</title>\n<link href=\"{}\"/>\n<published>{}</published>\n<updated>{}</updated>\n",
+        event.event_id,
+        escape_html(&title),
+        escape_html(&link),
+        rfc3339(published),
+        rfc3339(event.created_at)
+    );
+    if let Some(summary) = nostr_event.tag_value("summary") {
+        entry.push_str(&format!("<summary>{}</summary>\n", escape_html(summary)));
+    }
+    entry.push_str(&format!(
+        "<content type=\"html\">{}</content>\n</entry>\n",
+        escape_html(&html)
+    ));
+    entry
+}
+
+/// Atom feed of the notes and long-form articles of a pubkey.
+pub async fn pubkey_feed(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+
+    let events = fetch_feed_events(&pubkey, db_pool.get_ref()).await?;
+    let profile = fetch_profile(&pubkey, db_pool.get_ref()).await?;
+
+    let etag = etag::list_etag(
+        profile
+            .iter()
+            .chain(events.iter())
+            .map(|event| event.event_id.as_str()),
+    );
+    if let Some(response) = etag::not_modified(&req, &etag) {
+        return Ok(response);
+    }
+
+    // Links keep the prefix the feed was requested under: `server.base_path` and the
+    // archive's name.
+    let prefix = req
+        .path()
+        .rsplit_once("/feeds/")
+        .map_or("", |(prefix, _)| prefix);
+    let origin = {
+        let conn = req.connection_info();
+        format!("{}://{}", conn.scheme(), conn.host())
+    };
+    let base_url = format!("{}{}", origin, prefix);
+    let self_url = format!("{}{}", origin, req.uri());
+    let author = profile
+        .as_ref()
+        .and_then(profile_name)
+        .unwrap_or_else(|| pubkey.clone());
+    let updated = events
+        .iter()
+        .map(|event| event.created_at)
+        .max()
+        .unwrap_or_default();
+
+    let mut feed = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n<id>urn:nostr:{}</id>\n<title>{}</title>\n<link rel=\"self\" href=\"{}\"/>\n<updated>{}</updated>\n<author><name>{}</name></author>\n",
+        pubkey,
+        escape_html(&author),
+        escape_html(&self_url),
+        rfc3339(updated),
+        escape_html(&author)
+    );
+    for event in &events {
+        feed.push_str(&feed_entry(event, &base_url));
+    }
+    feed.push_str("</feed>\n");
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .insert_header((ETAG, etag.to_string()))
+        .body(feed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn feed_holds_notes_and_articles_under_the_profile_name() {
+        let db_pool = db::test_pool().await;
+        let mut profile = db::test_event('a', 0);
+        profile.content = r#"{"name":"Alice"}"#.to_string();
+        let note = db::test_event('b', 1);
+        let mut article = db::test_event('c', 30023);
+        article.tags = vec![vec!["d".to_string(), "essay".to_string()]];
+        let reaction = db::test_event('d', 7);
+        db::store_test_events(&db_pool, &[&profile, &note, &article, &reaction]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/feeds/{pubkey}.xml", web::get().to(pubkey_feed)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/feeds/{}.xml", profile.pubkey))
+            .to_request();
+        let body = call_and_read_body(&app, request).await;
+        let feed = std::str::from_utf8(&body).unwrap();
+        assert!(feed.contains("<author><name>Alice</name></author>"));
+        assert_eq!(feed.matches("<entry>").count(), 2);
+        assert!(!feed.contains(&reaction.id));
+    }
+}
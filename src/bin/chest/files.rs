@@ -0,0 +1,132 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::home::parse_cursor;
+use crate::users::escape_like;
+use crate::{etag, format_events, nostr, retain_visible, stream, DbEvent, OutputFormat};
+
+/// Number of file metadata events returned per page of `GET /files` unless `limit` is given
+const DEFAULT_FILES_LIMIT: i64 = 50;
+
+/// Largest page of file metadata events a client may request
+const MAX_FILES_LIMIT: i64 = 500;
+
+/// Query parameters of `GET /files`
+#[derive(Debug, Deserialize)]
+pub struct FilesQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Mime type of the files, e.g. `image/png`, or `image/*` for any image
+    mime: Option<String>,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+/// Query parameters of `GET /files/hash/{sha256}`
+#[derive(Debug, Deserialize)]
+pub struct FileHashQuery {
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+/// Lists archived NIP-94 file metadata events, newest first, optionally of a mime type. Pages
+/// are continued by passing the returned `next_cursor`, which is `null` on the last page.
+pub async fn list_files(
+    req: HttpRequest,
+    params: web::Query<FilesQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_FILES_LIMIT)
+        .clamp(1, MAX_FILES_LIMIT);
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT events.event_id, events.pubkey, events.created_at, events.kind, events.content,
+                events.sig, events.tags, events.folder, events.ref_event
+         FROM files JOIN public_events AS events ON events.event_id = files.event_id WHERE 1 = 1",
+    );
+    if let Some(mime) = params
+        .mime
+        .as_deref()
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+    {
+        let mime = mime.to_lowercase();
+        match mime.strip_suffix("/*") {
+            Some(family) => {
+                query.push(" AND files.mime LIKE ");
+                query.push_bind(format!("{}/%", escape_like(family)));
+                query.push(" ESCAPE '\\'");
+            }
+            None => {
+                query.push(" AND files.mime = ");
+                query.push_bind(mime);
+            }
+        }
+    }
+    if let Some(cursor) = params.cursor.as_deref() {
+        let (created_at, event_id) = parse_cursor(cursor)
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid cursor: {}", cursor)))?;
+        query.push(" AND (files.created_at < ");
+        query.push_bind(created_at);
+        query.push(" OR (files.created_at = ");
+        query.push_bind(created_at);
+        query.push(" AND files.event_id < ");
+        query.push_bind(event_id);
+        query.push("))");
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY files.created_at DESC, files.event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| format!("{}:{}", event.created_at, event.event_id))
+    } else {
+        None
+    };
+
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &serde_json::json!({
+            "events": format_events(&events, params.format),
+            "next_cursor": next_cursor,
+        }),
+    ))
+}
+
+/// Lists the archived file metadata events describing the file with the given SHA-256 hash,
+/// newest first.
+pub async fn files_by_hash(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<FileHashQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let sha256 = path.into_inner().to_lowercase();
+    if !nostr::is_hex32(&sha256) {
+        return Err(ApiError::BadRequest(format!("Invalid sha256: {}", sha256)));
+    }
+    stream::event_listing(&req, db_pool.get_ref(), params.format, move |columns| {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM public_events AS events WHERE event_id IN (SELECT event_id FROM files WHERE sha256 = ",
+            columns
+        ));
+        query.push_bind(sha256.clone());
+        query.push(") ORDER BY created_at DESC");
+        query
+    })
+    .await
+}
@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite};
+use std::collections::HashMap;
+
+/// NIP-01 subscription filter
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Filter {
+    pub ids: Option<Vec<String>>,
+    pub authors: Option<Vec<String>>,
+    pub kinds: Option<Vec<i64>>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+    /// Single-letter tag filters such as `#e` and `#p`
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl Filter {
+    /// Returns the `#x` tag filters as (tag name, values) pairs
+    fn tag_filters(&self) -> impl Iterator<Item = (&str, Vec<&str>)> {
+        self.extra.iter().filter_map(|(key, value)| {
+            let name = key.strip_prefix('#')?;
+            let values = value.as_array()?.iter().filter_map(Value::as_str).collect();
+            Some((name, values))
+        })
+    }
+
+    /// Appends a parenthesized SQL condition matching this filter against the `events` table
+    pub fn push_condition(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        query.push("(1 = 1");
+        if let Some(ids) = &self.ids {
+            push_in(query, "event_id", ids.iter().cloned());
+        }
+        if let Some(authors) = &self.authors {
+            push_in(query, "pubkey", authors.iter().cloned());
+        }
+        if let Some(kinds) = &self.kinds {
+            query.push(" AND kind IN (");
+            let mut separated = query.separated(", ");
+            for kind in kinds {
+                separated.push_bind(*kind);
+            }
+            if kinds.is_empty() {
+                separated.push("NULL");
+            }
+            query.push(")");
+        }
+        if let Some(since) = self.since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = self.until {
+            query.push(" AND created_at <= ").push_bind(until);
+        }
+        for (name, values) in self.tag_filters() {
+            query
+                .push(
+                    " AND EXISTS (SELECT 1 FROM json_each(events.tags) AS tag
+                     WHERE json_extract(tag.value, '$[0]') = ",
+                )
+                .push_bind(name.to_string());
+            push_in(
+                query,
+                "json_extract(tag.value, '$[1]')",
+                values.into_iter().map(str::to_string),
+            );
+            query.push(")");
+        }
+        query.push(")");
+    }
+}
+
+/// Appends `AND column IN (...)`, matching nothing when `values` is empty
+fn push_in(
+    query: &mut QueryBuilder<'_, Sqlite>,
+    column: &str,
+    values: impl Iterator<Item = String>,
+) {
+    query.push(format!(" AND {} IN (", column));
+    let mut separated = query.separated(", ");
+    let mut empty = true;
+    for value in values {
+        separated.push_bind(value);
+        empty = false;
+    }
+    if empty {
+        separated.push("NULL");
+    }
+    query.push(")");
+}
+
+/// Builds a `WHERE` clause matching any of the given filters, referring to the table as `events`
+pub fn push_where(query: &mut QueryBuilder<'_, Sqlite>, filters: &[Filter]) {
+    query.push(" WHERE (");
+    if filters.is_empty() {
+        query.push("1 = 0");
+    }
+    for (i, filter) in filters.iter().enumerate() {
+        if i > 0 {
+            query.push(" OR ");
+        }
+        filter.push_condition(query);
+    }
+    query.push(")");
+}
@@ -0,0 +1,324 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::BTreeSet;
+
+use crate::error::ApiError;
+use crate::home::parse_cursor;
+use crate::{etag, format_events, retain_visible, DbEvent, OutputFormat};
+
+/// Number of notes returned per page of `GET /geo` unless `limit` is given
+const DEFAULT_GEO_LIMIT: i64 = 50;
+
+/// Largest page of notes a client may request
+const MAX_GEO_LIMIT: i64 = 500;
+
+/// Most geohash cells a bounding box is covered with; the cells are as precise as this allows
+const MAX_CELLS: usize = 32;
+
+/// Longest geohash indexed
+const MAX_PRECISION: usize = 12;
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Query parameters of `GET /geo`
+#[derive(Debug, Deserialize)]
+pub struct GeoQuery {
+    /// `min_lon,min_lat,max_lon,max_lat`, i.e. west, south, east, north
+    bbox: String,
+    #[serde(default)]
+    format: OutputFormat,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+/// A bounding box in degrees
+#[derive(Debug, Clone, Copy)]
+struct BoundingBox {
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+}
+
+impl BoundingBox {
+    fn parse(bbox: &str) -> Option<Self> {
+        let values = bbox
+            .split(',')
+            .map(|value| value.trim().parse::<f64>().ok().filter(|v| v.is_finite()))
+            .collect::<Option<Vec<_>>>()?;
+        let [west, south, east, north] = values[..] else {
+            return None;
+        };
+        let valid = (-180.0..=180.0).contains(&west)
+            && (-180.0..=180.0).contains(&east)
+            && (-90.0..=90.0).contains(&south)
+            && (-90.0..=90.0).contains(&north)
+            && west <= east
+            && south <= north;
+        valid.then_some(Self {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
+
+/// Width and height in degrees of the geohash cells of a precision
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let lon_bits = (bits + 1) / 2;
+    let lat_bits = bits / 2;
+    (360.0 / 2f64.powi(lon_bits), 180.0 / 2f64.powi(lat_bits))
+}
+
+/// Indexes of the cells of the given size spanning `min..=max` along an axis running from
+/// `origin` to `-origin`
+fn cell_range(min: f64, max: f64, origin: f64, size: f64) -> std::ops::RangeInclusive<i64> {
+    let last = (-2.0 * origin / size) as i64 - 1;
+    let index = |value: f64| (((value - origin) / size).floor() as i64).clamp(0, last);
+    index(min)..=index(max)
+}
+
+/// Encodes a point as a geohash of the given precision
+fn encode(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut geohash = String::with_capacity(precision);
+    let mut even = true;
+    let (mut bits, mut value) = (0, 0);
+    while geohash.len() < precision {
+        let (range, coordinate) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let middle = (range.0 + range.1) / 2.0;
+        value <<= 1;
+        if coordinate >= middle {
+            value |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            geohash.push(BASE32[value] as char);
+            (bits, value) = (0, 0);
+        }
+    }
+    geohash
+}
+
+/// Covers a bounding box with the most precise geohash cells that keep their number within
+/// `MAX_CELLS`
+fn cover(bbox: BoundingBox) -> BTreeSet<String> {
+    let cells = |precision: usize| {
+        let (width, height) = cell_size(precision);
+        let lons = cell_range(bbox.west, bbox.east, -180.0, width);
+        let lats = cell_range(bbox.south, bbox.north, -90.0, height);
+        (lons, lats, width, height)
+    };
+    let precision = (1..=MAX_PRECISION)
+        .take_while(|&precision| {
+            let (lons, lats, _, _) = cells(precision);
+            lons.count() * lats.count() <= MAX_CELLS
+        })
+        .last()
+        .unwrap_or(1);
+    let (lons, lats, width, height) = cells(precision);
+    lats.flat_map(|lat| lons.clone().map(move |lon| (lat, lon)))
+        .map(|(lat, lon)| {
+            encode(
+                -90.0 + (lat as f64 + 0.5) * height,
+                -180.0 + (lon as f64 + 0.5) * width,
+                precision,
+            )
+        })
+        .collect()
+}
+
+/// Lists the archived notes located within a bounding box, newest first, by their most precise
+/// `g` tag. The box is covered with geohash cells, so notes slightly outside its edges can be
+/// returned, along with notes tagged with a coarser cell overlapping it. Pages are continued by
+/// passing the returned `next_cursor`, which is `null` on the last page.
+pub async fn notes_in_bbox(
+    req: HttpRequest,
+    params: web::Query<GeoQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let bbox = BoundingBox::parse(&params.bbox)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid bbox: {}", params.bbox)))?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_GEO_LIMIT)
+        .clamp(1, MAX_GEO_LIMIT);
+    let cells = cover(bbox);
+    let coarser: BTreeSet<&str> = cells
+        .iter()
+        .flat_map(|cell| (1..cell.len()).map(move |end| &cell[..end]))
+        .collect();
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT events.event_id, events.pubkey, events.created_at, events.kind, events.content,
+                events.sig, events.tags, events.folder, events.ref_event
+         FROM geohashes JOIN public_events AS events ON events.event_id = geohashes.event_id WHERE (",
+    );
+    // '{' sorts right after 'z', so the range holds every geohash starting with the cell.
+    for (i, cell) in cells.iter().enumerate() {
+        if i > 0 {
+            query.push(" OR ");
+        }
+        query.push("(geohashes.geohash >= ");
+        query.push_bind(cell.clone());
+        query.push(" AND geohashes.geohash < ");
+        query.push_bind(format!("{}{{", cell));
+        query.push(")");
+    }
+    if !coarser.is_empty() {
+        query.push(" OR geohashes.geohash IN (");
+        let mut separated = query.separated(", ");
+        for prefix in &coarser {
+            separated.push_bind(prefix.to_string());
+        }
+        query.push(")");
+    }
+    query.push(")");
+    if let Some(cursor) = params.cursor.as_deref() {
+        let (created_at, event_id) = parse_cursor(cursor)
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid cursor: {}", cursor)))?;
+        query.push(" AND (geohashes.created_at < ");
+        query.push_bind(created_at);
+        query.push(" OR (geohashes.created_at = ");
+        query.push_bind(created_at);
+        query.push(" AND geohashes.event_id < ");
+        query.push_bind(event_id);
+        query.push("))");
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY geohashes.created_at DESC, geohashes.event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| format!("{}:{}", event.created_at, event.event_id))
+    } else {
+        None
+    };
+
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &serde_json::json!({
+            "cells": cells,
+            "events": format_events(&events, params.format),
+            "next_cursor": next_cursor,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    #[test]
+    fn bounding_boxes() {
+        let bbox = BoundingBox::parse("13.3, 52.4,13.5,52.6").unwrap();
+        assert_eq!(
+            (bbox.west, bbox.south, bbox.east, bbox.north),
+            (13.3, 52.4, 13.5, 52.6)
+        );
+        for invalid in [
+            "",
+            "1,2,3",
+            "1,2,3,4,5",
+            "a,2,3,4",
+            "NaN,0,1,1",
+            "-181,0,0,1",
+            "0,-91,1,0",
+            "10,0,5,1",
+            "0,10,1,5",
+        ] {
+            assert!(BoundingBox::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(encode(-25.382708, -49.265506, 5), "6gkzw");
+        assert_eq!(encode(90.0, 180.0, 1), "z");
+        assert_eq!(encode(-90.0, -180.0, 1), "0");
+    }
+
+    #[test]
+    fn covering() {
+        // A small box is covered with precise cells around the points inside it.
+        let cells = cover(BoundingBox::parse("10.40,57.64,10.41,57.65").unwrap());
+        assert!(!cells.is_empty() && cells.len() <= MAX_CELLS);
+        assert!(
+            cells.iter().all(|cell| cell.starts_with("u4pr")),
+            "{:?}",
+            cells
+        );
+        assert!(cells
+            .iter()
+            .any(|cell| "u4pruydqqvj".starts_with(cell.as_str())));
+
+        // The whole world needs coarse cells, and all 32 of them.
+        let world = cover(BoundingBox::parse("-180,-90,180,90").unwrap());
+        assert_eq!(world.len(), 32);
+        assert!(world.iter().all(|cell| cell.len() == 1));
+    }
+
+    #[actix_web::test]
+    async fn notes_in_a_box_are_paged() {
+        let db_pool = db::test_pool().await;
+        let note = |id: char, offset: u64, latitude: f64, longitude: f64| {
+            let mut note = db::test_event(id, 1);
+            note.created_at += offset;
+            note.tags = vec![vec!["g".to_string(), encode(latitude, longitude, 9)]];
+            note
+        };
+        let older = note('a', 0, 52.52, 13.405);
+        let newer = note('b', 10, 52.45, 13.45);
+        let paris = note('c', 20, 48.85, 2.35);
+        db::store_test_events(&db_pool, &[&older, &newer, &paris]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/geo", web::get().to(notes_in_bbox)),
+        )
+        .await;
+        let page = |cursor: &str| {
+            TestRequest::get()
+                .uri(&format!(
+                    "/geo?bbox=13.3,52.4,13.5,52.6&format=nostr&limit=1{}",
+                    cursor
+                ))
+                .to_request()
+        };
+
+        let first: Value = call_and_read_body_json(&app, page("")).await;
+        assert_eq!(first["events"][0]["id"], newer.id.as_str());
+        let cursor = first["next_cursor"].as_str().unwrap();
+        let second: Value =
+            call_and_read_body_json(&app, page(&format!("&cursor={}", cursor))).await;
+        assert_eq!(second["events"][0]["id"], older.id.as_str());
+        assert_eq!(second["events"].as_array().unwrap().len(), 1);
+        assert!(second["next_cursor"].is_null());
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::error::ApiError;
+use crate::nostr;
+
+/// Deepest graph `GET /graph/{pubkey}` walks
+const MAX_DEPTH: usize = 2;
+
+/// Strongest neighbours whose own interactions are added at each further hop
+const EXPANDED_NEIGHBOURS: usize = 25;
+
+/// Query parameters of `GET /graph/{pubkey}`
+#[derive(Debug, Deserialize)]
+pub struct GraphQuery {
+    /// Hops from the pubkey, 1 or 2 (default 1)
+    depth: Option<usize>,
+}
+
+/// Interactions of one pubkey with another, counted from the archive
+#[derive(Debug, Serialize, Default)]
+struct Edge {
+    source: String,
+    target: String,
+    replies: i64,
+    reactions: i64,
+    zaps: i64,
+    /// Sum of the interactions
+    weight: i64,
+}
+
+/// Adds interaction counts, as `(source, target, folder, count)` rows, to the edges
+fn add_rows(
+    edges: &mut BTreeMap<(String, String), Edge>,
+    rows: Vec<(String, String, String, i64)>,
+) {
+    for (source, target, folder, count) in rows {
+        if source == target {
+            continue;
+        }
+        let edge = edges
+            .entry((source.clone(), target.clone()))
+            .or_insert_with(|| Edge {
+                source,
+                target,
+                ..Default::default()
+            });
+        match folder.as_str() {
+            "replies" => edge.replies = count,
+            "reactions" => edge.reactions = count,
+            "zaps" => edge.zaps = count,
+            _ => continue,
+        }
+        edge.weight = edge.replies + edge.reactions + edge.zaps;
+    }
+}
+
+/// Counts the replies, reactions and zaps a pubkey sent and received. Replies and reactions
+/// are attributed to the author of the archived event they reference, unless it is one of the
+/// operator's direct messages; zaps go from the author of the embedded zap request to the
+/// receipt's `p` tag.
+async fn interactions(
+    pubkey: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<(String, String, String, i64)>, sqlx::Error> {
+    let mut rows: Vec<(String, String, String, i64)> = sqlx::query_as(
+        r#"
+        SELECT e.pubkey, target.pubkey, e.folder, COUNT(*)
+        FROM public_events AS e JOIN public_events AS target ON target.event_id = e.ref_event
+        WHERE e.pubkey = ? AND e.folder IN ('replies', 'reactions')
+        GROUP BY target.pubkey, e.folder
+        "#,
+    )
+    .bind(pubkey)
+    .fetch_all(db_pool)
+    .await?;
+    rows.extend(
+        sqlx::query_as::<_, (String, String, String, i64)>(
+            r#"
+            SELECT e.pubkey, target.pubkey, e.folder, COUNT(*)
+            FROM public_events AS target JOIN public_events AS e ON e.ref_event = target.event_id
+            WHERE target.pubkey = ? AND e.folder IN ('replies', 'reactions')
+            GROUP BY e.pubkey, e.folder
+            "#,
+        )
+        .bind(pubkey)
+        .fetch_all(db_pool)
+        .await?,
+    );
+    rows.extend(
+        sqlx::query_as::<_, (String, String, String, i64)>(
+            r#"
+            SELECT sender, recipient, 'zaps', COUNT(*) FROM (
+                SELECT json_extract(json_extract(description.value, '$[1]'), '$.pubkey') AS sender,
+                       json_extract(recipient.value, '$[1]') AS recipient
+                FROM public_events AS events, json_each(events.tags) AS recipient,
+                     json_each(events.tags) AS description
+                WHERE folder = 'zaps'
+                  AND json_extract(recipient.value, '$[0]') = 'p'
+                  AND json_extract(description.value, '$[0]') = 'description'
+                  AND json_valid(json_extract(description.value, '$[1]'))
+            )
+            WHERE sender IS NOT NULL AND recipient IS NOT NULL AND (sender = ? OR recipient = ?)
+            GROUP BY sender, recipient
+            "#,
+        )
+        .bind(pubkey)
+        .bind(pubkey)
+        .fetch_all(db_pool)
+        .await?,
+    );
+    Ok(rows)
+}
+
+/// Summarizes who a pubkey replies to, reacts to and zaps, and who does so to them, as weighted
+/// edges. With `depth=2` the interactions of its strongest neighbours are included too.
+pub async fn interaction_graph(
+    path: web::Path<String>,
+    params: web::Query<GraphQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let depth = params.depth.unwrap_or(1);
+    if !(1..=MAX_DEPTH).contains(&depth) {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid depth: {} (expected 1 to {})",
+            depth, MAX_DEPTH
+        )));
+    }
+
+    let mut edges = BTreeMap::new();
+    let mut visited = HashSet::new();
+    let mut frontier = vec![pubkey.clone()];
+    for _ in 0..depth {
+        for pubkey in std::mem::take(&mut frontier) {
+            if visited.insert(pubkey.clone()) {
+                add_rows(&mut edges, interactions(&pubkey, db_pool.get_ref()).await?);
+            }
+        }
+        // The next hop expands the strongest neighbours reached so far.
+        let mut neighbours: BTreeMap<&str, i64> = BTreeMap::new();
+        for edge in edges.values() {
+            for end in [&edge.source, &edge.target] {
+                if !visited.contains(end) {
+                    *neighbours.entry(end).or_default() += edge.weight;
+                }
+            }
+        }
+        let mut neighbours: Vec<(&str, i64)> = neighbours.into_iter().collect();
+        neighbours.sort_by_key(|(_, weight)| Reverse(*weight));
+        frontier = neighbours
+            .into_iter()
+            .take(EXPANDED_NEIGHBOURS)
+            .map(|(pubkey, _)| pubkey.to_string())
+            .collect();
+    }
+
+    let nodes: BTreeSet<&str> = edges
+        .values()
+        .flat_map(|edge| [edge.source.as_str(), edge.target.as_str()])
+        .chain(std::iter::once(pubkey.as_str()))
+        .collect();
+    let mut edges: Vec<&Edge> = edges.values().collect();
+    edges.sort_by_key(|edge| Reverse(edge.weight));
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "pubkey": pubkey,
+        "depth": depth,
+        "nodes": nodes,
+        "edges": edges,
+    })))
+}
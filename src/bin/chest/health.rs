@@ -0,0 +1,267 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{nostr, HealthConfig};
+
+/// Weight of the newest sample in the latency average
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Health of an upstream relay. The counts are halved at every evaluation, so they reflect
+/// recent behaviour.
+#[derive(Debug, Default, Clone, Serialize, sqlx::FromRow)]
+pub struct RelayStatus {
+    relay_url: String,
+    /// Average milliseconds between a REQ and its EOSE, or a lookup and its answer
+    latency_ms: Option<f64>,
+    /// REQs and lookups answered
+    requests: f64,
+    /// Subscriptions closed by the relay, malformed messages, failed lookups and lost
+    /// connections
+    errors: f64,
+    /// Events rejected as malformed since the relay was added; unlike the other counts, never
+    /// halved
+    malformed: f64,
+    events: f64,
+    /// Events already archived or received from another relay
+    duplicates: f64,
+    /// Whether the relay is skipped for dynamic subscriptions, lookups and backfill
+    paused: bool,
+    updated_at: i64,
+}
+
+impl RelayStatus {
+    fn error_rate(&self) -> f64 {
+        match self.requests + self.errors {
+            total if total > 0.0 => self.errors / total,
+            _ => 0.0,
+        }
+    }
+
+    fn duplicate_ratio(&self) -> f64 {
+        match self.events {
+            events if events > 0.0 => self.duplicates / events,
+            _ => 0.0,
+        }
+    }
+
+    /// Scores a relay between 0 and 1, higher for faster relays with fewer errors and
+    /// duplicates. A relay without samples scores 1, so it gets tried.
+    fn score(&self) -> f64 {
+        let latency = self.latency_ms.unwrap_or_default();
+        (1.0 - self.error_rate()) * (1.0 - self.duplicate_ratio() / 2.0) * 1000.0
+            / (1000.0 + latency)
+    }
+}
+
+/// Tracks the latency, error rate and share of duplicates of every upstream relay, to ask the
+/// healthiest relays first and pause the ones that mostly send events already held. The
+/// statuses are stored in the `relay_health` table at every evaluation and survive restarts.
+#[derive(Debug)]
+pub struct RelayHealth {
+    config: HealthConfig,
+    relays: Mutex<HashMap<String, RelayStatus>>,
+}
+
+impl RelayHealth {
+    /// Loads the stored statuses of the given relays
+    pub async fn load(
+        config: HealthConfig,
+        relay_urls: &[String],
+        db_pool: &SqlitePool,
+    ) -> Result<Self, sqlx::Error> {
+        let stored: Vec<RelayStatus> = sqlx::query_as(
+            "SELECT relay_url, latency_ms, requests, errors, malformed, events, duplicates, paused,
+                 updated_at
+             FROM relay_health",
+        )
+        .fetch_all(db_pool)
+        .await?;
+        let mut stored: HashMap<String, RelayStatus> = stored
+            .into_iter()
+            .map(|status| (status.relay_url.clone(), status))
+            .collect();
+        let relays = relay_urls
+            .iter()
+            .map(|relay_url| {
+                let status = stored.remove(relay_url).unwrap_or_else(|| RelayStatus {
+                    relay_url: relay_url.clone(),
+                    ..RelayStatus::default()
+                });
+                (relay_url.clone(), status)
+            })
+            .collect();
+        Ok(Self {
+            config,
+            relays: Mutex::new(relays),
+        })
+    }
+
+    /// Applies a change to the status of a relay
+    fn update(&self, relay_url: &str, change: impl FnOnce(&mut RelayStatus)) {
+        if let Some(status) = self.relays.lock().unwrap().get_mut(relay_url) {
+            change(status);
+        }
+    }
+
+    /// Records the time a relay took to answer a REQ or lookup
+    pub fn record_latency(&self, relay_url: &str, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        self.update(relay_url, |status| {
+            status.requests += 1.0;
+            status.latency_ms = Some(status.latency_ms.map_or(sample, |average| {
+                average + LATENCY_SMOOTHING * (sample - average)
+            }));
+        });
+    }
+
+    pub fn record_error(&self, relay_url: &str) {
+        self.update(relay_url, |status| status.errors += 1.0);
+    }
+
+    /// Counts an event a relay sent that could not be decoded or failed the structural checks,
+    /// which is also an error
+    pub fn record_malformed(&self, relay_url: &str) {
+        self.update(relay_url, |status| {
+            status.errors += 1.0;
+            status.malformed += 1.0;
+        });
+    }
+
+    /// Counts an event a relay sent, and whether chest already had it
+    pub fn record_event(&self, relay_url: &str, duplicate: bool) {
+        self.update(relay_url, |status| {
+            status.events += 1.0;
+            if duplicate {
+                status.duplicates += 1.0;
+            }
+        });
+    }
+
+    pub fn is_paused(&self, relay_url: &str) -> bool {
+        self.relays
+            .lock()
+            .unwrap()
+            .get(relay_url)
+            .is_some_and(|status| status.paused)
+    }
+
+    /// Orders relays from the best score to the worst, leaving out the paused ones
+    pub fn ranked<'a>(&self, relay_urls: &'a [String]) -> Vec<&'a String> {
+        let relays = self.relays.lock().unwrap();
+        let mut ranked: Vec<(&String, f64)> = relay_urls
+            .iter()
+            .filter_map(|relay_url| match relays.get(relay_url) {
+                Some(status) if status.paused => None,
+                Some(status) => Some((relay_url, status.score())),
+                None => Some((relay_url, 1.0)),
+            })
+            .collect();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        ranked.into_iter().map(|(relay_url, _)| relay_url).collect()
+    }
+
+    /// Pauses or resumes each relay by its share of duplicates, stores the statuses and halves
+    /// the counts
+    async fn evaluate(&self, db_pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let now = nostr::now() as i64;
+        let statuses: Vec<RelayStatus> = {
+            let mut relays = self.relays.lock().unwrap();
+            for status in relays.values_mut() {
+                let paused = status.events >= self.config.min_events as f64
+                    && status.duplicate_ratio() >= self.config.pause_duplicate_ratio;
+                if paused != status.paused {
+                    println!(
+                        "{} relay {}: {:.0}% of its {:.0} recent events were duplicates",
+                        if paused { "Pausing" } else { "Resuming" },
+                        status.relay_url,
+                        status.duplicate_ratio() * 100.0,
+                        status.events
+                    );
+                    status.paused = paused;
+                }
+                status.updated_at = now;
+            }
+            relays.values().cloned().collect()
+        };
+        let mut tx = db_pool.begin().await?;
+        for status in &statuses {
+            sqlx::query(
+                "INSERT OR REPLACE INTO relay_health
+                     (relay_url, latency_ms, requests, errors, malformed, events, duplicates,
+                      paused, updated_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&status.relay_url)
+            .bind(status.latency_ms)
+            .bind(status.requests)
+            .bind(status.errors)
+            .bind(status.malformed)
+            .bind(status.events)
+            .bind(status.duplicates)
+            .bind(status.paused)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        for status in self.relays.lock().unwrap().values_mut() {
+            status.requests /= 2.0;
+            status.errors /= 2.0;
+            status.events /= 2.0;
+            status.duplicates /= 2.0;
+        }
+        Ok(())
+    }
+
+    /// Evaluates the relays every `interval` seconds
+    pub fn spawn(self: Arc<Self>, db_pool: SqlitePool) {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval.max(1)));
+            // The first tick completes immediately; evaluate once samples came in.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.evaluate(&db_pool).await {
+                    eprintln!("Failed to store relay health: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+/// A relay's status with its score, reported by `GET /admin/relays`
+#[derive(Debug, Serialize)]
+struct ScoredRelay {
+    #[serde(flatten)]
+    status: RelayStatus,
+    score: f64,
+}
+
+/// Reports the latency, error rate, share of duplicates and score of every relay, best first.
+pub async fn relay_health(
+    req: HttpRequest,
+    health: web::Data<RelayHealth>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_relays", "").await?;
+    let mut relays: Vec<ScoredRelay> = health
+        .relays
+        .lock()
+        .unwrap()
+        .values()
+        .map(|status| ScoredRelay {
+            score: status.score(),
+            status: status.clone(),
+        })
+        .collect();
+    relays.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(HttpResponse::Ok().json(relays))
+}
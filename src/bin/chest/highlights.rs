@@ -0,0 +1,72 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::{etag, format_events, retain_visible, DbEvent, FormatQuery};
+
+/// Collects every reference a highlight of `target` may carry: the target itself, the address of
+/// an addressed article, and the ids of all stored versions of that article
+async fn resolve_references(
+    target: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut references = vec![target.to_string()];
+
+    if let [kind, pubkey, d_tag] = target.split(':').collect::<Vec<_>>().as_slice() {
+        // Highlights referencing a specific version of the article carry that version's id.
+        let ids: Vec<(String,)> = sqlx::query_as(
+            "SELECT event_id FROM public_events AS events
+             WHERE folder = 'long' AND kind = ? AND pubkey = ? AND d_tag = ?",
+        )
+        .bind(kind.parse::<i64>().unwrap_or_default())
+        .bind(*pubkey)
+        .bind(*d_tag)
+        .fetch_all(db_pool)
+        .await?;
+        references.extend(ids.into_iter().map(|(id,)| id));
+    } else {
+        let address: Option<(i64, String, String)> = sqlx::query_as(
+            "SELECT kind, pubkey, d_tag FROM public_events AS events
+             WHERE folder = 'long' AND event_id = ? AND d_tag IS NOT NULL",
+        )
+        .bind(target)
+        .fetch_optional(db_pool)
+        .await?;
+        references
+            .extend(address.map(|(kind, pubkey, d_tag)| format!("{}:{}:{}", kind, pubkey, d_tag)));
+    }
+    Ok(references)
+}
+
+/// Lists NIP-84 highlights of a note or article, given an event id or `kind:pubkey:d` address.
+pub async fn list_highlights(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let target = path.into_inner();
+    let references = resolve_references(&target, db_pool.get_ref()).await?;
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = 'highlights' AND ref_event IN (",
+    );
+    let mut separated = query.separated(", ");
+    for reference in references {
+        separated.push_bind(reference);
+    }
+    query.push(") ORDER BY created_at DESC");
+
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &format_events(&events, params.format),
+    ))
+}
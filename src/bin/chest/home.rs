@@ -0,0 +1,200 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::{etag, format_events, nostr, retain_visible, DbEvent, OutputFormat};
+
+/// Number of events returned per page of `GET /feed/{pubkey}` unless `limit` is given
+const DEFAULT_FEED_LIMIT: i64 = 50;
+
+/// Largest page of the home feed a client may request
+const MAX_FEED_LIMIT: i64 = 500;
+
+/// Query parameters of `GET /feed/{pubkey}`
+#[derive(Debug, Deserialize)]
+pub struct HomeFeedQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    /// Also include replies written by the followed pubkeys
+    #[serde(default)]
+    include_replies: bool,
+}
+
+/// Parses a `<created_at>:<event_id>` cursor
+pub fn parse_cursor(cursor: &str) -> Option<(i64, String)> {
+    let (created_at, event_id) = cursor.split_once(':')?;
+    Some((created_at.parse().ok()?, event_id.to_string()))
+}
+
+/// Builds a home feed from the archived kind 3 follow list of a pubkey: the notes of every
+/// followed pubkey, newest first. Pages are continued by passing the returned `next_cursor`,
+/// which is `null` on the last page.
+pub async fn home_feed(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<HomeFeedQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_FEED_LIMIT)
+        .clamp(1, MAX_FEED_LIMIT);
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(|cursor| {
+            parse_cursor(cursor)
+                .ok_or_else(|| ApiError::BadRequest(format!("Invalid cursor: {}", cursor)))
+        })
+        .transpose()?;
+
+    let (has_follows,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM public_events AS events WHERE folder = 'lists' AND kind = 3 AND pubkey = ?)",
+    )
+    .bind(&pubkey)
+    .fetch_one(db_pool.get_ref())
+    .await?;
+    if !has_follows {
+        return Err(ApiError::NotFound("Follow list not found".to_string()));
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        r#"
+        SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+        FROM public_events AS events
+        WHERE pubkey IN (
+            SELECT json_extract(tag.value, '$[1]')
+            FROM public_events AS list, json_each(list.tags) AS tag
+            WHERE list.folder = 'lists' AND list.kind = 3 AND list.pubkey = "#,
+    );
+    query.push_bind(&pubkey);
+    query.push(" AND json_extract(tag.value, '$[0]') = 'p')");
+    if params.include_replies {
+        query.push(" AND folder IN ('notes', 'replies')");
+    } else {
+        query.push(" AND folder = 'notes'");
+    }
+    if let Some((created_at, event_id)) = cursor {
+        query.push(" AND (created_at < ");
+        query.push_bind(created_at);
+        query.push(" OR (created_at = ");
+        query.push_bind(created_at);
+        query.push(" AND event_id < ");
+        query.push_bind(event_id);
+        query.push("))");
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY created_at DESC, event_id DESC LIMIT ");
+    query.push_bind(limit + 1);
+    let mut events = query
+        .build_query_as::<DbEvent>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+
+    let next_cursor = if events.len() as i64 > limit {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| format!("{}:{}", event.created_at, event.event_id))
+    } else {
+        None
+    };
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &serde_json::json!({
+            "events": format_events(&events, params.format),
+            "next_cursor": next_cursor,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn pages_follow_the_cursor() {
+        let db_pool = db::test_pool().await;
+        let followed = "1".repeat(64);
+        let mut follows = db::test_event('a', 3);
+        follows.tags = vec![vec!["p".to_string(), followed.clone()]];
+        let notes: Vec<_> = [('b', 10), ('c', 30), ('d', 20), ('e', 20)]
+            .into_iter()
+            .map(|(id, offset)| {
+                let mut note = db::test_event(id, 1);
+                note.pubkey = followed.clone();
+                note.created_at += offset;
+                note
+            })
+            .collect();
+        let mut stranger = db::test_event('9', 1);
+        stranger.pubkey = "2".repeat(64);
+        let mut stored = vec![&follows, &stranger];
+        stored.extend(&notes);
+        db::store_test_events(&db_pool, &stored).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/feed/{pubkey}", web::get().to(home_feed)),
+        )
+        .await;
+        let page = |cursor: Option<&str>| {
+            let uri = match cursor {
+                Some(cursor) => format!(
+                    "/feed/{}?format=nostr&limit=2&cursor={}",
+                    follows.pubkey, cursor
+                ),
+                None => format!("/feed/{}?format=nostr&limit=2", follows.pubkey),
+            };
+            TestRequest::get().uri(&uri).to_request()
+        };
+        let ids = |page: &Value| -> Vec<String> {
+            page["events"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|event| event["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let first: Value = call_and_read_body_json(&app, page(None)).await;
+        assert_eq!(ids(&first), [notes[1].id.clone(), notes[3].id.clone()]);
+        let cursor = first["next_cursor"].as_str().unwrap();
+        assert_eq!(cursor, format!("{}:{}", notes[3].created_at, notes[3].id));
+
+        let second: Value = call_and_read_body_json(&app, page(Some(cursor))).await;
+        assert_eq!(ids(&second), [notes[2].id.clone(), notes[0].id.clone()]);
+        assert!(second["next_cursor"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn malformed_cursors_are_rejected() {
+        let db_pool = db::test_pool().await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/feed/{pubkey}", web::get().to(home_feed)),
+        )
+        .await;
+        let request = TestRequest::get()
+            .uri(&format!("/feed/{}?cursor=yesterday", "f".repeat(64)))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
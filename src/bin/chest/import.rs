@@ -0,0 +1,212 @@
+use futures_util::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::SqlitePool;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::str::FromStr;
+
+use crate::dms::{self, DmBackup};
+use crate::ingest::{self, DELETION_KIND};
+use crate::nostr::NostrEvent;
+use crate::AppConfig;
+
+/// Events between two progress lines
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Layout of the data being imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImportFormat {
+    /// One event JSON per line, as written by `strfry export` and most relay dump tools
+    Jsonl,
+    /// The SQLite database of nostr-rs-relay, read directly
+    NostrRsRelay,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "jsonl" | "strfry" => Ok(ImportFormat::Jsonl),
+            "nostr-rs-relay" => Ok(ImportFormat::NostrRsRelay),
+            other => Err(format!(
+                "Unknown format: {} (expected strfry, jsonl or nostr-rs-relay)",
+                other
+            )),
+        }
+    }
+}
+
+/// Parses the arguments following `import`: the format and the path to read, `-` for stdin
+fn parse_options(args: &[String]) -> Result<(ImportFormat, String), String> {
+    let mut format = ImportFormat::Jsonl;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next().ok_or("Missing value of --format")?.parse()?,
+            other if path.is_none() && (other == "-" || !other.starts_with('-')) => {
+                path = Some(other.to_string())
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    Ok((format, path.ok_or("Missing path to import")?))
+}
+
+/// Counts of an import, printed as it goes
+#[derive(Debug, Default)]
+struct ImportCounts {
+    read: u64,
+    imported: u64,
+    /// Lines or rows that are not events
+    malformed: u64,
+    /// Kinds the archive is not configured for, and direct messages of other users
+    unwanted: u64,
+    /// Events whose id or signature does not verify
+    invalid: u64,
+    /// Already archived, replaced by a newer version, banned, or without a folder
+    skipped: u64,
+}
+
+/// Archives events read from another relay's data, applying the same kind selection as
+/// ingestion from upstream relays
+struct Importer<'a> {
+    db_pool: &'a SqlitePool,
+    config: &'a AppConfig,
+    dms: Option<DmBackup>,
+    counts: ImportCounts,
+}
+
+impl Importer<'_> {
+    /// Archives one serialized event
+    async fn import(&mut self, json: &str) -> Result<(), sqlx::Error> {
+        self.counts.read += 1;
+        if self.counts.read.is_multiple_of(PROGRESS_INTERVAL) {
+            println!(
+                "Read {} events, imported {}",
+                self.counts.read, self.counts.imported
+            );
+        }
+        let Some(event) = serde_json::from_str::<NostrEvent>(json)
+            .ok()
+            .filter(|event| event.check_structure().is_ok())
+        else {
+            self.counts.malformed += 1;
+            return Ok(());
+        };
+        let wanted = match event.kind {
+            dms::ENCRYPTED_DM_KIND | dms::GIFT_WRAP_KIND => {
+                self.dms.as_ref().is_some_and(|dms| dms.accepts(&event))
+            }
+            kind => {
+                !ingest::EPHEMERAL_KINDS.contains(&kind) && self.config.event.kinds.contains(&kind)
+            }
+        };
+        if !wanted {
+            self.counts.unwanted += 1;
+            return Ok(());
+        }
+        if event.verify().is_err() {
+            self.counts.invalid += 1;
+            return Ok(());
+        }
+        if event.kind == DELETION_KIND {
+            ingest::apply_deletion(self.db_pool, &event).await?;
+        }
+        if ingest::store_event(
+            self.db_pool,
+            &event,
+            false,
+            false,
+            &self.config.versions,
+            &[],
+        )
+        .await?
+        {
+            self.counts.imported += 1;
+        } else {
+            self.counts.skipped += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Imports the events of a JSONL file, or of stdin for `-`
+async fn import_jsonl(importer: &mut Importer<'_>, path: &str) -> Result<(), String> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        Box::new(BufReader::new(file))
+    };
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        importer
+            .import(&line)
+            .await
+            .map_err(|e| format!("Database query error: {:?}", e))?;
+    }
+    Ok(())
+}
+
+/// Imports the events of a nostr-rs-relay database, which keeps each event's JSON in the
+/// `content` column of its `event` table, oldest first so deletions follow their targets
+async fn import_nostr_rs_relay(importer: &mut Importer<'_>, path: &str) -> Result<(), String> {
+    let db_error = |e: sqlx::Error| format!("Failed to read {}: {:?}", path, e);
+    let options = SqliteConnectOptions::from_str(path)
+        .map_err(db_error)?
+        .read_only(true);
+    let source = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .map_err(db_error)?;
+    let mut rows = sqlx::query_as::<_, (String,)>(
+        "SELECT content FROM event WHERE hidden IS NOT 1 ORDER BY created_at",
+    )
+    .fetch(&source);
+    while let Some((json,)) = rows.try_next().await.map_err(db_error)? {
+        importer
+            .import(&json)
+            .await
+            .map_err(|e| format!("Database query error: {:?}", e))?;
+    }
+    Ok(())
+}
+
+/// Seeds the archive from the data of another relay: `chest import --format strfry dump.jsonl`
+/// for JSONL exports (`-` reads stdin), `--format nostr-rs-relay nostr.db` for nostr-rs-relay
+/// databases.
+pub async fn import(
+    args: &[String],
+    db_pool: &SqlitePool,
+    config: &AppConfig,
+) -> Result<(), String> {
+    let (format, path) = parse_options(args)?;
+    let mut importer = Importer {
+        db_pool,
+        config,
+        dms: DmBackup::new(&config.dms),
+        counts: ImportCounts::default(),
+    };
+    match format {
+        ImportFormat::Jsonl => import_jsonl(&mut importer, &path).await?,
+        ImportFormat::NostrRsRelay => import_nostr_rs_relay(&mut importer, &path).await?,
+    }
+    let counts = importer.counts;
+    println!(
+        "Read {} events: {} imported, {} already archived or superseded, {} of unwanted kinds, \
+         {} invalid, {} malformed",
+        counts.read,
+        counts.imported,
+        counts.skipped,
+        counts.unwanted,
+        counts.invalid,
+        counts.malformed
+    );
+    Ok(())
+}
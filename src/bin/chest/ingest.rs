@@ -0,0 +1,1027 @@
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use std::sync::Arc;
+
+use crate::backfill::Backfill;
+use crate::blossom;
+use crate::cache::EventCache;
+use crate::db;
+use crate::dedup::SeenFilter;
+use crate::dms::{self, DmBackup};
+use crate::dryrun::DryRun;
+use crate::health::RelayHealth;
+use crate::media::MediaCache;
+use crate::mqtt::MqttBridge;
+use crate::nostr::{self, NostrEvent};
+use crate::notify::Notifier;
+use crate::previews::LinkPreviews;
+use crate::signer::Signer;
+use crate::stats::Stats;
+use crate::subscriptions::SubscriptionRegistry;
+use crate::timestamps;
+use crate::topics::TopicFilter;
+use crate::wot::{Verdict, WebOfTrust};
+use crate::{AppConfig, FilterAction, IngestConfig, OversizeAction, QuotaAction, VersionConfig};
+
+/// NIP-51 standard lists (replaceable per pubkey and kind), including the kind 3 follow list
+pub const LIST_KINDS: &[u64] = &[
+    3, 10000, 10001, 10002, 10003, 10004, 10005, 10006, 10007, 10009, 10015, 10030, 10050, 10101,
+    10102,
+];
+
+/// NIP-51 sets (parameterized replaceable, addressed by their `d` tag)
+pub const SET_KINDS: &[u64] = &[
+    30000, 30002, 30003, 30004, 30005, 30007, 30015, 30030, 30063, 39089,
+];
+
+/// NIP-01 ephemeral kinds, which relays do not store and chest never archives
+pub const EPHEMERAL_KINDS: std::ops::Range<u64> = 20000..30000;
+
+/// NIP-09 event deletion request
+pub const DELETION_KIND: u64 = 5;
+
+/// NIP-22 comment
+pub const COMMENT_KIND: u64 = 1111;
+
+/// NIP-72 community definition
+pub const COMMUNITY_KIND: u64 = 34550;
+
+/// NIP-53 live activity (e.g. a live stream)
+pub const LIVE_ACTIVITY_KIND: u64 = 30311;
+
+/// NIP-58 badge award, badge definition and the badges a profile accepted
+pub const BADGE_AWARD_KIND: u64 = 8;
+pub const BADGE_DEFINITION_KIND: u64 = 30009;
+pub const PROFILE_BADGES_KIND: u64 = 30008;
+
+/// NIP-52 date-based and time-based calendar events
+pub const DATE_CALENDAR_EVENT_KIND: u64 = 31922;
+pub const TIME_CALENDAR_EVENT_KIND: u64 = 31923;
+
+/// Returns whether a kind is parameterized replaceable, i.e. keyed by pubkey, kind and `d` tag
+fn is_parameterized_replaceable(kind: u64) -> bool {
+    (30000..40000).contains(&kind)
+}
+
+/// Returns the `d` tag of a parameterized replaceable event (empty if missing), `None` for other kinds
+fn d_tag(event: &NostrEvent) -> Option<String> {
+    is_parameterized_replaceable(event.kind)
+        .then(|| event.tag_value("d").unwrap_or_default().to_string())
+}
+
+/// Returns the id of the event a NIP-10 reply responds to: the `reply` marker, then the `root`
+/// marker, then the last positional `e` tag
+fn reply_target(event: &NostrEvent) -> Option<String> {
+    let e_tags: Vec<&Vec<String>> = event
+        .tags
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "e")
+        .collect();
+    let with_marker = |marker: &str| {
+        e_tags
+            .iter()
+            .find(|tag| tag.get(3).map(String::as_str) == Some(marker))
+            .map(|tag| tag[1].clone())
+    };
+    with_marker("reply")
+        .or_else(|| with_marker("root"))
+        .or_else(|| e_tags.last().map(|tag| tag[1].clone()))
+}
+
+/// Returns the channel a NIP-28 message is posted in
+fn channel_root(event: &NostrEvent) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .find(|tag| tag.len() >= 4 && tag[0] == "e" && tag[3] == "root")
+        .map(|tag| tag[1].clone())
+        .or_else(|| event.tag_value("e").map(str::to_string))
+}
+
+/// Returns the value of the last tag with the given name
+fn last_tag_value(event: &NostrEvent, name: &str) -> Option<String> {
+    event
+        .tags
+        .iter()
+        .rev()
+        .find(|tag| tag.len() >= 2 && tag[0] == name)
+        .map(|tag| tag[1].clone())
+}
+
+/// Decides which folder an event is archived in and which event it references.
+/// Returns `None` for kinds chest does not archive.
+pub fn classify(event: &NostrEvent) -> Option<(&'static str, Option<String>)> {
+    match event.kind {
+        0 => Some(("users", None)),
+        1 => match reply_target(event) {
+            Some(parent) => Some(("replies", Some(parent))),
+            // Quote reposts reference the quoted note with a `q` tag.
+            None => Some(("notes", event.tag_value("q").map(str::to_string))),
+        },
+        // NIP-22 comments name their parent with lowercase tags and their root with uppercase
+        // ones; comments on addressable events only carry the parent's `a` tag.
+        COMMENT_KIND => Some(("replies", event.tag_value("e").map(str::to_string))),
+        6 => Some(("reposts", last_tag_value(event, "e"))),
+        // NIP-28 channels: creation and metadata updates, and messages referencing the
+        // channel's creation event with the `root` marker (or their first `e` tag).
+        40 => Some(("channels", None)),
+        41 => Some(("channels", event.tag_value("e").map(str::to_string))),
+        42 => Some(("channel_messages", channel_root(event))),
+        7 => Some(("reactions", last_tag_value(event, "e"))),
+        9735 => Some(("zaps", last_tag_value(event, "e"))),
+        // Highlights of addressable content (articles) only carry an `a` tag.
+        9802 => Some((
+            "highlights",
+            last_tag_value(event, "e").or_else(|| last_tag_value(event, "a")),
+        )),
+        // Reports name the reported event when there is one, otherwise the reported pubkey.
+        1984 => Some((
+            "reports",
+            last_tag_value(event, "e").or_else(|| last_tag_value(event, "p")),
+        )),
+        // Kept so events arriving after their deletion request are not archived.
+        DELETION_KIND => Some(("deletions", last_tag_value(event, "e"))),
+        30023 | 30024 => Some(("long", None)),
+        // NIP-72 community definitions, and moderator approvals of posts addressed to them
+        COMMUNITY_KIND => Some(("communities", None)),
+        4550 => Some(("approvals", last_tag_value(event, "e"))),
+        // NIP-53 live activities, and the chat messages addressing them with an `a` tag
+        LIVE_ACTIVITY_KIND => Some(("live", None)),
+        1311 => Some(("live_chat", None)),
+        // NIP-58 badges: definitions, awards addressing them with an `a` tag, and the awards a
+        // profile chose to display
+        BADGE_DEFINITION_KIND | PROFILE_BADGES_KIND => Some(("badges", None)),
+        BADGE_AWARD_KIND => Some(("badge_awards", None)),
+        // NIP-52 calendar events, and the RSVPs addressing them with an `a` tag
+        DATE_CALENDAR_EVENT_KIND | TIME_CALENDAR_EVENT_KIND => Some(("calendar", None)),
+        31925 => Some(("rsvps", None)),
+        // NIP-03 OpenTimestamps attestations of the event named by their `e` tag, checked by
+        // the timestamps job
+        timestamps::ATTESTATION_KIND => {
+            Some(("attestations", event.tag_value("e").map(str::to_string)))
+        }
+        // NIP-94 file metadata, indexed by url, hash and mime type in the `files` table
+        1063 => Some(("files", None)),
+        // Direct messages of the operator, stored encrypted
+        dms::ENCRYPTED_DM_KIND | dms::GIFT_WRAP_KIND => Some(("dms", None)),
+        // Blossom server lists, used to fetch media by hash when their links fail
+        blossom::SERVER_LIST_KIND => Some(("lists", None)),
+        kind if LIST_KINDS.contains(&kind) || SET_KINDS.contains(&kind) => Some(("lists", None)),
+        // Zap requests are only meaningful embedded in their receipts.
+        _ => None,
+    }
+}
+
+/// Returns the address of the parameterized replaceable event (e.g. an article) that an
+/// interaction references with an `a` tag
+fn ref_address(event: &NostrEvent, folder: &str) -> Option<String> {
+    if event.kind == COMMENT_KIND {
+        return event.tag_value("a").map(str::to_string);
+    }
+    matches!(
+        folder,
+        "replies"
+            | "notes"
+            | "reactions"
+            | "zaps"
+            | "reposts"
+            | "approvals"
+            | "live_chat"
+            | "badge_awards"
+            | "rsvps"
+    )
+    .then(|| last_tag_value(event, "a"))
+    .flatten()
+}
+
+/// Copies the stored versions of a replaceable event into `event_versions` before they are
+/// replaced, keeping at most `max_versions` of them (0 for all)
+async fn keep_versions(
+    conn: &mut SqliteConnection,
+    event: &NostrEvent,
+    d_tag: Option<&str>,
+    max_versions: usize,
+) -> Result<(), sqlx::Error> {
+    let address = |query: &mut QueryBuilder<'_, Sqlite>| {
+        query.push(" WHERE pubkey = ");
+        query.push_bind(event.pubkey.clone());
+        query.push(" AND kind = ");
+        query.push_bind(event.kind as i64);
+        if let Some(d_tag) = d_tag {
+            query.push(" AND d_tag = ");
+            query.push_bind(d_tag.to_string());
+        }
+    };
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "INSERT OR IGNORE INTO event_versions
+             (event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event, d_tag,
+              superseded_at)
+         SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event, d_tag, ",
+    );
+    query.push_bind(nostr::now() as i64);
+    query.push(" FROM events");
+    address(&mut query);
+    query.build().execute(&mut *conn).await?;
+
+    if max_versions > 0 {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM event_versions");
+        address(&mut query);
+        query.push(" AND event_id NOT IN (SELECT event_id FROM event_versions");
+        address(&mut query);
+        query.push(" ORDER BY created_at DESC LIMIT ");
+        query.push_bind(max_versions as i64);
+        query.push(")");
+        query.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Removes older versions of a replaceable event, returning `false` if a newer version is already stored
+async fn replace_previous(
+    conn: &mut SqliteConnection,
+    event: &NostrEvent,
+    versions: &VersionConfig,
+) -> Result<bool, sqlx::Error> {
+    let d_tag = d_tag(event);
+    let d_condition = if d_tag.is_some() {
+        " AND d_tag = ?"
+    } else {
+        ""
+    };
+
+    let newer_query = format!(
+        "SELECT event_id FROM events WHERE pubkey = ? AND kind = ? AND created_at >= ?{}",
+        d_condition
+    );
+    let mut newer = sqlx::query_as::<_, (String,)>(&newer_query)
+        .bind(&event.pubkey)
+        .bind(event.kind as i64)
+        .bind(event.created_at as i64);
+    if let Some(d_tag) = &d_tag {
+        newer = newer.bind(d_tag);
+    }
+    if newer.fetch_optional(&mut *conn).await?.is_some() {
+        return Ok(false);
+    }
+
+    if versions.enabled {
+        keep_versions(conn, event, d_tag.as_deref(), versions.max_versions).await?;
+    }
+
+    let delete_query = format!(
+        "DELETE FROM events WHERE pubkey = ? AND kind = ?{}",
+        d_condition
+    );
+    let mut delete = sqlx::query(&delete_query)
+        .bind(&event.pubkey)
+        .bind(event.kind as i64);
+    if let Some(d_tag) = &d_tag {
+        delete = delete.bind(d_tag);
+    }
+    delete.execute(&mut *conn).await?;
+    Ok(true)
+}
+
+/// Returns whether moderators removed the event or banned its pubkey, or its author requested
+/// its deletion
+async fn is_banned(conn: &mut SqliteConnection, event: &NostrEvent) -> Result<bool, sqlx::Error> {
+    let (banned,): (bool,) = sqlx::query_as(
+        r#"
+        SELECT EXISTS (SELECT 1 FROM banned_events WHERE event_id = ?)
+            OR EXISTS (SELECT 1 FROM banned_pubkeys WHERE pubkey = ?)
+            OR EXISTS (
+                SELECT 1 FROM events AS deletion, json_each(deletion.tags) AS tag
+                WHERE deletion.folder = 'deletions' AND deletion.pubkey = ?
+                  AND json_extract(tag.value, '$[0]') = 'e'
+                  AND json_extract(tag.value, '$[1]') = ?
+            )
+        "#,
+    )
+    .bind(&event.id)
+    .bind(&event.pubkey)
+    .bind(&event.pubkey)
+    .bind(&event.id)
+    .fetch_one(conn)
+    .await?;
+    Ok(banned)
+}
+
+/// Returns whether an event was moved to cold storage, so relays sending it again do not bring
+/// it back into the events table
+async fn is_cold(conn: &mut SqliteConnection, event_id: &str) -> Result<bool, sqlx::Error> {
+    let (cold,): (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM cold_events WHERE event_id = ?)")
+            .bind(event_id)
+            .fetch_one(conn)
+            .await?;
+    Ok(cold)
+}
+
+/// Applies a NIP-09 deletion request: removes the referenced events written by the same author
+/// and blocklists them so relays that ignore deletions cannot bring them back. Requests whose
+/// signature does not verify are ignored, as anyone could otherwise erase anyone's events.
+pub async fn apply_deletion(db_pool: &SqlitePool, event: &NostrEvent) -> Result<u64, sqlx::Error> {
+    if event.kind != DELETION_KIND || event.verify().is_err() {
+        return Ok(0);
+    }
+    let mut deleted = 0;
+    for tag in event
+        .tags
+        .iter()
+        .filter(|tag| tag.len() >= 2 && tag[0] == "e")
+    {
+        // Only the requester's own events are removed, and only those are blocklisted.
+        let mut removed = 0;
+        for table in ["events", "event_versions", "cold_events"] {
+            removed += sqlx::query(&format!(
+                "DELETE FROM {} WHERE event_id = ? AND pubkey = ?",
+                table
+            ))
+            .bind(&tag[1])
+            .bind(&event.pubkey)
+            .execute(db_pool)
+            .await?
+            .rows_affected();
+        }
+        if removed == 0 {
+            continue;
+        }
+        sqlx::query(
+            "INSERT OR REPLACE INTO banned_events (event_id, banned_at, reason) VALUES (?, ?, ?)",
+        )
+        .bind(&tag[1])
+        .bind(event.created_at as i64)
+        .bind(format!("deletion request {}", event.id))
+        .execute(db_pool)
+        .await?;
+        deleted += removed;
+    }
+    Ok(deleted)
+}
+
+/// Folders of replaceable events: profiles, lists, communities, live activities, badges,
+/// calendar events and RSVPs. Only the newest version of each is kept.
+pub const REPLACEABLE_FOLDERS: &[&str] = &[
+    "users",
+    "lists",
+    "communities",
+    "live",
+    "badges",
+    "calendar",
+    "rsvps",
+];
+
+/// Size an event is charged against its author's byte quota
+fn quota_bytes(event: &NostrEvent) -> i64 {
+    let tags = serde_json::to_string(&event.tags).map_or(0, |tags| tags.len());
+    (event.content.len() + tags) as i64
+}
+
+/// Stores an event in its folder, returning whether a new row was written. The author's events
+/// listed in `evict` are deleted in the same transaction once the event is written, to keep
+/// within its quota; nothing is evicted for an event that is not stored. `truncated` marks an
+/// event cut down to the size limits, which is kept off the NIP-01 relay endpoint.
+pub async fn store_event(
+    db_pool: &SqlitePool,
+    event: &NostrEvent,
+    flagged: bool,
+    truncated: bool,
+    versions: &VersionConfig,
+    evict: &[String],
+) -> Result<bool, sqlx::Error> {
+    let Some((folder, ref_event)) = classify(event) else {
+        return Ok(false);
+    };
+
+    // Returning early drops the transaction, rolling back a replacement of a previous version.
+    let mut tx = db_pool.begin().await?;
+    if is_banned(&mut tx, event).await? || is_cold(&mut tx, &event.id).await? {
+        return Ok(false);
+    }
+
+    if REPLACEABLE_FOLDERS.contains(&folder) && !replace_previous(&mut tx, event, versions).await? {
+        return Ok(false);
+    }
+
+    let tags = serde_json::to_string(&event.tags).unwrap_or_else(|_| "[]".to_string());
+    let result = sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO events
+            (event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event, d_tag, flagged,
+             truncated, ref_address, delegator)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&event.id)
+    .bind(&event.pubkey)
+    .bind(event.created_at as i64)
+    .bind(event.kind as i64)
+    .bind(&event.content)
+    .bind(&event.sig)
+    .bind(tags)
+    .bind(folder)
+    .bind(ref_event)
+    .bind(d_tag(event))
+    .bind(flagged)
+    .bind(truncated)
+    .bind(ref_address(event, folder))
+    .bind(event.delegator())
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    for batch in evict.chunks(500) {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM events WHERE pubkey = ");
+        query.push_bind(&event.pubkey);
+        query.push(" AND event_id IN (");
+        let mut separated = query.separated(", ");
+        for event_id in batch {
+            separated.push_bind(event_id);
+        }
+        query.push(")");
+        query.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Picks the oldest of an author's candidate events, with their sizes, whose eviction brings
+/// its usage back within the quota, or returns `None` when evicting all of them is not enough
+fn select_evictions(
+    candidates: Vec<(String, i64)>,
+    mut excess_events: i64,
+    mut excess_bytes: i64,
+) -> Option<Vec<String>> {
+    let mut evicted = Vec::new();
+    for (event_id, size) in candidates {
+        if excess_events <= 0 && excess_bytes <= 0 {
+            break;
+        }
+        evicted.push(event_id);
+        excess_events -= 1;
+        excess_bytes -= size;
+    }
+    (excess_events <= 0 && excess_bytes <= 0).then_some(evicted)
+}
+
+/// Returns whether the event carries NIP-13 proof of work of at least `min_difficulty`, both
+/// committed to in its `nonce` tag and achieved by its id
+fn has_pow(event: &NostrEvent, min_difficulty: u32) -> bool {
+    event
+        .committed_difficulty()
+        .is_some_and(|target| target >= min_difficulty)
+        && event.difficulty() >= min_difficulty
+        && event.compute_id() == event.id
+}
+
+/// Returns whether a pubkey is one the configuration names as the operator's: the relay
+/// information pubkey, the web of trust root or an admin
+fn is_operator(config: &AppConfig, pubkey: &str) -> bool {
+    config.relay_info.pubkey.as_deref() == Some(pubkey)
+        || nostr::parse_pubkey(&config.wot.root_pubkey).as_deref() == Some(pubkey)
+        || config
+            .auth
+            .admin_pubkeys
+            .iter()
+            .any(|admin| admin == pubkey)
+}
+
+/// Decides whether an event is archived: the operator's pubkeys and pubkeys in the web of trust
+/// are accepted, others must carry enough proof of work when required, and are otherwise
+/// handled per the web of trust
+fn admission(event: &NostrEvent, config: &AppConfig, wot: &WebOfTrust) -> Verdict {
+    if wot.trusts(&event.pubkey) || is_operator(config, &event.pubkey) {
+        return Verdict::Accept;
+    }
+    let min_pow_difficulty = config.ingest.min_pow_difficulty;
+    if min_pow_difficulty > 0 {
+        return if has_pow(event, min_pow_difficulty) {
+            Verdict::Accept
+        } else {
+            Verdict::Drop
+        };
+    }
+    wot.verdict(&event.pubkey)
+}
+
+/// Checks that `created_at` lies between the configured earliest timestamp and the near future,
+/// since replaceable events dated far ahead would otherwise never be replaced
+fn check_timestamp(event: &NostrEvent, config: &IngestConfig) -> Verdict {
+    let latest = nostr::now() + config.max_future_skew;
+    if (config.min_created_at..=latest).contains(&event.created_at) {
+        return Verdict::Accept;
+    }
+    match config.invalid_timestamp_action {
+        FilterAction::Drop => Verdict::Drop,
+        FilterAction::Flag => Verdict::Flag,
+    }
+}
+
+/// Shortens a string to at most `max` bytes without splitting a character
+fn truncate_utf8(text: &mut String, max: usize) {
+    if text.len() > max {
+        let mut end = max;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
+/// Enforces the content and tag size limits, cutting oversized events down when configured to.
+/// Only a truncated event is flagged.
+fn check_size(event: &mut NostrEvent, config: &IngestConfig) -> Verdict {
+    let oversized = event.content.len() > config.max_content_length
+        || event.tags.len() > config.max_tags
+        || event
+            .tags
+            .iter()
+            .flatten()
+            .any(|value| value.len() > config.max_tag_value_length);
+    if !oversized {
+        return Verdict::Accept;
+    }
+    match config.oversize_action {
+        OversizeAction::Drop => Verdict::Drop,
+        OversizeAction::Truncate => {
+            truncate_utf8(&mut event.content, config.max_content_length);
+            event.tags.truncate(config.max_tags);
+            for value in event.tags.iter_mut().flatten() {
+                truncate_utf8(value, config.max_tag_value_length);
+            }
+            Verdict::Flag
+        }
+    }
+}
+
+/// State shared by everything that archives incoming events
+#[derive(Debug)]
+pub struct Ingestor {
+    pub db_pool: SqlitePool,
+    pub config: AppConfig,
+    pub wot: Arc<WebOfTrust>,
+    pub cache: Arc<EventCache>,
+    pub seen: Arc<SeenFilter>,
+    pub stats: Arc<Stats>,
+    /// Set when newly archived events are forwarded to an MQTT broker
+    pub mqtt: Option<MqttBridge>,
+    /// Set when matching events are announced on Telegram or Discord
+    pub notifier: Option<Arc<Notifier>>,
+    /// Set when referenced images and videos are downloaded into the media cache
+    pub media: Option<Arc<MediaCache>>,
+    /// Set when the metadata of web pages linked from notes is fetched
+    pub previews: Option<Arc<LinkPreviews>>,
+    /// Set when the operator's direct messages are archived
+    pub dms: Option<Arc<DmBackup>>,
+    /// Set when a NIP-46 remote signer is connected for publishing and relay AUTH
+    pub signer: Option<Arc<Signer>>,
+    /// Set when events are only counted, not written
+    pub dry_run: Option<Arc<DryRun>>,
+    /// Set when the relays' history is walked in pages
+    pub backfill: Option<Arc<Backfill>>,
+    /// Set when only notes on the configured topics are archived
+    pub topics: Option<TopicFilter>,
+    pub health: Arc<RelayHealth>,
+}
+
+impl Ingestor {
+    /// Hands an ephemeral event of one of the configured kinds to the MQTT and notification
+    /// sinks without touching the database. Its signature is checked first, as nothing verifies
+    /// it later, and copies received from other relays are skipped.
+    fn forward_ephemeral(&self, event: &NostrEvent) {
+        if !self.config.event.ephemeral_kinds.contains(&event.kind)
+            || self.seen.contains(&event.id)
+            || event.verify().is_err()
+        {
+            return;
+        }
+        self.seen.insert(&event.id);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish(event);
+        }
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(event);
+        }
+    }
+
+    /// Checks a new event against the per-pubkey quotas. Returns `None` when it may not be
+    /// archived, otherwise the author's oldest events to evict to make room for it, which
+    /// `store_event` deletes once the event is written. Replaceable events are exempt, as they
+    /// replace their previous version.
+    async fn quota_evictions(
+        &self,
+        event: &NostrEvent,
+    ) -> Result<Option<Vec<String>>, sqlx::Error> {
+        let config = &self.config.ingest;
+        let db_pool = &self.db_pool;
+        if config.max_events_per_pubkey == 0 && config.max_bytes_per_pubkey == 0 {
+            return Ok(Some(Vec::new()));
+        }
+        if classify(event).is_some_and(|(folder, _)| REPLACEABLE_FOLDERS.contains(&folder)) {
+            return Ok(Some(Vec::new()));
+        }
+        let (events, bytes): (i64, i64) =
+            sqlx::query_as("SELECT events, bytes FROM pubkey_usage WHERE pubkey = ?")
+                .bind(&event.pubkey)
+                .fetch_optional(db_pool)
+                .await?
+                .unwrap_or_default();
+        let max_events = match config.max_events_per_pubkey {
+            0 => i64::MAX,
+            max => max as i64,
+        };
+        let max_bytes = match config.max_bytes_per_pubkey {
+            0 => i64::MAX,
+            max => max as i64,
+        };
+        let new_bytes = quota_bytes(event);
+        let excess_events = events + 1 - max_events;
+        let excess_bytes = bytes.saturating_add(new_bytes) - max_bytes;
+        if excess_events <= 0 && excess_bytes <= 0 {
+            return Ok(Some(Vec::new()));
+        }
+        if config.quota_action == QuotaAction::Reject || new_bytes > max_bytes {
+            return Ok(None);
+        }
+
+        // Replaceable events and events newer than the new one are kept, so older events
+        // arriving late are rejected instead.
+        let mut query = QueryBuilder::<Sqlite>::new(format!(
+            "SELECT event_id, {} FROM events WHERE pubkey = ",
+            db::USAGE_BYTES
+        ));
+        query.push_bind(&event.pubkey);
+        query.push(" AND folder NOT IN (");
+        let mut separated = query.separated(", ");
+        for folder in REPLACEABLE_FOLDERS {
+            separated.push_bind(*folder);
+        }
+        query.push(") AND created_at < ");
+        query.push_bind(event.created_at as i64);
+        query.push(" ORDER BY created_at ASC, event_id ASC");
+        let candidates: Vec<(String, i64)> = query.build_query_as().fetch_all(db_pool).await?;
+        Ok(select_evictions(candidates, excess_events, excess_bytes))
+    }
+
+    /// Applies the ingestion filters to an event and archives it, returning it if it was newly
+    /// archived so the dynamic subscription rules can be applied
+    pub async fn ingest_event(&self, mut event: NostrEvent) -> Option<NostrEvent> {
+        if EPHEMERAL_KINDS.contains(&event.kind) {
+            self.forward_ephemeral(&event);
+            return None;
+        }
+        // Direct messages are only archived when they belong to the operator's DM backup.
+        let personal_dm = self.dms.as_ref().is_some_and(|dms| dms.accepts(&event));
+        let wanted = match event.kind {
+            dms::ENCRYPTED_DM_KIND | dms::GIFT_WRAP_KIND => personal_dm,
+            kind => self.config.event.kinds.contains(&kind),
+        };
+        if !wanted || self.seen.contains(&event.id) {
+            return None;
+        }
+        // Any relay can send events in anyone's name, so the signature is checked before an event
+        // is archived or replaces a previous version. This runs before the size check, which may
+        // truncate the event.
+        if let Err(e) = event.verify() {
+            eprintln!(
+                "Dropping event {} with an invalid signature: {}",
+                event.id, e
+            );
+            return None;
+        }
+        // Gift wraps carry randomized timestamps and encrypted content must not be truncated,
+        // so the operator's own messages skip the filters.
+        let verdicts = if personal_dm {
+            [Verdict::Accept; 4]
+        } else {
+            [
+                admission(&event, &self.config, &self.wot),
+                check_timestamp(&event, &self.config.ingest),
+                check_size(&mut event, &self.config.ingest),
+                self.topics
+                    .as_ref()
+                    .map_or(Verdict::Accept, |topics| topics.verdict(&event)),
+            ]
+        };
+        let truncated = verdicts[2] == Verdict::Flag;
+        if verdicts.contains(&Verdict::Drop) {
+            if let Some(dry_run) = &self.dry_run {
+                dry_run.record_dropped(&event);
+            }
+            return None;
+        }
+        let flagged = verdicts.contains(&Verdict::Flag);
+        // A dry run only counts the event; it still triggers the dynamic subscriptions so the
+        // counts cover what a real run would fetch.
+        if let Some(dry_run) = &self.dry_run {
+            self.seen.insert(&event.id);
+            return dry_run
+                .record(&self.db_pool, &event, flagged)
+                .await
+                .then_some(event);
+        }
+        if event.kind == DELETION_KIND {
+            match apply_deletion(&self.db_pool, &event).await {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    for tag in event
+                        .tags
+                        .iter()
+                        .filter(|tag| tag.len() >= 2 && tag[0] == "e")
+                    {
+                        self.cache.invalidate_event(&tag[1]);
+                    }
+                    println!("Deleted {} events on request of {}", deleted, event.pubkey)
+                }
+                Err(e) => eprintln!("Failed to apply deletion {}: {:?}", event.id, e),
+            }
+        }
+        let evict = if personal_dm {
+            Vec::new()
+        } else {
+            match self.quota_evictions(&event).await {
+                Ok(Some(evict)) => evict,
+                Ok(None) => return None,
+                Err(e) => {
+                    eprintln!("Failed to check the quota of {}: {:?}", event.pubkey, e);
+                    return None;
+                }
+            }
+        };
+        let stored = match store_event(
+            &self.db_pool,
+            &event,
+            flagged,
+            truncated,
+            &self.config.versions,
+            &evict,
+        )
+        .await
+        {
+            Ok(stored) => {
+                self.seen.insert(&event.id);
+                if stored && !evict.is_empty() {
+                    for event_id in &evict {
+                        self.cache.invalidate_event(event_id);
+                    }
+                    println!(
+                        "Evicted {} events of {} to stay within its quota",
+                        evict.len(),
+                        event.pubkey
+                    );
+                }
+                if stored {
+                    self.stats.record_ingested();
+                }
+                // The operator's direct messages stay in the archive, behind `/dms`.
+                if stored && !personal_dm {
+                    if let Some(mqtt) = &self.mqtt {
+                        mqtt.publish(&event);
+                    }
+                    if let Some(notifier) = &self.notifier {
+                        notifier.notify(&event);
+                    }
+                    if let Some(media) = &self.media {
+                        media.enqueue(&event);
+                    }
+                    if let Some(previews) = &self.previews {
+                        previews.enqueue(&event);
+                    }
+                }
+                stored
+            }
+            Err(e) => {
+                eprintln!("Failed to store event {}: {:?}", event.id, e);
+                return None;
+            }
+        };
+        if !stored {
+            return None;
+        }
+        if event.kind == 0 {
+            self.cache.invalidate_profile(&event.pubkey);
+        }
+        Some(event)
+    }
+
+    /// Handles a single text message received from an upstream relay, returning the event it
+    /// carried if it was newly archived
+    pub async fn handle_relay_message(
+        &self,
+        relay_url: &str,
+        text: &str,
+        registry: &SubscriptionRegistry,
+    ) -> Option<NostrEvent> {
+        let parts = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(parts)) => parts,
+            _ => {
+                eprintln!("Malformed message from {}: {}", relay_url, text);
+                self.health.record_error(relay_url);
+                return None;
+            }
+        };
+
+        match parts.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                if let Some(subscription_id) = parts.get(1).and_then(Value::as_str) {
+                    registry.record_event(relay_url, subscription_id);
+                }
+                let event: NostrEvent = match parts
+                    .get(2)
+                    .cloned()
+                    .map(serde_json::from_value)
+                    .transpose()
+                {
+                    Ok(Some(event)) => event,
+                    _ => {
+                        eprintln!("Malformed event from {}: {}", relay_url, text);
+                        self.health.record_malformed(relay_url);
+                        return None;
+                    }
+                };
+                if let Err(e) = event.check_structure() {
+                    eprintln!("Malformed event {} from {}: {}", event.id, relay_url, e);
+                    self.health.record_malformed(relay_url);
+                    return None;
+                }
+                if let (Some(backfill), Some(subscription_id)) =
+                    (&self.backfill, parts.get(1).and_then(Value::as_str))
+                {
+                    backfill.record_event(relay_url, subscription_id, event.created_at);
+                }
+                self.health
+                    .record_event(relay_url, self.seen.contains(&event.id));
+                return self.ingest_event(event).await;
+            }
+            Some("COUNT") => {
+                let count = parts
+                    .get(2)
+                    .and_then(|payload| payload.get("count"))
+                    .and_then(Value::as_u64)
+                    .unwrap_or_default();
+                println!(
+                    "Relay {} holds {} events for count request {}",
+                    relay_url,
+                    count,
+                    parts.get(1).unwrap_or(&Value::Null)
+                );
+            }
+            Some("EOSE") => {
+                if let Some(elapsed) = parts
+                    .get(1)
+                    .and_then(Value::as_str)
+                    .and_then(|subscription_id| registry.answered(relay_url, subscription_id))
+                {
+                    self.health.record_latency(relay_url, elapsed);
+                }
+                if let (Some(backfill), Some(subscription_id)) =
+                    (&self.backfill, parts.get(1).and_then(Value::as_str))
+                {
+                    if let Err(e) = backfill
+                        .end_of_page(relay_url, subscription_id, registry, &self.health)
+                        .await
+                    {
+                        eprintln!("Failed to store backfill progress: {:?}", e);
+                    }
+                }
+            }
+            Some("CLOSED") => {
+                if let Some(subscription_id) = parts.get(1).and_then(Value::as_str) {
+                    registry.record_closed(relay_url, subscription_id).await;
+                    self.health.record_error(relay_url);
+                    if let Some(backfill) = &self.backfill {
+                        backfill.closed(relay_url, subscription_id);
+                    }
+                }
+                println!("Message received from {}: {}", relay_url, text);
+            }
+            _ => println!("Message received from {}: {}", relay_url, text),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(id: char, created_at: u64) -> NostrEvent {
+        NostrEvent {
+            id: id.to_string().repeat(64),
+            pubkey: "f".repeat(64),
+            created_at,
+            kind: 1,
+            tags: Vec::new(),
+            content: "hello".to_string(),
+            sig: String::new(),
+        }
+    }
+
+    #[test]
+    fn operator_is_exempt_from_proof_of_work() {
+        let mut config = crate::test_config();
+        config.ingest.min_pow_difficulty = 8;
+        let wot = WebOfTrust::new(config.wot.clone());
+        let event = note('a', 1_700_000_000);
+        assert_eq!(admission(&event, &config, &wot), Verdict::Drop);
+
+        config.relay_info.pubkey = Some(event.pubkey.clone());
+        assert_eq!(admission(&event, &config, &wot), Verdict::Accept);
+        config.relay_info.pubkey = None;
+        config.auth.admin_pubkeys = vec![event.pubkey.clone()];
+        assert_eq!(admission(&event, &config, &wot), Verdict::Accept);
+        config.auth.admin_pubkeys.clear();
+        config.wot.root_pubkey = event.pubkey.clone();
+        assert_eq!(admission(&event, &config, &wot), Verdict::Accept);
+    }
+
+    async fn stored_ids(db_pool: &SqlitePool) -> Vec<String> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT event_id FROM events ORDER BY event_id")
+            .fetch_all(db_pool)
+            .await
+            .unwrap();
+        rows.into_iter().map(|(event_id,)| event_id).collect()
+    }
+
+    #[test]
+    fn evictions_cover_the_excess() {
+        let candidates = || {
+            vec![
+                ("a".to_string(), 10),
+                ("b".to_string(), 20),
+                ("c".to_string(), 30),
+            ]
+        };
+        assert_eq!(
+            select_evictions(candidates(), 1, 0),
+            Some(vec!["a".to_string()])
+        );
+        assert_eq!(
+            select_evictions(candidates(), 0, 25),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(select_evictions(candidates(), 4, 0), None);
+        assert_eq!(select_evictions(candidates(), 0, 61), None);
+    }
+
+    #[tokio::test]
+    async fn evicts_only_when_the_event_is_stored() {
+        let db_pool = db::test_pool().await;
+        let versions = VersionConfig::default();
+        let (old, stored, new) = (note('a', 1), note('b', 2), note('c', 3));
+        for event in [&old, &stored] {
+            assert!(store_event(&db_pool, event, false, false, &versions, &[])
+                .await
+                .unwrap());
+        }
+
+        // A second copy of a stored event is not written, so it must not evict anything.
+        let evict = [old.id.clone()];
+        assert!(
+            !store_event(&db_pool, &stored, false, false, &versions, &evict)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            stored_ids(&db_pool).await,
+            [old.id.clone(), stored.id.clone()]
+        );
+
+        assert!(store_event(&db_pool, &new, false, false, &versions, &evict)
+            .await
+            .unwrap());
+        assert_eq!(
+            stored_ids(&db_pool).await,
+            [stored.id.clone(), new.id.clone()]
+        );
+        let (events,): (i64,) = sqlx::query_as("SELECT events FROM pubkey_usage")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(events, 2);
+    }
+
+    #[tokio::test]
+    async fn replaceable_events_are_not_counted() {
+        let db_pool = db::test_pool().await;
+        let mut profile = note('d', 1);
+        profile.kind = 0;
+        store_event(
+            &db_pool,
+            &profile,
+            false,
+            false,
+            &VersionConfig::default(),
+            &[],
+        )
+        .await
+        .unwrap();
+        let usage: Option<(i64,)> = sqlx::query_as("SELECT events FROM pubkey_usage")
+            .fetch_optional(&db_pool)
+            .await
+            .unwrap();
+        assert!(usage.is_none());
+    }
+}
@@ -0,0 +1,428 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use cron::Schedule;
+use reqwest::multipart::{Form, Part};
+use reqwest::Body;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::ingest::Ingestor;
+use crate::signer::{self, UnsignedEvent};
+use crate::{cold, nostr, DbEvent, IpfsConfig, RelayWriters};
+
+/// NIP-78 application data event announcing the CID of the latest snapshot; addressable, so
+/// mirrors only ever see the newest one
+const SNAPSHOT_KIND: u64 = 30078;
+
+/// Files of a snapshot directory
+const EVENTS_FILE: &str = "events.jsonl";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Rows read from the database at a time
+const BATCH_SIZE: i64 = 1000;
+
+/// A batch of archived rows, in rowid order
+#[derive(Debug, sqlx::FromRow)]
+struct Row {
+    rowid: i64,
+    #[sqlx(flatten)]
+    event: DbEvent,
+}
+
+/// Outcome of the scheduled snapshots, reported by `GET /admin/snapshots`
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SnapshotStatus {
+    /// Unix time of the last snapshot attempt
+    last_attempt: Option<i64>,
+    /// Unix time of the last snapshot added to IPFS
+    last_success: Option<i64>,
+    /// CID of the directory holding the last snapshot
+    last_cid: Option<String>,
+    /// Number of events in the last snapshot
+    last_events: Option<u64>,
+    /// Error of the last attempt, if it failed
+    last_error: Option<String>,
+    /// Error of the last request to the remote pinning service, if it failed
+    last_pin_error: Option<String>,
+    /// Id of the event announcing the last snapshot
+    last_announcement: Option<String>,
+    /// Error of the last announcement, if it failed
+    last_announcement_error: Option<String>,
+    /// Unix time of the next scheduled snapshot
+    next_run: Option<i64>,
+}
+
+/// Description of a snapshot, written next to its events and used as the announcement content
+#[derive(Debug, Default, Serialize)]
+struct Manifest {
+    name: String,
+    created_at: i64,
+    events: u64,
+    /// Creation time of the oldest and newest events
+    since: Option<i64>,
+    until: Option<i64>,
+    /// Number of events of each kind
+    kinds: BTreeMap<i64, u64>,
+    /// Hex SHA-256 of the events file
+    sha256: String,
+    /// Size of the events file in bytes
+    size: u64,
+}
+
+impl Manifest {
+    fn add(&mut self, event: &DbEvent) {
+        self.events += 1;
+        self.since = Some(
+            self.since
+                .map_or(event.created_at, |t| t.min(event.created_at)),
+        );
+        self.until = Some(
+            self.until
+                .map_or(event.created_at, |t| t.max(event.created_at)),
+        );
+        *self.kinds.entry(event.kind).or_default() += 1;
+    }
+}
+
+/// Writes events as NIP-01 JSON lines while hashing them
+struct EventsWriter {
+    file: BufWriter<File>,
+    hasher: Sha256,
+    manifest: Manifest,
+}
+
+impl EventsWriter {
+    fn write(&mut self, event: &DbEvent) -> std::io::Result<()> {
+        // The operator's direct messages are private even when encrypted.
+        if event.folder == "dms" {
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(&event.to_nostr())?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.hasher.update(&line);
+        self.manifest.size += line.len() as u64;
+        self.manifest.add(event);
+        Ok(())
+    }
+}
+
+/// Entry of the newline-delimited response of the IPFS `add` API
+#[derive(Debug, Deserialize)]
+struct AddedEntry {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+/// Periodic snapshots of the archive added to IPFS, for others to mirror
+#[derive(Debug)]
+pub struct Snapshots {
+    config: IpfsConfig,
+    /// Directory of the cold storage segments, whose events are included
+    cold_directory: String,
+    status: Mutex<SnapshotStatus>,
+}
+
+impl Snapshots {
+    pub fn new(config: IpfsConfig, cold_directory: String) -> Self {
+        Self {
+            config,
+            cold_directory,
+            status: Mutex::new(SnapshotStatus::default()),
+        }
+    }
+
+    /// Writes every archived event, including those in cold storage, and the manifest to a
+    /// directory, returning the manifest
+    async fn write(&self, db_pool: &SqlitePool, directory: &Path) -> Result<Manifest, String> {
+        let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+        let events_path = directory.join(EVENTS_FILE);
+        let file = File::create(&events_path)
+            .map_err(|e| format!("Failed to create {}: {}", events_path.display(), e))?;
+        let mut writer = EventsWriter {
+            file: BufWriter::new(file),
+            hasher: Sha256::new(),
+            manifest: Manifest {
+                name: self.config.name.clone(),
+                created_at: nostr::now() as i64,
+                ..Manifest::default()
+            },
+        };
+        let write_error = |e: std::io::Error| format!("Failed to write {}: {}", EVENTS_FILE, e);
+
+        let mut last_rowid = 0i64;
+        loop {
+            let rows: Vec<Row> = sqlx::query_as(
+                "SELECT rowid, event_id, pubkey, created_at, kind, content, sig, tags, folder,
+                     ref_event
+                 FROM events WHERE rowid > ? AND invalid = 0 ORDER BY rowid LIMIT ?",
+            )
+            .bind(last_rowid)
+            .bind(BATCH_SIZE)
+            .fetch_all(db_pool)
+            .await
+            .map_err(db_error)?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            last_rowid = last.rowid;
+            for row in &rows {
+                writer.write(&row.event).map_err(write_error)?;
+            }
+        }
+
+        let segments: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, file FROM cold_segments ORDER BY id")
+                .fetch_all(db_pool)
+                .await
+                .map_err(db_error)?;
+        for (segment_id, file) in segments {
+            // Events deleted since the segment was written are no longer indexed.
+            let wanted: HashSet<String> =
+                sqlx::query_scalar("SELECT event_id FROM cold_events WHERE segment_id = ?")
+                    .bind(segment_id)
+                    .fetch_all(db_pool)
+                    .await
+                    .map_err(db_error)?
+                    .into_iter()
+                    .collect();
+            let path = Path::new(&self.cold_directory).join(&file);
+            let events = tokio::task::spawn_blocking({
+                let path = path.clone();
+                move || cold::read_segment(&path, &wanted)
+            })
+            .await
+            .map_err(|e| format!("Segment reader failed: {}", e))?
+            .map_err(|e| format!("Failed to read cold segment {}: {}", path.display(), e))?;
+            for event in &events {
+                writer.write(event).map_err(write_error)?;
+            }
+        }
+
+        let EventsWriter {
+            file,
+            hasher,
+            mut manifest,
+        } = writer;
+        file.into_inner()
+            .map_err(|e| write_error(e.into_error()))?
+            .sync_all()
+            .map_err(write_error)?;
+        manifest.sha256 = hex::encode(hasher.finalize());
+        let manifest_path = directory.join(MANIFEST_FILE);
+        let json = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+        fs::write(&manifest_path, json)
+            .map_err(|e| format!("Failed to write {}: {}", manifest_path.display(), e))?;
+        Ok(manifest)
+    }
+
+    /// Adds a snapshot directory to the local IPFS node and pins it there, returning the CID of
+    /// the directory
+    async fn add(&self, directory: &Path) -> Result<String, String> {
+        let mut form = Form::new();
+        for name in [EVENTS_FILE, MANIFEST_FILE] {
+            let path = directory.join(name);
+            let file = tokio::fs::File::open(&path)
+                .await
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+            let length = file
+                .metadata()
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+                .len();
+            let part = Part::stream_with_length(Body::from(file), length).file_name(name);
+            form = form.part("file", part);
+        }
+        let url = format!(
+            "{}/api/v0/add?pin=true&wrap-with-directory=true&cid-version=1",
+            self.config.api_url.trim_end_matches('/')
+        );
+        let response = reqwest::Client::new()
+            .post(url)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| format!("IPFS add request failed: {}", e))?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("IPFS add request failed: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("IPFS add rejected with {}: {}", status, body));
+        }
+        // One entry per added file, then the wrapping directory, which has an empty name.
+        body.lines()
+            .filter_map(|line| serde_json::from_str::<AddedEntry>(line).ok())
+            .find(|entry| entry.name.is_empty())
+            .map(|entry| entry.hash)
+            .ok_or_else(|| format!("IPFS add returned no directory CID: {}", body))
+    }
+
+    /// Asks the remote pinning service to pin a CID, following the IPFS Pinning Service API
+    async fn pin_remote(&self, cid: &str, manifest: &Manifest) -> Result<(), String> {
+        let pinning = &self.config.pinning;
+        let response = reqwest::Client::new()
+            .post(format!("{}/pins", pinning.endpoint.trim_end_matches('/')))
+            .bearer_auth(&pinning.access_token)
+            .json(&serde_json::json!({
+                "cid": cid,
+                "name": format!("{}-{}", manifest.name, manifest.created_at),
+            }))
+            .send()
+            .await
+            .map_err(|e| format!("Pin request failed: {}", e))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Pin request rejected with {}: {}", status, body));
+        }
+        Ok(())
+    }
+
+    /// Signs an event announcing the snapshot, archives it and publishes it to the upstream
+    /// relays, returning its id
+    async fn announce(
+        &self,
+        cid: &str,
+        manifest: &Manifest,
+        ingestor: &Ingestor,
+        relays: &RelayWriters,
+    ) -> Result<String, String> {
+        let signer = ingestor
+            .signer
+            .as_ref()
+            .ok_or("No remote signer is configured")?;
+        let event = UnsignedEvent {
+            kind: SNAPSHOT_KIND,
+            content: serde_json::to_string(manifest).map_err(|e| e.to_string())?,
+            tags: vec![
+                vec!["d".to_string(), format!("{}/snapshot", manifest.name)],
+                vec!["r".to_string(), format!("ipfs://{}", cid)],
+                vec![
+                    "alt".to_string(),
+                    "Nostr archive snapshot on IPFS".to_string(),
+                ],
+            ],
+            created_at: None,
+        };
+        let event = signer.sign(event).await?;
+        signer::broadcast(relays, &event).await;
+        ingestor.ingest_event(event.clone()).await;
+        Ok(event.id)
+    }
+
+    /// Writes, adds, pins and announces a snapshot, recording the outcome
+    async fn run(&self, db_pool: &SqlitePool, ingestor: &Ingestor, relays: &RelayWriters) {
+        let mut status = self.status.lock().unwrap().clone();
+        status.last_attempt = Some(nostr::now() as i64);
+        let directory = Path::new(&self.config.directory)
+            .join(format!("snapshot-{}", Utc::now().format("%Y%m%dT%H%M%SZ")));
+        let result = match self.write(db_pool, &directory).await {
+            Ok(manifest) => self.add(&directory).await.map(|cid| (cid, manifest)),
+            Err(e) => Err(e),
+        };
+        // The node holds its own copy once the snapshot is added, and a partial snapshot is
+        // never added.
+        if let Err(e) = fs::remove_dir_all(&directory) {
+            eprintln!("Failed to remove {}: {}", directory.display(), e);
+        }
+        match result {
+            Ok((cid, manifest)) => {
+                println!(
+                    "Snapshot of {} events added to IPFS as {}",
+                    manifest.events, cid
+                );
+                status.last_success = status.last_attempt;
+                status.last_cid = Some(cid.clone());
+                status.last_events = Some(manifest.events);
+                status.last_error = None;
+                if self.config.pinning.enabled {
+                    match self.pin_remote(&cid, &manifest).await {
+                        Ok(()) => status.last_pin_error = None,
+                        Err(e) => {
+                            eprintln!("Failed to pin snapshot {}: {}", cid, e);
+                            status.last_pin_error = Some(e);
+                        }
+                    }
+                }
+                if self.config.announce {
+                    match self.announce(&cid, &manifest, ingestor, relays).await {
+                        Ok(id) => {
+                            status.last_announcement = Some(id);
+                            status.last_announcement_error = None;
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to announce snapshot {}: {}", cid, e);
+                            status.last_announcement_error = Some(e);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Snapshot failed: {}", e);
+                status.last_error = Some(e);
+            }
+        }
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// Takes a snapshot at every time matching the configured cron schedule (UTC)
+    pub fn spawn(
+        self: Arc<Self>,
+        db_pool: SqlitePool,
+        ingestor: Arc<Ingestor>,
+        relays: Arc<RelayWriters>,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+        let schedule = match Schedule::from_str(&self.config.schedule) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                eprintln!(
+                    "IPFS snapshots disabled: invalid schedule {:?}: {}",
+                    self.config.schedule, e
+                );
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while let Some(next) = schedule.upcoming(Utc).next() {
+                self.status.lock().unwrap().next_run = Some(next.timestamp());
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                self.run(&db_pool, &ingestor, &relays).await;
+            }
+        });
+    }
+}
+
+/// Reports the state of the scheduled IPFS snapshots.
+pub async fn snapshot_status(
+    req: HttpRequest,
+    snapshots: web::Data<Snapshots>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_snapshots", "").await?;
+    let status = snapshots.status.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": snapshots.config.enabled,
+        "schedule": snapshots.config.schedule,
+        "status": status,
+    })))
+}
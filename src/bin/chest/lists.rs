@@ -0,0 +1,112 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::{etag, format_events, DbEvent, OutputFormat};
+
+/// Query parameters for list endpoints
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Restrict results to a single list kind
+    kind: Option<i64>,
+}
+
+/// Fetches the stored lists of a pubkey, optionally narrowed to a kind and `d` identifier
+async fn fetch_lists(
+    pubkey: &str,
+    d_tag: Option<&str>,
+    kind: Option<i64>,
+    db_pool: &SqlitePool,
+) -> Result<Vec<DbEvent>, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = 'lists' AND pubkey = ",
+    );
+    query.push_bind(pubkey.to_string());
+    if let Some(kind) = kind {
+        query.push(" AND kind = ").push_bind(kind);
+    }
+    if let Some(d_tag) = d_tag {
+        query.push(" AND d_tag = ").push_bind(d_tag.to_string());
+    }
+    query.push(" ORDER BY kind");
+    query.build_query_as::<DbEvent>().fetch_all(db_pool).await
+}
+
+/// Lists all archived NIP-51 lists and sets of a pubkey.
+pub async fn list_lists(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<ListQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let pubkey = path.into_inner();
+    let events = fetch_lists(&pubkey, None, params.kind, db_pool.get_ref()).await?;
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &format_events(&events, params.format),
+    ))
+}
+
+/// Returns the archived NIP-51 sets of a pubkey with the given `d` identifier.
+pub async fn get_list(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<ListQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (pubkey, d_tag) = path.into_inner();
+    let events = fetch_lists(&pubkey, Some(&d_tag), params.kind, db_pool.get_ref()).await?;
+    if events.is_empty() {
+        return Err(ApiError::NotFound("List not found".to_string()));
+    }
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &format_events(&events, params.format),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn lists_are_narrowed_by_kind_and_identifier() {
+        let db_pool = db::test_pool().await;
+        let follows = db::test_event('a', 3);
+        let mutes = db::test_event('b', 10000);
+        let mut friends = db::test_event('c', 30000);
+        friends.tags = vec![vec!["d".to_string(), "friends".to_string()]];
+        let mut family = db::test_event('d', 30000);
+        family.tags = vec![vec!["d".to_string(), "family".to_string()]];
+        db::store_test_events(&db_pool, &[&follows, &mutes, &friends, &family]).await;
+        let ids = |events: Vec<DbEvent>| -> Vec<String> {
+            events.into_iter().map(|event| event.event_id).collect()
+        };
+
+        let all = fetch_lists(&follows.pubkey, None, None, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 4);
+        let sets = fetch_lists(&follows.pubkey, None, Some(30000), &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(sets.len(), 2);
+        let named = fetch_lists(&follows.pubkey, Some("friends"), None, &db_pool)
+            .await
+            .unwrap();
+        assert_eq!(ids(named), [friends.id.clone()]);
+        let other = fetch_lists(&"1".repeat(64), None, None, &db_pool)
+            .await
+            .unwrap();
+        assert!(other.is_empty());
+    }
+}
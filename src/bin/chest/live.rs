@@ -0,0 +1,76 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{QueryBuilder, SqlitePool};
+
+use crate::error::ApiError;
+use crate::ingest::LIVE_ACTIVITY_KIND;
+use crate::nostr::{self, NostrEvent};
+use crate::{stream, OutputFormat};
+
+/// SQL condition matching the archived live activities whose `status` tag is `ended`
+pub const ENDED_CONDITION: &str = "(kind = 30311 AND EXISTS (
+    SELECT 1 FROM json_each(events.tags) AS tag
+    WHERE json_extract(tag.value, '$[0]') = 'status'
+      AND json_extract(tag.value, '$[1]') = 'ended'
+))";
+
+/// Returns whether the event is a live activity that has ended
+pub fn has_ended(event: &NostrEvent) -> bool {
+    event.kind == LIVE_ACTIVITY_KIND && event.tag_value("status") == Some("ended")
+}
+
+/// Query parameters of `GET /live/{naddr}/chat`
+#[derive(Debug, Deserialize)]
+pub struct LiveChatQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Only messages sent at or after this Unix time
+    since: Option<u64>,
+    /// Only messages sent at or before this Unix time
+    until: Option<u64>,
+    limit: Option<i64>,
+}
+
+/// Replays the archived kind 1311 chat of a live activity in the order it was sent
+pub async fn chat(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<LiveChatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let coordinate = nostr::parse_naddr(&input)
+        .or_else(|| nostr::parse_coordinate(&input))
+        .filter(|address| address.kind == LIVE_ACTIVITY_KIND)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid live activity address: {}", input)))?
+        .coordinate();
+    let LiveChatQuery {
+        format,
+        since,
+        until,
+        limit,
+    } = params.into_inner();
+
+    stream::event_listing(&req, db_pool.get_ref(), format, move |columns| {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM public_events AS events WHERE folder = 'live_chat' AND ref_address = ",
+            columns
+        ));
+        query.push_bind(coordinate.clone());
+        if let Some(since) = since {
+            query.push(" AND created_at >= ");
+            query.push_bind(since as i64);
+        }
+        if let Some(until) = until {
+            query.push(" AND created_at <= ");
+            query.push_bind(until as i64);
+        }
+        query.push(" ORDER BY created_at ASC, event_id ASC");
+        if let Some(limit) = limit {
+            query.push(" LIMIT ");
+            query.push_bind(limit.max(0));
+        }
+        query
+    })
+    .await
+}
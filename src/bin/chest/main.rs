@@ -0,0 +1,2988 @@
+use actix_web::{
+    middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError, Scope,
+};
+use config::ConfigError;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream};
+use url::Url;
+use uuid::Uuid;
+
+mod access_log;
+mod audit;
+mod auth;
+mod backfill;
+mod backup;
+mod badges;
+mod blossom;
+mod cache;
+mod calendar;
+mod channels;
+mod cold;
+mod communities;
+mod crypto;
+mod db;
+mod dedup;
+mod deflate;
+mod discovery;
+mod dms;
+mod doctor;
+mod dryrun;
+mod duplicates;
+mod encoding;
+mod engagement;
+mod error;
+mod etag;
+mod exists;
+mod export;
+mod feeds;
+mod files;
+mod filter;
+mod geo;
+mod graph;
+mod health;
+mod highlights;
+mod home;
+mod import;
+mod ingest;
+mod ipfs;
+mod lists;
+mod live;
+mod maintenance;
+mod markdown;
+mod media;
+mod moderation;
+mod mqtt;
+mod nip11;
+mod nostr;
+mod notify;
+mod orphans;
+mod ots;
+mod outbound;
+mod previews;
+mod ratelimit;
+mod redact;
+mod relay;
+mod reports;
+mod resolver;
+mod retention;
+mod reverify;
+mod s3;
+mod signer;
+mod stats;
+mod stream;
+mod subscriptions;
+mod threads;
+mod timestamps;
+mod tls;
+mod topics;
+mod typed;
+mod users;
+mod verify;
+mod versions;
+mod wot;
+
+use backfill::Backfill;
+use backup::Backups;
+use cache::EventCache;
+use db::WritePool;
+use dedup::SeenFilter;
+use dms::DmBackup;
+use dryrun::DryRun;
+use error::ApiError;
+use health::RelayHealth;
+use ingest::Ingestor;
+use ipfs::Snapshots;
+use maintenance::Maintenance;
+use media::MediaCache;
+use mqtt::MqttBridge;
+use nostr::NostrEvent;
+use notify::Notifier;
+use previews::LinkPreviews;
+use ratelimit::RateLimiter;
+use signer::Signer;
+use stats::Stats;
+use subscriptions::SubscriptionRegistry;
+use topics::TopicFilter;
+use wot::WebOfTrust;
+
+/// Configuration loaded from `config.toml`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AppConfig {
+    server: ServerConfig,
+    relays: RelayConfig,
+    event: EventConfig,
+    database: DatabaseConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    #[serde(default)]
+    api: ApiConfig,
+    #[serde(default)]
+    relay_info: RelayInfoConfig,
+    #[serde(default)]
+    wot: WotConfig,
+    #[serde(default)]
+    ingest: IngestConfig,
+    #[serde(default)]
+    topics: TopicConfig,
+    #[serde(default)]
+    subscriptions: SubscriptionConfig,
+    #[serde(default)]
+    orphans: OrphanConfig,
+    #[serde(default)]
+    fetch: FetchConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    backup: BackupConfig,
+    #[serde(default)]
+    maintenance: MaintenanceConfig,
+    #[serde(default)]
+    stats: StatsConfig,
+    #[serde(default)]
+    mqtt: MqttConfig,
+    #[serde(default)]
+    notify: NotifyConfig,
+    #[serde(default)]
+    media: MediaConfig,
+    #[serde(default)]
+    previews: PreviewConfig,
+    #[serde(default)]
+    dms: DmConfig,
+    #[serde(default)]
+    signer: SignerConfig,
+    #[serde(default)]
+    versions: VersionConfig,
+    #[serde(default)]
+    reverify: ReverifyConfig,
+    #[serde(default)]
+    cold: ColdConfig,
+    #[serde(default)]
+    ipfs: IpfsConfig,
+    #[serde(default)]
+    timestamps: TimestampConfig,
+    #[serde(default)]
+    health: HealthConfig,
+    #[serde(default)]
+    discovery: DiscoveryConfig,
+    #[serde(default)]
+    duplicates: DuplicatesConfig,
+    /// Subscriptions opened for the interactions with newly archived events
+    #[serde(default = "default_dynamic_rules")]
+    dynamic: Vec<DynamicRule>,
+    /// Additional archives served by the same process
+    #[serde(default)]
+    archives: Vec<ArchiveConfig>,
+}
+
+/// Independent archive with its own relays, filters and database, served under `/{name}/`.
+/// Every other setting is inherited from the main configuration.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ArchiveConfig {
+    /// Path segment of the archive's routes; takes precedence over the main archive's routes
+    name: String,
+    relays: RelayConfig,
+    event: EventConfig,
+    database: DatabaseConfig,
+    #[serde(default)]
+    ingest: IngestConfig,
+    #[serde(default)]
+    wot: WotConfig,
+    #[serde(default)]
+    topics: TopicConfig,
+    #[serde(default = "default_dynamic_rules")]
+    dynamic: Vec<DynamicRule>,
+}
+
+impl ArchiveConfig {
+    /// Returns the configuration of the archive: the main configuration with the archive's
+    /// settings applied and its files and MQTT topics kept apart from the main archive's
+    fn apply(&self, main: &AppConfig) -> AppConfig {
+        let mut config = main.clone();
+        config.relays = self.relays.clone();
+        config.event = self.event.clone();
+        config.database = self.database.clone();
+        config.ingest = self.ingest.clone();
+        config.wot = self.wot.clone();
+        config.topics = self.topics.clone();
+        config.dynamic = self.dynamic.clone();
+        config.archives = Vec::new();
+        config.backup.directory = format!("{}/{}", main.backup.directory, self.name);
+        config.backup.s3.prefix = format!("{}{}/", main.backup.s3.prefix, self.name);
+        config.media.directory = format!("{}/{}", main.media.directory, self.name);
+        config.cold.directory = format!("{}/{}", main.cold.directory, self.name);
+        config.ipfs.directory = format!("{}/{}", main.ipfs.directory, self.name);
+        config.ipfs.name = format!("{}/{}", main.ipfs.name, self.name);
+        config.mqtt.client_id = format!("{}-{}", main.mqtt.client_id, self.name);
+        config.mqtt.topic_prefix = format!("{}/{}", main.mqtt.topic_prefix, self.name);
+        // The operator's direct messages are kept in the main archive only.
+        config.dms.enabled = false;
+        config
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ServerConfig {
+    /// Address to listen on, or a list of them; `unix:/path/chest.sock` listens on a Unix
+    /// domain socket
+    bind_address: BindAddress,
+    /// Certificate and key to serve HTTPS with on the TCP addresses
+    #[serde(default)]
+    tls: ServerTlsConfig,
+    /// Path prefix of every route, e.g. `/chest` behind a reverse proxy sharing the host
+    #[serde(default)]
+    base_path: String,
+}
+
+impl ServerConfig {
+    /// Returns the route prefix with a leading slash and no trailing one, empty for the root
+    fn base_path(&self) -> String {
+        match self.base_path.trim_matches('/') {
+            "" => String::new(),
+            path => format!("/{}", path),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct ServerTlsConfig {
+    /// PEM file of the certificate chain, leaf first; HTTPS is off when empty
+    cert: String,
+    /// PEM file of the private key
+    key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum BindAddress {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl BindAddress {
+    fn addresses(&self) -> &[String] {
+        match self {
+            Self::One(address) => std::slice::from_ref(address),
+            Self::Many(addresses) => addresses,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RelayConfig {
+    urls: Vec<String>,
+    /// Whether to offer permessage-deflate, so relays compress the events they send
+    #[serde(default = "default_compression")]
+    compression: bool,
+    /// TLS settings of `wss` relays
+    #[serde(default)]
+    tls: TlsConfig,
+    /// TLS settings replacing `tls` for single relays, keyed by relay URL
+    #[serde(default)]
+    relay_tls: HashMap<String, TlsConfig>,
+}
+
+fn default_compression() -> bool {
+    true
+}
+
+impl RelayConfig {
+    /// Returns the TLS settings of a relay
+    fn tls_for(&self, relay_url: &str) -> &TlsConfig {
+        self.relay_tls.get(relay_url).unwrap_or(&self.tls)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct TlsConfig {
+    /// PEM file of CA certificates to trust besides the system ones, e.g. of a private PKI
+    ca_file: String,
+    /// Whether to trust the system's CA certificates
+    system_roots: bool,
+    /// SHA-256 fingerprints of the certificates accepted; when set, a relay presenting another
+    /// certificate is refused even if it chains to a trusted CA
+    pins: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_file: String::new(),
+            system_roots: true,
+            pins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct EventConfig {
+    kinds: Vec<u64>,
+    /// Ephemeral kinds (20000-29999) forwarded to the MQTT and notification sinks as they
+    /// arrive; ephemeral events are never archived
+    #[serde(default)]
+    ephemeral_kinds: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct DatabaseConfig {
+    /// Path to the SQLite database file (default: chest/events.db)
+    path: String,
+    /// SQLite `journal_mode` pragma: delete, truncate, persist, memory, wal or off
+    journal_mode: String,
+    /// SQLite `synchronous` pragma: off, normal, full or extra
+    synchronous: String,
+    /// Seconds a connection waits for a lock held by another one before failing
+    busy_timeout: u64,
+    /// SQLite `cache_size` pragma: pages per connection, or KiB when negative
+    cache_size: i64,
+    /// SQLite `mmap_size` pragma: bytes of the database file memory-mapped (0 disables)
+    mmap_size: u64,
+    /// SQLite `auto_vacuum` pragma: none, full or incremental; only takes effect on new
+    /// databases or after a full VACUUM
+    auto_vacuum: String,
+    /// Connections of the pool used for archiving and moderation; SQLite allows a single
+    /// writer at a time
+    write_connections: u32,
+    /// Connections of the read-only pool serving the HTTP API and relay subscriptions
+    read_connections: u32,
+    /// File holding the passphrase the database is encrypted with (SQLCipher); the
+    /// `CHEST_DATABASE_KEY` environment variable takes precedence. Without either the database
+    /// is stored unencrypted. Backups are encrypted with the same key
+    key_file: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: "events.db".to_string(),
+            journal_mode: "wal".to_string(),
+            synchronous: "normal".to_string(),
+            busy_timeout: 5,
+            cache_size: -64_000,
+            mmap_size: 268_435_456,
+            auto_vacuum: "incremental".to_string(),
+            write_connections: 1,
+            read_connections: 8,
+            key_file: String::new(),
+        }
+    }
+}
+
+/// Per-IP request rate limiting for the HTTP API
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct RateLimitConfig {
+    enabled: bool,
+    /// Tokens added to each client's bucket per second
+    requests_per_second: f64,
+    /// Maximum bucket size, i.e. the largest allowed burst of requests
+    burst: u32,
+    /// Addresses of the reverse proxies chest runs behind, or `unix` for proxies connecting over
+    /// a Unix socket. Requests from them are limited by the right-most `X-Forwarded-For` address
+    /// they did not add themselves.
+    trusted_proxies: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_second: 10.0,
+            burst: 20,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// Authentication for admin and write endpoints
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct AuthConfig {
+    /// Static keys accepted as `Authorization: Bearer <key>`
+    api_keys: Vec<String>,
+    /// Hex pubkeys allowed to authenticate with NIP-98 HTTP auth events
+    admin_pubkeys: Vec<String>,
+    /// Require authentication on read endpoints as well
+    protect_reads: bool,
+    /// Maximum age in seconds of a NIP-98 auth event
+    nip98_max_age: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            api_keys: Vec::new(),
+            admin_pubkeys: Vec::new(),
+            protect_reads: false,
+            nip98_max_age: 60,
+        }
+    }
+}
+
+/// Limits for the HTTP API
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct ApiConfig {
+    /// Maximum number of ids accepted by `POST /events/batch`
+    max_batch_size: usize,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 100,
+        }
+    }
+}
+
+/// What to do with events failing an ingestion filter
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FilterAction {
+    /// Do not archive the event
+    Drop,
+    /// Archive the event with its `flagged` column set
+    Flag,
+}
+
+/// Web of Trust spam filtering based on archived kind 3 follow lists
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct WotConfig {
+    enabled: bool,
+    /// Pubkey (hex or npub) the follow distance is measured from
+    root_pubkey: String,
+    /// Maximum follow distance from the root whose events are trusted
+    max_hops: u32,
+    action: FilterAction,
+    /// Seconds between recomputations of the trusted set
+    refresh_interval: u64,
+}
+
+impl Default for WotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            root_pubkey: String::new(),
+            max_hops: 2,
+            action: FilterAction::Drop,
+            refresh_interval: 300,
+        }
+    }
+}
+
+/// What to do with events exceeding the ingestion size limits
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum OversizeAction {
+    /// Do not archive the event
+    Drop,
+    /// Archive the event cut down to the limits with its `flagged` and `truncated` columns set;
+    /// its id and signature no longer verify, so it is neither served over the NIP-01 relay
+    /// endpoint nor marked invalid by the re-verification job
+    Truncate,
+}
+
+/// What to do with new events of a pubkey that reached its storage quota
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum QuotaAction {
+    /// Do not archive the new event
+    Reject,
+    /// Delete the pubkey's oldest events to make room for the new one
+    Evict,
+}
+
+/// Validation applied to events received from upstream relays
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct IngestConfig {
+    /// Minimum NIP-13 proof-of-work difficulty required from pubkeys outside the web of trust,
+    /// except the relay information pubkey, the web of trust root and the admins (0 disables
+    /// the check)
+    min_pow_difficulty: u32,
+    /// Seconds an event's `created_at` may lie in the future
+    max_future_skew: u64,
+    /// Earliest accepted `created_at`; nothing older can be a genuine nostr event
+    min_created_at: u64,
+    /// What to do with events whose timestamp is out of range
+    invalid_timestamp_action: FilterAction,
+    /// Maximum length of `content` in bytes
+    max_content_length: usize,
+    /// Maximum number of tags
+    max_tags: usize,
+    /// Maximum length in bytes of a single tag value
+    max_tag_value_length: usize,
+    oversize_action: OversizeAction,
+    /// Number of event ids the duplicate filter is sized for before its false positive rate
+    /// degrades; grows to twice the archive size at startup (0 disables the filter)
+    dedup_capacity: usize,
+    /// Share of new events wrongly taken for duplicates and dropped
+    dedup_false_positive_rate: f64,
+    /// Subscribe and validate as usual, but only log and count the events that would be
+    /// archived instead of writing them
+    dry_run: bool,
+    /// Most events archived per pubkey, replaceable events aside (0 for no limit)
+    max_events_per_pubkey: u64,
+    /// Most bytes of content and tags archived per pubkey, replaceable events aside (0 for no
+    /// limit)
+    max_bytes_per_pubkey: u64,
+    quota_action: QuotaAction,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            min_pow_difficulty: 0,
+            max_future_skew: 900,
+            // 2020-09-13, shortly before the first nostr events were published
+            min_created_at: 1_600_000_000,
+            invalid_timestamp_action: FilterAction::Drop,
+            max_content_length: 262_144,
+            // Follow lists of active users routinely hold thousands of `p` tags.
+            max_tags: 10_000,
+            max_tag_value_length: 8192,
+            oversize_action: OversizeAction::Drop,
+            dedup_capacity: 1_000_000,
+            dedup_false_positive_rate: 0.000_001,
+            dry_run: false,
+            max_events_per_pubkey: 0,
+            max_bytes_per_pubkey: 0,
+            quota_action: QuotaAction::Reject,
+        }
+    }
+}
+
+/// Topics archived notes must mention, since relays cannot filter by content. No topic
+/// disables the filter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct TopicConfig {
+    /// Words matched case-insensitively anywhere in the content
+    keywords: Vec<String>,
+    /// Hashtags, with or without `#`, matched against `t` tags and the content
+    hashtags: Vec<String>,
+    /// Regular expressions matched case-insensitively against the content
+    patterns: Vec<String>,
+    /// Folders the filter applies to; events of other folders are archived as usual
+    folders: Vec<String>,
+    /// What to do with events matching no topic
+    action: FilterAction,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            keywords: Vec::new(),
+            hashtags: Vec::new(),
+            patterns: Vec::new(),
+            folders: vec!["notes".to_string()],
+            action: FilterAction::Drop,
+        }
+    }
+}
+
+/// Engagement subscriptions for archived notes and articles
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct SubscriptionConfig {
+    /// Age in seconds of the notes and articles whose subscriptions are restored at startup
+    /// (0 disables restoring)
+    restore_window: u64,
+    /// Maximum number of event ids per restored subscription
+    batch_size: usize,
+    /// `limit` of the REQ for the archived kinds: the number of events per backfill page, or
+    /// the backlog asked of each relay when backfill is off (0 for the relay's default)
+    limit: usize,
+    /// Walk each relay's history backwards in pages of `limit` events, resuming after restarts,
+    /// instead of subscribing to whatever backlog the relay chooses to send
+    backfill: bool,
+    /// Unix time the backfill stops at (0 for the start of each relay's history)
+    backfill_since: u64,
+    /// Subscriptions kept open on a relay whose NIP-11 document advertises no
+    /// `max_subscriptions` (0 for no limit)
+    max_subscriptions: usize,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            restore_window: 7 * 24 * 60 * 60,
+            batch_size: 250,
+            limit: 500,
+            backfill: false,
+            backfill_since: 0,
+            max_subscriptions: 0,
+        }
+    }
+}
+
+/// Background lookup of events referenced by archived events but missing from the archive
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct OrphanConfig {
+    enabled: bool,
+    /// Seconds between reconciliation runs
+    interval: u64,
+    /// Maximum number of missing events requested per run
+    batch_size: usize,
+    /// Lookups after which a missing event is reported as unresolved and no longer requested
+    max_attempts: u32,
+}
+
+impl Default for OrphanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 600,
+            batch_size: 100,
+            max_attempts: 3,
+        }
+    }
+}
+
+/// On-demand lookup of events requested over HTTP but missing from the archive
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct FetchConfig {
+    enabled: bool,
+    /// Seconds to wait for the relays to answer
+    timeout: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: 3,
+        }
+    }
+}
+
+/// Capacities of the in-memory caches for single-event lookups (0 disables a cache)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct CacheConfig {
+    /// Maximum number of events cached by id
+    events: usize,
+    /// Maximum number of profiles cached by pubkey
+    profiles: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            events: 10_000,
+            profiles: 10_000,
+        }
+    }
+}
+
+/// Scheduled snapshots of the database
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct BackupConfig {
+    enabled: bool,
+    /// Cron expression (with seconds, in UTC) of the backup times
+    schedule: String,
+    /// Directory the snapshots are written to
+    directory: String,
+    /// Number of snapshots kept; older ones are deleted
+    keep: usize,
+    /// Offsite copy of every snapshot
+    s3: S3Config,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedule: "0 0 3 * * *".to_string(),
+            directory: "backups".to_string(),
+            keep: 7,
+            s3: S3Config::default(),
+        }
+    }
+}
+
+/// S3-compatible object storage the backup snapshots are uploaded to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct S3Config {
+    enabled: bool,
+    /// Base URL of the storage service, e.g. `https://s3.eu-central-1.amazonaws.com`
+    endpoint: String,
+    region: String,
+    bucket: String,
+    access_key: String,
+    /// Redacted from `GET /config`
+    secret_key: String,
+    /// Prepended to the snapshot file names to form the object keys
+    prefix: String,
+    /// Address the bucket in the URL path instead of the host name, as most self-hosted
+    /// services expect
+    path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: String::new(),
+            region: "us-east-1".to_string(),
+            bucket: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: "chest/".to_string(),
+            path_style: true,
+        }
+    }
+}
+
+/// Periodic database maintenance restricted to quiet hours
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct MaintenanceConfig {
+    enabled: bool,
+    /// Seconds between maintenance runs while within the quiet hours
+    interval: u64,
+    /// UTC hour the quiet hours start at
+    quiet_start_hour: u32,
+    /// UTC hour the quiet hours end at (exclusive); may be lower than the start to wrap
+    /// around midnight
+    quiet_end_hour: u32,
+    /// Maximum number of free pages reclaimed per run by incremental vacuum (0 for all)
+    vacuum_pages: u32,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 3600,
+            quiet_start_hour: 3,
+            quiet_end_hour: 5,
+            vacuum_pages: 0,
+        }
+    }
+}
+
+/// Archive statistics served by `GET /stats`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct StatsConfig {
+    /// Seconds between recomputations of the archive aggregates
+    refresh_interval: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: 300,
+        }
+    }
+}
+
+/// MQTT bridge publishing newly archived events to a broker
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct MqttConfig {
+    enabled: bool,
+    host: String,
+    port: u16,
+    client_id: String,
+    /// Leave empty to connect without authentication
+    username: String,
+    /// Redacted from `GET /config`
+    password: String,
+    /// Events are published to `{topic_prefix}/{kind}/{pubkey}`
+    topic_prefix: String,
+    /// MQTT quality of service level: 0, 1 or 2
+    qos: u8,
+    /// Kinds published (empty for all archived kinds)
+    kinds: Vec<u64>,
+    /// Hex pubkeys whose events are published (empty for all)
+    pubkeys: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "chest".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "chest".to_string(),
+            qos: 0,
+            kinds: Vec::new(),
+            pubkeys: Vec::new(),
+        }
+    }
+}
+
+/// Telegram and Discord notifications about newly archived events
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct NotifyConfig {
+    enabled: bool,
+    /// Kinds notified about (empty for all archived kinds)
+    kinds: Vec<u64>,
+    /// Hex pubkeys whose events, or events tagging them, are notified about (empty for all),
+    /// e.g. your own pubkey to hear about replies and zaps
+    pubkeys: Vec<String>,
+    /// Token of the Telegram bot sending the messages; redacted from `GET /config`
+    telegram_bot_token: String,
+    telegram_chat_id: String,
+    /// Discord webhook URL; redacted from `GET /config`
+    discord_webhook_url: String,
+    /// Seconds notifications are collected before being sent together
+    batch_interval: u64,
+    /// Maximum number of messages sent to each destination per batch
+    max_messages_per_batch: usize,
+    /// Maximum number of notifications waiting for the next batch; older ones are dropped
+    max_pending: usize,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            kinds: Vec::new(),
+            pubkeys: Vec::new(),
+            telegram_bot_token: String::new(),
+            telegram_chat_id: String::new(),
+            discord_webhook_url: String::new(),
+            batch_interval: 60,
+            max_messages_per_batch: 5,
+            max_pending: 1000,
+        }
+    }
+}
+
+/// Local copies of the images and videos referenced by archived events
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct MediaConfig {
+    enabled: bool,
+    /// Directory the files are stored in, named by their SHA-256 hash
+    directory: String,
+    /// Files larger than this many bytes are not downloaded
+    max_file_size: u64,
+    /// Downloads stop once the cached files take this many bytes (0 for no limit)
+    max_total_size: u64,
+    /// Mime types downloaded, exactly or by a `type/` prefix such as `image/`
+    mime_types: Vec<String>,
+    /// Maximum number of URLs waiting to be downloaded; further ones are skipped
+    max_pending: usize,
+    /// Seconds before a download is abandoned
+    timeout: u64,
+    /// Fetch files whose URL fails by their hash from the Blossom servers their author lists
+    /// in a kind 10063 event
+    blossom: bool,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "media".to_string(),
+            max_file_size: 20 * 1024 * 1024,
+            max_total_size: 0,
+            mime_types: vec!["image/".to_string(), "video/".to_string()],
+            max_pending: 1000,
+            timeout: 60,
+            blossom: true,
+        }
+    }
+}
+
+/// OpenGraph metadata of the web pages linked from archived notes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct PreviewConfig {
+    enabled: bool,
+    /// Maximum number of pages fetched per minute
+    requests_per_minute: u32,
+    /// Seconds before a page fetch is abandoned
+    timeout: u64,
+    /// Bytes of a page read looking for its metadata
+    max_body_size: usize,
+    /// Maximum number of links waiting to be fetched; further ones are skipped
+    max_pending: usize,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            requests_per_minute: 30,
+            timeout: 10,
+            max_body_size: 512 * 1024,
+            max_pending: 1000,
+        }
+    }
+}
+
+/// Personal backup of the direct messages sent to and by the operator's key
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct DmConfig {
+    enabled: bool,
+    /// Hex or `nsec` private key the messages are decrypted with; redacted from `GET /config`
+    private_key: String,
+}
+
+/// History of replaceable events: profiles, lists and other events replaced by newer versions
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct VersionConfig {
+    /// Keep superseded versions in the `event_versions` table instead of deleting them
+    enabled: bool,
+    /// Most superseded versions kept per event, oldest dropped first (0 for all)
+    max_versions: usize,
+}
+
+/// Background job checking the ids and signatures of archived events, marking the rows that do
+/// not verify
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct ReverifyConfig {
+    enabled: bool,
+    /// Events checked per batch
+    batch_size: i64,
+    /// Milliseconds to pause between batches, leaving the database to ingestion and requests
+    batch_pause: u64,
+    /// Seconds to wait for newly archived events once every event was checked
+    idle_interval: u64,
+}
+
+impl Default for ReverifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            batch_size: 500,
+            batch_pause: 1000,
+            idle_interval: 600,
+        }
+    }
+}
+
+/// Cold storage tier: old events moved out of SQLite into compressed segment files, still
+/// readable through the slower `/cold` routes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct ColdConfig {
+    enabled: bool,
+    /// Directory the segment files are written to
+    directory: String,
+    /// Events created more than this many days ago are moved
+    after_days: u64,
+    /// Seconds between two runs moving the events past the age
+    interval: u64,
+    /// Most events written to one segment file
+    segment_size: usize,
+    /// zstd compression level, from 1 (fastest) to 22 (smallest)
+    compression_level: i32,
+}
+
+impl Default for ColdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "cold".to_string(),
+            after_days: 365,
+            interval: 86400,
+            segment_size: 100000,
+            compression_level: 9,
+        }
+    }
+}
+
+/// Scheduled snapshots of the archive added to an IPFS node, optionally pinned by a remote
+/// pinning service and announced on the upstream relays so others can mirror the archive
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct IpfsConfig {
+    enabled: bool,
+    /// Cron expression (with seconds, in UTC) of the snapshot times
+    schedule: String,
+    /// Directory the snapshots are written to before they are added to the node
+    directory: String,
+    /// Base URL of the node's RPC API (Kubo), e.g. `http://127.0.0.1:5001`
+    api_url: String,
+    /// Name of the archive in the manifest; the announcement's `d` tag is `<name>/snapshot`
+    name: String,
+    /// Publish the CID of every snapshot as a NIP-78 event signed by the remote signer
+    announce: bool,
+    /// Service pinning the snapshots besides the local node
+    pinning: PinningConfig,
+}
+
+impl Default for IpfsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            schedule: "0 0 4 * * Sun".to_string(),
+            directory: "snapshots".to_string(),
+            api_url: "http://127.0.0.1:5001".to_string(),
+            name: "chest".to_string(),
+            announce: true,
+            pinning: PinningConfig::default(),
+        }
+    }
+}
+
+/// Remote service implementing the IPFS Pinning Service API
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct PinningConfig {
+    enabled: bool,
+    /// Base URL of the API, e.g. `https://api.pinata.cloud/psa`
+    endpoint: String,
+    /// Redacted from `GET /config`
+    access_token: String,
+}
+
+/// Checks of the archived NIP-03 OpenTimestamps attestations
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct TimestampConfig {
+    /// Verify the Bitcoin attestations against the block headers served by `esplora_url`
+    verify: bool,
+    /// Base URL of an Esplora API, e.g. a self-hosted one to keep lookups private
+    esplora_url: String,
+    /// Seconds between two runs checking new attestations
+    interval: u64,
+    /// Most attestations verified per run
+    batch_size: usize,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        Self {
+            verify: false,
+            esplora_url: "https://blockstream.info/api".to_string(),
+            interval: 600,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Scoring of the upstream relays by latency, errors and duplicates
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct HealthConfig {
+    /// Seconds between two evaluations; the counts are halved at each, so recent behaviour
+    /// weighs most
+    interval: u64,
+    /// Events a relay must have sent since the counts were last halved before it can be paused
+    min_events: u64,
+    /// Share of duplicates above which a relay is paused: no dynamic subscriptions, lookups or
+    /// backfill pages are sent to it until its share drops again (1 never pauses)
+    pause_duplicate_ratio: f64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: 300,
+            min_events: 500,
+            pause_duplicate_ratio: 0.95,
+        }
+    }
+}
+
+/// Discovery of relays from the hints in archived events
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct DiscoveryConfig {
+    /// Collect the relay hints of `e`, `p`, `a` and `q` tags and `nostr:` references
+    enabled: bool,
+    /// Seconds between two runs collecting hints and probing candidates
+    interval: u64,
+    /// Times a relay must be hinted before it is probed or added
+    min_hints: u64,
+    /// Most candidates probed per run
+    probes: usize,
+    /// Add the candidates that pass their probes to the relays subscribed to, from the next
+    /// start on
+    auto_add: bool,
+    /// Most relays discovery adds
+    max_relays: usize,
+    /// Share of probes in which a candidate must have had the event or author it was hinted
+    /// for
+    min_success_ratio: f64,
+    /// Patterns of the relay URLs that may be probed and added, `*` matching any characters;
+    /// empty allows all
+    allow: Vec<String>,
+    /// Patterns of the relay URLs never probed or added
+    deny: Vec<String>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 3600,
+            min_hints: 20,
+            probes: 10,
+            auto_add: false,
+            max_relays: 5,
+            min_success_ratio: 0.5,
+            allow: Vec::new(),
+            deny: vec![
+                "ws://localhost*".to_string(),
+                "ws://127.*".to_string(),
+                "*.onion*".to_string(),
+            ],
+        }
+    }
+}
+
+/// Detection of near-duplicate notes, clustered by simhash fingerprints of their content
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct DuplicatesConfig {
+    /// Fingerprint archived notes and replies in the background
+    enabled: bool,
+    /// Seconds between two runs fingerprinting the newly archived notes
+    interval: u64,
+    /// Notes shorter than this many characters are not fingerprinted, as short greetings
+    /// repeat without being spam
+    min_length: usize,
+    /// Most bits two fingerprints may differ in for their notes to be near-duplicates, at most 3
+    max_distance: u32,
+    /// Seconds back from a note within which earlier notes are compared to it
+    window: u64,
+    /// Notes a cluster must hold to be reported as a probable spam campaign
+    campaign_size: i64,
+}
+
+impl Default for DuplicatesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 600,
+            min_length: 40,
+            max_distance: 3,
+            window: 604800,
+            campaign_size: 20,
+        }
+    }
+}
+
+/// NIP-46 remote signer ("bunker") used to publish events and answer relay AUTH challenges
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct SignerConfig {
+    /// `bunker://<remote signer pubkey>?relay=<url>&secret=<secret>` URI given by the signer;
+    /// empty disables signing. Redacted from `GET /config`
+    bunker: String,
+    /// Hex or `nsec` key chest identifies itself to the signer with, random on every start when
+    /// empty. Signers that accept a connection secret only once need a fixed key
+    client_key: String,
+    /// Answer the NIP-42 AUTH challenges of the upstream relays
+    auth: bool,
+}
+
+impl Default for SignerConfig {
+    fn default() -> Self {
+        Self {
+            bunker: String::new(),
+            client_key: String::new(),
+            auth: true,
+        }
+    }
+}
+
+/// Subscription opened on every relay for each newly archived event of a trigger kind, e.g.
+/// for the reactions to a note
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DynamicRule {
+    /// Kinds of the archived events that trigger the subscription
+    trigger_kinds: Vec<u64>,
+    /// Kinds requested from the relays
+    subscribe_kinds: Vec<u64>,
+    /// Tag the requested events reference the triggering event with: `e` for its id, `p` for
+    /// its author, `a` for its address (parameterized replaceable events only)
+    tag: String,
+}
+
+/// Subscribes to the replies and quotes, reposts, reactions, zap receipts and NIP-22 comments
+/// of notes and articles, to the interactions addressing articles, to the posts and approvals
+/// of communities, and to the chat of live activities
+fn default_dynamic_rules() -> Vec<DynamicRule> {
+    vec![
+        DynamicRule {
+            trigger_kinds: vec![1, 30023, 30024],
+            subscribe_kinds: vec![1, 6, 7, 1111, 9735],
+            tag: "e".to_string(),
+        },
+        DynamicRule {
+            trigger_kinds: vec![30023, 30024],
+            subscribe_kinds: vec![1, 7, 1111, 9735, 9802],
+            tag: "a".to_string(),
+        },
+        DynamicRule {
+            trigger_kinds: vec![34550],
+            subscribe_kinds: vec![1, 1111, 4550],
+            tag: "a".to_string(),
+        },
+        DynamicRule {
+            trigger_kinds: vec![30311],
+            subscribe_kinds: vec![1311],
+            tag: "a".to_string(),
+        },
+    ]
+}
+
+/// Fields of the NIP-11 relay information document
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct RelayInfoConfig {
+    name: String,
+    description: String,
+    /// Hex pubkey of the operator
+    pubkey: Option<String>,
+    /// Alternative contact for the operator (e.g. an email or URL)
+    contact: Option<String>,
+    supported_nips: Vec<u32>,
+    max_message_length: Option<u64>,
+    max_subscriptions: Option<u64>,
+    max_filters: Option<u64>,
+    max_limit: Option<u64>,
+}
+
+impl Default for RelayInfoConfig {
+    fn default() -> Self {
+        Self {
+            name: "chest".to_string(),
+            description: "Nostr event archive".to_string(),
+            pubkey: None,
+            contact: None,
+            supported_nips: vec![1, 11, 45],
+            max_message_length: None,
+            max_subscriptions: None,
+            max_filters: None,
+            max_limit: None,
+        }
+    }
+}
+
+/// Database record structure for events
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+struct DbEvent {
+    event_id: String,
+    pubkey: String,
+    created_at: i64,
+    kind: i64,
+    content: String,
+    sig: String,
+    tags: String,
+    folder: String,
+    ref_event: Option<String>,
+}
+
+impl DbEvent {
+    /// Reconstructs the canonical NIP-01 event from the stored row
+    fn to_nostr(&self) -> NostrEvent {
+        NostrEvent {
+            id: self.event_id.clone(),
+            pubkey: self.pubkey.clone(),
+            created_at: self.created_at as u64,
+            kind: self.kind as u64,
+            tags: serde_json::from_str(&self.tags).unwrap_or_default(),
+            content: self.content.clone(),
+            sig: self.sig.clone(),
+        }
+    }
+
+    /// Returns the reason of the event's NIP-36 content warning, empty when it gives none, or
+    /// `None` when the event has no `content-warning` tag
+    fn content_warning(&self) -> Option<String> {
+        if !self.tags.contains("content-warning") {
+            return None;
+        }
+        let tags: Vec<Vec<String>> = serde_json::from_str(&self.tags).unwrap_or_default();
+        tags.into_iter()
+            .find(|tag| tag.first().map(String::as_str) == Some("content-warning"))
+            .map(|tag| tag.get(1).cloned().unwrap_or_default())
+    }
+}
+
+/// Output representation selected with the `format` query parameter
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// Stored row including folder and ref_event
+    #[default]
+    Db,
+    /// Canonical NIP-01 event JSON
+    Nostr,
+    /// Stored row with the tags parsed and a schema per folder: profiles of `users` events are
+    /// parsed from their content, and `zaps` carry their amount and sender
+    Typed,
+}
+
+/// Query parameters shared by event endpoints
+#[derive(Debug, Deserialize)]
+struct FormatQuery {
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+/// Query parameter of the event listings leaving out sensitive content
+#[derive(Debug, Deserialize)]
+struct SensitiveQuery {
+    /// Leave out the events with a NIP-36 content warning
+    #[serde(default)]
+    hide_sensitive: bool,
+}
+
+/// Returns whether a listing request sets `hide_sensitive=true`
+fn hides_sensitive(req: &HttpRequest) -> bool {
+    web::Query::<SensitiveQuery>::from_query(req.query_string())
+        .is_ok_and(|params| params.hide_sensitive)
+}
+
+/// Leaves the events with a content warning out of a listing when the request hides them
+fn retain_visible(req: &HttpRequest, events: &mut Vec<DbEvent>) {
+    if hides_sensitive(req) {
+        events.retain(|event| event.content_warning().is_none());
+    }
+}
+
+/// Serializes a stored event in the requested output format. Stored rows carry the reason of
+/// their content warning as `content_warning`; NIP-01 events keep the tag only.
+fn format_event(event: &DbEvent, format: OutputFormat) -> Value {
+    match format {
+        OutputFormat::Db => serde_json::to_value(event).map(|mut value| {
+            value["content_warning"] = event.content_warning().into();
+            value
+        }),
+        OutputFormat::Nostr => serde_json::to_value(event.to_nostr()),
+        OutputFormat::Typed => serde_json::to_value(typed::TypedEvent::from(event)),
+    }
+    .unwrap_or(Value::Null)
+}
+
+/// Serializes a list of stored events in the requested output format
+fn format_events(events: &[DbEvent], format: OutputFormat) -> Value {
+    Value::Array(
+        events
+            .iter()
+            .map(|event| format_event(event, format))
+            .collect(),
+    )
+}
+
+/// Transport of a relay connection, inflating the messages of relays that compress them
+type RelayStream = deflate::Inflate<MaybeTlsStream<TcpStream>>;
+
+/// Write half of a relay connection, shared with the listener tasks
+type WsWriter = Arc<
+    tokio::sync::Mutex<
+        futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<RelayStream>, Message>,
+    >,
+>;
+
+/// Write halves of the upstream relay connections, keyed by relay URL, shared with handlers
+#[derive(Debug)]
+struct RelayWriters(HashMap<String, WsWriter>);
+
+/// WebSocket connection holder
+#[derive(Debug)]
+struct WSConnection {
+    write: WsWriter,
+    read:
+        Option<futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<RelayStream>>>,
+}
+
+/// Manages a single WebSocket connection per relay
+#[derive(Debug)]
+struct WebSocketManager {
+    connections: HashMap<String, WSConnection>,
+}
+
+impl WebSocketManager {
+    /// Creates a new manager and attempts to connect to all provided relay URLs
+    async fn new(config: &RelayConfig) -> Self {
+        let mut connections = HashMap::new();
+        for relay_url in &config.urls {
+            if let Ok(conn) = Self::connect(relay_url, config).await {
+                connections.insert(relay_url.clone(), conn);
+                println!("Connected to relay: {}", relay_url);
+            } else {
+                eprintln!("Failed to connect to relay: {}", relay_url);
+            }
+        }
+        Self { connections }
+    }
+
+    /// Establishes a WebSocket connection to a single relay, offering permessage-deflate when
+    /// `compression` is enabled
+    async fn connect(
+        relay_url: &str,
+        config: &RelayConfig,
+    ) -> Result<WSConnection, Box<dyn Error>> {
+        let url = Url::parse(relay_url)?;
+        let stream = tls::connect(&url, config.tls_for(relay_url)).await?;
+        let mut request = url.as_str().into_client_request()?;
+        if config.compression {
+            request.headers_mut().insert(
+                "Sec-WebSocket-Extensions",
+                HeaderValue::from_static(deflate::OFFER),
+            );
+        }
+        let (ws_stream, _) = tokio_tungstenite::client_async(
+            request,
+            deflate::Inflate::new(stream, config.compression),
+        )
+        .await?;
+        let (write, read) = ws_stream.split();
+        Ok(WSConnection {
+            write: Arc::new(tokio::sync::Mutex::new(write)),
+            read: Some(read),
+        })
+    }
+
+    /// Sends a subscription REQ to a relay, if connected, within the relay's budget
+    async fn add_subscription(
+        &mut self,
+        relay_url: &str,
+        req_message: Value,
+        registry: &SubscriptionRegistry,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.connections.contains_key(relay_url) {
+            registry.request(relay_url, req_message.clone()).await?;
+            println!(
+                "Subscription added on relay: {} with request: {}",
+                relay_url, req_message
+            );
+        } else {
+            eprintln!("No connection found for relay: {}", relay_url);
+        }
+        Ok(())
+    }
+
+    /// Sends a NIP-45 COUNT request to a relay, if connected
+    async fn request_count(
+        &mut self,
+        relay_url: &str,
+        filter: Value,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(conn) = self.connections.get_mut(relay_url) {
+            let count_message = serde_json::json!(["COUNT", Uuid::new_v4().to_string(), filter]);
+            conn.write
+                .lock()
+                .await
+                .send(Message::Text(count_message.to_string()))
+                .await?;
+            println!(
+                "Count requested on relay: {} for filter: {}",
+                relay_url, filter
+            );
+        } else {
+            eprintln!("No connection found for relay: {}", relay_url);
+        }
+        Ok(())
+    }
+
+    /// Returns the write halves of all connections, keyed by relay URL
+    fn writers(&self) -> HashMap<String, WsWriter> {
+        self.connections
+            .iter()
+            .map(|(relay_url, conn)| (relay_url.clone(), conn.write.clone()))
+            .collect()
+    }
+
+    /// Listens to messages from all relay connections and archives received events
+    async fn listen(&mut self, ingestor: Arc<Ingestor>, registry: Arc<SubscriptionRegistry>) {
+        let writers = Arc::new(self.writers());
+        for (relay_url, conn) in self.connections.iter_mut() {
+            if let Some(mut read) = conn.read.take() {
+                let relay_url = relay_url.clone();
+                let ingestor = ingestor.clone();
+                let registry = registry.clone();
+                let writers = writers.clone();
+                tokio::spawn(async move {
+                    while let Some(message) = read.next().await {
+                        match message {
+                            Ok(Message::Text(text)) => {
+                                if let Some(answer) = match &ingestor.signer {
+                                    Some(signer) => signer.answer_auth(&relay_url, &text).await,
+                                    None => None,
+                                } {
+                                    if let Some(writer) = writers.get(&relay_url) {
+                                        if let Err(e) = writer
+                                            .lock()
+                                            .await
+                                            .send(Message::Text(answer.to_string()))
+                                            .await
+                                        {
+                                            eprintln!("Error sending AUTH to {}: {}", relay_url, e);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                if let Some(event) = ingestor
+                                    .handle_relay_message(&relay_url, &text, &registry)
+                                    .await
+                                {
+                                    subscribe_dynamic(
+                                        &writers,
+                                        &registry,
+                                        &ingestor.health,
+                                        &ingestor.config.dynamic,
+                                        &event,
+                                    )
+                                    .await;
+                                }
+                            }
+                            Ok(Message::Close(_)) => {
+                                println!("Connection closed for relay: {}", relay_url);
+                                break;
+                            }
+                            Err(e) => {
+                                eprintln!("Error receiving message from {}: {}", relay_url, e);
+                                ingestor.health.record_error(&relay_url);
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Applies the dynamic subscription rules triggered by a newly archived event on every relay
+/// its target is not yet subscribed on, paused relays aside
+async fn subscribe_dynamic(
+    writers: &HashMap<String, WsWriter>,
+    registry: &SubscriptionRegistry,
+    health: &RelayHealth,
+    rules: &[DynamicRule],
+    event: &NostrEvent,
+) {
+    for (index, rule) in rules.iter().enumerate() {
+        if !rule.trigger_kinds.contains(&event.kind) || live::has_ended(event) {
+            continue;
+        }
+        let Some(value) = subscriptions::target_value(&rule.tag, event) else {
+            continue;
+        };
+        let req_message = subscriptions::dynamic_request(rule, std::slice::from_ref(&value));
+        for relay_url in writers.keys() {
+            if health.is_paused(relay_url) || !registry.claim(relay_url, index, &value) {
+                continue;
+            }
+            if let Err(e) = registry.request(relay_url, req_message.clone()).await {
+                eprintln!("Error adding subscription on relay {}: {}", relay_url, e);
+            }
+        }
+    }
+}
+
+/// Loads configuration from `config.toml`
+fn load_config() -> Result<AppConfig, ConfigError> {
+    let settings = config::Config::builder()
+        .add_source(config::File::with_name("config"))
+        .build()?;
+    settings.try_deserialize::<AppConfig>()
+}
+
+/// Returns the default configuration, independent of `config.toml`, for tests
+#[cfg(test)]
+fn test_config() -> AppConfig {
+    serde_json::from_value(serde_json::json!({
+        "server": { "bind_address": "127.0.0.1:0" },
+        "relays": { "urls": [] },
+        "event": { "kinds": [] },
+        "database": {},
+    }))
+    .expect("the defaults deserialize")
+}
+
+/// Loads a single event from the database based on folder and identifier, going through the
+/// in-memory cache.
+async fn load_event(
+    folder: &str,
+    identifier: String,
+    db_pool: &SqlitePool,
+    cache: &EventCache,
+) -> Result<DbEvent, ApiError> {
+    let cached = if folder == "users" {
+        cache.profile(&identifier)
+    } else {
+        cache.event(&identifier)
+    };
+    if let Some(event) = cached.filter(|event| event.folder == folder) {
+        return Ok(event);
+    }
+    let query = if folder == "users" {
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = ? AND pubkey = ?"
+    } else {
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = ? AND event_id = ?"
+    };
+    let event = sqlx::query_as::<_, DbEvent>(query)
+        .bind(folder)
+        .bind(&identifier)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Event not found".to_string()))?;
+    if folder == "users" {
+        cache.insert_profile(&event);
+    } else {
+        cache.insert_event(&event);
+    }
+    Ok(event)
+}
+
+/// Query a single event from the database based on folder and identifier, going through the
+/// in-memory cache.
+async fn query_event(
+    req: &HttpRequest,
+    folder: &str,
+    identifier: String,
+    format: OutputFormat,
+    db_pool: &SqlitePool,
+    cache: &EventCache,
+) -> Result<HttpResponse, ApiError> {
+    let event = load_event(folder, identifier, db_pool, cache).await?;
+    Ok(etag::json_with_etag(
+        req,
+        etag::event_etag(&event.event_id),
+        &format_event(&event, format),
+    ))
+}
+
+/// HTTP endpoint to retrieve a user event.
+async fn get_user_event(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+    cache: web::Data<EventCache>,
+) -> Result<HttpResponse, ApiError> {
+    query_event(
+        &req,
+        "users",
+        id.into_inner(),
+        params.format,
+        db_pool.get_ref(),
+        cache.get_ref(),
+    )
+    .await
+}
+
+/// Query parameters of `GET /notes/{id}`
+#[derive(Debug, Deserialize)]
+struct NoteQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Ask the relays for the note when it is not archived yet
+    #[serde(default)]
+    fetch: bool,
+    /// Comma-separated related data to embed: `previews`, the metadata of the web pages the
+    /// note links to, `attestations`, the status of its NIP-03 timestamp attestations,
+    /// `counts`, its engagement counts, and `preview_replies(n)`, its first `n` replies.
+    /// Also accepted as `include`
+    #[serde(alias = "include")]
+    expand: Option<String>,
+}
+
+/// Replies embedded by `preview_replies` without a count
+const DEFAULT_PREVIEW_REPLIES: i64 = 3;
+
+/// Most replies `preview_replies(n)` embeds
+const MAX_PREVIEW_REPLIES: i64 = 20;
+
+/// HTTP endpoint to retrieve a note event.
+async fn get_note_event(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<NoteQuery>,
+    db_pool: web::Data<SqlitePool>,
+    cache: web::Data<EventCache>,
+    ingestor: web::Data<Ingestor>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut expand_previews = false;
+    let mut expand_attestations = false;
+    let mut expand_counts = false;
+    let mut preview_replies = None;
+    for field in params.expand.iter().flat_map(|expand| expand.split(',')) {
+        match field.trim() {
+            "previews" => expand_previews = true,
+            "attestations" => expand_attestations = true,
+            "counts" => expand_counts = true,
+            "preview_replies" => preview_replies = Some(DEFAULT_PREVIEW_REPLIES),
+            other if other.starts_with("preview_replies(") && other.ends_with(')') => {
+                let count = other["preview_replies(".len()..other.len() - 1]
+                    .parse::<i64>()
+                    .map_err(|_| ApiError::BadRequest(format!("Invalid reply count: {}", other)))?;
+                preview_replies = Some(count.clamp(1, MAX_PREVIEW_REPLIES));
+            }
+            other => {
+                return Err(ApiError::BadRequest(format!(
+                    "Unknown expand field: {}",
+                    other
+                )))
+            }
+        }
+    }
+    if params.fetch && nostr::is_hex32(&id) {
+        resolver::resolve(&id, ingestor.get_ref()).await?;
+    }
+    if !expand_previews && !expand_attestations && !expand_counts && preview_replies.is_none() {
+        return query_event(
+            &req,
+            "notes",
+            id,
+            params.format,
+            db_pool.get_ref(),
+            cache.get_ref(),
+        )
+        .await;
+    }
+
+    let event = load_event("notes", id, db_pool.get_ref(), cache.get_ref()).await?;
+    let mut item = format_event(&event, params.format);
+    // Previews are fetched, attestations checked and replies archived after the note is, so
+    // they are part of the ETag too.
+    let mut parts = Vec::new();
+    if let Value::Object(map) = &mut item {
+        if expand_previews {
+            let link_previews = previews::previews_for(&event.content, db_pool.get_ref()).await?;
+            parts.extend(previews::previews_etag_parts(&link_previews));
+            map.insert(
+                "previews".to_string(),
+                serde_json::to_value(&link_previews).unwrap_or_default(),
+            );
+        }
+        if expand_attestations {
+            let attestations =
+                timestamps::attestations_for(&event.event_id, db_pool.get_ref()).await?;
+            parts.extend(timestamps::attestations_etag_parts(&attestations));
+            map.insert(
+                "attestations".to_string(),
+                serde_json::to_value(&attestations).unwrap_or_default(),
+            );
+        }
+        if expand_counts {
+            let counts = engagement::fetch_counts(&event.event_id, db_pool.get_ref()).await?;
+            parts.push(format!(
+                "counts:{}:{}:{}:{}:{}",
+                counts.replies, counts.reactions, counts.zaps, counts.reposts, counts.quotes
+            ));
+            map.insert(
+                "counts".to_string(),
+                serde_json::to_value(&counts).unwrap_or_default(),
+            );
+        }
+        if let Some(limit) = preview_replies {
+            let replies = sqlx::query_as::<_, DbEvent>(
+                "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+                 FROM public_events AS events WHERE folder = 'replies' AND ref_event = ?
+                 ORDER BY created_at, event_id LIMIT ?",
+            )
+            .bind(&event.event_id)
+            .bind(limit)
+            .fetch_all(db_pool.get_ref())
+            .await?;
+            parts.extend(replies.iter().map(|reply| reply.event_id.clone()));
+            map.insert(
+                "preview_replies".to_string(),
+                format_events(&replies, params.format),
+            );
+        }
+    }
+    let etag = etag::list_etag(
+        std::iter::once(event.event_id.as_str()).chain(parts.iter().map(String::as_str)),
+    );
+    Ok(etag::json_with_etag(&req, etag, &item))
+}
+
+/// Fetches the latest stored version of a long-form article by its address
+async fn fetch_latest_long(
+    address: &nostr::Address,
+    db_pool: &SqlitePool,
+) -> Result<Option<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = 'long' AND pubkey = ? AND kind = ? AND d_tag = ?
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(&address.pubkey)
+    .bind(address.kind as i64)
+    .bind(&address.identifier)
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Fetches the latest stored version of any parameterized replaceable event by its address
+async fn fetch_latest_address(
+    address: &nostr::Address,
+    db_pool: &SqlitePool,
+) -> Result<Option<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE pubkey = ? AND kind = ? AND d_tag = ?
+         ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(&address.pubkey)
+    .bind(address.kind as i64)
+    .bind(&address.identifier)
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Fetches a long-form article by event id, or its latest version when given an `naddr`
+async fn fetch_long_event(id: &str, db_pool: &SqlitePool) -> Result<Option<DbEvent>, sqlx::Error> {
+    if let Some(address) = nostr::parse_naddr(id) {
+        return fetch_latest_long(&address, db_pool).await;
+    }
+    sqlx::query_as::<_, DbEvent>(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = 'long' AND event_id = ?",
+    )
+    .bind(id)
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Responds with a single long-form article, or 404 if it isn't archived
+fn long_event_response(
+    req: &HttpRequest,
+    event: Option<DbEvent>,
+    format: OutputFormat,
+) -> Result<HttpResponse, ApiError> {
+    let event = event.ok_or_else(|| ApiError::NotFound("Event not found".to_string()))?;
+    Ok(etag::json_with_etag(
+        req,
+        etag::event_etag(&event.event_id),
+        &format_event(&event, format),
+    ))
+}
+
+/// HTTP endpoint to retrieve a long-form event by id or `naddr`.
+async fn get_long_event(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let event = fetch_long_event(&id.into_inner(), db_pool.get_ref()).await?;
+    long_event_response(&req, event, params.format)
+}
+
+/// HTTP endpoint to retrieve the latest version of a long-form article by pubkey and d-tag.
+/// Also serves `/long/{id}/html`, an article rendered as an HTML page: when the d-tag is `html`
+/// and the first segment is an naddr or the id of an archived article, that article is rendered.
+/// Ids and pubkeys are both 64 hex characters but never coincide, so an article whose d-tag is
+/// `html` stays reachable by its address.
+async fn get_long_by_address(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (pubkey, d_tag) = path.into_inner();
+    if d_tag == "html" {
+        if let Some(event) = fetch_long_event(&pubkey, db_pool.get_ref()).await? {
+            return Ok(markdown::long_html_response(&req, &event));
+        }
+    }
+    let address = nostr::Address {
+        kind: 30023,
+        pubkey: nostr::parse_pubkey(&pubkey)
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", pubkey)))?,
+        identifier: d_tag,
+    };
+    let event = fetch_latest_long(&address, db_pool.get_ref()).await?;
+    long_event_response(&req, event, params.format)
+}
+
+/// Query parameters for folder listings
+#[derive(Debug, Deserialize)]
+struct FolderListQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    /// Comma-separated related data to embed; currently only `ref_event`
+    expand: Option<String>,
+}
+
+/// Fetches all stored events whose ids are in `ids`, except the operator's direct messages
+async fn fetch_events_by_ids(
+    ids: &[String],
+    db_pool: &SqlitePool,
+) -> Result<Vec<DbEvent>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events WHERE event_id IN (",
+    );
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(id);
+    }
+    query.push(")");
+    query.build_query_as::<DbEvent>().fetch_all(db_pool).await
+}
+
+/// Endpoint for listing events in a folder (e.g., replies, reactions, zaps, or reposts) based on a reference event.
+async fn list_folder_events(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<FolderListQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (folder, ref_event) = path.into_inner();
+
+    // Only allowed folder listings for replies, reactions, zaps, and reposts.
+    let allowed_folders = ["replies", "reactions", "zaps", "reposts"];
+    if !allowed_folders.contains(&folder.as_str()) {
+        return Err(ApiError::BadRequest("Invalid folder name".to_string()));
+    }
+
+    let mut expand_ref_event = false;
+    for field in params.expand.iter().flat_map(|expand| expand.split(',')) {
+        match field.trim() {
+            "ref_event" => expand_ref_event = true,
+            other => {
+                return Err(ApiError::BadRequest(format!(
+                    "Unknown expand field: {}",
+                    other
+                )))
+            }
+        }
+    }
+
+    // Interactions with articles may reference them by address (`naddr` or `a` tag value).
+    let address = nostr::parse_naddr(&ref_event).or_else(|| nostr::parse_coordinate(&ref_event));
+    let (column, reference) = match &address {
+        Some(address) => ("ref_address", address.coordinate()),
+        None => ("ref_event", ref_event),
+    };
+
+    // Threads can be large: without expansion the rows are streamed straight from the database.
+    if !expand_ref_event {
+        return stream::event_listing(&req, db_pool.get_ref(), params.format, move |columns| {
+            let mut query = QueryBuilder::new(format!(
+                "SELECT {} FROM public_events AS events WHERE folder = ",
+                columns
+            ));
+            query.push_bind(folder.clone());
+            query
+                .push(format!(" AND {} = ", column))
+                .push_bind(reference.clone());
+            query
+        })
+        .await;
+    }
+
+    let mut events = sqlx::query_as::<_, DbEvent>(&format!(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events WHERE folder = ? AND {} = ?",
+        column
+    ))
+    .bind(&folder)
+    .bind(&reference)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    retain_visible(&req, &mut events);
+
+    // Interactions with an address all reference its latest version; others their parent's id.
+    let mut parents = match &address {
+        Some(address) => fetch_latest_address(address, db_pool.get_ref())
+            .await?
+            .into_iter()
+            .collect(),
+        None => {
+            let mut ref_ids: Vec<String> = events
+                .iter()
+                .filter_map(|event| event.ref_event.clone())
+                .collect();
+            ref_ids.sort();
+            ref_ids.dedup();
+            fetch_events_by_ids(&ref_ids, db_pool.get_ref()).await?
+        }
+    };
+    retain_visible(&req, &mut parents);
+    let referenced: HashMap<String, DbEvent> = parents
+        .into_iter()
+        .map(|event| (event.event_id.clone(), event))
+        .collect();
+    let parent_of = |event: &DbEvent| match &address {
+        Some(_) => referenced.values().next(),
+        None => event.ref_event.as_ref().and_then(|id| referenced.get(id)),
+    };
+
+    // Parents may be archived after their children, so they are part of the ETag too.
+    let mut parent_ids: Vec<&str> = referenced.keys().map(String::as_str).collect();
+    parent_ids.sort();
+    let etag = etag::list_etag(
+        events
+            .iter()
+            .map(|event| event.event_id.as_str())
+            .chain(parent_ids),
+    );
+    let items: Vec<Value> = events
+        .iter()
+        .map(|event| {
+            let mut item = format_event(event, params.format);
+            let parent = parent_of(event)
+                .map(|parent| format_event(parent, params.format))
+                .unwrap_or(Value::Null);
+            if let Value::Object(map) = &mut item {
+                map.insert("referenced_event".to_string(), parent);
+            }
+            item
+        })
+        .collect();
+    Ok(etag::json_with_etag(&req, etag, &items))
+}
+
+/// HTTP endpoint to retrieve the application configuration, with credentials and deployment
+/// details redacted.
+async fn get_config(config: web::Data<AppConfig>) -> impl Responder {
+    HttpResponse::Ok().json(redact::redact(config.get_ref()))
+}
+
+/// Admin endpoint returning the full configuration, credentials included. Every view is
+/// recorded in the audit log.
+async fn get_admin_config(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_config", "").await?;
+    Ok(HttpResponse::Ok().json(config.get_ref()))
+}
+
+/// Sort direction of `created_at` in listings
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Asc,
+    /// Newest first
+    #[default]
+    Desc,
+}
+
+/// Query parameters of `GET /notes/pubkey/{pubkey}`
+#[derive(Debug, Deserialize)]
+struct PubkeyNotesQuery {
+    #[serde(default)]
+    format: OutputFormat,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    #[serde(default)]
+    order: SortOrder,
+    /// Also list the user's replies, which are archived in the `replies` folder
+    #[serde(default)]
+    include_replies: bool,
+    /// Also list the notes published on the user's behalf by NIP-26 delegated keys
+    #[serde(default)]
+    delegated: bool,
+}
+
+/// Lists the note events of a specific user based on their pubkey, as a timeline filtered by
+/// time range.
+async fn list_notes_by_pubkey(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<PubkeyNotesQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let pubkey = path.into_inner();
+    let PubkeyNotesQuery {
+        format,
+        since,
+        until,
+        limit,
+        order,
+        include_replies,
+        delegated,
+    } = params.into_inner();
+    stream::event_listing(&req, db_pool.get_ref(), format, move |columns| {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM public_events AS events WHERE (pubkey = ",
+            columns
+        ));
+        query.push_bind(pubkey.clone());
+        if delegated {
+            query.push(" OR delegator = ").push_bind(pubkey.clone());
+        }
+        query.push(")");
+        if include_replies {
+            query.push(" AND folder IN ('notes', 'replies')");
+        } else {
+            query.push(" AND folder = 'notes'");
+        }
+        if let Some(since) = since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = until {
+            query.push(" AND created_at <= ").push_bind(until);
+        }
+        query.push(match order {
+            SortOrder::Asc => " ORDER BY created_at ASC",
+            SortOrder::Desc => " ORDER BY created_at DESC",
+        });
+        if let Some(limit) = limit {
+            query.push(" LIMIT ").push_bind(limit);
+        }
+        query
+    })
+    .await
+}
+
+/// Request body for `POST /events/batch`
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    ids: Vec<String>,
+}
+
+/// Returns all stored events matching a list of ids (hex, note or nevent) in one response.
+async fn batch_events(
+    req: HttpRequest,
+    body: web::Json<BatchRequest>,
+    params: web::Query<FormatQuery>,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    if body.ids.len() > config.api.max_batch_size {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} ids may be requested at once",
+            config.api.max_batch_size
+        )));
+    }
+    let ids = body
+        .ids
+        .iter()
+        .map(|id| {
+            nostr::parse_event_id(id)
+                .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", id)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let events = fetch_events_by_ids(&ids, db_pool.get_ref()).await?;
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &format_events(&events, params.format),
+    ))
+}
+
+/// Fallback for requests that match no route.
+async fn not_found() -> HttpResponse {
+    ApiError::NotFound("Route not found".to_string()).error_response()
+}
+
+/// Opens the write and read-only pools of an archive's database, creating its schema. Exits
+/// when the database cannot be opened.
+async fn open_database(config: &DatabaseConfig) -> (SqlitePool, SqlitePool) {
+    // Create the SQLite connection pools: writes go through a dedicated pool so they do not
+    // contend with readers for connections.
+    let write_pool = db::connect(config, false)
+        .await
+        .expect("Failed to connect to the database");
+
+    // Create the events table if it does not exist and migrate older databases.
+    if let Err(e) = db::init_schema(&write_pool).await {
+        eprintln!("Failed to create table: {:?}", e);
+        std::process::exit(1);
+    }
+    let db_pool = db::connect(config, true)
+        .await
+        .expect("Failed to connect to the database");
+    (write_pool, db_pool)
+}
+
+/// State of an archive shared with the HTTP handlers
+#[derive(Clone)]
+struct Archive {
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+    cache: web::Data<EventCache>,
+    ingestor: web::Data<Ingestor>,
+    backups: web::Data<Backups>,
+    snapshots: web::Data<Snapshots>,
+    maintenance: web::Data<Maintenance>,
+    stats: web::Data<Stats>,
+    registry: web::Data<SubscriptionRegistry>,
+    health: web::Data<RelayHealth>,
+    relay_writers: web::Data<RelayWriters>,
+}
+
+impl Archive {
+    /// Serves the archive's routes under a path prefix, empty for the main archive
+    fn scope(&self, path: &str) -> Scope {
+        web::scope(path)
+            .app_data(self.config.clone())
+            .app_data(self.db_pool.clone())
+            .app_data(self.write_pool.clone())
+            .app_data(self.cache.clone())
+            .app_data(self.ingestor.clone())
+            .app_data(self.backups.clone())
+            .app_data(self.snapshots.clone())
+            .app_data(self.maintenance.clone())
+            .app_data(self.stats.clone())
+            .app_data(self.registry.clone())
+            .app_data(self.health.clone())
+            .app_data(self.relay_writers.clone())
+            .configure(routes)
+    }
+}
+
+/// Connects an archive to its relays, subscribes and starts its background tasks
+async fn start_archive(
+    mut config: AppConfig,
+    write_pool: SqlitePool,
+    db_pool: SqlitePool,
+    signer: Option<Arc<Signer>>,
+) -> Archive {
+    // Subscribe to the relays discovery added as well as the configured ones.
+    if config.discovery.enabled && config.discovery.auto_add {
+        match discovery::added_relays(&db_pool, &config.discovery).await {
+            Ok(added) => {
+                for relay_url in added {
+                    if !config.relays.urls.contains(&relay_url) {
+                        println!("Subscribing to discovered relay {}", relay_url);
+                        config.relays.urls.push(relay_url);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to load discovered relays: {:?}", e),
+        }
+    }
+
+    // Event kinds we want to subscribe to globally:
+    let mut global_event_kinds = vec![
+        1, 5, 6, 8, 40, 41, 42, 1040, 1063, 1111, 1984, 9802, 30008, 30009, 30023, 30024, 30311,
+        31922, 31923, 31925, 34550,
+    ];
+    global_event_kinds.extend_from_slice(ingest::LIST_KINDS);
+    global_event_kinds.extend_from_slice(ingest::SET_KINDS);
+    global_event_kinds.push(blossom::SERVER_LIST_KIND);
+
+    // Create a WebSocketManager for all relays.
+    let mut ws_manager = WebSocketManager::new(&config.relays).await;
+
+    // Remember which events already have engagement subscriptions, and
+    // every REQ sent to the relays.
+    for rule in config
+        .dynamic
+        .iter()
+        .filter(|rule| !subscriptions::is_supported(rule))
+    {
+        eprintln!(
+            "Ignoring dynamic subscription rule with unsupported tag {:?}",
+            rule.tag
+        );
+    }
+    let registry =
+        match SubscriptionRegistry::load(&db_pool, &config.dynamic, &config.subscriptions).await {
+            Ok(registry) => Arc::new(registry),
+            Err(e) => {
+                eprintln!("Failed to load subscription registry: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+
+    // Learn the subscription budget of every relay, and ask relays supporting NIP-45 how many
+    // events they hold before subscribing.
+    let writers = ws_manager.writers();
+    for relay_url in &config.relays.urls {
+        let info = match nip11::fetch(relay_url, config.relays.tls_for(relay_url)).await {
+            Ok(info) => info,
+            Err(e) => {
+                eprintln!("Failed to fetch NIP-11 document for {}: {}", relay_url, e);
+                Default::default()
+            }
+        };
+        if let Some(writer) = writers.get(relay_url) {
+            registry.connect(relay_url, writer.clone(), &info.limitation);
+        }
+        if info.supported_nips.contains(&45) {
+            let filter = serde_json::json!({ "kinds": global_event_kinds });
+            if let Err(e) = ws_manager.request_count(relay_url, filter).await {
+                eprintln!("Error requesting count on relay {}: {}", relay_url, e);
+            }
+        }
+    }
+
+    // Score the relays, to ask the healthiest first and pause those sending mostly duplicates.
+    let health =
+        match RelayHealth::load(config.health.clone(), &config.relays.urls, &write_pool).await {
+            Ok(health) => Arc::new(health),
+            Err(e) => {
+                eprintln!("Failed to load relay health: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+    health.clone().spawn(write_pool.clone());
+
+    // Walk the history of the relays in pages, so the live subscription only needs new events.
+    let backfill = config.subscriptions.backfill.then(|| {
+        Arc::new(Backfill::new(
+            &config.subscriptions,
+            &global_event_kinds,
+            writers.keys().cloned().collect(),
+            write_pool.clone(),
+        ))
+    });
+    // Relays do not keep ephemeral events, so only the live subscription asks for them.
+    let live_kinds: Vec<u64> = global_event_kinds
+        .iter()
+        .chain(
+            config
+                .event
+                .ephemeral_kinds
+                .iter()
+                .filter(|kind| ingest::EPHEMERAL_KINDS.contains(kind)),
+        )
+        .copied()
+        .collect();
+    let mut global_filter = serde_json::json!({ "kinds": live_kinds });
+    match &backfill {
+        Some(backfill) => global_filter["since"] = backfill.started_at.into(),
+        None if config.subscriptions.limit > 0 => {
+            global_filter["limit"] = config.subscriptions.limit.into()
+        }
+        None => {}
+    }
+
+    // Add a subscription for the global event kinds on each relay.
+    for relay_url in &config.relays.urls {
+        let subscription_id = Uuid::new_v4().to_string();
+        let req_message = serde_json::json!(["REQ", subscription_id, global_filter]);
+        if let Err(e) = ws_manager
+            .add_subscription(relay_url, req_message, &registry)
+            .await
+        {
+            eprintln!("Error adding subscription on relay {}: {}", relay_url, e);
+        }
+    }
+
+    if let Some(backfill) = &backfill {
+        if let Err(e) = backfill.start(&registry, &health).await {
+            eprintln!("Failed to start backfill: {:?}", e);
+        }
+    }
+
+    // Subscribe to the operator's direct messages.
+    let dms = DmBackup::new(&config.dms).map(Arc::new);
+    if let Some(dms) = &dms {
+        for relay_url in &config.relays.urls {
+            let mut req_message = serde_json::json!(["REQ", Uuid::new_v4().to_string()]);
+            if let Value::Array(parts) = &mut req_message {
+                parts.extend(dms.filters());
+            }
+            if let Err(e) = ws_manager
+                .add_subscription(relay_url, req_message, &registry)
+                .await
+            {
+                eprintln!("Error adding subscription on relay {}: {}", relay_url, e);
+            }
+        }
+    }
+
+    // Restore the dynamic subscriptions of recently archived events.
+    if config.subscriptions.restore_window > 0 {
+        let since = nostr::now().saturating_sub(config.subscriptions.restore_window);
+        for rule in &config.dynamic {
+            let values = match subscriptions::targets(&db_pool, rule, since).await {
+                Ok(values) => values,
+                Err(e) => {
+                    eprintln!("Failed to load recent events: {:?}", e);
+                    continue;
+                }
+            };
+            for batch in values.chunks(config.subscriptions.batch_size.max(1)) {
+                let req_message = subscriptions::dynamic_request(rule, batch);
+                for relay_url in &config.relays.urls {
+                    if let Err(e) = ws_manager
+                        .add_subscription(relay_url, req_message.clone(), &registry)
+                        .await
+                    {
+                        eprintln!(
+                            "Error restoring subscriptions on relay {}: {}",
+                            relay_url, e
+                        );
+                    }
+                }
+            }
+            println!(
+                "Restored subscriptions to kinds {:?} for {} #{} values",
+                rule.subscribe_kinds,
+                values.len(),
+                rule.tag
+            );
+        }
+    }
+
+    // Only archive the notes on the configured topics.
+    let topics = match TopicFilter::new(&config.topics) {
+        Ok(topics) => topics,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Keep the web of trust up to date as follow lists are archived.
+    let wot = Arc::new(WebOfTrust::new(config.wot.clone()));
+    wot.clone().spawn_refresh(write_pool.clone());
+
+    // Check the signatures of events archived before, or without, verification.
+    reverify::spawn(config.reverify.clone(), write_pool.clone());
+
+    // Check the proofs of the archived timestamp attestations.
+    timestamps::spawn(config.timestamps.clone(), write_pool.clone());
+
+    // Move old events to compressed segment files.
+    cold::spawn(config.cold.clone(), write_pool.clone());
+
+    // Count what would be archived instead of writing it.
+    let dry_run = DryRun::new(&config.ingest).map(Arc::new);
+    if let Some(dry_run) = &dry_run {
+        println!("Dry run: events are counted but not archived");
+        dry_run.clone().spawn_report();
+    }
+
+    // Collect the relays hinted by archived events.
+    discovery::spawn(
+        config.discovery.clone(),
+        config.relays.urls.clone(),
+        write_pool.clone(),
+    );
+
+    // Cluster near-duplicate notes to expose spam campaigns.
+    duplicates::spawn(config.duplicates.clone(), write_pool.clone());
+
+    // Look up the parents of reactions, zaps, replies and reposts that are not archived.
+    orphans::spawn_reconciliation(
+        write_pool.clone(),
+        writers.keys().cloned().collect(),
+        registry.clone(),
+        health.clone(),
+        config.orphans.clone(),
+    );
+
+    // Snapshot the database on the configured schedule.
+    let backups = Arc::new(Backups::new(config.backup.clone(), config.database.clone()));
+    backups.clone().spawn();
+
+    // Keep the database compact and its statistics fresh during quiet hours.
+    let maintenance = Arc::new(Maintenance::new(config.maintenance.clone()));
+    maintenance.clone().spawn(write_pool.clone());
+
+    // Cache single-event lookups; relay listeners invalidate replaced and deleted events.
+    let cache = Arc::new(EventCache::new(&config.cache));
+
+    // Remember archived event ids so copies received from several relays skip the database.
+    let seen = match SeenFilter::load(&db_pool, &config.ingest).await {
+        Ok(seen) => Arc::new(seen),
+        Err(e) => {
+            eprintln!("Failed to load archived event ids: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Aggregate archive statistics in the background.
+    let stats = Arc::new(Stats::new(config.stats.clone()));
+    stats.clone().spawn_refresh(db_pool.clone());
+
+    // Notify chat channels about matching events in batches.
+    let notifier = Notifier::new(&config.notify).map(Arc::new);
+    if let Some(notifier) = &notifier {
+        notifier.clone().spawn();
+    }
+
+    // Download the media referenced by archived events in the background.
+    let media = MediaCache::new(&config.media, write_pool.clone()).map(|(media, receiver)| {
+        let media = Arc::new(media);
+        media.clone().spawn(receiver);
+        media
+    });
+
+    // Fetch the metadata of linked web pages in the background.
+    let previews =
+        LinkPreviews::new(&config.previews, write_pool.clone()).map(|(previews, receiver)| {
+            let previews = Arc::new(previews);
+            previews.clone().spawn(receiver);
+            previews
+        });
+
+    let ingestor = Arc::new(Ingestor {
+        db_pool: write_pool.clone(),
+        config: config.clone(),
+        wot,
+        cache: cache.clone(),
+        seen,
+        stats: stats.clone(),
+        mqtt: MqttBridge::start(&config.mqtt),
+        notifier,
+        media,
+        previews,
+        dms,
+        signer,
+        dry_run,
+        backfill,
+        topics,
+        health: health.clone(),
+    });
+
+    // Add snapshots of the archive to IPFS and announce them on the configured schedule.
+    let relay_writers = Arc::new(RelayWriters(ws_manager.writers()));
+    let snapshots = Arc::new(Snapshots::new(
+        config.ipfs.clone(),
+        config.cold.directory.clone(),
+    ));
+    snapshots
+        .clone()
+        .spawn(db_pool.clone(), ingestor.clone(), relay_writers.clone());
+
+    // Start listening to messages on all WebSocket connections.
+    ws_manager.listen(ingestor.clone(), registry.clone()).await;
+
+    // Share configuration and database pool with the HTTP server.
+    Archive {
+        config: web::Data::new(config),
+        db_pool: web::Data::new(db_pool),
+        write_pool: web::Data::new(WritePool(write_pool)),
+        cache: web::Data::from(cache),
+        ingestor: web::Data::from(ingestor),
+        backups: web::Data::from(backups),
+        snapshots: web::Data::from(snapshots),
+        maintenance: web::Data::from(maintenance),
+        stats: web::Data::from(stats),
+        registry: web::Data::from(registry),
+        health: web::Data::from(health),
+        relay_writers: web::Data::from(relay_writers),
+    }
+}
+
+/// Registers the routes of an archive
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg // Relay WebSocket endpoint and NIP-11 relay information document
+        .route("/", web::get().to(relay::root))
+        // The same without a trailing slash, under `server.base_path` or an archive's name
+        .route("", web::get().to(relay::root))
+        // Archived profiles, searchable by name
+        .route("/users", web::get().to(users::list_users))
+        // Single event endpoints
+        .route("/users/{id}", web::get().to(get_user_event))
+        .route(
+            "/users/{id}/versions",
+            web::get().to(versions::profile_versions),
+        )
+        .route("/notes/{id}", web::get().to(get_note_event))
+        // Root and ancestors of a reply
+        .route("/notes/{id}/root", web::get().to(threads::thread_root))
+        .route("/long/{id}", web::get().to(get_long_event))
+        // Article by address, or rendered as HTML at `/long/{id}/html`
+        .route("/long/{pubkey}/{d_tag}", web::get().to(get_long_by_address))
+        // Stored versions of an article, to follow its edits
+        .route(
+            "/long/{pubkey}/{d_tag}/versions",
+            web::get().to(versions::long_versions),
+        )
+        // Folder listing endpoints
+        .route(
+            "/{folder:replies|reactions|zaps|reposts}/{ref_event}",
+            web::get().to(list_folder_events),
+        )
+        // List all notes for a specific user by pubkey.
+        .route(
+            "/notes/pubkey/{pubkey}",
+            web::get().to(list_notes_by_pubkey),
+        )
+        // NIP-84 highlights of an event or article address
+        .route(
+            "/highlights/{ref}",
+            web::get().to(highlights::list_highlights),
+        )
+        // NIP-51 lists and sets
+        .route("/lists/{pubkey}", web::get().to(lists::list_lists))
+        .route("/lists/{pubkey}/{d}", web::get().to(lists::get_list))
+        // NIP-56 reports of an event or pubkey
+        .route("/reports/{ref}", web::get().to(reports::list_reports))
+        .route("/admin/reports", web::get().to(reports::report_summary))
+        // Referenced events that could not be found on any relay
+        .route("/admin/orphans", web::get().to(orphans::list_orphans))
+        .route("/admin/backups", web::get().to(backup::backup_status))
+        .route("/admin/snapshots", web::get().to(ipfs::snapshot_status))
+        // REQs currently open on the upstream relays
+        .route(
+            "/admin/subscriptions",
+            web::get().to(subscriptions::list_subscriptions),
+        )
+        // Progress of the paged walk through each relay's history
+        .route("/admin/backfill", web::get().to(backfill::backfill_status))
+        .route("/admin/relays", web::get().to(health::relay_health))
+        // Full configuration, credentials included
+        .route("/admin/config", web::get().to(get_admin_config))
+        .route(
+            "/admin/relays/candidates",
+            web::get().to(discovery::list_candidates),
+        )
+        // Clusters of near-duplicate notes, and banning their authors at once
+        .route(
+            "/admin/duplicates",
+            web::get().to(duplicates::list_clusters),
+        )
+        .route(
+            "/admin/duplicates/{id}",
+            web::get().to(duplicates::get_cluster),
+        )
+        .route(
+            "/admin/duplicates/{id}/ban",
+            web::post().to(duplicates::ban_cluster),
+        )
+        .route(
+            "/admin/maintenance",
+            web::get().to(maintenance::maintenance_metrics),
+        )
+        // Events moved to cold storage, read from their compressed segments
+        .route("/cold/events", web::get().to(cold::query_cold))
+        .route("/cold/events/{id}", web::get().to(cold::get_cold_event))
+        // Admin actions, newest first
+        .route("/admin/audit", web::get().to(audit::list_audit))
+        // Events signed by the remote signer and published upstream
+        .route("/admin/publish", web::post().to(signer::publish_event))
+        // Moderator removal of events and pubkeys
+        .route(
+            "/admin/events/{id}",
+            web::delete().to(moderation::delete_event),
+        )
+        .route(
+            "/admin/pubkeys/{pubkey}",
+            web::delete().to(moderation::delete_pubkey),
+        )
+        // Engagement counts for an event
+        .route(
+            "/engagement/{id}",
+            web::get().to(engagement::engagement_counts),
+        )
+        // Engagement per time bucket since publication
+        .route(
+            "/engagement/{id}/timeline",
+            web::get().to(engagement::engagement_timeline),
+        )
+        // Reaction counts grouped by content
+        .route(
+            "/reactions/{ref_event}/summary",
+            web::get().to(engagement::reaction_summary),
+        )
+        // NIP-28 public chat channels
+        .route("/channels", web::get().to(channels::list_channels))
+        .route("/channels/{id}", web::get().to(channels::get_channel))
+        .route(
+            "/channels/{id}/messages",
+            web::get().to(channels::list_channel_messages),
+        )
+        // NIP-72 community posts
+        .route(
+            "/communities/{naddr}/posts",
+            web::get().to(communities::list_posts),
+        )
+        // NIP-58 badges displayed on a profile
+        .route(
+            "/profiles/{pubkey}/badges",
+            web::get().to(badges::profile_badges),
+        )
+        // Decrypted direct messages of the operator
+        .route("/dms", web::get().to(dms::list_dms))
+        // OpenGraph metadata of links found in archived notes
+        .route("/previews", web::get().to(previews::get_preview))
+        // NIP-03 timestamp attestations of an event and their verification status
+        .route(
+            "/attestations/{id}",
+            web::get().to(timestamps::get_attestations),
+        )
+        // Cached copies of referenced media, by SHA-256 hash
+        .route("/media/{sha256}", web::get().to(media::get_media))
+        // NIP-94 file metadata
+        .route("/files", web::get().to(files::list_files))
+        .route("/files/hash/{sha256}", web::get().to(files::files_by_hash))
+        // NIP-52 calendar events of a pubkey, as JSON or iCalendar, and their RSVPs
+        .route(
+            "/calendar/{pubkey}.ics",
+            web::get().to(calendar::ical_export),
+        )
+        .route("/calendar/{pubkey}", web::get().to(calendar::list_calendar))
+        .route(
+            "/calendar/{pubkey}/{d}/rsvps",
+            web::get().to(calendar::list_rsvps),
+        )
+        // NIP-53 live activity chat
+        .route("/live/{naddr}/chat", web::get().to(live::chat))
+        // Notes located within a bounding box, by their geohash
+        .route("/geo", web::get().to(geo::notes_in_bbox))
+        // Who a pubkey interacts with, weighted by replies, reactions and zaps
+        .route("/graph/{pubkey}", web::get().to(graph::interaction_graph))
+        // Chronological notes of the pubkeys a user follows
+        .route("/feed/{pubkey}", web::get().to(home::home_feed))
+        // Atom feed of a user's notes and articles
+        .route("/feeds/{pubkey}.xml", web::get().to(feeds::pubkey_feed))
+        // Fetch several events by id in one request
+        .route("/events/batch", web::post().to(batch_events))
+        // Check which events are archived without fetching them
+        .route("/events/exists", web::post().to(exists::events_exist))
+        .route("/events/{id}", web::head().to(exists::head_event))
+        // Archive statistics and ingestion rates
+        .route("/stats", web::get().to(stats::get_stats))
+        .route("/stats/timeseries", web::get().to(stats::get_timeseries))
+        .route("/stats/authors", web::get().to(stats::top_authors))
+        .route("/stats/clients", web::get().to(stats::client_usage))
+        // Configuration endpoint
+        .route("/config", web::get().to(get_config));
+}
+
+/// Main entry point of the application.
+/// 1. Loads configuration.
+/// 2. Creates a SQLite connection pool and ensures the events table exists.
+/// 3. Subscribes to certain event kinds on all relays.
+/// 4. Starts the HTTP server.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Diagnose the setup before anything else relies on it being valid.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("doctor") {
+        if let Err(e) = doctor::doctor(&args[1..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Load configuration
+    let config: AppConfig = match load_config() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Failed to read configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Loaded configuration: {}", redact::redact(&config));
+
+    let (write_pool, db_pool) = open_database(&config.database).await;
+
+    // Run a one-off subcommand against the archive instead of the server.
+    if let Some(command) = args.first() {
+        let result = match command.as_str() {
+            "export-site" => export::export_site(&args[1..], &db_pool).await,
+            "import" => import::import(&args[1..], &write_pool, &config).await,
+            "prune" => retention::prune(&args[1..], &write_pool).await,
+            "stats" => stats::print_stats(&args[1..], &db_pool).await,
+            "verify" => verify::verify(&args[1..], &write_pool).await,
+            other => Err(format!("Unknown command: {}", other)),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Refuse a rate limit that would reject every request before anything starts.
+    let rate_limiter_data = match RateLimiter::new(config.rate_limit.clone()) {
+        Ok(limiter) => web::Data::new(limiter),
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Connect to the remote signer used for publishing and relay AUTH.
+    let signer = match Signer::connect(&config.signer).await {
+        Ok(signer) => signer.map(|signer| {
+            println!("Connected to remote signer for pubkey: {}", signer.pubkey);
+            Arc::new(signer)
+        }),
+        Err(e) => {
+            eprintln!("{}, publishing and relay AUTH disabled", e);
+            None
+        }
+    };
+
+    // Start the additional archives first, so the main archive's routes do not shadow them.
+    let mut archives = Vec::new();
+    for archive in &config.archives {
+        if archive.name.is_empty()
+            || !archive
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            eprintln!("Invalid archive name: {:?}", archive.name);
+            std::process::exit(1);
+        }
+        let archive_config = archive.apply(&config);
+        let (write_pool, db_pool) = open_database(&archive_config.database).await;
+        println!("Starting archive: {}", archive.name);
+        archives.push((
+            format!("{}/{}", config.server.base_path(), archive.name),
+            start_archive(archive_config, write_pool, db_pool, signer.clone()).await,
+        ));
+    }
+    archives.push((
+        config.server.base_path(),
+        start_archive(config.clone(), write_pool, db_pool, signer).await,
+    ));
+
+    let config_data = web::Data::new(config.clone());
+
+    let mut server = HttpServer::new(move || {
+        let app = App::new()
+            // The middleware reads the main archive's configuration.
+            .app_data(config_data.clone())
+            .app_data(rate_limiter_data.clone())
+            // Render extractor failures with the same JSON error format as handlers.
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .wrap(middleware::Compress::default())
+            .wrap(middleware::from_fn(auth::auth_guard))
+            .wrap(middleware::from_fn(ratelimit::rate_limit))
+            .wrap(middleware::from_fn(access_log::log_requests));
+        archives
+            .iter()
+            .fold(app, |app, (path, archive)| app.service(archive.scope(path)))
+            .default_service(web::to(not_found))
+    });
+    let tls_config = if config.server.tls.cert.is_empty() {
+        None
+    } else {
+        Some(tls::server_config(&config.server.tls)?)
+    };
+    for address in config.server.bind_address.addresses() {
+        server = match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                // A socket left behind by a previous run would fail the bind.
+                if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                server.bind_uds(path)?
+            }
+            #[cfg(not(unix))]
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform",
+                ))
+            }
+            None => match &tls_config {
+                Some(tls_config) => server.bind_rustls_0_23(address, tls_config.clone())?,
+                None => server.bind(address)?,
+            },
+        };
+        let https = tls_config.is_some() && !address.starts_with("unix:");
+        println!(
+            "Listening on {}{}",
+            address,
+            if https { " with TLS" } else { "" }
+        );
+    }
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use db::test_event;
+
+    #[actix_web::test]
+    async fn batch_omits_direct_messages() {
+        let db_pool = db::test_pool().await;
+        let (note, dm) = (test_event('a', 1), test_event('b', 4));
+        db::store_test_events(&db_pool, &[&note, &dm]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(test_config()))
+                .app_data(web::Data::new(db_pool))
+                .route("/events/batch", web::post().to(batch_events)),
+        )
+        .await;
+        let request = TestRequest::post()
+            .uri("/events/batch?format=nostr")
+            .set_json(serde_json::json!({ "ids": [note.id, dm.id] }))
+            .to_request();
+        let events: Vec<nostr::NostrEvent> = call_and_read_body_json(&app, request).await;
+        let ids: Vec<&str> = events.iter().map(|event| event.id.as_str()).collect();
+        assert_eq!(ids, [note.id.as_str()]);
+    }
+
+    #[actix_web::test]
+    async fn long_html_and_html_addresses_coexist() {
+        let db_pool = db::test_pool().await;
+        let mut article = test_event('a', 30023);
+        article.tags = vec![vec!["d".to_string(), "html".to_string()]];
+        article.content = "# Title".to_string();
+        db::store_test_events(&db_pool, &[&article]).await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(db_pool))
+                .route("/long/{pubkey}/{d_tag}", web::get().to(get_long_by_address)),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/long/{}/html", article.id))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(
+            response
+                .headers()
+                .get(actix_web::http::header::CONTENT_TYPE)
+                .unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let page = actix_web::test::read_body(response).await;
+        assert!(String::from_utf8_lossy(&page).contains("<h1>Title</h1>"));
+
+        let request = TestRequest::get()
+            .uri(&format!("/long/{}/html", article.pubkey))
+            .to_request();
+        let stored: DbEvent = call_and_read_body_json(&app, request).await;
+        assert_eq!(stored.event_id, article.id);
+    }
+}
@@ -0,0 +1,208 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Timelike, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{nostr, MaintenanceConfig};
+
+/// Outcome of one step of a maintenance run
+#[derive(Debug, Clone, Serialize)]
+struct StepResult {
+    step: &'static str,
+    duration_ms: u64,
+    /// Step-specific figures, e.g. pages freed or WAL frames checkpointed
+    detail: Option<String>,
+    error: Option<String>,
+}
+
+/// Metrics of the maintenance task, reported by `GET /admin/maintenance`
+#[derive(Debug, Default, Clone, Serialize)]
+struct MaintenanceMetrics {
+    runs: u64,
+    failed_steps: u64,
+    /// Unix time the last run started
+    last_run: Option<i64>,
+    last_duration_ms: Option<u64>,
+    last_steps: Vec<StepResult>,
+}
+
+/// Periodic ANALYZE, vacuuming, full-text index optimization and WAL checkpointing, restricted to
+/// the configured quiet hours
+#[derive(Debug)]
+pub struct Maintenance {
+    config: MaintenanceConfig,
+    metrics: Mutex<MaintenanceMetrics>,
+}
+
+/// Returns whether `hour` lies in the window from `start` to `end` (exclusive), which may wrap
+/// around midnight
+fn in_quiet_hours(hour: u32, start: u32, end: u32) -> bool {
+    if start <= end {
+        (start..end).contains(&hour)
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+/// Reclaims free pages when the database uses incremental auto-vacuum
+async fn incremental_vacuum(db_pool: &SqlitePool, pages: u32) -> Result<String, sqlx::Error> {
+    let (auto_vacuum,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+        .fetch_one(db_pool)
+        .await?;
+    // 2 is INCREMENTAL; other modes either vacuum on commit or not at all.
+    if auto_vacuum != 2 {
+        return Ok("skipped: auto_vacuum is not incremental".to_string());
+    }
+    let (before,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+        .fetch_one(db_pool)
+        .await?;
+    // A limit of 0 frees every page on the freelist.
+    sqlx::query(&format!("PRAGMA incremental_vacuum({})", pages))
+        .execute(db_pool)
+        .await?;
+    let (after,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+        .fetch_one(db_pool)
+        .await?;
+    Ok(format!("{} pages freed", before - after))
+}
+
+/// Merges the segments of every FTS5 index into one
+async fn optimize_fts(db_pool: &SqlitePool) -> Result<String, sqlx::Error> {
+    let tables: Vec<(String,)> = sqlx::query_as(
+        "SELECT name FROM sqlite_master
+         WHERE type = 'table' AND sql LIKE 'CREATE VIRTUAL TABLE%USING fts5%'",
+    )
+    .fetch_all(db_pool)
+    .await?;
+    for (table,) in &tables {
+        let table = table.replace('"', "\"\"");
+        sqlx::query(&format!(
+            "INSERT INTO \"{0}\"(\"{0}\") VALUES ('optimize')",
+            table
+        ))
+        .execute(db_pool)
+        .await?;
+    }
+    Ok(format!("{} indexes optimized", tables.len()))
+}
+
+/// Copies the WAL into the database file and truncates it
+async fn checkpoint(db_pool: &SqlitePool) -> Result<String, sqlx::Error> {
+    let (busy, log, checkpointed): (i64, i64, i64) =
+        sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+            .fetch_one(db_pool)
+            .await?;
+    Ok(format!(
+        "{} of {} frames checkpointed{}",
+        checkpointed.max(0),
+        log.max(0),
+        if busy != 0 {
+            ", blocked by readers"
+        } else {
+            ""
+        }
+    ))
+}
+
+/// Runs a maintenance step, measuring its duration
+async fn timed(
+    step: &'static str,
+    future: impl Future<Output = Result<String, sqlx::Error>>,
+) -> StepResult {
+    let started = Instant::now();
+    let (detail, error) = match future.await {
+        Ok(detail) => ((!detail.is_empty()).then_some(detail), None),
+        Err(e) => {
+            eprintln!("Maintenance step {} failed: {:?}", step, e);
+            (None, Some(e.to_string()))
+        }
+    };
+    StepResult {
+        step,
+        duration_ms: started.elapsed().as_millis() as u64,
+        detail,
+        error,
+    }
+}
+
+impl Maintenance {
+    pub fn new(config: MaintenanceConfig) -> Self {
+        Self {
+            config,
+            metrics: Mutex::new(MaintenanceMetrics::default()),
+        }
+    }
+
+    /// Runs every maintenance step, recording their outcome
+    async fn run(&self, db_pool: &SqlitePool) {
+        let started_at = nostr::now() as i64;
+        let started = Instant::now();
+        let analyze = async {
+            sqlx::query("ANALYZE")
+                .execute(db_pool)
+                .await
+                .map(|_| String::new())
+        };
+        let steps = vec![
+            timed("analyze", analyze).await,
+            timed(
+                "incremental_vacuum",
+                incremental_vacuum(db_pool, self.config.vacuum_pages),
+            )
+            .await,
+            timed("fts_optimize", optimize_fts(db_pool)).await,
+            timed("wal_checkpoint", checkpoint(db_pool)).await,
+        ];
+
+        let duration_ms = started.elapsed().as_millis() as u64;
+        println!("Database maintenance finished in {} ms", duration_ms);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.runs += 1;
+        metrics.failed_steps += steps.iter().filter(|step| step.error.is_some()).count() as u64;
+        metrics.last_run = Some(started_at);
+        metrics.last_duration_ms = Some(duration_ms);
+        metrics.last_steps = steps;
+    }
+
+    /// Runs maintenance every `interval` seconds while the UTC hour is within the quiet hours
+    pub fn spawn(self: Arc<Self>, db_pool: SqlitePool) {
+        if !self.config.enabled {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.config.interval.max(1)));
+            loop {
+                interval.tick().await;
+                let hour = Utc::now().hour();
+                if in_quiet_hours(
+                    hour,
+                    self.config.quiet_start_hour,
+                    self.config.quiet_end_hour,
+                ) {
+                    self.run(&db_pool).await;
+                }
+            }
+        });
+    }
+}
+
+/// Reports the metrics of the database maintenance task.
+pub async fn maintenance_metrics(
+    req: HttpRequest,
+    maintenance: web::Data<Maintenance>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_maintenance", "").await?;
+    let metrics = maintenance.metrics.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": maintenance.config.enabled,
+        "metrics": metrics,
+    })))
+}
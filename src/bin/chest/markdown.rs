@@ -0,0 +1,75 @@
+use actix_web::http::header::ETAG;
+use actix_web::{HttpRequest, HttpResponse};
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::nostr::NostrEvent;
+use crate::{etag, DbEvent};
+
+/// Renders markdown to HTML, removing scripts, event handlers and unsafe URLs
+pub fn render_markdown(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+
+    ammonia::Builder::default()
+        // Articles commonly link to other nostr entities with `nostr:` URIs (NIP-21).
+        .add_url_schemes(["nostr"])
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .set_tag_attribute_value("img", "loading", "lazy")
+        .set_tag_attribute_value("img", "referrerpolicy", "no-referrer")
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+/// Escapes text for inclusion in HTML element content
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the plain text content of a note as an HTML paragraph
+pub fn render_note(content: &str) -> String {
+    format!("<p>{}</p>", escape_html(content).replace('\n', "<br>"))
+}
+
+/// Responds with a kind 30023 article rendered as a standalone HTML page.
+pub fn long_html_response(req: &HttpRequest, event: &DbEvent) -> HttpResponse {
+    let event = event.to_nostr();
+    let etag = etag::event_etag(&event.id);
+    if let Some(response) = etag::not_modified(req, &etag) {
+        return response;
+    }
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header((ETAG, etag.to_string()))
+        .body(article_page(&event))
+}
+
+/// Wraps a rendered article in a minimal HTML document
+fn article_page(event: &NostrEvent) -> String {
+    let title = escape_html(event.tag_value("title").unwrap_or("Untitled"));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n",
+        title
+    );
+    if let Some(summary) = event.tag_value("summary") {
+        page.push_str(&format!(
+            "<meta name=\"description\" content=\"{}\">\n",
+            escape_html(summary)
+        ));
+    }
+    page.push_str(&format!("</head>\n<body>\n<article>\n<h1>{}</h1>\n", title));
+    if let Some(image) = event.tag_value("image") {
+        page.push_str(&render_markdown(&format!("![]({})", image)));
+    }
+    page.push_str(&render_markdown(&event.content));
+    page.push_str("</article>\n</body>\n</html>\n");
+    page
+}
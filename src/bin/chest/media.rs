@@ -0,0 +1,508 @@
+use actix_web::http::header::{
+    CacheControl, CacheDirective, CONTENT_SECURITY_POLICY, ETAG, X_CONTENT_TYPE_OPTIONS,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool, Transaction};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::blossom;
+use crate::error::ApiError;
+use crate::etag;
+use crate::nostr::{self, NostrEvent};
+use crate::outbound;
+use crate::{AppConfig, MediaConfig};
+
+/// File extensions of the note links worth downloading; anything else is left alone
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "avif", "svg", "mp4", "webm", "mov", "m4v",
+];
+
+/// Downloads the images and videos referenced by archived events into content-addressed
+/// storage, so they can be served after the original links go dead
+#[derive(Debug)]
+pub struct MediaCache {
+    config: MediaConfig,
+    db_pool: SqlitePool,
+    client: reqwest::Client,
+    queue: mpsc::Sender<MediaRequest>,
+}
+
+/// A media file referenced by an archived event
+#[derive(Debug)]
+pub struct MediaRequest {
+    url: String,
+    /// Hash of the file when the event names it, letting it be fetched from Blossom servers
+    sha256: Option<String>,
+    /// Author of the event, whose Blossom servers are tried when the URL fails
+    pubkey: String,
+}
+
+/// Returns whether a link points to an image or video file, judging by its extension
+pub fn is_media_link(url: &url::Url) -> bool {
+    url.path().rsplit_once('.').is_some_and(|(_, extension)| {
+        MEDIA_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    })
+}
+
+/// Path a cached file is stored at, sharded by the first two characters of its hash
+pub fn media_path(directory: &str, sha256: &str) -> PathBuf {
+    Path::new(directory).join(&sha256[..2]).join(sha256)
+}
+
+/// Returns the media an event references: links to media files in the content of notes,
+/// `url` entries of `imeta` tags, and the `url` tag of NIP-94 file metadata. Hashes come from
+/// `x` tags and `imeta` entries, or from Blossom URLs themselves.
+fn media_requests(event: &NostrEvent) -> Vec<MediaRequest> {
+    let mut urls: Vec<(String, Option<String>)> = Vec::new();
+    if event.kind == 1063 {
+        if let Some(url) = event.tag_value("url") {
+            urls.push((url.to_string(), event.tag_value("x").map(str::to_lowercase)));
+        }
+    }
+    for tag in event
+        .tags
+        .iter()
+        .filter(|tag| tag.first().map(String::as_str) == Some("imeta"))
+    {
+        let entry = |name: &str| {
+            tag.iter()
+                .skip(1)
+                .find_map(|entry| entry.strip_prefix(name)?.strip_prefix(' '))
+        };
+        if let Some(url) = entry("url") {
+            urls.push((url.to_string(), entry("x").map(str::to_lowercase)));
+        }
+    }
+    if event.kind == 1 {
+        for word in event.content.split_whitespace() {
+            let Ok(url) = url::Url::parse(word) else {
+                continue;
+            };
+            if is_media_link(&url) {
+                urls.push((word.to_string(), None));
+            }
+        }
+    }
+    urls.retain(|(url, _)| url.starts_with("https://") || url.starts_with("http://"));
+    // The first mention of a URL is kept, as tags name the hash that content links lack.
+    let mut seen = HashSet::new();
+    urls.retain(|(url, _)| seen.insert(url.clone()));
+    urls.into_iter()
+        .map(|(url, sha256)| MediaRequest {
+            sha256: sha256
+                .filter(|sha256| nostr::is_hex32(sha256))
+                .or_else(|| blossom::blob_hash(&url)),
+            url,
+            pubkey: event.pubkey.clone(),
+        })
+        .collect()
+}
+
+impl MediaCache {
+    /// Returns `None` when media caching is disabled
+    pub fn new(
+        config: &MediaConfig,
+        db_pool: SqlitePool,
+    ) -> Option<(Self, mpsc::Receiver<MediaRequest>)> {
+        if !config.enabled {
+            return None;
+        }
+        let client = outbound::client(Duration::from_secs(config.timeout.max(1))).ok()?;
+        let (queue, receiver) = mpsc::channel(config.max_pending.max(1));
+        Some((
+            Self {
+                config: config.clone(),
+                db_pool,
+                client,
+                queue,
+            },
+            receiver,
+        ))
+    }
+
+    /// Queues the media referenced by a newly archived event for download. URLs are dropped
+    /// while the queue is full.
+    pub fn enqueue(&self, event: &NostrEvent) {
+        for request in media_requests(event) {
+            if self.queue.try_send(request).is_err() {
+                eprintln!("Media download queue full, skipping media of {}", event.id);
+                break;
+            }
+        }
+    }
+
+    /// Returns whether a mime type is allowed, either listed exactly or by a `type/` prefix
+    fn allows(&self, mime: &str) -> bool {
+        self.config.mime_types.iter().any(|allowed| {
+            if allowed.ends_with('/') {
+                mime.starts_with(allowed.as_str())
+            } else {
+                mime == allowed
+            }
+        })
+    }
+
+    /// Returns whether a file is already cached
+    async fn is_cached(&self, sha256: &str) -> Result<bool, String> {
+        let (cached,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM media WHERE sha256 = ?)")
+                .bind(sha256)
+                .fetch_one(&self.db_pool)
+                .await
+                .map_err(|e| format!("Failed to look up media: {}", e))?;
+        Ok(cached)
+    }
+
+    /// Caches a referenced file from its URL, falling back to the author's Blossom servers
+    /// when the URL fails and the file's hash is known. Returns the hash of the cached file, or
+    /// `None` when it was skipped.
+    async fn cache(&self, request: &MediaRequest) -> Result<Option<String>, String> {
+        if let Some(sha256) = &request.sha256 {
+            if self.is_cached(sha256).await? {
+                return Ok(None);
+            }
+        }
+        let error = match self.download(&request.url, request.sha256.as_deref()).await {
+            Ok(cached) => return Ok(cached),
+            Err(e) => e,
+        };
+        let Some(sha256) = request.sha256.as_deref().filter(|_| self.config.blossom) else {
+            return Err(error);
+        };
+        let servers = blossom::servers(&self.db_pool, &request.pubkey)
+            .await
+            .map_err(|e| format!("Failed to look up Blossom servers: {}", e))?;
+        for server in servers {
+            let url = blossom::blob_url(&server, sha256);
+            match self.download(&url, Some(sha256)).await {
+                Ok(cached) => return Ok(cached),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        Err(error)
+    }
+
+    /// Downloads a URL into the cache, checking the file against its expected hash if known.
+    /// Returns the hash of the file, or `None` when it was skipped.
+    async fn download(&self, url: &str, expected: Option<&str>) -> Result<Option<String>, String> {
+        let (cached,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM media WHERE url = ?)")
+                .bind(url)
+                .fetch_one(&self.db_pool)
+                .await
+                .map_err(|e| format!("Failed to look up media: {}", e))?;
+        if cached {
+            return Ok(None);
+        }
+
+        // Both note links and Blossom server lists come from untrusted events.
+        let response = self
+            .client
+            .get(outbound::check_url(url)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+        let mime = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if !self.allows(&mime) {
+            return Ok(None);
+        }
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.config.max_file_size)
+        {
+            return Ok(None);
+        }
+
+        // Files are written under a temporary name while hashed, then moved to their address.
+        let directory = Path::new(&self.config.directory);
+        tokio::fs::create_dir_all(directory)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+        let partial = directory.join(format!("{}.partial", Uuid::new_v4()));
+        let result = self.write_partial(response, &partial).await;
+        let (sha256, size) = match result {
+            Ok(Some(written)) => written,
+            Ok(None) | Err(_) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return result.map(|_| None);
+            }
+        };
+        if expected.is_some_and(|expected| expected != sha256) {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(format!("Hash mismatch for {}: got {}", url, sha256));
+        }
+
+        let (total,): (i64,) = sqlx::query_as(
+            "SELECT COALESCE(SUM(size), 0) FROM (SELECT DISTINCT sha256, size FROM media)",
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| format!("Failed to sum media sizes: {}", e))?;
+        let path = media_path(&self.config.directory, &sha256);
+        let exists = tokio::fs::try_exists(&path).await.unwrap_or(false);
+        if !exists
+            && self.config.max_total_size > 0
+            && total as u64 + size > self.config.max_total_size
+        {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(None);
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|e| format!("Failed to move {}: {}", partial.display(), e))?;
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO media (url, sha256, mime, size, fetched_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(url)
+        .bind(&sha256)
+        .bind(&mime)
+        .bind(size as i64)
+        .bind(nostr::now() as i64)
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("Failed to record media: {}", e))?;
+        Ok(Some(sha256))
+    }
+
+    /// Streams a response body to a file while hashing it, returning its hash and size, or
+    /// `None` once it exceeds `max_file_size`
+    async fn write_partial(
+        &self,
+        response: reqwest::Response,
+        partial: &Path,
+    ) -> Result<Option<(String, u64)>, String> {
+        let mut file = tokio::fs::File::create(partial)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", partial.display(), e))?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| format!("Failed to download media: {}", e))?;
+            size += chunk.len() as u64;
+            if size > self.config.max_file_size {
+                return Ok(None);
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+        }
+        file.flush()
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+        Ok(Some((hex::encode(hasher.finalize()), size)))
+    }
+
+    /// Downloads queued media one at a time in the background
+    pub fn spawn(self: std::sync::Arc<Self>, mut receiver: mpsc::Receiver<MediaRequest>) {
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                match self.cache(&request).await {
+                    Ok(Some(sha256)) => println!("Cached media {} as {}", request.url, sha256),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Forgets the cached media referenced by the events and previous versions whose `column` is
+/// `value`, e.g. every event of a pubkey, within a moderation transaction. Every URL of a file
+/// is forgotten, so removed content is not served under another link either. Returns the
+/// hashes of the files to delete once the transaction commits.
+pub async fn forget_media(
+    tx: &mut Transaction<'_, Sqlite>,
+    column: &str,
+    value: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut urls = Vec::new();
+    for table in ["events", "event_versions"] {
+        let rows: Vec<(String, i64, i64, String, String)> = sqlx::query_as(&format!(
+            "SELECT pubkey, created_at, kind, content, tags FROM {} WHERE {} = ?
+               AND (content LIKE '%http%' OR tags LIKE '%http%')",
+            table, column
+        ))
+        .bind(value)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (pubkey, created_at, kind, content, tags) in rows {
+            let event = NostrEvent {
+                id: String::new(),
+                pubkey,
+                created_at: created_at as u64,
+                kind: kind as u64,
+                tags: serde_json::from_str(&tags).unwrap_or_default(),
+                content,
+                sig: String::new(),
+            };
+            urls.extend(
+                media_requests(&event)
+                    .into_iter()
+                    .map(|request| request.url),
+            );
+        }
+    }
+
+    let mut hashes = BTreeSet::new();
+    for batch in urls.chunks(500) {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT sha256 FROM media WHERE url IN (");
+        let mut separated = query.separated(", ");
+        for url in batch {
+            separated.push_bind(url);
+        }
+        query.push(")");
+        let rows: Vec<(String,)> = query.build_query_as().fetch_all(&mut *tx).await?;
+        hashes.extend(rows.into_iter().map(|(sha256,)| sha256));
+    }
+    let hashes: Vec<String> = hashes.into_iter().collect();
+    for batch in hashes.chunks(500) {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM media WHERE sha256 IN (");
+        let mut separated = query.separated(", ");
+        for sha256 in batch {
+            separated.push_bind(sha256);
+        }
+        query.push(")");
+        query.build().execute(&mut *tx).await?;
+    }
+    Ok(hashes)
+}
+
+/// Deletes the files of forgotten media from the cache directory
+pub async fn remove_files(directory: &str, hashes: &[String]) {
+    for sha256 in hashes {
+        let path = media_path(directory, sha256);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Serves a cached media file by its SHA-256 hash. Files are served with their remote mime
+/// type, so they are sandboxed and never sniffed: an SVG or HTML file cannot run scripts on the
+/// API's origin.
+pub async fn get_media(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+    config: web::Data<AppConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let sha256 = path.into_inner().to_lowercase();
+    if !nostr::is_hex32(&sha256) {
+        return Err(ApiError::BadRequest(format!("Invalid sha256: {}", sha256)));
+    }
+    let mime: Option<(String,)> = sqlx::query_as("SELECT mime FROM media WHERE sha256 = ? LIMIT 1")
+        .bind(&sha256)
+        .fetch_optional(db_pool.get_ref())
+        .await?;
+    let (mime,) = mime.ok_or_else(|| ApiError::NotFound("Media not found".to_string()))?;
+
+    // Content never changes for a given hash.
+    let etag = actix_web::http::header::EntityTag::new_strong(sha256.clone());
+    if let Some(response) = etag::not_modified(&req, &etag) {
+        return Ok(response);
+    }
+    let body = tokio::fs::read(media_path(&config.media.directory, &sha256))
+        .await
+        .map_err(|_| ApiError::NotFound("Media not found".to_string()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime)
+        .insert_header((ETAG, etag.to_string()))
+        .insert_header((X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((CONTENT_SECURITY_POLICY, "sandbox"))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(31_536_000),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ]))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_urls_are_requested_once() {
+        let hash = "a".repeat(64);
+        let event = NostrEvent {
+            id: String::new(),
+            pubkey: String::new(),
+            created_at: 0,
+            kind: 1,
+            tags: vec![vec![
+                "imeta".to_string(),
+                "url https://example.com/a.png".to_string(),
+                format!("x {}", hash),
+            ]],
+            content:
+                "https://example.com/a.png https://example.com/b.jpg https://example.com/a.png"
+                    .to_string(),
+            sig: String::new(),
+        };
+        let requests = media_requests(&event);
+        let urls: Vec<&str> = requests
+            .iter()
+            .map(|request| request.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            ["https://example.com/a.png", "https://example.com/b.jpg"]
+        );
+        assert_eq!(requests[0].sha256.as_deref(), Some(hash.as_str()));
+    }
+
+    #[tokio::test]
+    async fn forgets_every_url_of_removed_media() {
+        let db_pool = crate::db::test_pool().await;
+        let event_id = "b".repeat(64);
+        sqlx::query(
+            "INSERT INTO events (event_id, pubkey, created_at, kind, content, sig, tags, folder)
+             VALUES (?, 'p', 1, 1, 'look https://example.com/a.png', '', '[]', 'notes')",
+        )
+        .bind(&event_id)
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        for url in ["https://example.com/a.png", "https://mirror.example/a.png"] {
+            sqlx::query("INSERT INTO media (url, sha256, mime, size, fetched_at) VALUES (?, 'h', 'image/png', 1, 1)")
+                .bind(url)
+                .execute(&db_pool)
+                .await
+                .unwrap();
+        }
+
+        let mut tx = db_pool.begin().await.unwrap();
+        let hashes = forget_media(&mut tx, "event_id", &event_id).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(hashes, ["h"]);
+        let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM media")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
@@ -0,0 +1,139 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::{Sqlite, Transaction};
+
+use crate::audit;
+use crate::cache::EventCache;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::media;
+use crate::nostr;
+use crate::AppConfig;
+
+/// Query parameters for the admin delete endpoints
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    /// Why the content is removed, kept in the audit log and blocklist
+    reason: Option<String>,
+}
+
+/// Removes an archived event and blocklists its id so it is not archived again.
+pub async fn delete_event(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<DeleteQuery>,
+    config: web::Data<AppConfig>,
+    write_pool: web::Data<WritePool>,
+    cache: web::Data<EventCache>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let event_id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", input)))?;
+    let reason = params.reason.as_deref();
+
+    let mut tx = write_pool.0.begin().await?;
+    // Removed content must not stay downloadable from the media cache.
+    let media = media::forget_media(&mut tx, "event_id", &event_id).await?;
+    let deleted = sqlx::query("DELETE FROM events WHERE event_id = ?")
+        .bind(&event_id)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    sqlx::query("DELETE FROM event_versions WHERE event_id = ?")
+        .bind(&event_id)
+        .execute(&mut tx)
+        .await?;
+    // The segment keeps the event's data, but without its index row it cannot be read.
+    sqlx::query("DELETE FROM cold_events WHERE event_id = ?")
+        .bind(&event_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO banned_events (event_id, banned_at, reason) VALUES (?, ?, ?)",
+    )
+    .bind(&event_id)
+    .bind(nostr::now() as i64)
+    .bind(reason)
+    .execute(&mut tx)
+    .await?;
+    audit::record(
+        &mut tx,
+        &audit::actor(&req),
+        "delete_event",
+        &event_id,
+        reason,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    cache.invalidate_event(&event_id);
+    media::remove_files(&config.media.directory, &media).await;
+
+    println!("Deleted event {} ({} rows)", event_id, deleted);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "event_id": event_id,
+        "deleted": deleted,
+    })))
+}
+
+/// Removes every archived event of a pubkey and blocklists the pubkey within a transaction,
+/// recording the action in the audit log. Returns the number of events removed and the hashes
+/// of the cached media to delete once the transaction commits.
+pub async fn remove_pubkey(
+    tx: &mut Transaction<'_, Sqlite>,
+    actor: &str,
+    pubkey: &str,
+    reason: Option<&str>,
+) -> Result<(u64, Vec<String>), sqlx::Error> {
+    let media = media::forget_media(tx, "pubkey", pubkey).await?;
+    let deleted = sqlx::query("DELETE FROM events WHERE pubkey = ?")
+        .bind(pubkey)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query("DELETE FROM event_versions WHERE pubkey = ?")
+        .bind(pubkey)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM cold_events WHERE pubkey = ?")
+        .bind(pubkey)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO banned_pubkeys (pubkey, banned_at, reason) VALUES (?, ?, ?)",
+    )
+    .bind(pubkey)
+    .bind(nostr::now() as i64)
+    .bind(reason)
+    .execute(&mut *tx)
+    .await?;
+    audit::record(&mut *tx, actor, "delete_pubkey", pubkey, reason, None).await?;
+    Ok((deleted, media))
+}
+
+/// Removes every archived event of a pubkey and blocklists the pubkey.
+pub async fn delete_pubkey(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<DeleteQuery>,
+    config: web::Data<AppConfig>,
+    write_pool: web::Data<WritePool>,
+    cache: web::Data<EventCache>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    let reason = params.reason.as_deref();
+
+    let mut tx = write_pool.0.begin().await?;
+    let (deleted, media) = remove_pubkey(&mut tx, &audit::actor(&req), &pubkey, reason).await?;
+    tx.commit().await?;
+    cache.invalidate_pubkey(&pubkey);
+    media::remove_files(&config.media.directory, &media).await;
+
+    println!("Deleted {} events of pubkey {}", deleted, pubkey);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "pubkey": pubkey,
+        "deleted": deleted,
+    })))
+}
@@ -0,0 +1,93 @@
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use std::time::Duration;
+
+use crate::nostr::NostrEvent;
+use crate::{dms, MqttConfig};
+
+/// Messages queued for the broker before new events are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Seconds to wait before reconnecting to an unreachable broker
+const RECONNECT_DELAY: u64 = 5;
+
+/// Publishes newly archived events to an MQTT broker under `{topic_prefix}/{kind}/{pubkey}`
+#[derive(Debug)]
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: AsyncClient,
+}
+
+impl MqttBridge {
+    /// Connects to the configured broker, returning `None` when the bridge is disabled. The
+    /// connection is driven by a background task that reconnects after failures.
+    pub fn start(config: &MqttConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if !config.username.is_empty() {
+            options.set_credentials(&config.username, &config.password);
+        }
+        let (client, mut event_loop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let host = config.host.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = event_loop.poll().await {
+                    eprintln!("MQTT connection to {} failed: {}", host, e);
+                    tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY)).await;
+                }
+            }
+        });
+        Some(Self {
+            config: config.clone(),
+            client,
+        })
+    }
+
+    /// Returns whether an event passes the configured kind and pubkey filters. Direct messages
+    /// never do.
+    fn matches(&self, event: &NostrEvent) -> bool {
+        !dms::is_direct_message(event)
+            && (self.config.kinds.is_empty() || self.config.kinds.contains(&event.kind))
+            && (self.config.pubkeys.is_empty() || self.config.pubkeys.contains(&event.pubkey))
+    }
+
+    /// Queues an event as NIP-01 JSON for the broker; events are dropped while the queue is full
+    pub fn publish(&self, event: &NostrEvent) {
+        if !self.matches(event) {
+            return;
+        }
+        let topic = format!(
+            "{}/{}/{}",
+            self.config.topic_prefix, event.kind, event.pubkey
+        );
+        let qos = match self.config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        };
+        let payload = serde_json::to_vec(event).unwrap_or_default();
+        if let Err(e) = self.client.try_publish(topic, qos, false, payload) {
+            eprintln!("Failed to queue event {} for MQTT: {}", event.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_event;
+
+    #[tokio::test]
+    async fn direct_messages_are_not_published() {
+        let config = MqttConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let bridge = MqttBridge::start(&config).unwrap();
+        assert!(bridge.matches(&test_event('a', 1)));
+        assert!(!bridge.matches(&test_event('b', 4)));
+        assert!(!bridge.matches(&test_event('c', 1059)));
+    }
+}
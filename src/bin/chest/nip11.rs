@@ -0,0 +1,131 @@
+use actix_web::http::header::ACCEPT;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+use url::Url;
+
+use crate::error::ApiError;
+use crate::{tls, AppConfig, TlsConfig};
+
+/// Media type clients send to request the relay information document
+const NOSTR_JSON: &str = "application/nostr+json";
+
+/// Relay limitations advertised in the information document
+#[derive(Debug, Serialize)]
+struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_message_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_subscriptions: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_filters: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_limit: Option<u64>,
+    auth_required: bool,
+    payment_required: bool,
+    restricted_writes: bool,
+}
+
+/// NIP-11 relay information document
+#[derive(Debug, Serialize)]
+struct RelayInformation<'a> {
+    name: &'a str,
+    description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contact: Option<&'a str>,
+    supported_nips: &'a [u32],
+    software: &'static str,
+    version: &'static str,
+    limitation: Limitation,
+}
+
+/// Serves the NIP-11 document for requests to the root that accept `application/nostr+json`.
+pub async fn relay_information(req: HttpRequest, config: web::Data<AppConfig>) -> HttpResponse {
+    let wants_nostr_json = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(NOSTR_JSON));
+    if !wants_nostr_json {
+        return ApiError::NotFound("Route not found".to_string()).error_response();
+    }
+
+    let info = &config.relay_info;
+    let document = RelayInformation {
+        name: &info.name,
+        description: &info.description,
+        pubkey: info.pubkey.as_deref(),
+        contact: info.contact.as_deref(),
+        supported_nips: &info.supported_nips,
+        software: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        limitation: Limitation {
+            max_message_length: info.max_message_length,
+            max_subscriptions: info.max_subscriptions,
+            max_filters: info.max_filters,
+            max_limit: info.max_limit,
+            auth_required: config.auth.protect_reads,
+            payment_required: false,
+            // chest only archives events it pulls from upstream relays.
+            restricted_writes: true,
+        },
+    };
+
+    HttpResponse::Ok()
+        .content_type(NOSTR_JSON)
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .insert_header(("Access-Control-Allow-Headers", "*"))
+        .insert_header(("Access-Control-Allow-Methods", "GET"))
+        .json(document)
+}
+
+/// Subset of a remote relay's information document that chest cares about
+#[derive(Debug, Deserialize, Default)]
+pub struct RemoteRelayInformation {
+    #[serde(default)]
+    pub supported_nips: Vec<u32>,
+    #[serde(default)]
+    pub limitation: RemoteLimitation,
+}
+
+/// Restrictions a remote relay advertises
+#[derive(Debug, Deserialize, Default)]
+pub struct RemoteLimitation {
+    #[serde(default)]
+    pub payment_required: bool,
+    /// Subscriptions a connection may keep open
+    pub max_subscriptions: Option<u64>,
+    /// Filters a single REQ may carry
+    pub max_filters: Option<u64>,
+    /// Largest `limit` the relay honors
+    pub max_limit: Option<u64>,
+}
+
+/// Fetches the NIP-11 document of an upstream relay with the relay's TLS settings
+pub async fn fetch(
+    relay_url: &str,
+    tls: &TlsConfig,
+) -> Result<RemoteRelayInformation, Box<dyn Error>> {
+    let mut url = Url::parse(relay_url)?;
+    let scheme = if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| "cannot convert relay URL to HTTP")?;
+
+    let response = tls::http_client(tls)?
+        .get(url)
+        .header(ACCEPT.as_str(), NOSTR_JSON)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?;
+    tls::check_response(tls, &response)?;
+    let info = response.json().await?;
+    Ok(info)
+}
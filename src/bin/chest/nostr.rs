@@ -0,0 +1,600 @@
+use bech32::FromBase32;
+use secp256k1::{schnorr::Signature, Message, XOnlyPublicKey, SECP256K1};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Nostr event structure
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NostrEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u64,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl NostrEvent {
+    /// Computes the NIP-01 event id: sha256 of the serialized `[0, pubkey, created_at, kind, tags, content]`
+    pub fn compute_id(&self) -> String {
+        let serialized = serde_json::json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ]);
+        hex::encode(Sha256::digest(serialized.to_string().as_bytes()))
+    }
+
+    /// Checks that the id matches the event content and that `sig` is a valid schnorr signature over it
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        if self.compute_id() != self.id {
+            return Err("event id does not match its content".into());
+        }
+        let id = hex::decode(&self.id)?;
+        let pubkey = XOnlyPublicKey::from_slice(&hex::decode(&self.pubkey)?)?;
+        let sig = Signature::from_slice(&hex::decode(&self.sig)?)?;
+        SECP256K1.verify_schnorr(&sig, &Message::from_digest_slice(&id)?, &pubkey)?;
+        Ok(())
+    }
+
+    /// Checks the shape of an event before it is archived: `id` and `pubkey` must be 32 bytes and
+    /// `sig` 64 bytes of lowercase hex, `kind` within NIP-01's 0 to 65535, and every tag a
+    /// non-empty array of strings (the strings are enforced when deserializing)
+    pub fn check_structure(&self) -> Result<(), String> {
+        if !is_hex32(&self.id) {
+            return Err(format!("invalid id {:?}", self.id));
+        }
+        if !is_hex32(&self.pubkey) {
+            return Err(format!("invalid pubkey {:?}", self.pubkey));
+        }
+        if self.sig.len() != 128 || !is_lower_hex(&self.sig) {
+            return Err(format!("invalid sig {:?}", self.sig));
+        }
+        if self.kind > MAX_KIND {
+            return Err(format!("kind {} out of range", self.kind));
+        }
+        if self.tags.iter().any(Vec::is_empty) {
+            return Err("empty tag".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the NIP-13 proof-of-work difficulty: the number of leading zero bits of the id
+    pub fn difficulty(&self) -> u32 {
+        let mut bits = 0;
+        for digit in self.id.chars().map(|c| c.to_digit(16).unwrap_or(0)) {
+            if digit == 0 {
+                bits += 4;
+            } else {
+                bits += digit.leading_zeros() - 28;
+                break;
+            }
+        }
+        bits
+    }
+
+    /// Returns the target difficulty committed to in the NIP-13 `nonce` tag
+    pub fn committed_difficulty(&self) -> Option<u32> {
+        self.tags
+            .iter()
+            .find(|tag| tag.len() >= 3 && tag[0] == "nonce")
+            .and_then(|tag| tag[2].parse().ok())
+    }
+
+    /// Returns the delegator of an event carrying a NIP-26 `delegation` tag, when the event's own
+    /// signature verifies, the delegator's token signs this pubkey and the conditions, and the
+    /// event meets them. A valid tag copied onto a forged event must not attribute it to the
+    /// delegator, hence the first check.
+    pub fn delegator(&self) -> Option<String> {
+        let tag = self
+            .tags
+            .iter()
+            .find(|tag| tag.len() >= 4 && tag[0] == "delegation")?;
+        let (delegator, conditions, token) = (&tag[1], &tag[2], &tag[3]);
+        if !is_hex32(delegator) || !self.meets_conditions(conditions) {
+            return None;
+        }
+        self.verify().ok()?;
+        let digest: [u8; 32] =
+            Sha256::digest(format!("nostr:delegation:{}:{}", self.pubkey, conditions).as_bytes())
+                .into();
+        let pubkey = XOnlyPublicKey::from_slice(&hex::decode(delegator).ok()?).ok()?;
+        let sig = Signature::from_slice(&hex::decode(token).ok()?).ok()?;
+        SECP256K1
+            .verify_schnorr(&sig, &Message::from_digest(digest), &pubkey)
+            .ok()?;
+        Some(delegator.clone())
+    }
+
+    /// Checks a NIP-26 conditions string such as `kind=1&created_at>1700000000`: the event must
+    /// be of one of the `kind` clauses, if any, and meet every `created_at` bound. Unknown
+    /// clauses fail the check.
+    fn meets_conditions(&self, conditions: &str) -> bool {
+        let mut kinds = Vec::new();
+        for clause in conditions.split('&').filter(|clause| !clause.is_empty()) {
+            let met = if let Some(kind) = clause.strip_prefix("kind=") {
+                match kind.parse::<u64>() {
+                    Ok(kind) => {
+                        kinds.push(kind);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            } else if let Some(time) = clause.strip_prefix("created_at<") {
+                time.parse().is_ok_and(|time: u64| self.created_at < time)
+            } else if let Some(time) = clause.strip_prefix("created_at>") {
+                time.parse().is_ok_and(|time: u64| self.created_at > time)
+            } else {
+                false
+            };
+            if !met {
+                return false;
+            }
+        }
+        kinds.is_empty() || kinds.contains(&self.kind)
+    }
+
+    /// Returns the first value of the first tag with the given name
+    pub fn tag_value(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+/// Current unix time in seconds, the unit of `created_at`
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Highest event kind NIP-01 defines
+const MAX_KIND: u64 = 65535;
+
+/// Returns whether `s` is a 32-byte lowercase hex string
+pub fn is_hex32(s: &str) -> bool {
+    s.len() == 64 && is_lower_hex(s)
+}
+
+/// Returns whether `s` only holds lowercase hex digits
+fn is_lower_hex(s: &str) -> bool {
+    s.bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Returns the amount of a BOLT11 invoice in millisatoshis, `None` if it has no amount
+pub fn bolt11_msats(invoice: &str) -> Option<u64> {
+    let invoice = invoice.to_lowercase();
+    let hrp = &invoice[..invoice.rfind('1')?];
+    // The currency prefix (`lnbc`, `lntb`, `lnbcrt`, ...) is followed by the amount digits.
+    let amount = hrp
+        .strip_prefix("ln")?
+        .trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let digits_end = amount
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(amount.len());
+    let (digits, multiplier) = amount.split_at(digits_end);
+    let value: u64 = digits.parse().ok()?;
+    // Amounts are denominated in bitcoin, 10^11 millisatoshis.
+    match multiplier {
+        "" => value.checked_mul(100_000_000_000),
+        "m" => value.checked_mul(100_000_000),
+        "u" => value.checked_mul(100_000),
+        "n" => value.checked_mul(100),
+        "p" => Some(value / 10),
+        _ => None,
+    }
+}
+
+/// Decodes a NIP-19 bech32 entity (optionally prefixed with `nostr:`) into its prefix and payload
+fn decode_bech32(input: &str) -> Option<(String, Vec<u8>)> {
+    let input = input.strip_prefix("nostr:").unwrap_or(input);
+    let (hrp, data, _) = bech32::decode(input).ok()?;
+    let bytes = Vec::<u8>::from_base32(&data).ok()?;
+    Some((hrp, bytes))
+}
+
+/// Returns the values of every TLV record of the given type in a NIP-19 payload
+fn tlv_values(data: &[u8], wanted: u8) -> Vec<&[u8]> {
+    let mut values = Vec::new();
+    let mut rest = data;
+    while let [kind, len, tail @ ..] = rest {
+        let len = *len as usize;
+        if tail.len() < len {
+            break;
+        }
+        if *kind == wanted {
+            values.push(&tail[..len]);
+        }
+        rest = &tail[len..];
+    }
+    values
+}
+
+/// Finds the value of the first TLV record of the given type in a NIP-19 payload
+fn tlv_value(data: &[u8], wanted: u8) -> Option<&[u8]> {
+    tlv_values(data, wanted).into_iter().next()
+}
+
+/// Returns the relay hints of an `nevent1...`, `nprofile1...` or `naddr1...` entity
+pub fn relay_hints(input: &str) -> Vec<String> {
+    let Some((hrp, data)) = decode_bech32(input.trim()) else {
+        return Vec::new();
+    };
+    if !matches!(hrp.as_str(), "nevent" | "nprofile" | "naddr") {
+        return Vec::new();
+    }
+    tlv_values(&data, 1)
+        .into_iter()
+        .filter_map(|relay| String::from_utf8(relay.to_vec()).ok())
+        .collect()
+}
+
+/// Parses an event id given as hex, `note1...` or `nevent1...`, returning it as hex
+pub fn parse_event_id(input: &str) -> Option<String> {
+    let input = input.trim();
+    if is_hex32(input) {
+        return Some(input.to_string());
+    }
+    let (hrp, data) = decode_bech32(input)?;
+    let id = match hrp.as_str() {
+        "note" => data.as_slice(),
+        "nevent" => tlv_value(&data, 0)?,
+        _ => return None,
+    };
+    (id.len() == 32).then(|| hex::encode(id))
+}
+
+/// Parses a pubkey given as hex, `npub1...` or `nprofile1...`, returning it as hex
+pub fn parse_pubkey(input: &str) -> Option<String> {
+    let input = input.trim();
+    if is_hex32(input) {
+        return Some(input.to_string());
+    }
+    let (hrp, data) = decode_bech32(input)?;
+    let pubkey = match hrp.as_str() {
+        "npub" => data.as_slice(),
+        "nprofile" => tlv_value(&data, 0)?,
+        _ => return None,
+    };
+    (pubkey.len() == 32).then(|| hex::encode(pubkey))
+}
+
+/// Coordinates of a parameterized replaceable event
+#[derive(Debug, Clone)]
+pub struct Address {
+    pub kind: u64,
+    pub pubkey: String,
+    pub identifier: String,
+}
+
+impl Address {
+    /// Formats the address as the `<kind>:<pubkey>:<d tag>` value of an `a` tag
+    pub fn coordinate(&self) -> String {
+        format!("{}:{}:{}", self.kind, self.pubkey, self.identifier)
+    }
+}
+
+/// Parses an `a` tag value `<kind>:<pubkey>:<d tag>`
+pub fn parse_coordinate(input: &str) -> Option<Address> {
+    let mut parts = input.splitn(3, ':');
+    let kind = parts.next()?.parse().ok()?;
+    let pubkey = parts.next().filter(|pubkey| is_hex32(pubkey))?;
+    Some(Address {
+        kind,
+        pubkey: pubkey.to_string(),
+        identifier: parts.next()?.to_string(),
+    })
+}
+
+/// Parses an `naddr1...` entity into the address it points to
+pub fn parse_naddr(input: &str) -> Option<Address> {
+    let (hrp, data) = decode_bech32(input.trim())?;
+    if hrp != "naddr" {
+        return None;
+    }
+    let identifier = String::from_utf8(tlv_value(&data, 0)?.to_vec()).ok()?;
+    let pubkey = tlv_value(&data, 2).filter(|pubkey| pubkey.len() == 32)?;
+    let kind: [u8; 4] = tlv_value(&data, 3)?.try_into().ok()?;
+    Some(Address {
+        kind: u32::from_be_bytes(kind) as u64,
+        pubkey: hex::encode(pubkey),
+        identifier,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bech32::{ToBase32, Variant};
+    use secp256k1::Keypair;
+
+    fn encode_bech32(hrp: &str, data: &[u8]) -> String {
+        bech32::encode(hrp, data.to_base32(), Variant::Bech32).unwrap()
+    }
+
+    /// Encodes a NIP-19 TLV record
+    fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+        [&[kind, value.len() as u8], value].concat()
+    }
+
+    fn event_at(kind: u64, created_at: u64) -> NostrEvent {
+        NostrEvent {
+            id: String::new(),
+            pubkey: String::new(),
+            created_at,
+            kind,
+            tags: Vec::new(),
+            content: String::new(),
+            sig: String::new(),
+        }
+    }
+
+    fn keypair() -> Keypair {
+        Keypair::new(SECP256K1, &mut rand::thread_rng())
+    }
+
+    fn sign_digest(keypair: &Keypair, digest: [u8; 32]) -> String {
+        SECP256K1
+            .sign_schnorr(&Message::from_digest(digest), keypair)
+            .to_string()
+    }
+
+    fn signed_event(
+        keypair: &Keypair,
+        kind: u64,
+        created_at: u64,
+        tags: Vec<Vec<String>>,
+    ) -> NostrEvent {
+        let mut event = NostrEvent {
+            id: String::new(),
+            pubkey: keypair.x_only_public_key().0.to_string(),
+            created_at,
+            kind,
+            tags,
+            content: "hello".to_string(),
+            sig: String::new(),
+        };
+        event.id = event.compute_id();
+        let mut digest = [0u8; 32];
+        hex::decode_to_slice(&event.id, &mut digest).unwrap();
+        event.sig = sign_digest(keypair, digest);
+        event
+    }
+
+    /// Signs a NIP-26 delegation of `delegatee` under `conditions` and returns its tag
+    fn delegation_tag(delegator: &Keypair, delegatee: &Keypair, conditions: &str) -> Vec<String> {
+        let digest = Sha256::digest(format!(
+            "nostr:delegation:{}:{}",
+            delegatee.x_only_public_key().0,
+            conditions
+        ));
+        vec![
+            "delegation".to_string(),
+            delegator.x_only_public_key().0.to_string(),
+            conditions.to_string(),
+            sign_digest(delegator, digest.into()),
+        ]
+    }
+
+    #[test]
+    fn event_ids() {
+        let id = "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e";
+        let bytes = hex::decode(id).unwrap();
+        let note = encode_bech32("note", &bytes);
+        assert_eq!(parse_event_id(&note).as_deref(), Some(id));
+        assert_eq!(
+            parse_event_id(&format!(" nostr:{} ", note)).as_deref(),
+            Some(id)
+        );
+        assert_eq!(parse_event_id(id).as_deref(), Some(id));
+        let nevent = encode_bech32("nevent", &[tlv(1, b"wss://r"), tlv(0, &bytes)].concat());
+        assert_eq!(parse_event_id(&nevent).as_deref(), Some(id));
+
+        // Other entities, short ids, uppercase hex and broken checksums are refused.
+        assert_eq!(parse_event_id(&encode_bech32("npub", &bytes)), None);
+        assert_eq!(parse_event_id(&encode_bech32("note", &bytes[..31])), None);
+        assert_eq!(parse_event_id(&id.to_uppercase()), None);
+        let last = if note.ends_with('q') { "p" } else { "q" };
+        assert_eq!(
+            parse_event_id(&format!("{}{}", &note[..note.len() - 1], last)),
+            None
+        );
+        assert_eq!(decode_bech32("nostr:"), None);
+    }
+
+    #[test]
+    fn tlv_records() {
+        let data = [tlv(1, b"a"), tlv(0, b"id"), tlv(1, b"b")].concat();
+        assert_eq!(tlv_value(&data, 1), Some(b"a".as_slice()));
+        assert_eq!(tlv_value(&data, 0), Some(b"id".as_slice()));
+        assert_eq!(tlv_value(&data, 2), None);
+        // A record longer than the payload ends it.
+        let truncated = [tlv(0, b"a"), vec![1, 5, b'b']].concat();
+        assert_eq!(tlv_value(&truncated, 0), Some(b"a".as_slice()));
+        assert_eq!(tlv_value(&truncated, 1), None);
+    }
+
+    #[test]
+    fn pubkeys() {
+        let pubkey = "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e";
+        let npub = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg";
+        assert_eq!(parse_pubkey(npub).as_deref(), Some(pubkey));
+        assert_eq!(
+            parse_pubkey(&format!(" nostr:{} ", npub)).as_deref(),
+            Some(pubkey)
+        );
+        assert_eq!(parse_pubkey(pubkey).as_deref(), Some(pubkey));
+        let bytes = hex::decode(pubkey).unwrap();
+        let nprofile = encode_bech32("nprofile", &[tlv(0, &bytes), tlv(1, b"wss://r")].concat());
+        assert_eq!(parse_pubkey(&nprofile).as_deref(), Some(pubkey));
+
+        assert_eq!(parse_pubkey(&encode_bech32("note", &bytes)), None);
+        assert_eq!(parse_pubkey(&encode_bech32("npub", &bytes[..31])), None);
+        assert_eq!(parse_pubkey(&pubkey.to_uppercase()), None);
+    }
+
+    #[test]
+    fn addresses() {
+        let pubkey = "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e";
+        let data = [
+            tlv(0, b"my-article"),
+            tlv(1, b"wss://relay.example.com"),
+            tlv(2, &hex::decode(pubkey).unwrap()),
+            tlv(3, &30023u32.to_be_bytes()),
+        ]
+        .concat();
+        let naddr = encode_bech32("naddr", &data);
+        for input in [naddr.clone(), format!("nostr:{}", naddr)] {
+            let address = parse_naddr(&input).unwrap();
+            assert_eq!(address.kind, 30023);
+            assert_eq!(address.pubkey, pubkey);
+            assert_eq!(address.identifier, "my-article");
+        }
+
+        // Without a kind, with a short pubkey, or of another type
+        let no_kind = [tlv(0, b"my-article"), tlv(2, &[0x7e; 32])].concat();
+        assert!(parse_naddr(&encode_bech32("naddr", &no_kind)).is_none());
+        let short = [
+            tlv(0, b"a"),
+            tlv(2, &[0x7e; 31]),
+            tlv(3, &1u32.to_be_bytes()),
+        ]
+        .concat();
+        assert!(parse_naddr(&encode_bech32("naddr", &short)).is_none());
+        assert!(parse_naddr(&encode_bech32("nevent", &data)).is_none());
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let mut event = event_at(1, 1_700_000_000);
+        event.id = "000000000e9d97a1ab09fc381030b346cdd7a142ad57e6df0b46dc9bef6c7e2d".to_string();
+        assert_eq!(event.difficulty(), 36);
+        event.id = "ff".repeat(32);
+        assert_eq!(event.difficulty(), 0);
+        event.id = format!("01{}", "ff".repeat(31));
+        assert_eq!(event.difficulty(), 7);
+
+        assert_eq!(event.committed_difficulty(), None);
+        event.tags = vec![vec!["nonce".to_string(), "776797".to_string()]];
+        assert_eq!(event.committed_difficulty(), None);
+        event.tags = vec![vec![
+            "nonce".to_string(),
+            "776797".to_string(),
+            "20".to_string(),
+        ]];
+        assert_eq!(event.committed_difficulty(), Some(20));
+    }
+
+    #[test]
+    fn invoice_amounts() {
+        assert_eq!(bolt11_msats("lnbc2500u1pvjluez"), Some(250_000_000));
+        assert_eq!(bolt11_msats("LNBC20M1PVJLUEZ"), Some(2_000_000_000));
+        assert_eq!(bolt11_msats("lntb10n1pvjluez"), Some(1_000));
+        assert_eq!(bolt11_msats("lnbcrt10p1pvjluez"), Some(1));
+        assert_eq!(bolt11_msats("lnbc21pvjluez"), Some(200_000_000_000));
+        // Invoices without an amount, malformed ones and overflowing amounts
+        assert_eq!(bolt11_msats("lnbc1pvjluez"), None);
+        assert_eq!(bolt11_msats("lnbc2500upvjluez"), None);
+        assert_eq!(bolt11_msats("lnbc3x1pvjluez"), None);
+        assert_eq!(bolt11_msats("bc2500u1pvjluez"), None);
+        assert_eq!(bolt11_msats("lnbc99999999999999999u1pvjluez"), None);
+    }
+
+    #[test]
+    fn coordinates() {
+        let pubkey = "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e";
+        let coordinate = format!("30023:{}:my-article", pubkey);
+        assert_eq!(
+            parse_coordinate(&coordinate).unwrap().coordinate(),
+            coordinate
+        );
+        // Identifiers may hold colons, and an empty one is valid.
+        let address = parse_coordinate(&format!("30023:{}:a:b", pubkey)).unwrap();
+        assert_eq!((address.kind, address.identifier.as_str()), (30023, "a:b"));
+        assert!(parse_coordinate(&format!("30023:{}:", pubkey)).is_some());
+        assert!(parse_coordinate(&format!("30023:{}", pubkey)).is_none());
+        assert!(parse_coordinate("30023:abc:my-article").is_none());
+        assert!(parse_coordinate(&format!("x:{}:my-article", pubkey)).is_none());
+    }
+
+    #[test]
+    fn relay_hints_of_entities() {
+        let data = [tlv(1, b"wss://a"), tlv(0, &[0x7e; 32]), tlv(1, b"wss://b")].concat();
+        assert_eq!(
+            tlv_values(&data, 1),
+            vec![b"wss://a".as_slice(), b"wss://b".as_slice()]
+        );
+        let truncated = [tlv(1, b"wss://a"), vec![1, 5, b'b']].concat();
+        assert_eq!(tlv_values(&truncated, 1), vec![b"wss://a".as_slice()]);
+        for hrp in ["nevent", "nprofile", "naddr"] {
+            let hints = relay_hints(&encode_bech32(hrp, &data));
+            assert_eq!(hints, vec!["wss://a".to_string(), "wss://b".to_string()]);
+        }
+        assert!(relay_hints(&encode_bech32("note", &[0x7e; 32])).is_empty());
+        assert!(relay_hints("not an entity").is_empty());
+    }
+
+    #[test]
+    fn conditions() {
+        let event = event_at(1, 1_700_000_000);
+        assert!(event.meets_conditions(""));
+        assert!(event.meets_conditions("kind=1"));
+        assert!(event.meets_conditions("kind=0&kind=1"));
+        assert!(!event.meets_conditions("kind=7"));
+        assert!(event.meets_conditions("created_at>1600000000&created_at<1800000000"));
+        assert!(!event.meets_conditions("created_at>1700000000"));
+        assert!(!event.meets_conditions("created_at<1700000000"));
+        assert!(!event.meets_conditions("kind=1&created_at<1600000000"));
+        assert!(!event.meets_conditions("kind=x"));
+        assert!(!event.meets_conditions("pubkey=abc"));
+    }
+
+    #[test]
+    fn valid_delegation() {
+        let (delegator, delegatee) = (keypair(), keypair());
+        let conditions = "kind=1&created_at>1600000000";
+        let tag = delegation_tag(&delegator, &delegatee, conditions);
+        let event = signed_event(&delegatee, 1, 1_700_000_000, vec![tag]);
+        assert_eq!(
+            event.delegator(),
+            Some(delegator.x_only_public_key().0.to_string())
+        );
+    }
+
+    #[test]
+    fn delegation_outside_conditions() {
+        let (delegator, delegatee) = (keypair(), keypair());
+        let tag = delegation_tag(&delegator, &delegatee, "kind=1");
+        let event = signed_event(&delegatee, 7, 1_700_000_000, vec![tag]);
+        assert_eq!(event.delegator(), None);
+    }
+
+    #[test]
+    fn delegation_of_another_pubkey() {
+        let (delegator, delegatee, other) = (keypair(), keypair(), keypair());
+        let tag = delegation_tag(&delegator, &delegatee, "kind=1");
+        let event = signed_event(&other, 1, 1_700_000_000, vec![tag]);
+        assert_eq!(event.delegator(), None);
+    }
+
+    #[test]
+    fn delegation_copied_onto_forged_event() {
+        let (delegator, delegatee) = (keypair(), keypair());
+        let tag = delegation_tag(&delegator, &delegatee, "kind=1");
+        let mut event = signed_event(&delegatee, 1, 1_700_000_000, vec![tag]);
+        event.content = "forged".to_string();
+        event.id = event.compute_id();
+        assert_eq!(event.delegator(), None);
+    }
+}
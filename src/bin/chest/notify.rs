@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::dms;
+use crate::ingest::classify;
+use crate::nostr::{self, NostrEvent};
+use crate::NotifyConfig;
+
+/// Characters of an event's content quoted in its notification
+const EXCERPT_LENGTH: usize = 200;
+
+/// Telegram rejects messages longer than this many characters
+const TELEGRAM_MAX_LENGTH: usize = 4096;
+
+/// Discord rejects webhook messages longer than this many characters
+const DISCORD_MAX_LENGTH: usize = 2000;
+
+/// Sends batched Telegram and Discord messages about newly archived events matching a filter
+#[derive(Debug)]
+pub struct Notifier {
+    config: NotifyConfig,
+    /// Notification lines waiting for the next batch, oldest first
+    pending: Mutex<VecDeque<String>>,
+    client: reqwest::Client,
+}
+
+/// Shortens text to `max` characters, marking the cut with an ellipsis
+fn excerpt(text: &str, max: usize) -> String {
+    let mut short: String = text.chars().take(max).collect();
+    if text.chars().count() > max {
+        short.push('…');
+    }
+    short
+}
+
+/// Abbreviates a hex pubkey for display
+fn short_pubkey(pubkey: &str) -> &str {
+    pubkey.get(..12).unwrap_or(pubkey)
+}
+
+/// Describes an archived event in one line
+fn describe(event: &NostrEvent) -> String {
+    let author = short_pubkey(&event.pubkey);
+    match classify(event).map(|(folder, _)| folder) {
+        Some("zaps") => {
+            // Receipts are signed by the LNURL server; the sender is in the `P` tag.
+            let sender = event.tag_value("P").map_or(author, short_pubkey);
+            let sats = event
+                .tag_value("bolt11")
+                .and_then(nostr::bolt11_msats)
+                .map_or(0, |msats| msats / 1000);
+            format!("⚡ {} sats zapped by {}", sats, sender)
+        }
+        Some("reactions") => format!("{} reacted {}", author, excerpt(&event.content, 20)),
+        Some("replies") => format!(
+            "💬 {} replied: {}",
+            author,
+            excerpt(&event.content, EXCERPT_LENGTH)
+        ),
+        Some("reposts") => format!("🔁 {} reposted", author),
+        Some(folder) => format!(
+            "{} (kind {}) by {}: {}",
+            folder,
+            event.kind,
+            author,
+            excerpt(&event.content, EXCERPT_LENGTH)
+        ),
+        None => format!("kind {} by {}", event.kind, author),
+    }
+}
+
+/// Splits lines into messages of at most `max` characters, lines longer than that cut short
+fn batch_messages(lines: &[String], max: usize) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        let line = excerpt(line, max - 1);
+        if !current.is_empty() && current.chars().count() + line.chars().count() + 1 > max {
+            messages.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    if !current.is_empty() {
+        messages.push(current);
+    }
+    messages
+}
+
+impl Notifier {
+    /// Returns `None` when notifications are disabled or no destination is configured
+    pub fn new(config: &NotifyConfig) -> Option<Self> {
+        let has_destination = !config.telegram_bot_token.is_empty()
+            && !config.telegram_chat_id.is_empty()
+            || !config.discord_webhook_url.is_empty();
+        if !config.enabled || !has_destination {
+            return None;
+        }
+        Some(Self {
+            config: config.clone(),
+            pending: Mutex::new(VecDeque::new()),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Returns whether an event matches the configured filter: its kind is listed (if any
+    /// are) and it is written by or tags one of the watched pubkeys (if any are). Direct
+    /// messages never match.
+    fn matches(&self, event: &NostrEvent) -> bool {
+        if dms::is_direct_message(event) {
+            return false;
+        }
+        let kind_matches = self.config.kinds.is_empty() || self.config.kinds.contains(&event.kind);
+        let pubkey_matches = self.config.pubkeys.is_empty()
+            || self.config.pubkeys.contains(&event.pubkey)
+            || event.tags.iter().any(|tag| {
+                tag.len() >= 2 && tag[0] == "p" && self.config.pubkeys.contains(&tag[1])
+            });
+        kind_matches && pubkey_matches
+    }
+
+    /// Queues a notification for a newly archived event if it matches the filter. The oldest
+    /// queued notifications are dropped once `max_pending` is reached.
+    pub fn notify(&self, event: &NostrEvent) {
+        if !self.matches(event) {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.push_back(describe(event));
+        while pending.len() > self.config.max_pending.max(1) {
+            pending.pop_front();
+        }
+    }
+
+    async fn send_telegram(&self, text: &str) -> Result<(), reqwest::Error> {
+        let url = format!(
+            "https://api.telegram.org/bot{}/sendMessage",
+            self.config.telegram_bot_token
+        );
+        self.client
+            .post(url)
+            .json(&serde_json::json!({
+                "chat_id": self.config.telegram_chat_id,
+                "text": text,
+                "disable_web_page_preview": true,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn send_discord(&self, text: &str) -> Result<(), reqwest::Error> {
+        self.client
+            .post(&self.config.discord_webhook_url)
+            .json(&serde_json::json!({ "content": text }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Sends the queued notifications every `batch_interval` seconds, at most
+    /// `max_messages_per_batch` messages per destination each time; notifications beyond that
+    /// are dropped so a burst of events cannot get the bot or webhook rate limited.
+    pub fn spawn(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let period = Duration::from_secs(self.config.batch_interval.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let lines: Vec<String> = self.pending.lock().unwrap().drain(..).collect();
+                if lines.is_empty() {
+                    continue;
+                }
+                let limit = self.config.max_messages_per_batch.max(1);
+                let mut deferred = 0;
+                if !self.config.telegram_bot_token.is_empty()
+                    && !self.config.telegram_chat_id.is_empty()
+                {
+                    let messages = batch_messages(&lines, TELEGRAM_MAX_LENGTH);
+                    deferred = deferred.max(messages.len().saturating_sub(limit));
+                    for message in messages.iter().take(limit) {
+                        if let Err(e) = self.send_telegram(message).await {
+                            eprintln!("Failed to send Telegram notification: {}", e);
+                        }
+                    }
+                }
+                if !self.config.discord_webhook_url.is_empty() {
+                    let messages = batch_messages(&lines, DISCORD_MAX_LENGTH);
+                    deferred = deferred.max(messages.len().saturating_sub(limit));
+                    for message in messages.iter().take(limit) {
+                        if let Err(e) = self.send_discord(message).await {
+                            eprintln!("Failed to send Discord notification: {}", e);
+                        }
+                    }
+                }
+                if deferred > 0 {
+                    eprintln!(
+                        "Notification rate limit reached, {} messages dropped",
+                        deferred
+                    );
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_event;
+
+    #[test]
+    fn direct_messages_are_not_notified() {
+        let config = NotifyConfig {
+            enabled: true,
+            discord_webhook_url: "https://discord.invalid/webhook".to_string(),
+            ..Default::default()
+        };
+        let notifier = Notifier::new(&config).unwrap();
+        for event in [
+            test_event('a', 4),
+            test_event('b', 1059),
+            test_event('c', 1),
+        ] {
+            notifier.notify(&event);
+        }
+        assert_eq!(notifier.pending.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::health::RelayHealth;
+use crate::subscriptions::SubscriptionRegistry;
+use crate::{nostr, OrphanConfig};
+
+/// Number of orphans returned by `GET /admin/orphans` unless `limit` is given
+const DEFAULT_LIST_LIMIT: i64 = 100;
+
+/// Records the parents missing from the archive and returns those still worth requesting
+async fn collect_orphans(
+    db_pool: &SqlitePool,
+    config: &OrphanConfig,
+) -> Result<Vec<String>, sqlx::Error> {
+    // Parents that arrived since the last run are no longer orphans.
+    sqlx::query("DELETE FROM orphans WHERE event_id IN (SELECT event_id FROM events)")
+        .execute(db_pool)
+        .await?;
+
+    let missing: Vec<(String,)> = sqlx::query_as(
+        r#"
+        SELECT DISTINCT child.ref_event FROM events AS child
+        WHERE child.folder IN ('replies', 'reactions', 'zaps', 'reposts')
+          AND child.ref_event IS NOT NULL
+          AND NOT EXISTS (SELECT 1 FROM events WHERE event_id = child.ref_event)
+          AND NOT EXISTS (
+              SELECT 1 FROM orphans
+              WHERE orphans.event_id = child.ref_event AND orphans.attempts >= ?
+          )
+        LIMIT ?
+        "#,
+    )
+    .bind(config.max_attempts)
+    .bind(config.batch_size as i64)
+    .fetch_all(db_pool)
+    .await?;
+
+    let now = nostr::now() as i64;
+    for (event_id,) in &missing {
+        sqlx::query(
+            r#"
+            INSERT INTO orphans (event_id, first_seen, last_attempt, attempts) VALUES (?, ?, ?, 1)
+            ON CONFLICT (event_id) DO UPDATE SET last_attempt = excluded.last_attempt,
+                                                 attempts = attempts + 1
+            "#,
+        )
+        .bind(event_id)
+        .bind(now)
+        .bind(now)
+        .execute(db_pool)
+        .await?;
+    }
+    Ok(missing.into_iter().map(|(event_id,)| event_id).collect())
+}
+
+/// Periodically requests missing parent events by id from every relay that is not paused; the
+/// responses are archived by the regular relay listeners
+pub fn spawn_reconciliation(
+    db_pool: SqlitePool,
+    relay_urls: Vec<String>,
+    registry: Arc<SubscriptionRegistry>,
+    health: Arc<RelayHealth>,
+    config: OrphanConfig,
+) {
+    if !config.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        loop {
+            interval.tick().await;
+            let event_ids = match collect_orphans(&db_pool, &config).await {
+                Ok(event_ids) if event_ids.is_empty() => continue,
+                Ok(event_ids) => event_ids,
+                Err(e) => {
+                    eprintln!("Failed to collect orphaned references: {:?}", e);
+                    continue;
+                }
+            };
+            let req_message =
+                serde_json::json!(["REQ", Uuid::new_v4().to_string(), { "ids": event_ids }]);
+            for relay_url in health.ranked(&relay_urls) {
+                if let Err(e) = registry.request(relay_url, req_message.clone()).await {
+                    eprintln!(
+                        "Error requesting missing events on relay {}: {}",
+                        relay_url, e
+                    );
+                }
+            }
+            println!("Requested {} missing parent events", event_ids.len());
+        }
+    });
+}
+
+/// Query parameters for `GET /admin/orphans`
+#[derive(Debug, Deserialize)]
+pub struct OrphanQuery {
+    limit: Option<i64>,
+}
+
+/// A referenced event that is missing from the archive
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Orphan {
+    event_id: String,
+    first_seen: i64,
+    last_attempt: i64,
+    attempts: i64,
+    /// Archived events referencing it
+    references: i64,
+}
+
+/// Lists referenced events that are still missing after lookups, most referenced first.
+pub async fn list_orphans(
+    req: HttpRequest,
+    params: web::Query<OrphanQuery>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_orphans", "").await?;
+    let orphans = sqlx::query_as::<_, Orphan>(
+        r#"
+        SELECT event_id, first_seen, last_attempt, attempts,
+               (SELECT COUNT(*) FROM events WHERE ref_event = orphans.event_id) AS "references"
+        FROM orphans
+        WHERE NOT EXISTS (SELECT 1 FROM events WHERE events.event_id = orphans.event_id)
+        ORDER BY "references" DESC, first_seen
+        LIMIT ?
+        "#,
+    )
+    .bind(params.limit.unwrap_or(DEFAULT_LIST_LIMIT))
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "orphans": orphans })))
+}
@@ -0,0 +1,183 @@
+use sha2::{Digest, Sha256};
+
+/// Magic bytes opening every serialized OpenTimestamps proof
+const HEADER_MAGIC: &[u8] = b"\x00OpenTimestamps\x00\x00Proof\x00\xbf\x89\xe2\xe8\x84\xe8\x92\x94";
+
+/// Proof format version understood by this parser
+const MAJOR_VERSION: u64 = 1;
+
+/// Attestation tags of the Bitcoin block header and pending calendar attestations
+const BITCOIN_TAG: [u8; 8] = [0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01];
+const PENDING_TAG: [u8; 8] = [0x83, 0xdf, 0xe3, 0x0d, 0x2e, 0xf9, 0x0c, 0x8e];
+
+/// Limits of the reference implementation, bounding the work a hostile proof can cause
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const MAX_DEPTH: usize = 256;
+
+/// Operation tags
+const OP_SHA256: u8 = 0x08;
+const OP_APPEND: u8 = 0xf0;
+const OP_PREPEND: u8 = 0xf1;
+const OP_REVERSE: u8 = 0xf2;
+const OP_HEXLIFY: u8 = 0xf3;
+
+/// Tag of an attestation, and of a fork before each branch but the last
+const ATTESTATION: u8 = 0x00;
+const FORK: u8 = 0xff;
+
+/// A statement that a message existed at some time, reached by applying the proof's operations
+/// to the timestamped digest
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attestation {
+    /// The message is the merkle root of the Bitcoin block at this height
+    Bitcoin { height: u64, commitment: Vec<u8> },
+    /// A calendar promised to include the message in a future Bitcoin transaction
+    Pending { uri: String },
+    /// An attestation of a kind this parser does not know, e.g. for another chain
+    Unknown,
+}
+
+/// A parsed proof
+#[derive(Debug)]
+pub struct Proof {
+    /// Digest the proof timestamps
+    pub digest: Vec<u8>,
+    pub attestations: Vec<Attestation>,
+}
+
+/// Reads the binary serialization of a proof
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < length {
+            return Err("truncated proof".to_string());
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads an unsigned LEB128 integer
+    fn varuint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("integer too large".to_string())
+    }
+
+    /// Reads a length-prefixed byte string of at most `max` bytes
+    fn varbytes(&mut self, max: usize) -> Result<&'a [u8], String> {
+        let length = self.varuint()? as usize;
+        if length > max {
+            return Err(format!("byte string of {} bytes is too long", length));
+        }
+        self.bytes(length)
+    }
+
+    /// Reads an attestation following its tag byte
+    fn attestation(&mut self, message: &[u8]) -> Result<Attestation, String> {
+        let tag: [u8; 8] = self.bytes(8)?.try_into().expect("8 bytes were read");
+        let mut payload = Reader {
+            data: self.varbytes(MAX_MESSAGE_LENGTH)?,
+        };
+        Ok(match tag {
+            BITCOIN_TAG => Attestation::Bitcoin {
+                height: payload.varuint()?,
+                commitment: message.to_vec(),
+            },
+            PENDING_TAG => Attestation::Pending {
+                uri: String::from_utf8_lossy(payload.varbytes(1000)?).into_owned(),
+            },
+            _ => Attestation::Unknown,
+        })
+    }
+
+    /// Applies the operation of a tag to a message, returning the result
+    fn operation(&mut self, tag: u8, message: &[u8]) -> Result<Vec<u8>, String> {
+        let result = match tag {
+            OP_SHA256 => Sha256::digest(message).to_vec(),
+            OP_APPEND => [message, self.varbytes(MAX_MESSAGE_LENGTH)?].concat(),
+            OP_PREPEND => [self.varbytes(MAX_MESSAGE_LENGTH)?, message].concat(),
+            OP_REVERSE => message.iter().rev().copied().collect(),
+            OP_HEXLIFY => hex::encode(message).into_bytes(),
+            other => return Err(format!("unsupported operation 0x{:02x}", other)),
+        };
+        if result.len() > MAX_MESSAGE_LENGTH {
+            return Err("message too long".to_string());
+        }
+        Ok(result)
+    }
+
+    /// Reads the attestation or the operation and subtree of a tag
+    fn branch(
+        &mut self,
+        tag: u8,
+        message: &[u8],
+        depth: usize,
+        attestations: &mut Vec<Attestation>,
+    ) -> Result<(), String> {
+        if tag == ATTESTATION {
+            attestations.push(self.attestation(message)?);
+        } else {
+            let result = self.operation(tag, message)?;
+            self.timestamp(&result, depth + 1, attestations)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the tree of operations and attestations applying to a message
+    fn timestamp(
+        &mut self,
+        message: &[u8],
+        depth: usize,
+        attestations: &mut Vec<Attestation>,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("proof too deep".to_string());
+        }
+        let mut tag = self.byte()?;
+        while tag == FORK {
+            let branch = self.byte()?;
+            self.branch(branch, message, depth, attestations)?;
+            tag = self.byte()?;
+        }
+        self.branch(tag, message, depth, attestations)
+    }
+}
+
+/// Parses a serialized `.ots` proof of a SHA-256 digest
+pub fn parse(data: &[u8]) -> Result<Proof, String> {
+    let mut reader = Reader { data };
+    if reader.bytes(HEADER_MAGIC.len()).ok() != Some(HEADER_MAGIC) {
+        return Err("not an OpenTimestamps proof".to_string());
+    }
+    let version = reader.varuint()?;
+    if version != MAJOR_VERSION {
+        return Err(format!("unsupported proof version {}", version));
+    }
+    if reader.byte()? != OP_SHA256 {
+        return Err("proof is not of a SHA-256 digest".to_string());
+    }
+    let digest = reader.bytes(32)?.to_vec();
+    let mut attestations = Vec::new();
+    reader.timestamp(&digest, 0, &mut attestations)?;
+    if !reader.data.is_empty() {
+        return Err("trailing data after the proof".to_string());
+    }
+    Ok(Proof {
+        digest,
+        attestations,
+    })
+}
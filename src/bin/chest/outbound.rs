@@ -0,0 +1,178 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use url::{Host, Url};
+
+/// Time allowed for connecting to a server, within the overall timeout of a fetch
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Redirects followed before a fetch is abandoned
+const MAX_REDIRECTS: usize = 5;
+
+/// Returns whether an IPv4 address is reachable on the public internet
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT)
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved
+        || a >= 240)
+}
+
+/// Returns whether an IP address is reachable on the public internet, so fetching it cannot
+/// reach the archive's own host or network. IPv6 addresses embedding an IPv4 address are
+/// judged by it.
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_global_v4(ip);
+            }
+            let segments = ip.segments();
+            // NAT64 prefix 64:ff9b::/96
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_global_v4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local fe80::/10 and the deprecated site-local fec0::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] & 0xffc0) == 0xfec0
+                // Documentation 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+                // IPv4-compatible ::/96
+                || segments[..6] == [0; 6])
+        }
+    }
+}
+
+/// Returns whether a URL may be fetched: http or https, to a host name or a public IP address.
+/// Host names are checked once resolved, by `PublicResolver`.
+fn allowed_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && match url.host() {
+            Some(Host::Domain(_)) => true,
+            Some(Host::Ipv4(ip)) => is_global(ip.into()),
+            Some(Host::Ipv6(ip)) => is_global(ip.into()),
+            None => false,
+        }
+}
+
+/// Resolves host names with the system resolver, failing when any of their addresses is not
+/// public. Checking the addresses actually connected to also covers redirects and hosts whose
+/// DNS answer changes between a check and the fetch.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !is_global(addr.ip())) {
+                return Err(
+                    format!("{} resolves to non-public address {}", host, addr.ip()).into(),
+                );
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Builds an HTTP client for URLs found in untrusted events, such as note links. It only
+/// connects to public addresses, on the first request and on every redirect, and gives up on
+/// servers that are slow to connect or answer.
+pub fn client(timeout: Duration) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+        .timeout(timeout)
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if !allowed_url(attempt.url()) {
+                let error = format!("redirect to non-public address {}", attempt.url());
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+}
+
+/// Parses a URL taken from an event, rejecting ones that must not be fetched
+pub fn check_url(url: &str) -> Result<Url, String> {
+    Url::parse(url)
+        .ok()
+        .filter(allowed_url)
+        .ok_or_else(|| format!("Refusing to fetch {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_global(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_global(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn urls() {
+        assert!(check_url("https://example.com/page").is_ok());
+        assert!(check_url("http://1.1.1.1/").is_ok());
+        assert!(check_url("http://127.0.0.1:8080/admin").is_err());
+        assert!(check_url("http://[::1]/").is_err());
+        assert!(check_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check_url("http://0x7f000001/").is_err());
+        assert!(check_url("file:///etc/passwd").is_err());
+    }
+
+    #[tokio::test]
+    async fn loopback_names_are_refused() {
+        let client = client(Duration::from_secs(5)).unwrap();
+        let error = client.get("http://localhost:9/").send().await.unwrap_err();
+        assert!(format!("{:?}", error).contains("non-public"), "{:?}", error);
+    }
+}
@@ -0,0 +1,287 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::error::ApiError;
+use crate::media::is_media_link;
+use crate::nostr::{self, NostrEvent};
+use crate::outbound;
+use crate::{etag, PreviewConfig};
+
+/// OpenGraph or Twitter card metadata of a link found in an archived note
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LinkPreview {
+    url: String,
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    site_name: Option<String>,
+    /// Unix time the page was fetched
+    fetched_at: i64,
+}
+
+/// Returns the web page links in the content of a note, leaving out images and videos
+pub fn page_links(content: &str) -> Vec<String> {
+    let mut links: Vec<String> = content
+        .split_whitespace()
+        .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .filter(|word| url::Url::parse(word).is_ok_and(|url| !is_media_link(&url)))
+        .map(str::to_string)
+        .collect();
+    links.sort();
+    links.dedup();
+    links
+}
+
+/// Decodes the HTML entities commonly found in meta tag values
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Returns the value of an attribute of an HTML tag, e.g. `content` of `<meta ...>`
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(found) = lower[offset..].find(name) {
+        let start = offset + found;
+        offset = start + name.len();
+        // The name must be a whole attribute name followed by `=`.
+        let preceded = lower[..start].ends_with(|c: char| c.is_ascii_whitespace());
+        let rest = lower[offset..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next()?,
+            _ => value
+                .split(|c: char| c.is_ascii_whitespace() || c == '>')
+                .next()?,
+        };
+        return Some(decode_entities(value.trim()));
+    }
+    None
+}
+
+/// Extracts the title, description, image and site name of a page from its OpenGraph and
+/// Twitter card meta tags, falling back to its `<title>`
+fn parse_metadata(html: &str) -> [Option<String>; 4] {
+    let mut fields: [Option<String>; 4] = Default::default();
+    let names = [
+        ["og:title", "twitter:title"],
+        ["og:description", "twitter:description"],
+        ["og:image", "twitter:image"],
+        ["og:site_name", "twitter:site"],
+    ];
+    let lower = html.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(found) = lower[offset..].find("<meta") {
+        let start = offset + found;
+        let Some(length) = lower[start..].find('>') else {
+            break;
+        };
+        let tag = &html[start..start + length + 1];
+        offset = start + length + 1;
+        let Some(key) = attribute(tag, "property").or_else(|| attribute(tag, "name")) else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+        for (field, candidates) in fields.iter_mut().zip(&names) {
+            if field.is_none() && candidates.contains(&key.as_str()) {
+                *field = attribute(tag, "content").filter(|value| !value.is_empty());
+            }
+        }
+    }
+    if fields[0].is_none() {
+        fields[0] = lower.find("<title").and_then(|start| {
+            let open = start + lower[start..].find('>')? + 1;
+            let close = open + lower[open..].find("</title")?;
+            Some(decode_entities(html[open..close].trim())).filter(|title| !title.is_empty())
+        });
+    }
+    fields
+}
+
+/// Fetches the metadata of the web pages linked from archived notes in the background, at
+/// most `requests_per_minute` pages a minute
+#[derive(Debug)]
+pub struct LinkPreviews {
+    config: PreviewConfig,
+    db_pool: SqlitePool,
+    client: reqwest::Client,
+    queue: mpsc::Sender<String>,
+}
+
+impl LinkPreviews {
+    /// Returns `None` when link previews are disabled
+    pub fn new(
+        config: &PreviewConfig,
+        db_pool: SqlitePool,
+    ) -> Option<(Self, mpsc::Receiver<String>)> {
+        if !config.enabled {
+            return None;
+        }
+        let client = outbound::client(Duration::from_secs(config.timeout.max(1))).ok()?;
+        let (queue, receiver) = mpsc::channel(config.max_pending.max(1));
+        Some((
+            Self {
+                config: config.clone(),
+                db_pool,
+                client,
+                queue,
+            },
+            receiver,
+        ))
+    }
+
+    /// Queues the links of a newly archived note. Links are dropped while the queue is full.
+    pub fn enqueue(&self, event: &NostrEvent) {
+        if event.kind != 1 {
+            return;
+        }
+        for link in page_links(&event.content) {
+            if self.queue.try_send(link).is_err() {
+                eprintln!("Link preview queue full, skipping links of {}", event.id);
+                break;
+            }
+        }
+    }
+
+    /// Fetches a page and stores its metadata, unless it was already fetched
+    async fn fetch(&self, url: &str) -> Result<(), String> {
+        let (fetched,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM link_previews WHERE url = ?)")
+                .bind(url)
+                .fetch_one(&self.db_pool)
+                .await
+                .map_err(|e| format!("Failed to look up link preview: {}", e))?;
+        if fetched {
+            return Ok(());
+        }
+
+        // Links come from anyone's notes, so they must not reach the archive's own network.
+        let response = self
+            .client
+            .get(outbound::check_url(url)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+        let is_html = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.to_ascii_lowercase().contains("html"));
+        // Metadata lives in the head, so only the start of large pages is read.
+        let mut html = Vec::new();
+        if is_html {
+            let mut body = response.bytes_stream();
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| format!("Failed to read {}: {}", url, e))?;
+                html.extend_from_slice(&chunk);
+                if html.len() >= self.config.max_body_size {
+                    html.truncate(self.config.max_body_size);
+                    break;
+                }
+            }
+        }
+        let [title, description, image, site_name] =
+            parse_metadata(&String::from_utf8_lossy(&html));
+
+        // Pages without metadata are recorded too, so they are not fetched again.
+        sqlx::query(
+            "INSERT OR REPLACE INTO link_previews
+                 (url, title, description, image, site_name, fetched_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(url)
+        .bind(title)
+        .bind(description)
+        .bind(image)
+        .bind(site_name)
+        .bind(nostr::now() as i64)
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| format!("Failed to store link preview: {}", e))?;
+        Ok(())
+    }
+
+    /// Fetches queued links one at a time, spaced to respect `requests_per_minute`
+    pub fn spawn(self: Arc<Self>, mut receiver: mpsc::Receiver<String>) {
+        tokio::spawn(async move {
+            let period = Duration::from_secs(60) / self.config.requests_per_minute.max(1);
+            let mut interval = tokio::time::interval(period);
+            while let Some(url) = receiver.recv().await {
+                interval.tick().await;
+                if let Err(e) = self.fetch(&url).await {
+                    eprintln!("{}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Fetches the stored previews of the web page links in a note's content
+pub async fn previews_for(
+    content: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<LinkPreview>, sqlx::Error> {
+    let links = page_links(content);
+    if links.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT url, title, description, image, site_name, fetched_at
+         FROM link_previews WHERE url IN (",
+    );
+    let mut separated = query.separated(", ");
+    for link in links {
+        separated.push_bind(link);
+    }
+    query.push(") ORDER BY url");
+    query.build_query_as().fetch_all(db_pool).await
+}
+
+/// Returns a stable tag identifying the fetched versions of a set of previews
+pub fn previews_etag_parts(previews: &[LinkPreview]) -> Vec<String> {
+    previews
+        .iter()
+        .map(|preview| format!("{}@{}", preview.url, preview.fetched_at))
+        .collect()
+}
+
+/// Query parameters of `GET /previews`
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    url: String,
+}
+
+/// Returns the stored OpenGraph metadata of a link, or 404 if it has not been fetched.
+pub async fn get_preview(
+    req: HttpRequest,
+    params: web::Query<PreviewQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let preview: Option<LinkPreview> = sqlx::query_as(
+        "SELECT url, title, description, image, site_name, fetched_at
+         FROM link_previews WHERE url = ?",
+    )
+    .bind(&params.url)
+    .fetch_optional(db_pool.get_ref())
+    .await?;
+    let preview = preview.ok_or_else(|| ApiError::NotFound("Preview not found".to_string()))?;
+    let parts = previews_etag_parts(std::slice::from_ref(&preview));
+    let etag = etag::list_etag(parts.iter().map(String::as_str));
+    Ok(etag::json_with_etag(&req, etag, &preview))
+}
@@ -0,0 +1,226 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, ResponseError};
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::RateLimitConfig;
+
+/// Most client buckets kept; the least recently seen client is forgotten beyond that
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// Entry of `trusted_proxies` standing for reverse proxies connecting over a Unix socket
+const UNIX_PROXY: &str = "unix";
+
+/// Token bucket state for a single client address
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-IP token bucket rate limiter shared across HTTP workers
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Addresses of the reverse proxies whose `X-Forwarded-For` entries are trusted
+    proxies: Vec<IpAddr>,
+    /// Whether requests over a Unix socket come from a trusted proxy
+    unix_proxy: bool,
+    buckets: Mutex<LruCache<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Fails when an enabled limiter could never refill or never admit a request
+    pub fn new(config: RateLimitConfig) -> Result<Self, String> {
+        if config.enabled {
+            if !config.requests_per_second.is_finite() || config.requests_per_second <= 0.0 {
+                return Err(format!(
+                    "rate_limit.requests_per_second must be a positive number, got {}",
+                    config.requests_per_second
+                ));
+            }
+            if config.burst < 1 {
+                return Err("rate_limit.burst must be at least 1".to_string());
+            }
+        }
+        let proxies = config
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| match proxy.parse() {
+                Ok(ip) => Some(ip),
+                Err(_) if proxy == UNIX_PROXY => None,
+                Err(_) => {
+                    eprintln!("Ignoring invalid trusted proxy address: {}", proxy);
+                    None
+                }
+            })
+            .collect();
+        let unix_proxy = config
+            .trusted_proxies
+            .iter()
+            .any(|proxy| proxy == UNIX_PROXY);
+        Ok(Self {
+            config,
+            proxies,
+            unix_proxy,
+            buckets: Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_TRACKED_CLIENTS).expect("the capacity is not zero"),
+            )),
+        })
+    }
+
+    /// Takes a token for `ip`, returning the time to wait until one is available if the bucket is empty
+    fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let rate = self.config.requests_per_second;
+        let burst = self.config.burst as f64;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.get_or_insert_mut(ip, || Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Returns whether a connection comes from one of the trusted reverse proxies; connections
+    /// over a Unix socket have no address
+    fn is_trusted_proxy(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ip) => self.proxies.contains(&ip),
+            None => self.unix_proxy,
+        }
+    }
+
+    /// Determines the client address. Behind a trusted reverse proxy it is the right-most
+    /// `X-Forwarded-For` entry not added by another trusted proxy: entries further left are
+    /// written by the client itself and cannot be trusted.
+    fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        if !self.is_trusted_proxy(peer) {
+            return peer;
+        }
+        let forwarded: Vec<&str> = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        for entry in forwarded.iter().rev() {
+            match entry.parse::<IpAddr>() {
+                Ok(ip) if self.is_trusted_proxy(Some(ip)) => continue,
+                Ok(ip) => return Some(ip),
+                Err(_) => break,
+            }
+        }
+        peer
+    }
+}
+
+/// Middleware rejecting requests with 429 once a client exhausts its bucket.
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limited = match req.app_data::<web::Data<RateLimiter>>() {
+        Some(limiter) if limiter.config.enabled => match limiter.client_ip(&req) {
+            Some(ip) => limiter.check(ip).err(),
+            None => None,
+        },
+        _ => None,
+    };
+
+    if let Some(wait) = limited {
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let response = ApiError::RateLimited { retry_after }.error_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn limiter(trusted_proxies: &[&str]) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|proxy| proxy.to_string())
+                .collect(),
+            ..RateLimitConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn request(peer: &str, forwarded_for: &str) -> ServiceRequest {
+        TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded_for))
+            .to_srv_request()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let req = request("203.0.113.7:4000", "198.51.100.1");
+        assert_eq!(limiter(&[]).client_ip(&req), "203.0.113.7".parse().ok());
+        assert_eq!(
+            limiter(&["10.0.0.1"]).client_ip(&req),
+            "203.0.113.7".parse().ok()
+        );
+    }
+
+    #[test]
+    fn right_most_forwarded_address_is_used() {
+        // The client made up the first entry; the proxy appended the address it saw.
+        let req = request("10.0.0.1:4000", "1.2.3.4, 198.51.100.1");
+        let limiter = limiter(&["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(limiter.client_ip(&req), "198.51.100.1".parse().ok());
+        let chained = request("10.0.0.1:4000", "1.2.3.4, 198.51.100.1, 10.0.0.2");
+        assert_eq!(limiter.client_ip(&chained), "198.51.100.1".parse().ok());
+    }
+
+    #[test]
+    fn limits_that_never_admit_requests_are_rejected() {
+        let config = |requests_per_second, burst| RateLimitConfig {
+            enabled: true,
+            requests_per_second,
+            burst,
+            ..RateLimitConfig::default()
+        };
+        assert!(RateLimiter::new(config(0.0, 20)).is_err());
+        assert!(RateLimiter::new(config(-1.0, 20)).is_err());
+        assert!(RateLimiter::new(config(f64::NAN, 20)).is_err());
+        assert!(RateLimiter::new(config(10.0, 0)).is_err());
+        assert!(RateLimiter::new(config(0.5, 1)).is_ok());
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let limiter = limiter(&[]);
+        for i in 0..MAX_TRACKED_CLIENTS as u32 + 10 {
+            let _ = limiter.check(IpAddr::from(i.to_be_bytes()));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_TRACKED_CLIENTS);
+    }
+}
@@ -0,0 +1,139 @@
+use serde_json::Value;
+
+use crate::AppConfig;
+
+/// Replaces the settings `GET /config` does not show
+const REDACTED: &str = "[redacted]";
+
+/// Settings `GET /config` shows, as dotted paths. A path covers everything below it, `*`
+/// matches any key, and list elements are not part of paths. Everything else is redacted:
+/// credentials, and file paths and hosts describing the deployment. The settings of the
+/// additional archives follow the same list below `archives`.
+const EXPOSED: &[&str] = &[
+    "server.bind_address",
+    "server.base_path",
+    "relays.urls",
+    "relays.compression",
+    "relays.tls.system_roots",
+    "relays.tls.pins",
+    "relays.relay_tls.*.system_roots",
+    "relays.relay_tls.*.pins",
+    "event",
+    "database.journal_mode",
+    "database.synchronous",
+    "database.busy_timeout",
+    "database.cache_size",
+    "database.mmap_size",
+    "database.auto_vacuum",
+    "database.write_connections",
+    "database.read_connections",
+    "rate_limit",
+    "auth.protect_reads",
+    "auth.nip98_max_age",
+    "api",
+    "relay_info",
+    "wot",
+    "ingest",
+    "topics",
+    "subscriptions",
+    "orphans",
+    "fetch",
+    "cache",
+    "backup.enabled",
+    "backup.schedule",
+    "backup.keep",
+    "backup.s3.enabled",
+    "maintenance",
+    "stats",
+    "mqtt.enabled",
+    "mqtt.port",
+    "mqtt.topic_prefix",
+    "mqtt.qos",
+    "mqtt.kinds",
+    "mqtt.pubkeys",
+    "notify.enabled",
+    "notify.kinds",
+    "notify.pubkeys",
+    "notify.batch_interval",
+    "notify.max_messages_per_batch",
+    "notify.max_pending",
+    "media.enabled",
+    "media.max_file_size",
+    "media.max_total_size",
+    "media.mime_types",
+    "media.max_pending",
+    "media.blossom",
+    "previews",
+    "dms.enabled",
+    "signer.auth",
+    "versions",
+    "reverify",
+    "cold.enabled",
+    "cold.after_days",
+    "cold.interval",
+    "cold.segment_size",
+    "cold.compression_level",
+    "ipfs.enabled",
+    "ipfs.schedule",
+    "ipfs.announce",
+    "ipfs.pinning.enabled",
+    "timestamps",
+    "health",
+    "discovery",
+    "duplicates",
+    "dynamic",
+    "archives.name",
+];
+
+/// Returns whether the setting at a path is shown
+fn exposed(path: &[String]) -> bool {
+    EXPOSED.iter().any(|pattern| {
+        let pattern: Vec<&str> = pattern.split('.').collect();
+        let matches = |path: &[String]| {
+            pattern.len() <= path.len()
+                && pattern
+                    .iter()
+                    .zip(path)
+                    .all(|(segment, key)| *segment == "*" || segment == key)
+        };
+        matches(path) || (path.first().is_some_and(|key| key == "archives") && matches(&path[1..]))
+    })
+}
+
+/// Redacts the settings below a path that are not exposed
+fn redact_value(value: Value, path: &mut Vec<String>) -> Value {
+    if exposed(path) {
+        return value;
+    }
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    path.push(key.clone());
+                    let value = redact_value(value, path);
+                    path.pop();
+                    (key, value)
+                })
+                .collect(),
+        ),
+        // Lists of settings, such as the archives, are redacted item by item.
+        Value::Array(items) if items.iter().any(Value::is_object) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| redact_value(item, path))
+                .collect(),
+        ),
+        // Unset settings are left visible, as they reveal nothing.
+        Value::Null => Value::Null,
+        Value::String(text) if text.is_empty() => Value::String(text),
+        Value::Array(items) if items.is_empty() => Value::Array(items),
+        _ => Value::String(REDACTED.to_string()),
+    }
+}
+
+/// Returns the configuration with every setting outside the allowlist redacted, for
+/// `GET /config` and the startup log
+pub fn redact(config: &AppConfig) -> Value {
+    let value = serde_json::to_value(config).unwrap_or(Value::Null);
+    redact_value(value, &mut Vec::new())
+}
@@ -0,0 +1,279 @@
+use actix_web::http::header::UPGRADE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{Message, Session};
+use serde_json::{json, Value};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::filter::{self, Filter};
+use crate::{nip11, AppConfig, DbEvent};
+
+/// Result limit applied to REQ filters that don't set one (or ask for more than allowed)
+const DEFAULT_MAX_LIMIT: i64 = 500;
+
+/// Serves the relay WebSocket on upgrade requests and the NIP-11 document otherwise.
+pub async fn root(
+    req: HttpRequest,
+    body: web::Payload,
+    config: web::Data<AppConfig>,
+    db_pool: web::Data<SqlitePool>,
+) -> actix_web::Result<HttpResponse> {
+    let is_upgrade = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    if !is_upgrade {
+        return Ok(nip11::relay_information(req, config).await);
+    }
+
+    let (response, session, mut messages) = actix_ws::handle(&req, body)?;
+    let max_limit = config
+        .relay_info
+        .max_limit
+        .map_or(DEFAULT_MAX_LIMIT, |limit| limit as i64);
+    actix_web::rt::spawn(async move {
+        let mut session = session;
+        while let Some(Ok(message)) = messages.recv().await {
+            let result = match message {
+                Message::Text(text) => {
+                    handle_message(&text, &mut session, db_pool.get_ref(), max_limit).await
+                }
+                Message::Ping(bytes) => session.pong(&bytes).await,
+                Message::Close(_) => break,
+                _ => Ok(()),
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    Ok(response)
+}
+
+/// Sends a JSON relay message to the client
+async fn send(session: &mut Session, message: Value) -> Result<(), actix_ws::Closed> {
+    session.text(message.to_string()).await
+}
+
+/// Parses the filters following the subscription id in a REQ or COUNT message
+fn parse_filters(message: &[Value]) -> Result<Vec<Filter>, String> {
+    message
+        .iter()
+        .skip(2)
+        .map(|value| serde_json::from_value(value.clone()).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Handles a single client message
+async fn handle_message(
+    text: &str,
+    session: &mut Session,
+    db_pool: &SqlitePool,
+    max_limit: i64,
+) -> Result<(), actix_ws::Closed> {
+    let message: Vec<Value> = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(_) => return send(session, json!(["NOTICE", "invalid: malformed message"])).await,
+    };
+    let command = message.first().and_then(Value::as_str).unwrap_or_default();
+    let subscription_id = message
+        .get(1)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+
+    match command {
+        "REQ" | "COUNT" => {
+            let filters = match parse_filters(&message) {
+                Ok(filters) => filters,
+                Err(e) => {
+                    let reason = format!("invalid: {}", e);
+                    return send(session, json!(["CLOSED", subscription_id, reason])).await;
+                }
+            };
+            let result = if command == "REQ" {
+                send_stored_events(&subscription_id, &filters, session, db_pool, max_limit).await
+            } else {
+                send_count(&subscription_id, &filters, session, db_pool).await
+            };
+            match result {
+                Ok(sent) => sent,
+                Err(e) => {
+                    eprintln!("Relay query error: {:?}", e);
+                    send(
+                        session,
+                        json!(["CLOSED", subscription_id, "error: internal error"]),
+                    )
+                    .await
+                }
+            }
+        }
+        // Stored-event subscriptions end at EOSE, so there is nothing to tear down.
+        "CLOSE" => Ok(()),
+        "EVENT" => {
+            let event_id = message
+                .get(1)
+                .and_then(|event| event.get("id"))
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            send(
+                session,
+                json!(["OK", event_id, false, "restricted: this relay is read-only"]),
+            )
+            .await
+        }
+        _ => send(session, json!(["NOTICE", "invalid: unknown message type"])).await,
+    }
+}
+
+/// Leaves out the events truncated on ingestion, as their signatures no longer verify
+const SIGNED_ONLY: &str = " AND truncated = 0";
+
+/// Returns the number of stored events sent for a filter: its `limit` within `0..=max_limit`,
+/// since SQLite reads a negative `LIMIT` as no limit at all
+fn stored_limit(filter: &Filter, max_limit: i64) -> i64 {
+    filter
+        .limit
+        .map_or(max_limit, |limit| limit.clamp(0, max_limit))
+}
+
+/// Answers a REQ with matching stored events followed by EOSE
+async fn send_stored_events(
+    subscription_id: &str,
+    filters: &[Filter],
+    session: &mut Session,
+    db_pool: &SqlitePool,
+    max_limit: i64,
+) -> Result<Result<(), actix_ws::Closed>, sqlx::Error> {
+    for filter in filters {
+        for event in stored_events(filter, stored_limit(filter, max_limit), db_pool).await? {
+            if let Err(closed) =
+                send(session, json!(["EVENT", subscription_id, event.to_nostr()])).await
+            {
+                return Ok(Err(closed));
+            }
+        }
+    }
+    Ok(send(session, json!(["EOSE", subscription_id])).await)
+}
+
+/// Loads the newest stored events matching a REQ filter
+async fn stored_events(
+    filter: &Filter,
+    limit: i64,
+    db_pool: &SqlitePool,
+) -> Result<Vec<DbEvent>, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM public_events AS events",
+    );
+    filter::push_where(&mut query, std::slice::from_ref(filter));
+    query
+        .push(SIGNED_ONLY)
+        .push(" ORDER BY created_at DESC LIMIT ")
+        .push_bind(limit);
+    query.build_query_as::<DbEvent>().fetch_all(db_pool).await
+}
+
+/// Counts the stored events matching any of the filters
+async fn count_events(filters: &[Filter], db_pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM public_events AS events");
+    filter::push_where(&mut query, filters);
+    query.push(SIGNED_ONLY);
+    let (count,): (i64,) = query.build_query_as().fetch_one(db_pool).await?;
+    Ok(count)
+}
+
+/// Answers a NIP-45 COUNT with the number of stored events matching any of the filters
+async fn send_count(
+    subscription_id: &str,
+    filters: &[Filter],
+    session: &mut Session,
+    db_pool: &SqlitePool,
+) -> Result<Result<(), actix_ws::Closed>, sqlx::Error> {
+    let count = count_events(filters, db_pool).await?;
+    Ok(send(
+        session,
+        json!(["COUNT", subscription_id, { "count": count }]),
+    )
+    .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, test_event};
+    use crate::{ingest, VersionConfig};
+
+    #[tokio::test]
+    async fn direct_messages_are_not_served() {
+        let db_pool = db::test_pool().await;
+        let events = [
+            test_event('a', 1),
+            test_event('b', 4),
+            test_event('c', 1059),
+        ];
+        db::store_test_events(&db_pool, &events.iter().collect::<Vec<_>>()).await;
+
+        let filters: Vec<Filter> = [
+            json!({ "kinds": [1, 4, 1059] }),
+            json!({ "ids": ["b".repeat(64)] }),
+            json!({ "#p": ["e".repeat(64)] }),
+            json!({}),
+        ]
+        .into_iter()
+        .map(|filter| serde_json::from_value(filter).unwrap())
+        .collect();
+        for filter in &filters {
+            let events = stored_events(filter, 10, &db_pool).await.unwrap();
+            assert!(
+                events.iter().all(|event| event.folder != "dms"),
+                "{:?}",
+                filter
+            );
+        }
+        assert_eq!(count_events(&filters, &db_pool).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_events_are_not_served() {
+        let db_pool = db::test_pool().await;
+        for (event, truncated) in [(test_event('a', 1), false), (test_event('b', 1), true)] {
+            ingest::store_event(
+                &db_pool,
+                &event,
+                true,
+                truncated,
+                &VersionConfig::default(),
+                &[],
+            )
+            .await
+            .unwrap();
+        }
+
+        let filter: Filter = serde_json::from_value(json!({ "kinds": [1] })).unwrap();
+        let events = stored_events(&filter, 10, &db_pool).await.unwrap();
+        let ids: Vec<&str> = events.iter().map(|event| event.event_id.as_str()).collect();
+        assert_eq!(ids, ["a".repeat(64)]);
+        assert_eq!(count_events(&[filter], &db_pool).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        let limit = |limit| {
+            stored_limit(
+                &Filter {
+                    limit,
+                    ..Default::default()
+                },
+                500,
+            )
+        };
+        assert_eq!(limit(None), 500);
+        assert_eq!(limit(Some(20)), 20);
+        assert_eq!(limit(Some(5000)), 500);
+        assert_eq!(limit(Some(0)), 0);
+        assert_eq!(limit(Some(-1)), 0);
+    }
+}
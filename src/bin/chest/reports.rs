@@ -0,0 +1,107 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::{etag, format_events, nostr, retain_visible, DbEvent, FormatQuery};
+
+/// Number of entries returned by the report summary unless `limit` is given
+const DEFAULT_SUMMARY_LIMIT: usize = 50;
+
+/// Lists NIP-56 reports of an event, or of a pubkey including reports of its events.
+pub async fn list_reports(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let reference = nostr::parse_event_id(&input)
+        .or_else(|| nostr::parse_pubkey(&input))
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id or pubkey: {}", input)))?;
+
+    let mut events = sqlx::query_as::<_, DbEvent>(
+        r#"
+        SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+        FROM public_events AS events
+        WHERE folder = 'reports' AND (ref_event = ? OR EXISTS (
+            SELECT 1 FROM json_each(events.tags) AS tag
+            WHERE json_extract(tag.value, '$[0]') = 'p' AND json_extract(tag.value, '$[1]') = ?
+        ))
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(&reference)
+    .bind(&reference)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+    retain_visible(&req, &mut events);
+    let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &format_events(&events, params.format),
+    ))
+}
+
+/// Query parameters for `GET /admin/reports`
+#[derive(Debug, Deserialize)]
+pub struct SummaryQuery {
+    limit: Option<usize>,
+}
+
+/// Reports received by a single event or pubkey
+#[derive(Debug, Serialize, Default)]
+struct ReportedTarget {
+    #[serde(rename = "ref")]
+    reference: String,
+    reports: i64,
+    /// Number of reports per NIP-56 report type
+    reasons: BTreeMap<String, i64>,
+}
+
+/// Lists the most reported events and pubkeys with their report types, for moderators.
+pub async fn report_summary(
+    req: HttpRequest,
+    params: web::Query<SummaryQuery>,
+    db_pool: web::Data<SqlitePool>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_reports", "").await?;
+    // The report type is the third element of the tag naming the reported event or pubkey.
+    let rows: Vec<(String, String, i64)> = sqlx::query_as(
+        r#"
+        SELECT ref_event, COALESCE((
+            SELECT json_extract(tag.value, '$[2]') FROM json_each(events.tags) AS tag
+            WHERE json_extract(tag.value, '$[0]') IN ('e', 'p')
+              AND json_extract(tag.value, '$[1]') = events.ref_event
+            LIMIT 1
+        ), 'other') AS reason, COUNT(*)
+        FROM public_events AS events
+        WHERE folder = 'reports' AND ref_event IS NOT NULL
+        GROUP BY ref_event, reason
+        "#,
+    )
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    let mut targets: BTreeMap<String, ReportedTarget> = BTreeMap::new();
+    for (reference, reason, count) in rows {
+        let target = targets
+            .entry(reference.clone())
+            .or_insert_with(|| ReportedTarget {
+                reference,
+                ..Default::default()
+            });
+        target.reports += count;
+        *target.reasons.entry(reason).or_default() += count;
+    }
+
+    let mut reported: Vec<ReportedTarget> = targets.into_values().collect();
+    reported.sort_by_key(|target| std::cmp::Reverse(target.reports));
+    reported.truncate(params.limit.unwrap_or(DEFAULT_SUMMARY_LIMIT));
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "reported": reported })))
+}
@@ -0,0 +1,119 @@
+use futures_util::stream::FuturesUnordered;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::ingest::Ingestor;
+use crate::nostr::NostrEvent;
+use crate::{tls, TlsConfig};
+
+/// Requests a single event by id over a short-lived connection to a relay, returning it once
+/// the relay sends a copy whose id and signature verify
+async fn request_event(
+    relay_url: &str,
+    event_id: &str,
+    tls: &TlsConfig,
+) -> Result<Option<NostrEvent>, Box<dyn Error>> {
+    let mut ws_stream = tls::connect_websocket(relay_url, tls).await?;
+    let subscription_id = Uuid::new_v4().to_string();
+    let req_message =
+        serde_json::json!(["REQ", subscription_id, { "ids": [event_id], "limit": 1 }]);
+    ws_stream
+        .send(Message::Text(req_message.to_string()))
+        .await?;
+
+    let mut found = None;
+    while let Some(message) = ws_stream.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        if parts.get(1).and_then(Value::as_str) != Some(subscription_id.as_str()) {
+            continue;
+        }
+        match parts.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                let event = parts
+                    .get(2)
+                    .cloned()
+                    .and_then(|event| serde_json::from_value::<NostrEvent>(event).ok());
+                if let Some(event) = event {
+                    if event.id == event_id && event.verify().is_ok() {
+                        found = Some(event);
+                        break;
+                    }
+                }
+            }
+            Some("EOSE" | "CLOSED") => break,
+            _ => {}
+        }
+    }
+    let _ = ws_stream.close(None).await;
+    Ok(found)
+}
+
+/// Returns whether an event with the given id is archived, in any folder
+async fn is_archived(event_id: &str, db_pool: &SqlitePool) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT 1 FROM events WHERE event_id = ?")
+        .bind(event_id)
+        .fetch_optional(db_pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Makes sure an event is archived, asking the configured relays for it when it is missing, the
+/// healthiest first and paused relays aside. Returns whether the event is in the archive
+/// afterwards.
+pub async fn resolve(event_id: &str, ingestor: &Ingestor) -> Result<bool, sqlx::Error> {
+    if is_archived(event_id, &ingestor.db_pool).await? {
+        return Ok(true);
+    }
+    let config = &ingestor.config;
+    if !config.fetch.enabled {
+        return Ok(false);
+    }
+
+    let health = &ingestor.health;
+    let mut requests: FuturesUnordered<_> = health
+        .ranked(&config.relays.urls)
+        .into_iter()
+        .map(|relay_url| async move {
+            let started = Instant::now();
+            let result = request_event(relay_url, event_id, config.relays.tls_for(relay_url)).await;
+            (relay_url, started.elapsed(), result)
+        })
+        .collect();
+    let first_found = async {
+        while let Some((relay_url, elapsed, result)) = requests.next().await {
+            match result {
+                Ok(found) => {
+                    health.record_latency(relay_url, elapsed);
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+                Err(e) => {
+                    health.record_error(relay_url);
+                    eprintln!(
+                        "Error fetching event {} from {}: {}",
+                        event_id, relay_url, e
+                    )
+                }
+            }
+        }
+        None
+    };
+    let timeout = Duration::from_secs(config.fetch.timeout);
+    let Ok(Some(event)) = tokio::time::timeout(timeout, first_found).await else {
+        return Ok(false);
+    };
+
+    ingestor.ingest_event(event).await;
+    is_archived(event_id, &ingestor.db_pool).await
+}
@@ -0,0 +1,144 @@
+use chrono::NaiveDate;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::db::USAGE_BYTES;
+
+/// Events deleted per transaction, keeping the WAL and the write lock small
+const PRUNE_BATCH_SIZE: i64 = 10_000;
+
+/// Which archived events a prune removes; every set criterion must match
+#[derive(Debug, Default, Clone)]
+pub struct Retention {
+    /// Events created before this unix time
+    pub before: Option<i64>,
+    /// Events of these kinds (empty for any)
+    pub kinds: Vec<u64>,
+}
+
+/// Events of one kind a prune would remove
+#[derive(Debug, sqlx::FromRow)]
+pub struct PrunePreview {
+    pub kind: i64,
+    pub events: i64,
+    /// Bytes of content and tags, as charged against the per-pubkey quotas
+    pub bytes: i64,
+}
+
+impl Retention {
+    /// Appends the `WHERE` clause selecting the events to remove
+    fn push_conditions(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        query.push(" WHERE 1 = 1");
+        if let Some(before) = self.before {
+            query.push(" AND created_at < ");
+            query.push_bind(before);
+        }
+        if !self.kinds.is_empty() {
+            query.push(" AND kind IN (");
+            let mut separated = query.separated(", ");
+            for kind in &self.kinds {
+                separated.push_bind(*kind as i64);
+            }
+            query.push(")");
+        }
+    }
+
+    /// Counts the events a prune would remove, per kind
+    pub async fn preview(&self, db_pool: &SqlitePool) -> Result<Vec<PrunePreview>, sqlx::Error> {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT kind, COUNT(*) AS events, COALESCE(SUM({}), 0) AS bytes FROM events",
+            USAGE_BYTES
+        ));
+        self.push_conditions(&mut query);
+        query.push(" GROUP BY kind ORDER BY kind");
+        query.build_query_as().fetch_all(db_pool).await
+    }
+
+    /// Removes the matching events in batches, returning how many were removed
+    pub async fn prune(&self, db_pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+        let mut removed = 0;
+        loop {
+            let mut query =
+                QueryBuilder::new("DELETE FROM events WHERE rowid IN (SELECT rowid FROM events");
+            self.push_conditions(&mut query);
+            query.push(" LIMIT ");
+            query.push_bind(PRUNE_BATCH_SIZE);
+            query.push(")");
+            let deleted = query.build().execute(db_pool).await?.rows_affected();
+            removed += deleted;
+            if deleted == 0 {
+                return Ok(removed);
+            }
+        }
+    }
+}
+
+/// Parses a `--before` value: a `YYYY-MM-DD` date (midnight UTC) or a unix time
+fn parse_time(input: &str) -> Option<i64> {
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc().timestamp())
+        .or_else(|| input.parse().ok())
+}
+
+/// Parses the arguments following `prune`, returning the criteria and whether to only preview
+fn parse_options(args: &[String]) -> Result<(Retention, bool), String> {
+    let mut retention = Retention::default();
+    let mut dry_run = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--before" => {
+                let value = args.next().ok_or("Missing value of --before")?;
+                retention.before = Some(parse_time(value).ok_or(format!(
+                    "Invalid time: {} (expected YYYY-MM-DD or a unix time)",
+                    value
+                ))?);
+            }
+            "--kinds" => {
+                let value = args.next().ok_or("Missing value of --kinds")?;
+                retention.kinds = value
+                    .split(',')
+                    .map(|kind| {
+                        kind.trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid kind: {}", kind))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "--dry-run" => dry_run = true,
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    // Refuse to empty the whole archive by forgetting the filters.
+    if retention.before.is_none() && retention.kinds.is_empty() {
+        return Err("Missing --before or --kinds".to_string());
+    }
+    Ok((retention, dry_run))
+}
+
+/// Removes the archived events matching `--before` and `--kinds`, or with `--dry-run` only
+/// reports how many events of each kind would be removed.
+pub async fn prune(args: &[String], db_pool: &SqlitePool) -> Result<(), String> {
+    let (retention, dry_run) = parse_options(args)?;
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+
+    if dry_run {
+        let preview = retention.preview(db_pool).await.map_err(db_error)?;
+        for kind in &preview {
+            println!(
+                "kind {}: {} events ({} bytes)",
+                kind.kind, kind.events, kind.bytes
+            );
+        }
+        println!(
+            "Would remove {} events ({} bytes)",
+            preview.iter().map(|kind| kind.events).sum::<i64>(),
+            preview.iter().map(|kind| kind.bytes).sum::<i64>()
+        );
+        return Ok(());
+    }
+    let removed = retention.prune(db_pool).await.map_err(db_error)?;
+    println!("Removed {} events", removed);
+    Ok(())
+}
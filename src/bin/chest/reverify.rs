@@ -0,0 +1,123 @@
+use serde::Serialize;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::time::Duration;
+
+use crate::{nostr, DbEvent, ReverifyConfig};
+
+/// A batch of archived rows, in rowid order
+#[derive(Debug, sqlx::FromRow)]
+struct Row {
+    rowid: i64,
+    #[sqlx(flatten)]
+    event: DbEvent,
+}
+
+/// Progress of the re-verification job, reported by `GET /stats`
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Progress {
+    /// Rows checked so far
+    pub checked: i64,
+    /// Rows whose id or signature did not verify
+    pub invalid: i64,
+    /// Rows archived after the last checked one, still to be checked
+    pub pending: i64,
+    /// Unix time the last batch was checked
+    pub updated_at: i64,
+}
+
+/// Reads the progress of the job, `None` before its first batch
+pub async fn progress(db_pool: &SqlitePool) -> Result<Option<Progress>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT checked, invalid,
+             (SELECT COUNT(*) FROM events WHERE rowid > last_rowid AND truncated = 0) AS pending, updated_at
+         FROM reverification WHERE id = 1",
+    )
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Checks the next batch of rows, marking those whose id or signature does not verify. Rows
+/// truncated on ingestion are known not to verify and skipped. Returns the number of rows
+/// checked, 0 once every archived row was.
+async fn check_batch(db_pool: &SqlitePool, batch_size: i64) -> Result<usize, sqlx::Error> {
+    let last_rowid: Option<(i64,)> =
+        sqlx::query_as("SELECT last_rowid FROM reverification WHERE id = 1")
+            .fetch_optional(db_pool)
+            .await?;
+    let rows: Vec<Row> = sqlx::query_as(
+        "SELECT rowid, event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event
+         FROM events WHERE rowid > ? AND truncated = 0 ORDER BY rowid LIMIT ?",
+    )
+    .bind(last_rowid.map_or(0, |(rowid,)| rowid))
+    .bind(batch_size)
+    .fetch_all(db_pool)
+    .await?;
+    let Some(last) = rows.last().map(|row| row.rowid) else {
+        return Ok(0);
+    };
+    let checked = rows.len();
+    // Schnorr verification is CPU bound: keep it off the runtime's worker threads.
+    let invalid = tokio::task::spawn_blocking(move || {
+        rows.into_iter()
+            .filter(|row| row.event.to_nostr().verify().is_err())
+            .map(|row| row.event.event_id)
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    let mut tx = db_pool.begin().await?;
+    if !invalid.is_empty() {
+        let mut query =
+            QueryBuilder::<Sqlite>::new("UPDATE events SET invalid = 1 WHERE event_id IN (");
+        let mut ids = query.separated(", ");
+        for event_id in &invalid {
+            ids.push_bind(event_id);
+        }
+        query.push(")");
+        query.build().execute(&mut tx).await?;
+    }
+    sqlx::query(
+        "INSERT INTO reverification (id, last_rowid, checked, invalid, updated_at)
+         VALUES (1, ?, ?, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET last_rowid = excluded.last_rowid,
+             checked = checked + excluded.checked, invalid = invalid + excluded.invalid,
+             updated_at = excluded.updated_at",
+    )
+    .bind(last)
+    .bind(checked as i64)
+    .bind(invalid.len() as i64)
+    .bind(nostr::now() as i64)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    if !invalid.is_empty() {
+        println!(
+            "Re-verification: {} of {} events failed to verify",
+            invalid.len(),
+            checked
+        );
+    }
+    Ok(checked)
+}
+
+/// Walks the archived events in small batches, pausing between them so ingestion and requests
+/// keep priority, then keeps checking the events archived since
+pub fn spawn(config: ReverifyConfig, db_pool: SqlitePool) {
+    if !config.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            let pause = match check_batch(&db_pool, config.batch_size.max(1)).await {
+                Ok(0) => Duration::from_secs(config.idle_interval.max(1)),
+                Ok(_) => Duration::from_millis(config.batch_pause),
+                Err(e) => {
+                    eprintln!("Re-verification batch failed: {:?}", e);
+                    Duration::from_secs(config.idle_interval.max(1))
+                }
+            };
+            tokio::time::sleep(pause).await;
+        }
+    });
+}
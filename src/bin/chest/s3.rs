@@ -0,0 +1,108 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use url::Url;
+
+use crate::S3Config;
+
+/// Payload hash sent instead of hashing the whole snapshot before uploading it
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Percent-encodes a path segment as required by AWS Signature Version 4
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Uploads a file to the configured bucket under `prefix` + `name`, signing the request with
+/// AWS Signature Version 4. Returns the object key.
+pub async fn upload(config: &S3Config, path: &Path, name: &str) -> Result<String, String> {
+    let key = format!("{}{}", config.prefix, name);
+    let mut url = Url::parse(&config.endpoint)
+        .map_err(|e| format!("Invalid endpoint {:?}: {}", config.endpoint, e))?;
+    let object_path = if config.path_style {
+        format!("/{}/{}", config.bucket, key)
+    } else {
+        let host = url.host_str().unwrap_or_default().to_string();
+        url.set_host(Some(&format!("{}.{}", config.bucket, host)))
+            .map_err(|e| format!("Invalid bucket host: {}", e))?;
+        format!("/{}", key)
+    };
+    let canonical_uri: String = object_path
+        .split('/')
+        .map(uri_encode)
+        .collect::<Vec<_>>()
+        .join("/");
+    url.set_path(&canonical_uri);
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        canonical_uri, host, UNSIGNED_PAYLOAD, amz_date, signed_headers, UNSIGNED_PAYLOAD
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = [date.as_str(), config.region.as_str(), "s3", "aws4_request"]
+        .iter()
+        .fold(
+            format!("AWS4{}", config.secret_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part),
+        );
+    let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key, scope, signed_headers, signature
+    );
+
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let length = file
+        .metadata()
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .len();
+    let response = reqwest::Client::new()
+        .put(url)
+        .header(AUTHORIZATION, authorization)
+        .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+        .header("x-amz-date", amz_date)
+        .header(CONTENT_LENGTH, length)
+        .body(file)
+        .send()
+        .await
+        .map_err(|e| format!("Upload request failed: {}", e))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Upload rejected with {}: {}", status, body));
+    }
+    Ok(key)
+}
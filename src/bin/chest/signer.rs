@@ -0,0 +1,396 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::{SinkExt, StreamExt};
+use secp256k1::{Keypair, Message as SchnorrMessage, SecretKey, SECP256K1};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use uuid::Uuid;
+
+use crate::audit;
+use crate::crypto;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::ingest::Ingestor;
+use crate::nostr::{self, NostrEvent};
+use crate::{RelayWriters, SignerConfig};
+
+/// NIP-46 request and response messages exchanged with the remote signer
+const NOSTR_CONNECT_KIND: u64 = 24133;
+
+/// NIP-42 event answering a relay's AUTH challenge
+const CLIENT_AUTH_KIND: u64 = 22242;
+
+/// How long a request waits for the remote signer, which may ask its user for approval
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delay before reconnecting to the bunker relays after the connection dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// An event before it is signed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsignedEvent {
+    pub kind: u64,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub tags: Vec<Vec<String>>,
+    /// Defaults to the current time
+    pub created_at: Option<u64>,
+}
+
+/// Signs an event with a local key
+fn sign_local(keypair: &Keypair, event: UnsignedEvent) -> NostrEvent {
+    let mut event = NostrEvent {
+        id: String::new(),
+        pubkey: keypair.x_only_public_key().0.to_string(),
+        created_at: event.created_at.unwrap_or_else(nostr::now),
+        kind: event.kind,
+        tags: event.tags,
+        content: event.content,
+        sig: String::new(),
+    };
+    event.id = event.compute_id();
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(&event.id, &mut digest).expect("event ids are 32 hex bytes");
+    event.sig = SECP256K1
+        .sign_schnorr(&SchnorrMessage::from_digest(digest), keypair)
+        .to_string();
+    event
+}
+
+/// Parsed `bunker://<remote signer pubkey>?relay=<url>&secret=<secret>` URI
+pub fn parse_bunker_uri(uri: &str) -> Result<(String, Vec<String>, Option<String>), String> {
+    let url = url::Url::parse(uri).map_err(|e| format!("Invalid bunker URI: {}", e))?;
+    if url.scheme() != "bunker" {
+        return Err("Bunker URI must start with bunker://".to_string());
+    }
+    let remote_pubkey = url
+        .host_str()
+        .and_then(nostr::parse_pubkey)
+        .ok_or("Bunker URI has no valid remote signer pubkey")?;
+    let mut relays = Vec::new();
+    let mut secret = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "relay" => relays.push(value.into_owned()),
+            "secret" => secret = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    if relays.is_empty() {
+        return Err("Bunker URI has no relay".to_string());
+    }
+    Ok((remote_pubkey, relays, secret))
+}
+
+/// NIP-46 client of a remote signer, reached through the relays of its bunker URI
+#[derive(Debug)]
+struct Bunker {
+    /// Key chest identifies itself to the remote signer with; it signs nothing else
+    client: Keypair,
+    remote_pubkey: String,
+    conversation_key: [u8; 32],
+    outgoing: mpsc::Sender<String>,
+    /// Reply channels of the requests awaiting a response, keyed by request id
+    pending: Mutex<HashMap<String, oneshot::Sender<Result<String, String>>>>,
+}
+
+impl Bunker {
+    /// Sends a request to the remote signer and waits for its result
+    async fn request(&self, method: &str, params: Vec<String>) -> Result<String, String> {
+        let id = Uuid::new_v4().to_string();
+        let payload = serde_json::json!({ "id": id, "method": method, "params": params });
+        let content = crypto::nip44_encrypt(&self.conversation_key, &payload.to_string())
+            .ok_or("Failed to encrypt signer request")?;
+        let event = sign_local(
+            &self.client,
+            UnsignedEvent {
+                kind: NOSTR_CONNECT_KIND,
+                content,
+                tags: vec![vec!["p".to_string(), self.remote_pubkey.clone()]],
+                created_at: None,
+            },
+        );
+
+        let (reply, response) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), reply);
+        let message = serde_json::json!(["EVENT", event]).to_string();
+        if self.outgoing.send(message).await.is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return Err("Bunker connection is closed".to_string());
+        }
+        match tokio::time::timeout(REQUEST_TIMEOUT, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("Bunker connection is closed".to_string()),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(format!("Remote signer did not answer {} in time", method))
+            }
+        }
+    }
+
+    /// Hands a response from the remote signer to the request it answers
+    fn handle_message(&self, text: &str) {
+        let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        if parts.first().and_then(Value::as_str) != Some("EVENT") {
+            return;
+        }
+        let Some(event) = parts
+            .get(2)
+            .cloned()
+            .and_then(|event| serde_json::from_value::<NostrEvent>(event).ok())
+        else {
+            return;
+        };
+        if event.kind != NOSTR_CONNECT_KIND
+            || event.pubkey != self.remote_pubkey
+            || event.verify().is_err()
+        {
+            return;
+        }
+        let Some(response) = crypto::nip44_decrypt(&self.conversation_key, &event.content)
+            .and_then(|plaintext| serde_json::from_str::<Value>(&plaintext).ok())
+        else {
+            eprintln!("Undecryptable message from remote signer: {}", event.id);
+            return;
+        };
+        let result = response["result"].as_str().unwrap_or_default();
+        let error = response["error"].as_str().unwrap_or_default();
+        // The request stays pending while the user approves it at the given URL.
+        if result == "auth_url" {
+            println!("Remote signer asks for approval at: {}", error);
+            return;
+        }
+        let Some(id) = response["id"].as_str() else {
+            return;
+        };
+        if let Some(reply) = self.pending.lock().unwrap().remove(id) {
+            let _ = reply.send(if error.is_empty() {
+                Ok(result.to_string())
+            } else {
+                Err(error.to_string())
+            });
+        }
+    }
+
+    /// Relays requests to the remote signer and its responses back, reconnecting to the next
+    /// bunker relay whenever the connection drops
+    fn spawn(self: Arc<Self>, relays: Vec<String>, mut outgoing: mpsc::Receiver<String>) {
+        tokio::spawn(async move {
+            let client_pubkey = self.client.x_only_public_key().0.to_string();
+            for relay_url in relays.iter().cycle() {
+                let mut ws_stream = match connect_async(relay_url.as_str()).await {
+                    Ok((ws_stream, _)) => ws_stream,
+                    Err(e) => {
+                        eprintln!("Failed to connect to bunker relay {}: {}", relay_url, e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+                let req_message = serde_json::json!([
+                    "REQ",
+                    Uuid::new_v4().to_string(),
+                    {
+                        "kinds": [NOSTR_CONNECT_KIND],
+                        "#p": [client_pubkey],
+                        "since": nostr::now(),
+                    }
+                ]);
+                if ws_stream
+                    .send(Message::Text(req_message.to_string()))
+                    .await
+                    .is_ok()
+                {
+                    println!("Connected to bunker relay: {}", relay_url);
+                    loop {
+                        tokio::select! {
+                            request = outgoing.recv() => {
+                                let Some(request) = request else {
+                                    return;
+                                };
+                                if let Err(e) = ws_stream.send(Message::Text(request)).await {
+                                    eprintln!("Error sending to bunker relay {}: {}", relay_url, e);
+                                    break;
+                                }
+                            }
+                            message = ws_stream.next() => match message {
+                                Some(Ok(Message::Text(text))) => self.handle_message(&text),
+                                Some(Ok(Message::Close(_))) | None => break,
+                                Some(Err(e)) => {
+                                    eprintln!("Error receiving from bunker relay {}: {}", relay_url, e);
+                                    break;
+                                }
+                                _ => {}
+                            },
+                        }
+                    }
+                }
+                eprintln!("Bunker relay connection closed: {}", relay_url);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+}
+
+/// Signs the events chest publishes and its NIP-42 AUTH responses through a NIP-46 remote
+/// signer, so the operator's key never has to be stored by chest
+#[derive(Debug)]
+pub struct Signer {
+    /// Hex pubkey events are signed with
+    pub pubkey: String,
+    /// Whether to answer the AUTH challenges of the upstream relays
+    auth: bool,
+    bunker: Arc<Bunker>,
+}
+
+impl Signer {
+    /// Connects to the remote signer of the configured bunker URI. Returns `Ok(None)` when no
+    /// bunker is configured.
+    pub async fn connect(config: &SignerConfig) -> Result<Option<Self>, String> {
+        if config.bunker.is_empty() {
+            return Ok(None);
+        }
+        let (remote_pubkey, relays, secret) = parse_bunker_uri(&config.bunker)?;
+        let client_secret = if config.client_key.is_empty() {
+            SecretKey::new(&mut rand::thread_rng())
+        } else {
+            crypto::parse_secret_key(&config.client_key).ok_or("Invalid signer.client_key")?
+        };
+        let conversation_key = crypto::nip44_conversation_key(&client_secret, &remote_pubkey)
+            .ok_or("Invalid remote signer pubkey")?;
+        let (outgoing, receiver) = mpsc::channel(64);
+        let bunker = Arc::new(Bunker {
+            client: Keypair::from_secret_key(SECP256K1, &client_secret),
+            remote_pubkey: remote_pubkey.clone(),
+            conversation_key,
+            outgoing,
+            pending: Mutex::new(HashMap::new()),
+        });
+        bunker.clone().spawn(relays, receiver);
+
+        let mut params = vec![remote_pubkey];
+        params.extend(secret);
+        bunker
+            .request("connect", params)
+            .await
+            .map_err(|e| format!("Remote signer refused the connection: {}", e))?;
+        let pubkey = bunker.request("get_public_key", Vec::new()).await?;
+        if !nostr::is_hex32(&pubkey) {
+            return Err(format!(
+                "Remote signer returned an invalid pubkey: {}",
+                pubkey
+            ));
+        }
+        Ok(Some(Self {
+            pubkey,
+            auth: config.auth,
+            bunker,
+        }))
+    }
+
+    /// Has the remote signer sign an event, checking that it signed it with the expected key
+    pub async fn sign(&self, event: UnsignedEvent) -> Result<NostrEvent, String> {
+        let event = UnsignedEvent {
+            created_at: Some(event.created_at.unwrap_or_else(nostr::now)),
+            ..event
+        };
+        let template = serde_json::to_string(&event).map_err(|e| e.to_string())?;
+        let signed = self.bunker.request("sign_event", vec![template]).await?;
+        let signed: NostrEvent = serde_json::from_str(&signed)
+            .map_err(|e| format!("Remote signer returned an invalid event: {}", e))?;
+        if signed.pubkey != self.pubkey || signed.kind != event.kind || signed.verify().is_err() {
+            return Err("Remote signer returned an event with an invalid signature".to_string());
+        }
+        Ok(signed)
+    }
+
+    /// Returns the signed `["AUTH", event]` answer to a relay message, if it is an AUTH
+    /// challenge that should be answered
+    pub async fn answer_auth(&self, relay_url: &str, text: &str) -> Option<Value> {
+        if !self.auth || !text.contains("\"AUTH\"") {
+            return None;
+        }
+        let parts: Vec<Value> = serde_json::from_str(text).ok()?;
+        if parts.first().and_then(Value::as_str) != Some("AUTH") {
+            return None;
+        }
+        let challenge = parts.get(1).and_then(Value::as_str)?;
+        let event = UnsignedEvent {
+            kind: CLIENT_AUTH_KIND,
+            content: String::new(),
+            tags: vec![
+                vec!["relay".to_string(), relay_url.to_string()],
+                vec!["challenge".to_string(), challenge.to_string()],
+            ],
+            created_at: None,
+        };
+        match self.sign(event).await {
+            Ok(event) => Some(serde_json::json!(["AUTH", event])),
+            Err(e) => {
+                eprintln!("Failed to sign AUTH for {}: {}", relay_url, e);
+                None
+            }
+        }
+    }
+}
+
+/// Sends an event to every upstream relay, returning the URLs of the relays it was sent to
+pub async fn broadcast(relays: &RelayWriters, event: &NostrEvent) -> Vec<String> {
+    let message = serde_json::json!(["EVENT", event]).to_string();
+    let mut published = Vec::new();
+    for (relay_url, writer) in &relays.0 {
+        match writer
+            .lock()
+            .await
+            .send(Message::Text(message.clone()))
+            .await
+        {
+            Ok(()) => published.push(relay_url.clone()),
+            Err(e) => eprintln!("Error publishing to relay {}: {}", relay_url, e),
+        }
+    }
+    published.sort();
+    published
+}
+
+/// Signs an event with the remote signer, archives it and publishes it to every upstream relay.
+pub async fn publish_event(
+    req: HttpRequest,
+    body: web::Json<UnsignedEvent>,
+    ingestor: web::Data<Ingestor>,
+    relays: web::Data<RelayWriters>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    let signer = ingestor
+        .signer
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("No remote signer is configured".to_string()))?;
+    let event = signer
+        .sign(body.into_inner())
+        .await
+        .map_err(ApiError::BadGateway)?;
+
+    // Recorded before the event leaves, so a failure to audit cannot follow a publication.
+    audit::record(
+        &write_pool.0,
+        &audit::actor(&req),
+        "publish_event",
+        &event.id,
+        None,
+        Some(&serde_json::json!({ "kind": event.kind })),
+    )
+    .await?;
+    let published = broadcast(&relays, &event).await;
+    let archived = ingestor.ingest_event(event.clone()).await.is_some();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "event": event,
+        "archived": archived,
+        "relays": published,
+    })))
+}
@@ -0,0 +1,637 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::error::ApiError;
+use crate::feeds::rfc3339;
+use crate::{nostr, reverify, StatsConfig};
+
+/// Minutes of ingestion history kept for the rates
+const RATE_WINDOW_MINUTES: u64 = 24 * 60;
+
+/// Number of authors returned by `GET /stats/authors` unless `limit` is given
+const DEFAULT_AUTHORS_LIMIT: usize = 50;
+
+/// Number of clients reported by `GET /stats/clients` unless `limit` is given
+const DEFAULT_CLIENTS_LIMIT: i64 = 20;
+
+/// Seconds covered by a row of the activity table, the finest time series resolution
+const ACTIVITY_BUCKET: u64 = 3600;
+
+/// Archive aggregates, recomputed periodically so requests never scan the events table
+#[derive(Debug, Clone, Serialize)]
+struct StatsSnapshot {
+    total_events: i64,
+    folders: BTreeMap<String, i64>,
+    kinds: BTreeMap<i64, i64>,
+    distinct_pubkeys: i64,
+    oldest_created_at: Option<i64>,
+    newest_created_at: Option<i64>,
+    /// Bytes used by the database file
+    database_size: i64,
+    /// Events whose id or signature failed re-verification
+    invalid_events: i64,
+    /// Progress of the signature re-verification job, `null` until it checked a batch
+    reverification: Option<reverify::Progress>,
+    /// Unix time the aggregates were computed
+    computed_at: i64,
+}
+
+/// Cached archive statistics and counts of the events archived since startup
+#[derive(Debug)]
+pub struct Stats {
+    config: StatsConfig,
+    snapshot: RwLock<Option<StatsSnapshot>>,
+    /// Events archived per minute (unix time / 60), oldest first
+    ingested: Mutex<VecDeque<(u64, u64)>>,
+}
+
+/// Computes the aggregates of the whole archive
+async fn compute_snapshot(db_pool: &SqlitePool) -> Result<StatsSnapshot, sqlx::Error> {
+    let folders: Vec<(String, i64)> =
+        sqlx::query_as("SELECT folder, COUNT(*) FROM public_events AS events GROUP BY folder")
+            .fetch_all(db_pool)
+            .await?;
+    let kinds: Vec<(i64, i64)> =
+        sqlx::query_as("SELECT kind, COUNT(*) FROM public_events AS events GROUP BY kind")
+            .fetch_all(db_pool)
+            .await?;
+    let (distinct_pubkeys, oldest_created_at, newest_created_at): (i64, Option<i64>, Option<i64>) =
+        sqlx::query_as(
+            "SELECT COUNT(DISTINCT pubkey), MIN(created_at), MAX(created_at) FROM public_events AS events",
+        )
+        .fetch_one(db_pool)
+        .await?;
+    let (database_size,): (i64,) = sqlx::query_as(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+    )
+    .fetch_one(db_pool)
+    .await?;
+    let (invalid_events,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM public_events AS events WHERE invalid = 1")
+            .fetch_one(db_pool)
+            .await?;
+    Ok(StatsSnapshot {
+        total_events: folders.iter().map(|(_, count)| count).sum(),
+        folders: folders.into_iter().collect(),
+        kinds: kinds.into_iter().collect(),
+        distinct_pubkeys,
+        oldest_created_at,
+        newest_created_at,
+        database_size,
+        invalid_events,
+        reverification: reverify::progress(db_pool).await?,
+        computed_at: nostr::now() as i64,
+    })
+}
+
+impl Stats {
+    pub fn new(config: StatsConfig) -> Self {
+        Self {
+            config,
+            snapshot: RwLock::new(None),
+            ingested: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Counts an event newly written to the archive
+    pub fn record_ingested(&self) {
+        let minute = nostr::now() / 60;
+        let mut ingested = self.ingested.lock().unwrap();
+        match ingested.back_mut() {
+            Some((last, count)) if *last == minute => *count += 1,
+            _ => ingested.push_back((minute, 1)),
+        }
+        while ingested
+            .front()
+            .is_some_and(|(first, _)| *first + RATE_WINDOW_MINUTES <= minute)
+        {
+            ingested.pop_front();
+        }
+    }
+
+    /// Returns the number of events archived within the last `minutes`
+    fn ingested_since(&self, minutes: u64) -> u64 {
+        let now = nostr::now() / 60;
+        self.ingested
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(minute, _)| minute + minutes > now)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Recomputes the aggregates every `refresh_interval` seconds
+    pub fn spawn_refresh(self: Arc<Self>, db_pool: SqlitePool) {
+        tokio::spawn(async move {
+            let period = Duration::from_secs(self.config.refresh_interval.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match compute_snapshot(&db_pool).await {
+                    Ok(snapshot) => *self.snapshot.write().unwrap() = Some(snapshot),
+                    Err(e) => eprintln!("Failed to compute archive statistics: {:?}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Reports archive statistics and recent ingestion rates.
+pub async fn get_stats(
+    stats: web::Data<Stats>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let cached = stats.snapshot.read().unwrap().clone();
+    let snapshot = match cached {
+        Some(snapshot) => snapshot,
+        // Requests arriving before the first refresh compute the aggregates themselves.
+        None => {
+            let snapshot = compute_snapshot(db_pool.get_ref()).await?;
+            *stats.snapshot.write().unwrap() = Some(snapshot.clone());
+            snapshot
+        }
+    };
+    let mut body = serde_json::to_value(snapshot).unwrap_or_default();
+    body["ingested_last_hour"] = stats.ingested_since(60).into();
+    body["ingested_last_day"] = stats.ingested_since(RATE_WINDOW_MINUTES).into();
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Parses a duration such as `90s`, `15m`, `6h`, `1d` or `2w` into seconds
+pub fn parse_duration(input: &str) -> Option<u64> {
+    let split = input.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = input.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.parse::<u64>().ok()?.checked_mul(seconds)
+}
+
+/// Query parameters of `GET /stats/timeseries`
+#[derive(Debug, Deserialize)]
+pub struct TimeseriesQuery {
+    /// Width of the buckets, a whole number of hours (default `1d`)
+    bucket: Option<String>,
+    /// Comma-separated kinds to count (default all)
+    kinds: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+/// Number of events created within one bucket
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct TimeseriesPoint {
+    /// Unix time the bucket starts at
+    start: i64,
+    count: i64,
+}
+
+/// Reports the number of archived events per time bucket of their `created_at`. Buckets are
+/// aligned to multiples of their width since the unix epoch, so days start at midnight UTC.
+pub async fn get_timeseries(
+    params: web::Query<TimeseriesQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let bucket_param = params.bucket.as_deref().unwrap_or("1d");
+    let bucket = parse_duration(bucket_param)
+        .filter(|bucket| *bucket > 0 && bucket % ACTIVITY_BUCKET == 0)
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Invalid bucket: {} (expected a whole number of hours, e.g. 6h or 1d)",
+                bucket_param
+            ))
+        })?;
+    let kinds = params
+        .kinds
+        .iter()
+        .flat_map(|kinds| kinds.split(','))
+        .map(|kind| {
+            kind.trim()
+                .parse::<i64>()
+                .map_err(|_| ApiError::BadRequest(format!("Invalid kind: {}", kind)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut query = QueryBuilder::<Sqlite>::new("SELECT bucket - bucket % ");
+    query.push_bind(bucket as i64);
+    query.push(" AS start, SUM(count) AS count FROM activity WHERE count > 0");
+    if !kinds.is_empty() {
+        query.push(" AND kind IN (");
+        let mut separated = query.separated(", ");
+        for kind in &kinds {
+            separated.push_bind(*kind);
+        }
+        query.push(")");
+    }
+    if let Some(since) = params.since {
+        query.push(" AND bucket >= ");
+        query.push_bind((since - since % bucket) as i64);
+    }
+    if let Some(until) = params.until {
+        query.push(" AND bucket < ");
+        query.push_bind(until as i64);
+    }
+    query.push(" GROUP BY start ORDER BY start");
+    let points = query
+        .build_query_as::<TimeseriesPoint>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "bucket": bucket,
+        "points": points,
+    })))
+}
+
+/// Measure authors are ranked by in `GET /stats/authors`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthorRanking {
+    /// Notes published
+    #[default]
+    Notes,
+    /// Reactions received on archived events
+    ReactionsReceived,
+    /// Sats received in zap receipts
+    ZapSats,
+}
+
+/// Query parameters of `GET /stats/authors`
+#[derive(Debug, Deserialize)]
+pub struct AuthorsQuery {
+    /// Period counted back from now (default `30d`)
+    window: Option<String>,
+    #[serde(default)]
+    by: AuthorRanking,
+    limit: Option<usize>,
+}
+
+/// An author's rank score with their profile metadata
+#[derive(Debug, Serialize)]
+struct RankedAuthor {
+    pubkey: String,
+    score: i64,
+    /// Parsed kind 0 content, `null` if the profile is not archived
+    profile: Value,
+}
+
+/// Sums the sats zapped to each pubkey since `since`, using the amounts of the receipts' invoices
+async fn zap_sats_by_recipient(
+    db_pool: &SqlitePool,
+    since: i64,
+) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    let receipts: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT json_extract(recipient.value, '$[1]'), json_extract(invoice.value, '$[1]')
+        FROM public_events AS events, json_each(events.tags) AS recipient, json_each(events.tags) AS invoice
+        WHERE folder = 'zaps' AND created_at >= ?
+          AND json_extract(recipient.value, '$[0]') = 'p'
+          AND json_extract(invoice.value, '$[0]') = 'bolt11'
+        "#,
+    )
+    .bind(since)
+    .fetch_all(db_pool)
+    .await?;
+    let mut totals: HashMap<String, i64> = HashMap::new();
+    for (pubkey, invoice) in receipts {
+        if let Some(msats) = nostr::bolt11_msats(&invoice) {
+            *totals.entry(pubkey).or_default() += (msats / 1000) as i64;
+        }
+    }
+    let mut totals: Vec<(String, i64)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(totals)
+}
+
+/// Scores the top `limit` pubkeys by a ranking measure since `since`, best first
+async fn author_scores(
+    db_pool: &SqlitePool,
+    by: AuthorRanking,
+    since: i64,
+    limit: usize,
+) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    match by {
+        AuthorRanking::Notes => {
+            sqlx::query_as(
+                "SELECT pubkey, COUNT(*) AS score FROM public_events AS events
+                 WHERE folder = 'notes' AND created_at >= ?
+                 GROUP BY pubkey ORDER BY score DESC, pubkey LIMIT ?",
+            )
+            .bind(since)
+            .bind(limit as i64)
+            .fetch_all(db_pool)
+            .await
+        }
+        // Reactions count towards the author of the archived event they react to.
+        AuthorRanking::ReactionsReceived => {
+            sqlx::query_as(
+                "SELECT target.pubkey, COUNT(*) AS score
+                 FROM public_events AS reaction JOIN public_events AS target ON target.event_id = reaction.ref_event
+                 WHERE reaction.folder = 'reactions' AND reaction.created_at >= ?
+                 GROUP BY target.pubkey ORDER BY score DESC, target.pubkey LIMIT ?",
+            )
+            .bind(since)
+            .bind(limit as i64)
+            .fetch_all(db_pool)
+            .await
+        }
+        AuthorRanking::ZapSats => {
+            let mut totals = zap_sats_by_recipient(db_pool, since).await?;
+            totals.truncate(limit);
+            Ok(totals)
+        }
+    }
+}
+
+/// Loads the parsed kind 0 content of the archived profiles among `pubkeys`
+async fn fetch_profiles(
+    db_pool: &SqlitePool,
+    pubkeys: impl Iterator<Item = &String>,
+) -> Result<HashMap<String, Value>, sqlx::Error> {
+    let mut profiles = HashMap::new();
+    let mut pubkeys = pubkeys.peekable();
+    if pubkeys.peek().is_none() {
+        return Ok(profiles);
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT pubkey, content FROM public_events AS events WHERE folder = 'users' AND pubkey IN (",
+    );
+    let mut separated = query.separated(", ");
+    for pubkey in pubkeys {
+        separated.push_bind(pubkey);
+    }
+    query.push(")");
+    let rows: Vec<(String, String)> = query.build_query_as().fetch_all(db_pool).await?;
+    for (pubkey, content) in rows {
+        profiles.insert(pubkey, serde_json::from_str(&content).unwrap_or_default());
+    }
+    Ok(profiles)
+}
+
+/// Ranks pubkeys by notes published, reactions received or sats zapped to them within a
+/// window, with their archived profiles.
+pub async fn top_authors(
+    params: web::Query<AuthorsQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let window_param = params.window.as_deref().unwrap_or("30d");
+    let window = parse_duration(window_param)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid window: {}", window_param)))?;
+    let since = nostr::now().saturating_sub(window) as i64;
+    let limit = params.limit.unwrap_or(DEFAULT_AUTHORS_LIMIT);
+
+    let scores = author_scores(db_pool.get_ref(), params.by, since, limit).await?;
+    let mut profiles =
+        fetch_profiles(db_pool.get_ref(), scores.iter().map(|(pubkey, _)| pubkey)).await?;
+    let authors: Vec<RankedAuthor> = scores
+        .into_iter()
+        .map(|(pubkey, score)| RankedAuthor {
+            profile: profiles.remove(&pubkey).unwrap_or_default(),
+            pubkey,
+            score,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "window": window,
+        "authors": authors,
+    })))
+}
+
+/// Query parameters of `GET /stats/clients`
+#[derive(Debug, Deserialize)]
+pub struct ClientsQuery {
+    /// Width of the buckets (default `30d`)
+    bucket: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    /// Number of clients reported, those used by the most authors first
+    limit: Option<i64>,
+}
+
+/// Use of a client within a period
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ClientUsage {
+    client: String,
+    /// Notes and replies published with the client
+    notes: i64,
+    /// Distinct authors who published with it
+    authors: i64,
+}
+
+/// Use of the clients within one bucket
+#[derive(Debug, Serialize)]
+struct ClientPoint {
+    /// Unix time the bucket starts at
+    start: i64,
+    clients: Vec<ClientUsage>,
+}
+
+/// Reports which clients the archived notes and replies were published with, according to their
+/// NIP-89 `client` tag: the clients used by the most authors over the whole period, and their
+/// use per time bucket. Buckets are aligned to multiples of their width since the unix epoch.
+pub async fn client_usage(
+    params: web::Query<ClientsQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let bucket_param = params.bucket.as_deref().unwrap_or("30d");
+    let bucket = parse_duration(bucket_param)
+        .filter(|bucket| *bucket > 0)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid bucket: {}", bucket_param)))?
+        as i64;
+    let since = params.since.unwrap_or(0) as i64;
+    let until = params.until.map_or(i64::MAX, |until| until as i64);
+    let limit = params.limit.unwrap_or(DEFAULT_CLIENTS_LIMIT).clamp(1, 1000);
+
+    let clients: Vec<ClientUsage> = sqlx::query_as(
+        "SELECT client, COUNT(*) AS notes, COUNT(DISTINCT pubkey) AS authors FROM note_clients
+         WHERE created_at >= ? AND created_at < ?
+         GROUP BY client ORDER BY authors DESC, notes DESC, client LIMIT ?",
+    )
+    .bind(since)
+    .bind(until)
+    .bind(limit)
+    .fetch_all(db_pool.get_ref())
+    .await?;
+
+    // Rows of the reported clients only, per bucket
+    let rows: Vec<(i64, String, i64, i64)> = if clients.is_empty() {
+        Vec::new()
+    } else {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT created_at - created_at % ");
+        query.push_bind(bucket);
+        query.push(
+            " AS start, client, COUNT(*) AS notes, COUNT(DISTINCT pubkey) AS authors
+             FROM note_clients WHERE created_at >= ",
+        );
+        query.push_bind(since);
+        query.push(" AND created_at < ");
+        query.push_bind(until);
+        query.push(" AND client IN (");
+        let mut separated = query.separated(", ");
+        for usage in &clients {
+            separated.push_bind(&usage.client);
+        }
+        query.push(") GROUP BY start, client ORDER BY start, authors DESC, notes DESC, client");
+        query.build_query_as().fetch_all(db_pool.get_ref()).await?
+    };
+    let mut points: Vec<ClientPoint> = Vec::new();
+    for (start, client, notes, authors) in rows {
+        let usage = ClientUsage {
+            client,
+            notes,
+            authors,
+        };
+        match points.last_mut() {
+            Some(point) if point.start == start => point.clients.push(usage),
+            _ => points.push(ClientPoint {
+                start,
+                clients: vec![usage],
+            }),
+        }
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "bucket": bucket,
+        "clients": clients,
+        "points": points,
+    })))
+}
+
+/// Options of `chest stats`
+#[derive(Debug)]
+struct StatsOptions {
+    window: u64,
+    by: AuthorRanking,
+    limit: usize,
+}
+
+/// Parses the arguments following `stats`
+fn parse_options(args: &[String]) -> Result<StatsOptions, String> {
+    let mut options = StatsOptions {
+        window: 30 * 24 * 60 * 60,
+        by: AuthorRanking::default(),
+        limit: 10,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("Missing value of {}", arg));
+        match arg.as_str() {
+            "--window" => {
+                let value = value?;
+                options.window =
+                    parse_duration(value).ok_or(format!("Invalid window: {}", value))?;
+            }
+            "--by" => {
+                let value = value?;
+                options.by =
+                    serde_json::from_value(Value::String(value.clone())).map_err(|_| {
+                        format!(
+                            "Invalid ranking: {} (expected notes, reactions_received or zap_sats)",
+                            value
+                        )
+                    })?;
+            }
+            "--limit" => {
+                let value = value?;
+                options.limit = value
+                    .parse()
+                    .map_err(|_| format!("Invalid limit: {}", value))?;
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+/// Prints the archive statistics and top authors of `GET /stats` and `GET /stats/authors`
+/// without the server running.
+pub async fn print_stats(args: &[String], db_pool: &SqlitePool) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+
+    let snapshot = compute_snapshot(db_pool).await.map_err(db_error)?;
+    println!(
+        "Events: {} by {} pubkeys",
+        snapshot.total_events, snapshot.distinct_pubkeys
+    );
+    if let (Some(oldest), Some(newest)) = (snapshot.oldest_created_at, snapshot.newest_created_at) {
+        println!("Created: {} to {}", rfc3339(oldest), rfc3339(newest));
+    }
+    println!(
+        "Database size: {:.1} MiB",
+        snapshot.database_size as f64 / (1024.0 * 1024.0)
+    );
+    if snapshot.invalid_events > 0 {
+        println!("Invalid events: {}", snapshot.invalid_events);
+    }
+
+    println!("\nFolders:");
+    let mut folders: Vec<_> = snapshot.folders.iter().collect();
+    folders.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    for (folder, count) in folders {
+        println!("  {:<20} {:>12}", folder, count);
+    }
+    println!("\nKinds:");
+    let mut kinds: Vec<_> = snapshot.kinds.iter().collect();
+    kinds.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    for (kind, count) in kinds {
+        println!("  {:<20} {:>12}", kind, count);
+    }
+
+    let since = nostr::now().saturating_sub(options.window) as i64;
+    let scores = author_scores(db_pool, options.by, since, options.limit)
+        .await
+        .map_err(db_error)?;
+    let profiles = fetch_profiles(db_pool, scores.iter().map(|(pubkey, _)| pubkey))
+        .await
+        .map_err(db_error)?;
+    let by = serde_json::to_value(options.by).unwrap_or_default();
+    println!(
+        "\nTop authors by {} since {}:",
+        by.as_str().unwrap_or_default(),
+        rfc3339(since)
+    );
+    for (rank, (pubkey, score)) in scores.iter().enumerate() {
+        let name = profiles
+            .get(pubkey)
+            .and_then(|profile| {
+                profile["name"]
+                    .as_str()
+                    .or(profile["display_name"].as_str())
+            })
+            .unwrap_or_default();
+        println!("  {:>3}. {} {:>12}  {}", rank + 1, pubkey, score, name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn direct_messages_are_not_counted() {
+        let db_pool = db::test_pool().await;
+        db::store_test_events(
+            &db_pool,
+            &[&db::test_event('a', 1), &db::test_event('b', 4)],
+        )
+        .await;
+
+        let snapshot = compute_snapshot(&db_pool).await.unwrap();
+
+        assert_eq!(snapshot.total_events, 1);
+        assert!(!snapshot.folders.contains_key("dms"));
+        assert!(!snapshot.kinds.contains_key(&4));
+    }
+}
@@ -0,0 +1,98 @@
+use actix_web::http::header::{ETAG, VARY};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use tokio::sync::mpsc;
+
+use crate::encoding::Encoding;
+use crate::error::ApiError;
+use crate::{
+    etag, format_event, format_events, hides_sensitive, retain_visible, DbEvent, OutputFormat,
+};
+
+/// Columns selected to read full event rows
+pub const EVENT_COLUMNS: &str =
+    "event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event";
+
+/// Number of encoded events buffered ahead of a slow client
+const STREAM_BUFFER: usize = 64;
+
+/// Responds with the events selected by a query as a JSON array streamed row by row, so large
+/// listings are never held in memory at once.
+///
+/// `build` is called with the columns to select; it is first run for the event ids alone to
+/// derive the ETag and answer conditional requests, then for the full rows. MessagePack and
+/// CBOR responses are buffered. Events with a content warning are left out when the request
+/// sets `hide_sensitive=true`; as their tags never change, the ETag of the ids still holds.
+pub async fn event_listing<F>(
+    req: &HttpRequest,
+    db_pool: &SqlitePool,
+    format: OutputFormat,
+    build: F,
+) -> Result<HttpResponse, ApiError>
+where
+    F: Fn(&str) -> QueryBuilder<'static, Sqlite> + Send + 'static,
+{
+    let ids: Vec<(String,)> = build("event_id")
+        .build_query_as()
+        .fetch_all(db_pool)
+        .await?;
+    let encoding = Encoding::from_request(req);
+    if encoding != Encoding::Json {
+        let mut events = build(EVENT_COLUMNS)
+            .build_query_as::<DbEvent>()
+            .fetch_all(db_pool)
+            .await?;
+        retain_visible(req, &mut events);
+        let etag = etag::list_etag(events.iter().map(|event| event.event_id.as_str()));
+        return Ok(etag::json_with_etag(
+            req,
+            etag,
+            &format_events(&events, format),
+        ));
+    }
+    let etag = etag::list_etag(ids.iter().map(|(id,)| id.as_str()));
+    if let Some(response) = etag::not_modified(req, &etag) {
+        return Ok(response);
+    }
+
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, std::io::Error>>(STREAM_BUFFER);
+    let db_pool = db_pool.clone();
+    let hide_sensitive = hides_sensitive(req);
+    tokio::spawn(async move {
+        let mut query = build(EVENT_COLUMNS);
+        let mut rows = query.build_query_as::<DbEvent>().fetch(&db_pool);
+        let mut separator = "[";
+        while let Some(row) = rows.next().await {
+            let chunk = match row {
+                Ok(event) if hide_sensitive && event.content_warning().is_some() => continue,
+                Ok(event) => {
+                    let item = format!("{}{}", separator, format_event(&event, format));
+                    separator = ",";
+                    Ok(Bytes::from(item))
+                }
+                // Aborts the response; the client sees a truncated body rather than valid JSON.
+                Err(e) => {
+                    eprintln!("Database query error while streaming: {:?}", e);
+                    Err(std::io::Error::other(e))
+                }
+            };
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+        let end = if separator == "[" { "[]" } else { "]" };
+        let _ = sender.send(Ok(Bytes::from_static(end.as_bytes()))).await;
+    });
+
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag.to_string()))
+        .insert_header((VARY, "Accept"))
+        .content_type(encoding.content_type())
+        .streaming(body))
+}
@@ -0,0 +1,506 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::SinkExt;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::{self, Message};
+use uuid::Uuid;
+
+use crate::audit;
+use crate::db::WritePool;
+use crate::error::ApiError;
+use crate::live;
+use crate::nip11::RemoteLimitation;
+use crate::nostr::{self, NostrEvent};
+use crate::{DynamicRule, SubscriptionConfig, WsWriter};
+
+/// Returns the SQL expression over the events table computing the value a rule's tag filter
+/// matches
+fn target_column(tag: &str) -> Option<&'static str> {
+    match tag {
+        "e" => Some("event_id"),
+        "p" => Some("pubkey"),
+        "a" => Some("kind || ':' || pubkey || ':' || d_tag"),
+        _ => None,
+    }
+}
+
+/// Returns the value of a rule's tag filter referencing an archived event: its id for `e`,
+/// its author for `p` and its address for `a` (parameterized replaceable events only)
+pub fn target_value(tag: &str, event: &NostrEvent) -> Option<String> {
+    match tag {
+        "e" => Some(event.id.clone()),
+        "p" => Some(event.pubkey.clone()),
+        "a" if (30000..40000).contains(&event.kind) => Some(
+            nostr::Address {
+                kind: event.kind,
+                pubkey: event.pubkey.clone(),
+                identifier: event.tag_value("d").unwrap_or_default().to_string(),
+            }
+            .coordinate(),
+        ),
+        _ => None,
+    }
+}
+
+/// Returns whether chest knows how to reference triggering events with a rule's tag
+pub fn is_supported(rule: &DynamicRule) -> bool {
+    target_column(&rule.tag).is_some()
+}
+
+/// A REQ sent to an upstream relay, reported by `GET /admin/subscriptions`
+#[derive(Debug, Clone, Serialize)]
+struct RelaySubscription {
+    filters: Vec<Value>,
+    /// Unix time the REQ was sent
+    created_at: u64,
+    events_received: u64,
+    /// When the REQ was sent, until the relay signals the end of its stored events
+    #[serde(skip)]
+    sent_at: Option<Instant>,
+}
+
+/// Connection of a relay and the limits its NIP-11 document advertises
+#[derive(Debug)]
+struct RelayBudget {
+    writer: WsWriter,
+    max_subscriptions: Option<usize>,
+    max_filters: Option<usize>,
+    max_limit: Option<u64>,
+    /// REQs waiting for one of the open subscriptions to close
+    queued: VecDeque<Value>,
+}
+
+/// What to do with a REQ once the budget of its relay was checked
+enum Dispatch {
+    /// Send this REQ, either the requested one or an open subscription it was merged into
+    Send(WsWriter, Value),
+    /// Merged into a queued REQ, or queued itself
+    Wait,
+}
+
+/// Tracks which targets of the dynamic subscription rules are subscribed on each relay, so
+/// that a target seen again (from another relay or after a restart) is not subscribed twice,
+/// and every REQ open on the relays. Targets are keyed `{rule index}:{tag value}`.
+///
+/// REQs go out through [`SubscriptionRegistry::request`], which keeps each relay within the
+/// `max_subscriptions`, `max_filters` and `max_limit` of its NIP-11 document: once a relay's
+/// subscriptions are used up, a REQ is merged into a queued or open one with the same filter
+/// but for the values of one list, or queued until a subscription closes.
+#[derive(Debug, Default)]
+pub struct SubscriptionRegistry {
+    /// Targets of events archived before startup
+    archived: HashSet<String>,
+    /// Targets subscribed since startup, by relay URL
+    active: Mutex<HashMap<String, HashSet<String>>>,
+    /// REQs sent since startup and not closed by the relay, by relay URL and subscription id
+    requests: Mutex<BTreeMap<String, BTreeMap<String, RelaySubscription>>>,
+    /// Connected relays, by relay URL
+    relays: Mutex<HashMap<String, RelayBudget>>,
+    /// Subscriptions kept open on relays that advertise no limit
+    default_max_subscriptions: Option<usize>,
+    /// Values a merged list may grow to
+    max_merged_values: usize,
+}
+
+impl SubscriptionRegistry {
+    /// Rebuilds the registry from the events already in the database
+    pub async fn load(
+        db_pool: &SqlitePool,
+        rules: &[DynamicRule],
+        config: &SubscriptionConfig,
+    ) -> Result<Self, sqlx::Error> {
+        let mut archived = HashSet::new();
+        for (index, rule) in rules.iter().enumerate() {
+            for value in targets(db_pool, rule, 0).await? {
+                archived.insert(format!("{}:{}", index, value));
+            }
+        }
+        Ok(Self {
+            archived,
+            active: Mutex::new(HashMap::new()),
+            requests: Mutex::new(BTreeMap::new()),
+            relays: Mutex::new(HashMap::new()),
+            default_max_subscriptions: (config.max_subscriptions > 0)
+                .then_some(config.max_subscriptions),
+            max_merged_values: config.batch_size.max(1),
+        })
+    }
+
+    /// Registers the connection to a relay and the limits it advertises
+    pub fn connect(&self, relay_url: &str, writer: WsWriter, limitation: &RemoteLimitation) {
+        let budget = RelayBudget {
+            writer,
+            max_subscriptions: limitation
+                .max_subscriptions
+                .map(|max| max.max(1) as usize)
+                .or(self.default_max_subscriptions),
+            max_filters: limitation.max_filters.map(|max| max.max(1) as usize),
+            max_limit: limitation.max_limit,
+            queued: VecDeque::new(),
+        };
+        self.relays
+            .lock()
+            .unwrap()
+            .insert(relay_url.to_string(), budget);
+    }
+
+    /// Returns the largest `limit` a relay honors, if it advertises one
+    pub fn max_limit(&self, relay_url: &str) -> Option<u64> {
+        self.relays
+            .lock()
+            .unwrap()
+            .get(relay_url)
+            .and_then(|relay| relay.max_limit)
+    }
+
+    /// Records a subscription of rule `rule_index` for `value` on `relay_url`, returning
+    /// `false` if it already exists
+    pub fn claim(&self, relay_url: &str, rule_index: usize, value: &str) -> bool {
+        let key = format!("{}:{}", rule_index, value);
+        if self.archived.contains(&key) {
+            return false;
+        }
+        self.active
+            .lock()
+            .unwrap()
+            .entry(relay_url.to_string())
+            .or_default()
+            .insert(key)
+    }
+
+    /// Sends a REQ to a relay within its budget: `limit`s are lowered to the relay's
+    /// `max_limit` and the filters split over several REQs of at most `max_filters`, each sent
+    /// if a subscription is free and otherwise merged or queued
+    pub async fn request(
+        &self,
+        relay_url: &str,
+        req_message: Value,
+    ) -> Result<(), tungstenite::Error> {
+        let Some((subscription_id, mut filters)) = split_request(&req_message) else {
+            return Ok(());
+        };
+        let (max_filters, max_limit) = match self.relays.lock().unwrap().get(relay_url) {
+            Some(relay) => (relay.max_filters, relay.max_limit),
+            None => {
+                eprintln!("No connection found for relay: {}", relay_url);
+                return Ok(());
+            }
+        };
+        if let Some(max_limit) = max_limit {
+            for filter in &mut filters {
+                if let Some(limit) = filter.get("limit").and_then(Value::as_u64) {
+                    filter["limit"] = limit.min(max_limit).into();
+                }
+            }
+        }
+        let chunk_size = max_filters.unwrap_or(filters.len()).max(1);
+        for (index, chunk) in filters.chunks(chunk_size).enumerate() {
+            // Only the first part keeps the caller's subscription id.
+            let subscription_id = match index {
+                0 => subscription_id.clone(),
+                _ => Uuid::new_v4().to_string(),
+            };
+            let mut message = vec![Value::from("REQ"), Value::from(subscription_id)];
+            message.extend_from_slice(chunk);
+            if let Dispatch::Send(writer, message) = self.dispatch(relay_url, Value::Array(message))
+            {
+                self.send(relay_url, &writer, message).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decides whether a REQ within its relay's filter limits is sent, merged or queued
+    fn dispatch(&self, relay_url: &str, req_message: Value) -> Dispatch {
+        let mut relays = self.relays.lock().unwrap();
+        let Some(relay) = relays.get_mut(relay_url) else {
+            return Dispatch::Wait;
+        };
+        let mut requests = self.requests.lock().unwrap();
+        let open = requests.entry(relay_url.to_string()).or_default();
+        if relay.max_subscriptions.is_none_or(|max| open.len() < max) {
+            record(open, &req_message);
+            return Dispatch::Send(relay.writer.clone(), req_message);
+        }
+        let Some((subscription_id, filters)) = split_request(&req_message) else {
+            return Dispatch::Wait;
+        };
+        let [filter] = filters.as_slice() else {
+            relay.queued.push_back(req_message);
+            return Dispatch::Wait;
+        };
+        for queued in relay.queued.iter_mut() {
+            let merged = split_request(queued).and_then(|(_, queued_filters)| match queued_filters
+                .as_slice()
+            {
+                [queued_filter] => merge_filters(queued_filter, filter, self.max_merged_values),
+                _ => None,
+            });
+            if let Some(merged) = merged {
+                queued[2] = merged;
+                return Dispatch::Wait;
+            }
+        }
+        for (open_id, subscription) in open.iter_mut() {
+            let merged = match subscription.filters.as_slice() {
+                [open_filter] => merge_filters(open_filter, filter, self.max_merged_values),
+                _ => None,
+            };
+            if let Some(merged) = merged {
+                // A REQ reusing a subscription id replaces that subscription.
+                subscription.filters = vec![merged.clone()];
+                subscription.sent_at = Some(Instant::now());
+                return Dispatch::Send(
+                    relay.writer.clone(),
+                    serde_json::json!(["REQ", open_id, merged]),
+                );
+            }
+        }
+        println!(
+            "Subscription budget of {} used up, queueing REQ {}",
+            relay_url, subscription_id
+        );
+        relay.queued.push_back(req_message);
+        Dispatch::Wait
+    }
+
+    /// Sends a REQ reserved in the registry, forgetting it if the connection failed
+    async fn send(
+        &self,
+        relay_url: &str,
+        writer: &WsWriter,
+        req_message: Value,
+    ) -> Result<(), tungstenite::Error> {
+        let result = writer
+            .lock()
+            .await
+            .send(Message::Text(req_message.to_string()))
+            .await;
+        if result.is_err() {
+            if let Some(subscription_id) = req_message.get(1).and_then(Value::as_str) {
+                if let Some(subscriptions) = self.requests.lock().unwrap().get_mut(relay_url) {
+                    subscriptions.remove(subscription_id);
+                }
+            }
+        }
+        result
+    }
+
+    /// Closes a subscription on a relay and frees its place for a queued REQ
+    pub async fn close(&self, relay_url: &str, subscription_id: &str) {
+        let writer = self
+            .relays
+            .lock()
+            .unwrap()
+            .get(relay_url)
+            .map(|relay| relay.writer.clone());
+        if let Some(writer) = writer {
+            let close_message = serde_json::json!(["CLOSE", subscription_id]);
+            if let Err(e) = writer
+                .lock()
+                .await
+                .send(Message::Text(close_message.to_string()))
+                .await
+            {
+                eprintln!("Error closing subscription on relay {}: {}", relay_url, e);
+            }
+        }
+        self.record_closed(relay_url, subscription_id).await;
+    }
+
+    /// Counts an event a relay sent for one of the recorded subscriptions
+    pub fn record_event(&self, relay_url: &str, subscription_id: &str) {
+        if let Some(subscription) = self
+            .requests
+            .lock()
+            .unwrap()
+            .get_mut(relay_url)
+            .and_then(|subscriptions| subscriptions.get_mut(subscription_id))
+        {
+            subscription.events_received += 1;
+        }
+    }
+
+    /// Returns how long a relay took to send the stored events of a subscription, once: later
+    /// EOSEs for the same REQ return `None`
+    pub fn answered(&self, relay_url: &str, subscription_id: &str) -> Option<Duration> {
+        self.requests
+            .lock()
+            .unwrap()
+            .get_mut(relay_url)?
+            .get_mut(subscription_id)?
+            .sent_at
+            .take()
+            .map(|sent_at| sent_at.elapsed())
+    }
+
+    /// Forgets a subscription that was closed, and sends the next queued REQ in its place
+    pub async fn record_closed(&self, relay_url: &str, subscription_id: &str) {
+        let next = {
+            let mut relays = self.relays.lock().unwrap();
+            let mut requests = self.requests.lock().unwrap();
+            let open = requests.entry(relay_url.to_string()).or_default();
+            if open.remove(subscription_id).is_none() {
+                return;
+            }
+            relays.get_mut(relay_url).and_then(|relay| {
+                if relay.max_subscriptions.is_some_and(|max| open.len() >= max) {
+                    return None;
+                }
+                let req_message = relay.queued.pop_front()?;
+                record(open, &req_message);
+                Some((relay.writer.clone(), req_message))
+            })
+        };
+        if let Some((writer, req_message)) = next {
+            if let Err(e) = self.send(relay_url, &writer, req_message).await {
+                eprintln!("Error sending queued subscription to {}: {}", relay_url, e);
+            }
+        }
+    }
+}
+
+/// Records a REQ message among the subscriptions of a relay
+fn record(subscriptions: &mut BTreeMap<String, RelaySubscription>, req_message: &Value) {
+    let Some((subscription_id, filters)) = split_request(req_message) else {
+        return;
+    };
+    subscriptions.insert(
+        subscription_id,
+        RelaySubscription {
+            filters,
+            created_at: nostr::now(),
+            events_received: 0,
+            sent_at: Some(Instant::now()),
+        },
+    );
+}
+
+/// Splits a REQ message into its subscription id and filters
+fn split_request(req_message: &Value) -> Option<(String, Vec<Value>)> {
+    let subscription_id = req_message.get(1).and_then(Value::as_str)?;
+    let filters = req_message.as_array()?.iter().skip(2).cloned().collect();
+    Some((subscription_id.to_string(), filters))
+}
+
+/// Merges two filters that are equal but for the values of one `ids`, `authors` or tag list,
+/// as long as the merged list holds at most `max_values` values. Filters with a `limit` are
+/// never merged, since the limit would then apply to both.
+fn merge_filters(existing: &Value, new: &Value, max_values: usize) -> Option<Value> {
+    let (existing, new) = (existing.as_object()?, new.as_object()?);
+    if existing.len() != new.len() || existing.contains_key("limit") {
+        return None;
+    }
+    let mut differing = None;
+    for (key, value) in existing {
+        if new.get(key)? != value {
+            if differing.is_some() {
+                return None;
+            }
+            differing = Some(key);
+        }
+    }
+    let Some(key) = differing else {
+        return Some(Value::Object(existing.clone()));
+    };
+    if key != "ids" && key != "authors" && !key.starts_with('#') {
+        return None;
+    }
+    let mut values = existing[key].as_array()?.clone();
+    for value in new[key].as_array()? {
+        if !values.contains(value) {
+            values.push(value.clone());
+        }
+    }
+    if values.len() > max_values {
+        return None;
+    }
+    let mut merged: Map<String, Value> = existing.clone();
+    merged.insert(key.clone(), Value::Array(values));
+    Some(Value::Object(merged))
+}
+
+/// Fetches the distinct tag values referencing the archived events of a rule's trigger kinds
+/// created since `since`, newest first
+pub async fn targets(
+    db_pool: &SqlitePool,
+    rule: &DynamicRule,
+    since: u64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let Some(column) = target_column(&rule.tag) else {
+        return Ok(Vec::new());
+    };
+    if rule.trigger_kinds.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = QueryBuilder::<Sqlite>::new(format!(
+        "SELECT {0} FROM events WHERE {0} IS NOT NULL AND created_at >= ",
+        column
+    ));
+    query.push_bind(since as i64);
+    query.push(" AND kind IN (");
+    let mut separated = query.separated(", ");
+    for kind in &rule.trigger_kinds {
+        separated.push_bind(*kind as i64);
+    }
+    // Chats of live activities that have ended are not followed anymore.
+    query.push(format!(") AND NOT {}", live::ENDED_CONDITION));
+    query.push(format!(
+        " GROUP BY {} ORDER BY MAX(created_at) DESC",
+        column
+    ));
+    let values: Vec<(String,)> = query.build_query_as().fetch_all(db_pool).await?;
+    Ok(values.into_iter().map(|(value,)| value).collect())
+}
+
+/// Builds the REQ of a dynamic subscription rule for the given tag values
+pub fn dynamic_request(rule: &DynamicRule, values: &[String]) -> Value {
+    let mut filter = serde_json::json!({ "kinds": rule.subscribe_kinds });
+    filter[format!("#{}", rule.tag)] = serde_json::json!(values);
+    serde_json::json!(["REQ", Uuid::new_v4().to_string(), filter])
+}
+
+/// Lists the REQs open on every relay with their filters and the number of events received,
+/// and the REQs queued until a relay's subscription budget frees up.
+pub async fn list_subscriptions(
+    req: HttpRequest,
+    registry: web::Data<SubscriptionRegistry>,
+    write_pool: web::Data<WritePool>,
+) -> Result<HttpResponse, ApiError> {
+    audit::record_view(&req, &write_pool, "view_subscriptions", "").await?;
+    let requests = registry.requests.lock().unwrap().clone();
+    let mut relays: BTreeMap<String, Vec<Value>> = requests
+        .into_iter()
+        .map(|(relay_url, subscriptions)| {
+            let subscriptions = subscriptions
+                .into_iter()
+                .map(|(subscription_id, subscription)| {
+                    let mut item = serde_json::to_value(subscription).unwrap_or_default();
+                    item["subscription_id"] = subscription_id.into();
+                    item
+                })
+                .collect();
+            (relay_url, subscriptions)
+        })
+        .collect();
+    for (relay_url, relay) in registry.relays.lock().unwrap().iter() {
+        let queued =
+            relay
+                .queued
+                .iter()
+                .filter_map(split_request)
+                .map(|(subscription_id, filters)| {
+                    serde_json::json!({
+                        "subscription_id": subscription_id,
+                        "filters": filters,
+                        "queued": true,
+                    })
+                });
+        relays.entry(relay_url.clone()).or_default().extend(queued);
+    }
+    Ok(HttpResponse::Ok().json(relays))
+}
@@ -0,0 +1,145 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+
+use crate::error::ApiError;
+use crate::ingest::Ingestor;
+use crate::stream::EVENT_COLUMNS;
+use crate::{etag, format_event, format_events, nostr, resolver, DbEvent, OutputFormat};
+
+/// Most ancestors walked from a reply to its root, so malformed threads stay cheap
+const MAX_DEPTH: usize = 200;
+
+/// Folders a thread is made of; a parent in any other folder is reported as missing
+const THREAD_FOLDERS: [&str; 3] = ["notes", "replies", "long"];
+
+/// Query parameters of `GET /notes/{id}/root`
+#[derive(Debug, Deserialize)]
+pub struct RootQuery {
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+/// Loads an archived note, reply or article, asking the relays for it first when it is missing
+/// and fetching is enabled
+async fn load(event_id: &str, ingestor: &Ingestor) -> Result<Option<DbEvent>, ApiError> {
+    resolver::resolve(event_id, ingestor).await?;
+    Ok(fetch(event_id, &ingestor.db_pool).await?)
+}
+
+async fn fetch(event_id: &str, db_pool: &SqlitePool) -> Result<Option<DbEvent>, sqlx::Error> {
+    sqlx::query_as::<_, DbEvent>(&format!(
+        "SELECT {} FROM public_events WHERE event_id = ? AND folder IN ({})",
+        EVENT_COLUMNS,
+        THREAD_FOLDERS
+            .iter()
+            .map(|folder| format!("'{}'", folder))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+    .bind(event_id)
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Returns the root a reply declares: the NIP-10 `root` marker, or the uppercase `E` tag of a
+/// NIP-22 comment
+fn declared_root(event: &DbEvent) -> Option<String> {
+    let tags: Vec<Vec<String>> = serde_json::from_str(&event.tags).unwrap_or_default();
+    tags.iter()
+        .find(|tag| tag.len() >= 4 && tag[0] == "e" && tag[3] == "root")
+        .or_else(|| tags.iter().find(|tag| tag.len() >= 2 && tag[0] == "E"))
+        .map(|tag| tag[1].clone())
+}
+
+/// Walks up the thread of a note or reply to its root, following the stored parent of every
+/// reply and fetching missing ancestors from the relays when fetching is enabled. Responds with
+/// the root and the ancestors between it and the event, root first; a note is its own root.
+/// When an ancestor cannot be found or is not a note, reply or article, the chain stops at the
+/// last one found, `missing` names the absent parent, and the root is the one the event
+/// declares if it is archived.
+pub async fn thread_root(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<RootQuery>,
+    ingestor: web::Data<Ingestor>,
+) -> Result<HttpResponse, ApiError> {
+    let input = id.into_inner();
+    let event_id = nostr::parse_event_id(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", input)))?;
+    let event = load(&event_id, &ingestor)
+        .await?
+        .filter(|event| matches!(event.folder.as_str(), "notes" | "replies"))
+        .ok_or_else(|| ApiError::NotFound("Event not found".to_string()))?;
+
+    let mut seen = HashSet::from([event.event_id.clone()]);
+    let mut ancestors = Vec::new();
+    let mut current = event.clone();
+    let mut missing = None;
+    let mut root = loop {
+        if current.folder != "replies" {
+            break Some(current);
+        }
+        let Some(parent_id) = current.ref_event.clone() else {
+            break None;
+        };
+        if ancestors.len() >= MAX_DEPTH || !seen.insert(parent_id.clone()) {
+            break None;
+        }
+        let Some(parent) = load(&parent_id, &ingestor).await? else {
+            missing = Some(parent_id);
+            break None;
+        };
+        ancestors.push(parent.clone());
+        current = parent;
+    };
+    if root.is_some() {
+        ancestors.pop();
+    } else if let Some(root_id) = declared_root(&event) {
+        root = match ancestors
+            .iter()
+            .position(|ancestor| ancestor.event_id == root_id)
+        {
+            Some(index) => {
+                ancestors.truncate(index + 1);
+                ancestors.pop()
+            }
+            None => load(&root_id, &ingestor).await?,
+        };
+    }
+    ancestors.reverse();
+
+    let etag = etag::list_etag(
+        root.iter()
+            .chain(&ancestors)
+            .map(|ancestor| ancestor.event_id.as_str())
+            .chain(missing.as_deref()),
+    );
+    let body = serde_json::json!({
+        "root": root.as_ref().map(|root| format_event(root, params.format)),
+        "ancestors": format_events(&ancestors, params.format),
+        "missing": missing,
+    });
+    Ok(etag::json_with_etag(&req, etag, &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, test_event};
+
+    #[tokio::test]
+    async fn parents_outside_threads_are_not_loaded() {
+        let db_pool = db::test_pool().await;
+        let (dm, reaction, mut reply) =
+            (test_event('a', 4), test_event('b', 7), test_event('c', 1));
+        reply.tags = vec![vec!["e".to_string(), dm.id.clone()]];
+        db::store_test_events(&db_pool, &[&dm, &reaction, &reply]).await;
+
+        let stored = fetch(&reply.id, &db_pool).await.unwrap().unwrap();
+        assert_eq!(stored.ref_event.as_deref(), Some(dm.id.as_str()));
+        assert!(fetch(&dm.id, &db_pool).await.unwrap().is_none());
+        assert!(fetch(&reaction.id, &db_pool).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,308 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::ApiError;
+use crate::ots::{self, Attestation};
+use crate::{etag, nostr, TimestampConfig};
+
+/// NIP-03 OpenTimestamps attestation events
+pub const ATTESTATION_KIND: u64 = 1040;
+
+/// Attestation events indexed per statement
+const INDEX_BATCH_SIZE: i64 = 500;
+
+/// Outcome of checking an attestation event
+#[derive(Debug, Default)]
+struct Checked {
+    /// `invalid`, `pending` or `unverified`
+    status: &'static str,
+    /// Height of the earliest Bitcoin block attesting the event
+    bitcoin_height: Option<i64>,
+    /// Hex merkle root the proof commits to in that block
+    commitment: Option<String>,
+    /// Calendar of a proof not yet anchored in Bitcoin
+    calendar: Option<String>,
+    error: Option<String>,
+}
+
+impl Checked {
+    fn invalid(error: impl Into<String>) -> Self {
+        Self {
+            status: "invalid",
+            error: Some(error.into()),
+            ..Self::default()
+        }
+    }
+}
+
+/// Parses the base64 `.ots` proof of an attestation event and checks that it timestamps the
+/// referenced event
+fn check(content: &str, target: Option<&str>) -> Checked {
+    let Some(target) = target else {
+        return Checked::invalid("no e tag naming the attested event");
+    };
+    let Ok(data) = base64::engine::general_purpose::STANDARD.decode(content.trim()) else {
+        return Checked::invalid("content is not base64");
+    };
+    let proof = match ots::parse(&data) {
+        Ok(proof) => proof,
+        Err(e) => return Checked::invalid(e),
+    };
+    if hex::encode(&proof.digest) != target {
+        return Checked::invalid("proof does not timestamp the attested event id");
+    }
+    let bitcoin = proof
+        .attestations
+        .iter()
+        .filter_map(|attestation| match attestation {
+            Attestation::Bitcoin { height, commitment } => Some((*height, commitment)),
+            _ => None,
+        })
+        .min_by_key(|(height, _)| *height);
+    if let Some((height, commitment)) = bitcoin {
+        return Checked {
+            status: "unverified",
+            bitcoin_height: Some(height as i64),
+            commitment: Some(hex::encode(commitment)),
+            ..Checked::default()
+        };
+    }
+    match proof
+        .attestations
+        .iter()
+        .find_map(|attestation| match attestation {
+            Attestation::Pending { uri } => Some(uri.clone()),
+            _ => None,
+        }) {
+        Some(calendar) => Checked {
+            status: "pending",
+            calendar: Some(calendar),
+            ..Checked::default()
+        },
+        None => Checked::invalid("proof has no Bitcoin or calendar attestation"),
+    }
+}
+
+/// Checks the next batch of archived attestation events without a status, returning how many
+/// were checked
+async fn index_batch(db_pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let rows: Vec<(String, String, Option<String>)> = sqlx::query_as(
+        "SELECT e.event_id, e.content, e.ref_event FROM events AS e
+         LEFT JOIN attestations AS a ON a.event_id = e.event_id
+         WHERE e.folder = 'attestations' AND a.event_id IS NULL
+         LIMIT ?",
+    )
+    .bind(INDEX_BATCH_SIZE)
+    .fetch_all(db_pool)
+    .await?;
+    let now = nostr::now() as i64;
+    let mut tx = db_pool.begin().await?;
+    for (event_id, content, target) in &rows {
+        let checked = check(content, target.as_deref());
+        sqlx::query(
+            "INSERT OR REPLACE INTO attestations
+                 (event_id, target_event, status, bitcoin_height, commitment, calendar, error,
+                  checked_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(event_id)
+        .bind(target)
+        .bind(checked.status)
+        .bind(checked.bitcoin_height)
+        .bind(checked.commitment)
+        .bind(checked.calendar)
+        .bind(checked.error)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(rows.len())
+}
+
+/// Block fields returned by an Esplora API
+#[derive(Debug, Deserialize)]
+struct Block {
+    merkle_root: String,
+    timestamp: i64,
+}
+
+/// Looks up the Bitcoin block at a height through an Esplora API
+async fn fetch_block(client: &reqwest::Client, base: &str, height: i64) -> Result<Block, String> {
+    let base = base.trim_end_matches('/');
+    let hash = client
+        .get(format!("{}/block-height/{}", base, height))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to look up block {}: {}", height, e))?
+        .text()
+        .await
+        .map_err(|e| format!("Failed to look up block {}: {}", height, e))?;
+    client
+        .get(format!("{}/block/{}", base, hash.trim()))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to fetch block {}: {}", height, e))?
+        .json()
+        .await
+        .map_err(|e| format!("Failed to fetch block {}: {}", height, e))
+}
+
+/// Checks the Bitcoin attestations not verified yet against the block headers, returning how
+/// many were checked
+async fn verify_batch(db_pool: &SqlitePool, config: &TimestampConfig) -> Result<usize, String> {
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+    let rows: Vec<(String, i64, String)> = sqlx::query_as(
+        "SELECT event_id, bitcoin_height, commitment FROM attestations
+         WHERE status = 'unverified' ORDER BY checked_at LIMIT ?",
+    )
+    .bind(config.batch_size as i64)
+    .fetch_all(db_pool)
+    .await
+    .map_err(db_error)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut blocks: HashMap<i64, Result<Block, String>> = HashMap::new();
+    for (event_id, height, commitment) in &rows {
+        if !blocks.contains_key(height) {
+            let block = fetch_block(&client, &config.esplora_url, *height).await;
+            blocks.insert(*height, block);
+        }
+        // Block explorers show merkle roots byte-reversed.
+        let (status, attested_at, error) = match &blocks[height] {
+            Ok(block) => {
+                let mut root = hex::decode(&block.merkle_root).unwrap_or_default();
+                root.reverse();
+                if hex::encode(root) == *commitment {
+                    ("verified", Some(block.timestamp), None)
+                } else {
+                    let error = format!("proof does not match the merkle root of block {}", height);
+                    ("invalid", None, Some(error))
+                }
+            }
+            // Left unverified, to retry on a later run.
+            Err(e) => ("unverified", None, Some(e.clone())),
+        };
+        sqlx::query(
+            "UPDATE attestations SET status = ?, attested_at = ?, error = ?, checked_at = ?
+             WHERE event_id = ?",
+        )
+        .bind(status)
+        .bind(attested_at)
+        .bind(error)
+        .bind(nostr::now() as i64)
+        .bind(event_id)
+        .execute(db_pool)
+        .await
+        .map_err(db_error)?;
+    }
+    Ok(rows.len())
+}
+
+/// Checks newly archived attestation events every `interval` seconds, and verifies their
+/// Bitcoin attestations when enabled
+pub fn spawn(config: TimestampConfig, db_pool: SqlitePool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        loop {
+            interval.tick().await;
+            loop {
+                match index_batch(&db_pool).await {
+                    Ok(checked) if (checked as i64) < INDEX_BATCH_SIZE => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Failed to check timestamp attestations: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            if config.verify {
+                match verify_batch(&db_pool, &config).await {
+                    Ok(0) => {}
+                    Ok(checked) => println!("Verified {} timestamp attestations", checked),
+                    Err(e) => eprintln!("Failed to verify timestamp attestations: {}", e),
+                }
+            }
+        }
+    });
+}
+
+/// Status of an archived attestation of an event
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AttestationStatus {
+    /// Id of the kind 1040 attestation event
+    pub event_id: String,
+    pub pubkey: String,
+    pub created_at: i64,
+    /// `verified`, `unverified` (anchored in Bitcoin, not checked against the block yet),
+    /// `pending` (only promised by a calendar), `invalid` or `unchecked`
+    pub status: String,
+    pub bitcoin_height: Option<i64>,
+    /// Time of the Bitcoin block, once verified: the event existed before then
+    pub attested_at: Option<i64>,
+    pub calendar: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Lists the archived attestations of an event, oldest first
+pub async fn attestations_for(
+    event_id: &str,
+    db_pool: &SqlitePool,
+) -> Result<Vec<AttestationStatus>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT e.event_id, e.pubkey, e.created_at, COALESCE(a.status, 'unchecked') AS status,
+             a.bitcoin_height, a.attested_at, a.calendar, a.error
+         FROM public_events AS e LEFT JOIN attestations AS a ON a.event_id = e.event_id
+         WHERE e.folder = 'attestations' AND e.ref_event = ?
+         ORDER BY e.created_at, e.event_id",
+    )
+    .bind(event_id)
+    .fetch_all(db_pool)
+    .await
+}
+
+/// Returns a stable tag identifying the checked state of a set of attestations
+pub fn attestations_etag_parts(attestations: &[AttestationStatus]) -> Vec<String> {
+    attestations
+        .iter()
+        .map(|attestation| format!("{}@{}", attestation.event_id, attestation.status))
+        .collect()
+}
+
+/// Reports the NIP-03 timestamp attestations of an event, with the earliest time a verified
+/// one proves it existed.
+pub async fn get_attestations(
+    req: HttpRequest,
+    id: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let event_id = nostr::parse_event_id(&id)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid event id: {}", id)))?;
+    let attestations = attestations_for(&event_id, db_pool.get_ref()).await?;
+    let attested_at = attestations
+        .iter()
+        .filter_map(|attestation| attestation.attested_at)
+        .min();
+    let parts = attestations_etag_parts(&attestations);
+    let etag =
+        etag::list_etag(std::iter::once(event_id.as_str()).chain(parts.iter().map(String::as_str)));
+    Ok(etag::json_with_etag(
+        &req,
+        etag,
+        &serde_json::json!({
+            "event_id": event_id,
+            "attested_at": attested_at,
+            "attestations": attestations,
+        }),
+    ))
+}
@@ -0,0 +1,164 @@
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls::{self, Certificate};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use crate::{ServerTlsConfig, TlsConfig};
+
+/// Reads the extra CA certificates of a relay's TLS settings
+fn ca_bundle(config: &TlsConfig) -> Result<Option<Vec<u8>>, String> {
+    if config.ca_file.is_empty() {
+        return Ok(None);
+    }
+    std::fs::read(&config.ca_file)
+        .map(Some)
+        .map_err(|e| format!("Failed to read CA file {}: {}", config.ca_file, e))
+}
+
+/// Normalizes a certificate fingerprint to lowercase hex without separators, as printed by
+/// `openssl x509 -fingerprint -sha256`
+fn normalize_pin(pin: &str) -> String {
+    pin.chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Checks that every pin of a relay's TLS settings is a SHA-256 fingerprint
+pub fn validate(config: &TlsConfig) -> Result<(), String> {
+    for pin in &config.pins {
+        let fingerprint = normalize_pin(pin);
+        if fingerprint.len() != 64 || hex::decode(&fingerprint).is_err() {
+            return Err(format!("pin {:?} is not a hex SHA-256 fingerprint", pin));
+        }
+    }
+    Ok(())
+}
+
+/// Accepts the certificate a relay presented when it matches one of the pins, or when no pins
+/// are configured
+fn check_pins(config: &TlsConfig, certificate: Option<&[u8]>) -> Result<(), String> {
+    if config.pins.is_empty() {
+        return Ok(());
+    }
+    let fingerprint = certificate.map(|der| hex::encode(Sha256::digest(der)));
+    match fingerprint {
+        Some(fingerprint)
+            if config
+                .pins
+                .iter()
+                .any(|pin| normalize_pin(pin) == fingerprint) =>
+        {
+            Ok(())
+        }
+        Some(fingerprint) => Err(format!(
+            "certificate {} does not match the pinned fingerprints",
+            fingerprint
+        )),
+        None => Err("the relay presented no certificate to check the pins against".to_string()),
+    }
+}
+
+/// Builds the TLS connector of a relay's settings
+pub fn connector(config: &TlsConfig) -> Result<native_tls::TlsConnector, Box<dyn Error>> {
+    validate(config)?;
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.disable_built_in_roots(!config.system_roots);
+    if let Some(bundle) = ca_bundle(config)? {
+        for certificate in Certificate::stack_from_pem(&bundle)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Builds an HTTP client for a relay's NIP-11 document with the relay's TLS settings
+pub fn http_client(config: &TlsConfig) -> Result<reqwest::Client, Box<dyn Error>> {
+    validate(config)?;
+    let mut builder = reqwest::Client::builder()
+        .tls_built_in_root_certs(config.system_roots)
+        .tls_info(!config.pins.is_empty());
+    if let Some(bundle) = ca_bundle(config)? {
+        for certificate in reqwest::Certificate::from_pem_bundle(&bundle)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Checks the certificate of an HTTPS response against a relay's pins
+pub fn check_response(config: &TlsConfig, response: &reqwest::Response) -> Result<(), String> {
+    let certificate = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate());
+    match response.url().scheme() {
+        "https" => check_pins(config, certificate),
+        _ => Ok(()),
+    }
+}
+
+/// Opens a connection to a relay, over TLS with the relay's settings for `wss` URLs
+pub async fn connect(
+    url: &Url,
+    config: &TlsConfig,
+) -> Result<MaybeTlsStream<TcpStream>, Box<dyn Error>> {
+    let host = url.host_str().ok_or("Relay URL has no host")?;
+    let port = url.port_or_known_default().ok_or("Relay URL has no port")?;
+    let socket = TcpStream::connect((host, port)).await?;
+    if url.scheme() != "wss" {
+        return Ok(MaybeTlsStream::Plain(socket));
+    }
+    let connector = tokio_native_tls::TlsConnector::from(connector(config)?);
+    let domain = host.trim_start_matches('[').trim_end_matches(']');
+    let stream = connector.connect(domain, socket).await?;
+    let certificate = stream
+        .get_ref()
+        .peer_certificate()?
+        .map(|certificate| certificate.to_der())
+        .transpose()?;
+    check_pins(config, certificate.as_deref())?;
+    Ok(MaybeTlsStream::NativeTls(stream))
+}
+
+/// Opens a WebSocket connection to a relay with the relay's TLS settings
+pub async fn connect_websocket(
+    relay_url: &str,
+    config: &TlsConfig,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn Error>> {
+    let url = Url::parse(relay_url)?;
+    let stream = connect(&url, config).await?;
+    let (ws_stream, _) = tokio_tungstenite::client_async(url.as_str(), stream).await?;
+    Ok(ws_stream)
+}
+
+/// Loads the certificate chain and private key chest serves HTTPS with
+pub fn server_config(config: &ServerTlsConfig) -> io::Result<rustls::ServerConfig> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to open {}: {}", path, e)))
+    };
+    let certificates =
+        rustls_pemfile::certs(&mut open(&config.cert)?).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", config.cert),
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", config.key),
+        )
+    })?;
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,84 @@
+use regex::{RegexSet, RegexSetBuilder};
+
+use crate::ingest;
+use crate::nostr::NostrEvent;
+use crate::wot::Verdict;
+use crate::{FilterAction, TopicConfig};
+
+/// Restricts archiving to the events mentioning one of the configured topics
+#[derive(Debug)]
+pub struct TopicFilter {
+    /// Lowercase keywords looked for anywhere in the content
+    keywords: Vec<String>,
+    /// Lowercase hashtags, without their `#`
+    hashtags: Vec<String>,
+    patterns: RegexSet,
+    folders: Vec<String>,
+    action: FilterAction,
+}
+
+impl TopicFilter {
+    /// Builds the filter, or returns `None` when no topic is configured. Fails on an invalid
+    /// pattern, as archiving everything instead would go unnoticed.
+    pub fn new(config: &TopicConfig) -> Result<Option<Self>, String> {
+        if config.keywords.is_empty() && config.hashtags.is_empty() && config.patterns.is_empty() {
+            return Ok(None);
+        }
+        let patterns = RegexSetBuilder::new(&config.patterns)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("Invalid topic pattern: {}", e))?;
+        Ok(Some(Self {
+            keywords: config.keywords.iter().map(|k| k.to_lowercase()).collect(),
+            hashtags: config
+                .hashtags
+                .iter()
+                .map(|tag| tag.trim_start_matches('#').to_lowercase())
+                .collect(),
+            patterns,
+            folders: config.folders.clone(),
+            action: config.action,
+        }))
+    }
+
+    /// Returns whether an event is on one of the topics: its content contains a keyword, a
+    /// hashtag or a pattern match, or it carries one of the hashtags as a `t` tag
+    fn matches(&self, event: &NostrEvent) -> bool {
+        let content = event.content.to_lowercase();
+        if self
+            .keywords
+            .iter()
+            .any(|keyword| content.contains(keyword))
+        {
+            return true;
+        }
+        let tagged = event
+            .tags
+            .iter()
+            .filter(|tag| tag.len() >= 2 && tag[0] == "t")
+            .any(|tag| self.hashtags.contains(&tag[1].to_lowercase()));
+        let written = content
+            .split('#')
+            .skip(1)
+            .filter_map(|rest| {
+                rest.split(|c: char| !c.is_alphanumeric() && c != '_')
+                    .next()
+            })
+            .any(|tag| self.hashtags.iter().any(|hashtag| hashtag == tag));
+        tagged || written || self.patterns.is_match(&event.content)
+    }
+
+    /// Accepts events of the filtered folders that are on topic and events of other folders,
+    /// such as the replies and reactions to archived notes
+    pub fn verdict(&self, event: &NostrEvent) -> Verdict {
+        let filtered = ingest::classify(event)
+            .is_some_and(|(folder, _)| self.folders.iter().any(|f| f == folder));
+        if !filtered || self.matches(event) {
+            return Verdict::Accept;
+        }
+        match self.action {
+            FilterAction::Drop => Verdict::Drop,
+            FilterAction::Flag => Verdict::Flag,
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use serde::Serialize;
+
+use crate::nostr::{self, NostrEvent};
+use crate::DbEvent;
+
+/// Fields shared by every typed event, as stored with the tags parsed
+#[derive(Debug, Serialize)]
+pub struct EventHeader {
+    event_id: String,
+    pubkey: String,
+    created_at: i64,
+    kind: i64,
+    tags: Vec<Vec<String>>,
+    sig: String,
+    folder: String,
+    ref_event: Option<String>,
+    /// Reason of the NIP-36 content warning, empty when it gives none
+    content_warning: Option<String>,
+}
+
+impl From<&DbEvent> for EventHeader {
+    fn from(event: &DbEvent) -> Self {
+        Self {
+            event_id: event.event_id.clone(),
+            pubkey: event.pubkey.clone(),
+            created_at: event.created_at,
+            kind: event.kind,
+            tags: serde_json::from_str(&event.tags).unwrap_or_default(),
+            sig: event.sig.clone(),
+            folder: event.folder.clone(),
+            ref_event: event.ref_event.clone(),
+            content_warning: event.content_warning(),
+        }
+    }
+}
+
+/// Kind 0 profile metadata. Fields missing or not strings in the content are `null`.
+#[derive(Debug, Default, Serialize)]
+pub struct Profile {
+    name: Option<String>,
+    /// Also read from the deprecated `displayName`
+    display_name: Option<String>,
+    about: Option<String>,
+    picture: Option<String>,
+    banner: Option<String>,
+    website: Option<String>,
+    nip05: Option<String>,
+    lud06: Option<String>,
+    lud16: Option<String>,
+    bot: Option<bool>,
+}
+
+impl Profile {
+    /// Parses profile metadata field by field, so one malformed field does not lose the others
+    fn parse(content: &str) -> Self {
+        let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(content) else {
+            return Self::default();
+        };
+        let text = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| fields.get(*name)?.as_str())
+                .map(str::to_string)
+        };
+        Self {
+            name: text(&["name"]),
+            display_name: text(&["display_name", "displayName"]),
+            about: text(&["about"]),
+            picture: text(&["picture"]),
+            banner: text(&["banner"]),
+            website: text(&["website"]),
+            nip05: text(&["nip05"]),
+            lud06: text(&["lud06"]),
+            lud16: text(&["lud16"]),
+            bot: fields.get("bot").and_then(serde_json::Value::as_bool),
+        }
+    }
+}
+
+/// A NIP-57 zap receipt, parsed
+#[derive(Debug, Default, Serialize)]
+pub struct Zap {
+    /// Amount of the paid invoice
+    amount_msats: Option<u64>,
+    amount_sats: Option<u64>,
+    /// Pubkey that sent the zap: the author of the embedded zap request, or the `P` tag
+    sender: Option<String>,
+    /// Pubkey zapped
+    recipient: Option<String>,
+    /// Event or address zapped, if any
+    zapped_event: Option<String>,
+    zapped_address: Option<String>,
+    /// Message of the zap request
+    comment: Option<String>,
+}
+
+impl Zap {
+    fn parse(receipt: &NostrEvent) -> Self {
+        let amount_msats = receipt.tag_value("bolt11").and_then(nostr::bolt11_msats);
+        let request: Option<NostrEvent> = receipt
+            .tag_value("description")
+            .and_then(|description| serde_json::from_str(description).ok());
+        Self {
+            amount_msats,
+            amount_sats: amount_msats.map(|msats| msats / 1000),
+            sender: request
+                .as_ref()
+                .map(|request| request.pubkey.clone())
+                .or_else(|| receipt.tag_value("P").map(str::to_string)),
+            recipient: receipt.tag_value("p").map(str::to_string),
+            zapped_event: receipt.tag_value("e").map(str::to_string),
+            zapped_address: receipt.tag_value("a").map(str::to_string),
+            comment: request
+                .map(|request| request.content)
+                .filter(|content| !content.is_empty()),
+        }
+    }
+}
+
+/// A profile event with its metadata parsed instead of the raw content
+#[derive(Debug, Serialize)]
+pub struct UserEvent {
+    #[serde(flatten)]
+    header: EventHeader,
+    profile: Profile,
+}
+
+/// A zap receipt with its amount and sender parsed
+#[derive(Debug, Serialize)]
+pub struct ZapEvent {
+    #[serde(flatten)]
+    header: EventHeader,
+    content: String,
+    zap: Zap,
+}
+
+/// An event of a folder without a typed schema
+#[derive(Debug, Serialize)]
+pub struct GenericEvent {
+    #[serde(flatten)]
+    header: EventHeader,
+    content: String,
+}
+
+/// Response schema of an event, chosen by its folder
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TypedEvent {
+    User(UserEvent),
+    Zap(ZapEvent),
+    Generic(GenericEvent),
+}
+
+impl From<&DbEvent> for TypedEvent {
+    fn from(event: &DbEvent) -> Self {
+        let header = EventHeader::from(event);
+        match event.folder.as_str() {
+            "users" => Self::User(UserEvent {
+                header,
+                profile: Profile::parse(&event.content),
+            }),
+            "zaps" => Self::Zap(ZapEvent {
+                header,
+                content: event.content.clone(),
+                zap: Zap::parse(&event.to_nostr()),
+            }),
+            _ => Self::Generic(GenericEvent {
+                header,
+                content: event.content.clone(),
+            }),
+        }
+    }
+}
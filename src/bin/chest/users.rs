@@ -0,0 +1,88 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+
+/// Number of profiles returned by `GET /users` unless `limit` is given
+const DEFAULT_USERS_LIMIT: i64 = 50;
+
+/// Largest page of profiles a client may request
+const MAX_USERS_LIMIT: i64 = 500;
+
+/// Query parameters of `GET /users`
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    /// Text searched for in names and NIP-05 identifiers
+    q: Option<String>,
+}
+
+/// Searchable fields of an archived profile
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct ProfileRow {
+    pubkey: String,
+    event_id: String,
+    created_at: i64,
+    name: Option<String>,
+    display_name: Option<String>,
+    nip05: Option<String>,
+    about: Option<String>,
+    picture: Option<String>,
+}
+
+/// Escapes the wildcards of a `LIKE` pattern, with `\` as the escape character
+pub fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Lists archived profiles ordered by pubkey, optionally searching their names. Pages are
+/// continued by passing the returned `next_cursor`, which is `null` on the last page.
+pub async fn list_users(
+    params: web::Query<UsersQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_USERS_LIMIT)
+        .clamp(1, MAX_USERS_LIMIT);
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT pubkey, event_id, created_at, name, display_name, nip05, about, picture
+         FROM profiles WHERE 1 = 1",
+    );
+    if let Some(cursor) = &params.cursor {
+        query.push(" AND pubkey > ").push_bind(cursor.clone());
+    }
+    if let Some(q) = params.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("%{}%", escape_like(q));
+        query.push(" AND (name LIKE ");
+        query.push_bind(pattern.clone());
+        query.push(" ESCAPE '\\' OR display_name LIKE ");
+        query.push_bind(pattern.clone());
+        query.push(" ESCAPE '\\' OR nip05 LIKE ");
+        query.push_bind(pattern);
+        query.push(" ESCAPE '\\')");
+    }
+    // One extra row tells whether another page follows.
+    query.push(" ORDER BY pubkey LIMIT ").push_bind(limit + 1);
+    let mut users = query
+        .build_query_as::<ProfileRow>()
+        .fetch_all(db_pool.get_ref())
+        .await?;
+
+    let next_cursor = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|user| user.pubkey.clone())
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "users": users,
+        "next_cursor": next_cursor,
+    })))
+}
@@ -0,0 +1,200 @@
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::ingest;
+use crate::nostr::{self, NostrEvent};
+use crate::DbEvent;
+
+/// Rows read from the database at a time
+const BATCH_SIZE: i64 = 1000;
+
+/// A batch of archived rows, in rowid order
+#[derive(Debug, sqlx::FromRow)]
+struct Row {
+    rowid: i64,
+    #[sqlx(flatten)]
+    event: DbEvent,
+}
+
+/// Options of `chest verify`
+#[derive(Debug)]
+struct VerifyOptions {
+    /// Quarantine the rows that cannot be trusted and re-file the misfiled ones
+    fix: bool,
+}
+
+/// Parses the arguments following `verify`
+fn parse_options(args: &[String]) -> Result<VerifyOptions, String> {
+    let mut fix = false;
+    for arg in args {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    Ok(VerifyOptions { fix })
+}
+
+/// What is wrong with an archived row
+#[derive(Debug)]
+enum Problem {
+    /// The tags column is not a JSON array of string arrays
+    InvalidTags,
+    /// The id is not the hash of the event
+    IdMismatch,
+    /// The signature does not verify against the id and pubkey
+    InvalidSignature,
+    /// The event would not be archived in any folder
+    Unclassified,
+    /// The folder or referenced event differs from the ones the event is archived under
+    Misfiled {
+        folder: &'static str,
+        ref_event: Option<String>,
+    },
+}
+
+impl Problem {
+    /// Name of the problem in the report
+    fn name(&self) -> &'static str {
+        match self {
+            Problem::InvalidTags => "invalid tags",
+            Problem::IdMismatch => "id mismatch",
+            Problem::InvalidSignature => "invalid signature",
+            Problem::Unclassified => "unclassified",
+            Problem::Misfiled { .. } => "misfiled",
+        }
+    }
+}
+
+/// Checks a row, returning its problem if any
+fn check(row: &DbEvent) -> Option<Problem> {
+    let Ok(tags) = serde_json::from_str::<Vec<Vec<String>>>(&row.tags) else {
+        return Some(Problem::InvalidTags);
+    };
+    let event = NostrEvent {
+        tags,
+        ..row.to_nostr()
+    };
+    if event.compute_id() != event.id {
+        return Some(Problem::IdMismatch);
+    }
+    if event.verify().is_err() {
+        return Some(Problem::InvalidSignature);
+    }
+    let Some((folder, ref_event)) = ingest::classify(&event) else {
+        return Some(Problem::Unclassified);
+    };
+    (folder != row.folder || ref_event != row.ref_event)
+        .then_some(Problem::Misfiled { folder, ref_event })
+}
+
+/// Moves a row that cannot be trusted to the quarantine table
+async fn quarantine(
+    db_pool: &SqlitePool,
+    event_id: &str,
+    problem: &Problem,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO quarantine
+             (event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event, problem,
+              quarantined_at)
+         SELECT event_id, pubkey, created_at, kind, content, sig, tags, folder, ref_event, ?, ?
+         FROM events WHERE event_id = ?",
+    )
+    .bind(problem.name())
+    .bind(nostr::now() as i64)
+    .bind(event_id)
+    .execute(&mut tx)
+    .await?;
+    sqlx::query("DELETE FROM events WHERE event_id = ?")
+        .bind(event_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await
+}
+
+/// Checks the id, signature, tags and folder of every archived event and prints a report.
+/// With `--fix`, events that cannot be trusted are moved to the `quarantine` table and
+/// misfiled ones are moved to the folder they belong in.
+pub async fn verify(args: &[String], db_pool: &SqlitePool) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let db_error = |e: sqlx::Error| format!("Database query error: {:?}", e);
+
+    let mut checked = 0u64;
+    let mut problems: BTreeMap<&'static str, u64> = BTreeMap::new();
+    let mut quarantined = 0u64;
+    let mut refiled = 0u64;
+    let mut last_rowid = 0i64;
+    loop {
+        let rows: Vec<Row> = sqlx::query_as(
+            "SELECT rowid, event_id, pubkey, created_at, kind, content, sig, tags, folder,
+                 ref_event
+             FROM events WHERE rowid > ? ORDER BY rowid LIMIT ?",
+        )
+        .bind(last_rowid)
+        .bind(BATCH_SIZE)
+        .fetch_all(db_pool)
+        .await
+        .map_err(db_error)?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        last_rowid = last.rowid;
+        for Row { event: row, .. } in rows {
+            checked += 1;
+            let Some(problem) = check(&row) else {
+                continue;
+            };
+            *problems.entry(problem.name()).or_default() += 1;
+            match &problem {
+                Problem::Misfiled { folder, ref_event } => {
+                    println!(
+                        "{}: misfiled in {} ({}), belongs in {} ({})",
+                        row.event_id,
+                        row.folder,
+                        row.ref_event.as_deref().unwrap_or("no reference"),
+                        folder,
+                        ref_event.as_deref().unwrap_or("no reference")
+                    );
+                    if options.fix {
+                        sqlx::query(
+                            "UPDATE events SET folder = ?, ref_event = ? WHERE event_id = ?",
+                        )
+                        .bind(folder)
+                        .bind(ref_event)
+                        .bind(&row.event_id)
+                        .execute(db_pool)
+                        .await
+                        .map_err(db_error)?;
+                        refiled += 1;
+                    }
+                }
+                problem => {
+                    println!("{}: {}", row.event_id, problem.name());
+                    if options.fix {
+                        quarantine(db_pool, &row.event_id, problem)
+                            .await
+                            .map_err(db_error)?;
+                        quarantined += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let total: u64 = problems.values().sum();
+    println!("Checked {} events, {} with problems", checked, total);
+    for (name, count) in &problems {
+        println!("  {}: {}", name, count);
+    }
+    if options.fix {
+        println!(
+            "Quarantined {} events, re-filed {} events",
+            quarantined, refiled
+        );
+    } else if total > 0 {
+        println!("Run `chest verify --fix` to quarantine or re-file them");
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+
+use crate::error::ApiError;
+use crate::stream::{self, EVENT_COLUMNS};
+use crate::{nostr, FormatQuery};
+
+/// Builds the query selecting every stored version of a replaceable event, newest first: the
+/// current one and, when versions are kept, the superseded ones
+fn versions_query(
+    columns: &str,
+    pubkey: &str,
+    kind: u64,
+    d_tag: Option<&str>,
+) -> QueryBuilder<'static, Sqlite> {
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM (", columns));
+    for (index, table) in ["events", "event_versions"].into_iter().enumerate() {
+        if index > 0 {
+            query.push(" UNION ALL ");
+        }
+        query.push(format!(
+            "SELECT {} FROM {} WHERE pubkey = ",
+            EVENT_COLUMNS, table
+        ));
+        query.push_bind(pubkey.to_string());
+        query.push(" AND kind = ");
+        query.push_bind(kind as i64);
+        if let Some(d_tag) = d_tag {
+            query.push(" AND d_tag = ");
+            query.push_bind(d_tag.to_string());
+        }
+    }
+    query.push(") ORDER BY created_at DESC, event_id DESC");
+    query
+}
+
+/// Lists the stored versions of a long-form article, newest first, to follow its edits.
+pub async fn long_versions(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let (input, d_tag) = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    stream::event_listing(&req, db_pool.get_ref(), params.format, move |columns| {
+        versions_query(columns, &pubkey, 30023, Some(&d_tag))
+    })
+    .await
+}
+
+/// Lists the stored versions of a profile, newest first, to follow its changes.
+pub async fn profile_versions(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<FormatQuery>,
+    db_pool: web::Data<SqlitePool>,
+) -> Result<HttpResponse, ApiError> {
+    let input = path.into_inner();
+    let pubkey = nostr::parse_pubkey(&input)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid pubkey: {}", input)))?;
+    stream::event_listing(&req, db_pool.get_ref(), params.format, move |columns| {
+        versions_query(columns, &pubkey, 0, None)
+    })
+    .await
+}
@@ -0,0 +1,150 @@
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::{nostr, FilterAction, WotConfig};
+
+/// Number of pubkeys bound per query when walking follow lists or clearing flags
+const FOLLOWS_CHUNK_SIZE: usize = 500;
+
+/// How an incoming event is treated by the web of trust
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Flag,
+    Drop,
+}
+
+/// Set of pubkeys within the configured follow distance of the root pubkey
+#[derive(Debug)]
+pub struct WebOfTrust {
+    config: WotConfig,
+    /// Hex root pubkey; `None` when the filter is disabled
+    root: Option<String>,
+    trusted: RwLock<HashSet<String>>,
+}
+
+impl WebOfTrust {
+    pub fn new(config: WotConfig) -> Self {
+        let root = if config.enabled {
+            let root = nostr::parse_pubkey(&config.root_pubkey);
+            if root.is_none() {
+                eprintln!(
+                    "Web of trust disabled: invalid root pubkey {:?}",
+                    config.root_pubkey
+                );
+            }
+            root
+        } else {
+            None
+        };
+        let trusted = root.iter().cloned().collect();
+        Self {
+            config,
+            root,
+            trusted: RwLock::new(trusted),
+        }
+    }
+
+    /// Returns whether the filter is enabled and `pubkey` is within the follow distance
+    pub fn trusts(&self, pubkey: &str) -> bool {
+        self.root.is_some() && self.trusted.read().unwrap().contains(pubkey)
+    }
+
+    /// Decides whether events of `pubkey` are archived, flagged or dropped
+    pub fn verdict(&self, pubkey: &str) -> Verdict {
+        if self.root.is_none() || self.trusted.read().unwrap().contains(pubkey) {
+            return Verdict::Accept;
+        }
+        match self.config.action {
+            FilterAction::Drop => Verdict::Drop,
+            FilterAction::Flag => Verdict::Flag,
+        }
+    }
+
+    /// Recomputes the trusted set by walking archived follow lists breadth-first from the root,
+    /// returning its size
+    pub async fn refresh(&self, db_pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+        let Some(root) = &self.root else {
+            return Ok(0);
+        };
+        let mut trusted = HashSet::from([root.clone()]);
+        let mut frontier = vec![root.clone()];
+
+        for _ in 0..self.config.max_hops {
+            let mut next = Vec::new();
+            for chunk in frontier.chunks(FOLLOWS_CHUNK_SIZE) {
+                for follow in fetch_follows(chunk, db_pool).await? {
+                    if nostr::is_hex32(&follow) && trusted.insert(follow.clone()) {
+                        next.push(follow);
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+
+        // Events archived before their author became trusted no longer need review.
+        if self.config.action == FilterAction::Flag {
+            let pubkeys: Vec<String> = trusted.iter().cloned().collect();
+            for chunk in pubkeys.chunks(FOLLOWS_CHUNK_SIZE) {
+                let mut query = QueryBuilder::<Sqlite>::new(
+                    "UPDATE events SET flagged = 0 WHERE flagged = 1 AND pubkey IN (",
+                );
+                let mut separated = query.separated(", ");
+                for pubkey in chunk {
+                    separated.push_bind(pubkey.clone());
+                }
+                query.push(")");
+                query.build().execute(db_pool).await?;
+            }
+        }
+
+        let size = trusted.len();
+        *self.trusted.write().unwrap() = trusted;
+        Ok(size)
+    }
+
+    /// Refreshes the trusted set now and then every `refresh_interval` seconds
+    pub fn spawn_refresh(self: Arc<Self>, db_pool: SqlitePool) {
+        if self.root.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            let period = Duration::from_secs(self.config.refresh_interval.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match self.refresh(&db_pool).await {
+                    Ok(size) => println!("Web of trust refreshed: {} trusted pubkeys", size),
+                    Err(e) => eprintln!("Failed to refresh web of trust: {:?}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Fetches the pubkeys followed in the archived kind 3 lists of `pubkeys`
+async fn fetch_follows(
+    pubkeys: &[String],
+    db_pool: &SqlitePool,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT DISTINCT CAST(json_extract(tag.value, '$[1]') AS TEXT)
+         FROM events, json_each(events.tags) AS tag
+         WHERE events.folder = 'lists' AND events.kind = 3
+           AND json_extract(tag.value, '$[0]') = 'p'
+           AND json_extract(tag.value, '$[1]') IS NOT NULL
+           AND events.pubkey IN (",
+    );
+    let mut separated = query.separated(", ");
+    for pubkey in pubkeys {
+        separated.push_bind(pubkey.clone());
+    }
+    query.push(")");
+    let rows: Vec<(String,)> = query.build_query_as().fetch_all(db_pool).await?;
+    Ok(rows.into_iter().map(|(pubkey,)| pubkey).collect())
+}